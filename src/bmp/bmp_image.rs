@@ -1,12 +1,28 @@
-use byteorder::{ ByteOrder, LittleEndian };
+use byteorder::{ ByteOrder, LittleEndian, WriteBytesExt };
 use std::fs::File;
-use std::io::{ Read, Seek, SeekFrom };
+use std::io::{ self, BufWriter, Read, Seek, SeekFrom, Write };
+use crate::jpeg::jpeg_image::Density;
 use crate::pixel_matrix::pixel_matrix::PixelMatrix;
 use crate::utils::colorspace::RGBValue;
 
 const BMP_IMAGE_DATA_START_OFFSET: u64 = 10;
+const BMP_HEADER_SIZE_OFFSET: u64 = 14;
 const BMP_PIXEL_WIDTH_OFFSET: u64 = 18;
 const BMP_PIXEL_HEIGHT_OFFSET: u64 = 22;
+const BMP_BITS_PER_PIXEL_OFFSET: u64 = 28;
+const BMP_COMPRESSION_OFFSET: u64 = 30;
+const BMP_X_PIXELS_PER_METER_OFFSET: u64 = 38;
+const BMP_Y_PIXELS_PER_METER_OFFSET: u64 = 42;
+const BMP_COLOR_MASKS_OFFSET: u64 = 54; // red, green, blue and alpha masks, right after the 40 bytes header
+const BMP_COLOR_SPACE_TYPE_OFFSET: u64 = 70;
+const BMP_PROFILE_DATA_OFFSET: u64 = 126; // relative to the start of the header, like the profile itself
+
+const BMP_BITFIELDS_COMPRESSION: u32 = 3;
+
+const BMP_V5_HEADER_SIZE: u32 = 124;
+const BMP_FILE_HEADER_SIZE: u32 = 14;
+const BMP_INFO_HEADER_SIZE: u32 = 40;
+const BMP_PROFILE_EMBEDDED: u32 = 0x4d424544; // "MBED"
 
 pub struct BmpImage {
     file: Option<File>,
@@ -14,7 +30,12 @@ pub struct BmpImage {
     pub width: i32,
     pub height: i32,
     image_data_offset: u32,
+    bits_per_pixel: u16,
+    color_masks: Option<[u32; 4]>, // for 32 bit images with bitfields, the alpha mask is 0 if there is none
     pub pixels: PixelMatrix<RGBValue>,
+    pub alpha: Option<PixelMatrix<u8>>, // only 32 bit images have it
+    pub density: Option<Density>, // None if the file doesn't set its resolution
+    pub icc_profile: Option<Vec<u8>>, // only V5 headers can have an embedded profile
 }
 
 impl BmpImage {
@@ -42,7 +63,46 @@ impl BmpImage {
         _ = file.read_exact(&mut metadata_buffer);
         height = LittleEndian::read_i32(&metadata_buffer);
 
-        let pixels = PixelMatrix::new_with_default(width as usize, height as usize);
+        _ = file.seek(SeekFrom::Start(BMP_BITS_PER_PIXEL_OFFSET));
+        _ = file.read_exact(&mut metadata_buffer[0..2]);
+        let bits_per_pixel = LittleEndian::read_u16(&metadata_buffer[0..2]);
+        if bits_per_pixel != 24 && bits_per_pixel != 32 {
+            panic!("Only 24 and 32 bit bitmap images are supported!\n");
+        }
+
+        _ = file.seek(SeekFrom::Start(BMP_COMPRESSION_OFFSET));
+        _ = file.read_exact(&mut metadata_buffer);
+        let compression = LittleEndian::read_u32(&metadata_buffer);
+
+        _ = file.seek(SeekFrom::Start(BMP_HEADER_SIZE_OFFSET));
+        _ = file.read_exact(&mut metadata_buffer);
+        let header_size = LittleEndian::read_u32(&metadata_buffer);
+
+        let mut color_masks = None;
+        if bits_per_pixel == 32 && compression == BMP_BITFIELDS_COMPRESSION {
+            // the alpha mask is only part of the V3 header and newer ones (56 bytes or more)
+            let masks_amount = if header_size >= 56 { 4 } else { 3 };
+            let mut masks = [0u32; 4];
+            _ = file.seek(SeekFrom::Start(BMP_COLOR_MASKS_OFFSET));
+            for mask in masks.iter_mut().take(masks_amount) {
+                _ = file.read_exact(&mut metadata_buffer);
+                *mask = LittleEndian::read_u32(&metadata_buffer);
+            }
+            color_masks = Some(masks);
+        }
+
+        _ = file.seek(SeekFrom::Start(BMP_X_PIXELS_PER_METER_OFFSET));
+        _ = file.read_exact(&mut metadata_buffer);
+        let x_pixels_per_meter = LittleEndian::read_i32(&metadata_buffer);
+        _ = file.seek(SeekFrom::Start(BMP_Y_PIXELS_PER_METER_OFFSET));
+        _ = file.read_exact(&mut metadata_buffer);
+        let y_pixels_per_meter = LittleEndian::read_i32(&metadata_buffer);
+        let density = Density::from_pixels_per_meter(x_pixels_per_meter, y_pixels_per_meter);
+
+        let icc_profile = if header_size >= BMP_V5_HEADER_SIZE { Self::read_icc_profile(&mut file) } else { None };
+
+        // the pixels are only allocated when they are loaded, so rows can be read without holding the whole image
+        let pixels = PixelMatrix::new(0, 0);
 
         BmpImage {
             file: Some(file),
@@ -50,7 +110,95 @@ impl BmpImage {
             width,
             height,
             image_data_offset,
+            bits_per_pixel,
+            color_masks,
             pixels,
+            alpha: None,
+            density,
+            icc_profile,
+        }
+    }
+
+    fn read_icc_profile(file: &mut File) -> Option<Vec<u8>> {
+        // the color space type says if the profile is in the file, or if only a path to it is (which is ignored)
+        let mut metadata_buffer: [u8; 4] = [0; 4];
+        _ = file.seek(SeekFrom::Start(BMP_COLOR_SPACE_TYPE_OFFSET));
+        _ = file.read_exact(&mut metadata_buffer);
+        if LittleEndian::read_u32(&metadata_buffer) != BMP_PROFILE_EMBEDDED {
+            return None;
+        }
+
+        _ = file.seek(SeekFrom::Start(BMP_PROFILE_DATA_OFFSET));
+        _ = file.read_exact(&mut metadata_buffer);
+        let profile_offset = LittleEndian::read_u32(&metadata_buffer) as u64;
+        _ = file.read_exact(&mut metadata_buffer);
+        let profile_size = LittleEndian::read_u32(&metadata_buffer) as usize;
+
+        let mut profile = vec![0u8; profile_size];
+        _ = file.seek(SeekFrom::Start(BMP_HEADER_SIZE_OFFSET + profile_offset));
+        file.read_exact(&mut profile).ok().map(|_| profile)
+    }
+
+    fn get_masked_value(pixel: u32, mask: u32) -> u8 {
+        // the value of the bits under the mask, scaled to 8 bits
+        if mask == 0 {
+            return 0;
+        }
+        let value = (pixel & mask) >> mask.trailing_zeros();
+        let max_value = mask >> mask.trailing_zeros();
+        ((value * 255 + max_value / 2) / max_value) as u8
+    }
+
+    fn get_row_size(&self) -> usize {
+        // each line is padded so that its size in bytes is a multiple of 4 (BMP format)
+        ((self.width as usize) * (self.bits_per_pixel as usize / 8)).div_ceil(4) * 4
+    }
+
+    fn decode_pixel(&self, pixel_buffer: &[u8; 4]) -> (RGBValue, u8) {
+        match self.color_masks {
+            Some(masks) if self.bits_per_pixel == 32 => {
+                let pixel = LittleEndian::read_u32(pixel_buffer);
+                (
+                    (
+                        Self::get_masked_value(pixel, masks[0]),
+                        Self::get_masked_value(pixel, masks[1]),
+                        Self::get_masked_value(pixel, masks[2]),
+                    ),
+                    Self::get_masked_value(pixel, masks[3]),
+                )
+            }
+            // BMP stores the pixel in BGR order (yeah, not kidding), 32 bit images have the alpha at the end
+            _ => ((pixel_buffer[2], pixel_buffer[1], pixel_buffer[0]), pixel_buffer[3]),
+        }
+    }
+
+    pub fn read_row(&mut self, row: usize) -> Vec<(RGBValue, u8)> {
+        // a single row (counting from the top), with the alpha of each pixel, for encoders that don't hold the whole image
+        let row_size = self.get_row_size();
+        let bytes_per_pixel = (self.bits_per_pixel / 8) as usize;
+        let row_offset = (self.image_data_offset as u64) + (((self.height as usize) - 1 - row) * row_size) as u64;
+
+        let mut row_buffer = vec![0u8; row_size];
+        _ = self.file.as_ref().unwrap().seek(SeekFrom::Start(row_offset));
+        _ = self.file.as_ref().unwrap().read_exact(&mut row_buffer);
+
+        let mut pixel_buffer: [u8; 4] = [0u8; 4];
+        row_buffer
+            .chunks(bytes_per_pixel)
+            .take(self.width as usize)
+            .map(|pixel| {
+                pixel_buffer[0..bytes_per_pixel].copy_from_slice(pixel);
+                self.decode_pixel(&pixel_buffer)
+            })
+            .collect()
+    }
+
+    pub fn has_alpha_channel(&mut self) -> bool {
+        // the same rule as load_pixels, reading the rows one at a time when the fourth byte has to be checked
+        match self.color_masks {
+            _ if self.bits_per_pixel != 32 => false,
+            Some(masks) => masks[3] != 0,
+            None => (0..self.height as usize).any(|row| self.read_row(row).iter().any(|(_, alpha)| *alpha != 0)),
         }
     }
 
@@ -60,25 +208,30 @@ impl BmpImage {
             .unwrap()
             .seek(SeekFrom::Start(self.image_data_offset as u64));
 
-        let mut pixel_buffer: [u8; 3] = [0u8; 3];
+        self.pixels = PixelMatrix::new_with_default(self.width as usize, self.height as usize);
+        if self.bits_per_pixel == 32 {
+            self.alpha = Some(PixelMatrix::new_with_default(self.width as usize, self.height as usize));
+        }
+
+        let bytes_per_pixel = (self.bits_per_pixel / 8) as usize;
+        let mut pixel_buffer: [u8; 4] = [0u8; 4];
 
         // the file image data in bmp files goes left to right, bottom to top
         // here, it will be stored left to right, top to bottom
 
-        let bytes_to_ignore = ((self.width as u32) * 3).div_ceil(4) * 4 - (self.width as u32) * 3; // the * 3 is to account for 3 bytes for each pixel
+        let bytes_to_ignore = self.get_row_size() - (self.width as usize) * bytes_per_pixel;
 
         for row in (0..self.height as usize).rev() {
             for col in 0..self.width as usize {
-                _ = self.file.as_ref().unwrap().read(&mut pixel_buffer);
+                _ = self.file.as_ref().unwrap().read(&mut pixel_buffer[0..bytes_per_pixel]);
 
-                // BMP stores the pixel in BGR order (yeah, not kidding)
-                self.pixels.set_pixel(row, col, (
-                    pixel_buffer[2],
-                    pixel_buffer[1],
-                    pixel_buffer[0],
-                ));
+                let (pixel, alpha) = self.decode_pixel(&pixel_buffer);
+                self.pixels.set_pixel(row, col, pixel);
+                if let Some(alpha_channel) = &mut self.alpha {
+                    alpha_channel.set_pixel(row, col, alpha);
+                }
 
-                // ignore bytes added at the end of each line so that its size in bytes is a multiple of 4 (BMP format)
+                // ignore bytes added at the end of each line
                 if col == (self.width as usize) - 1 {
                     for _ in 0..bytes_to_ignore {
                         let mut aux = [0u8];
@@ -87,9 +240,117 @@ impl BmpImage {
                 }
             }
         }
+
+        // Many 32 bit images don't use the fourth byte, leaving it at 0, and bitfield images may not have an alpha mask.
+        // In both cases, the image is opaque
+        let has_alpha = match (&self.alpha, self.color_masks) {
+            (Some(_), Some(masks)) => masks[3] != 0,
+            (Some(alpha), None) => alpha.pixels.iter().any(|a| *a != 0),
+            (None, _) => false,
+        };
+        if !has_alpha {
+            self.alpha = None;
+        }
     }
 
     pub fn pixel_amount(&self) -> i32 {
         self.width * self.height
     }
+
+    pub fn from_pixels(pixels: PixelMatrix<RGBValue>, density: Option<Density>) -> BmpImage {
+        // an image to be written, which is always 24 bits
+        BmpImage {
+            file: None,
+            path: None,
+            width: pixels.width as i32,
+            height: pixels.height as i32,
+            image_data_offset: BMP_FILE_HEADER_SIZE + BMP_INFO_HEADER_SIZE,
+            bits_per_pixel: 24,
+            color_masks: None,
+            pixels,
+            alpha: None,
+            density,
+            icc_profile: None,
+        }
+    }
+
+    pub fn write_bmp(&self, file: &mut impl Write) -> io::Result<()> {
+        let row_size = self.get_row_size();
+        let image_size = row_size * (self.height as usize);
+        let (x_pixels_per_meter, y_pixels_per_meter) = self.density
+            .as_ref()
+            .and_then(|density| density.to_pixels_per_meter())
+            .unwrap_or((0, 0));
+
+        // file header
+        file.write_all(b"BM")?;
+        file.write_u32::<LittleEndian>(self.image_data_offset + (image_size as u32))?; // file size
+        file.write_u32::<LittleEndian>(0)?; // reserved
+        file.write_u32::<LittleEndian>(self.image_data_offset)?;
+
+        // info header, a positive height means the rows go bottom to top
+        file.write_u32::<LittleEndian>(BMP_INFO_HEADER_SIZE)?;
+        file.write_i32::<LittleEndian>(self.width)?;
+        file.write_i32::<LittleEndian>(self.height)?;
+        file.write_u16::<LittleEndian>(1)?; // planes
+        file.write_u16::<LittleEndian>(self.bits_per_pixel)?;
+        file.write_u32::<LittleEndian>(0)?; // no compression
+        file.write_u32::<LittleEndian>(image_size as u32)?;
+        file.write_i32::<LittleEndian>(x_pixels_per_meter)?;
+        file.write_i32::<LittleEndian>(y_pixels_per_meter)?;
+        file.write_u32::<LittleEndian>(0)?; // colors in the palette
+        file.write_u32::<LittleEndian>(0)?; // important colors
+
+        // like in load_pixels, the rows go bottom to top, in BGR order and padded to a multiple of 4 bytes
+        let mut row_buffer = vec![0u8; row_size];
+        for row in (0..self.height as usize).rev() {
+            for col in 0..self.width as usize {
+                let (r, g, b) = self.pixels.get_pixel(row, col).unwrap();
+                row_buffer[col * 3..col * 3 + 3].copy_from_slice(&[b, g, r]);
+            }
+            file.write_all(&row_buffer)?;
+        }
+        Ok(())
+    }
+
+    pub fn generate_file(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_bmp(&mut file)?;
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::jpeg_image::DensityUnit;
+
+    #[test]
+    fn test_write_and_read_back() {
+        // 3 pixels of 3 bytes are padded to 12 bytes per row
+        let pixels = PixelMatrix::new_from_pixels(3, 2, vec![
+            (1, 2, 3),
+            (4, 5, 6),
+            (7, 8, 9),
+            (10, 11, 12),
+            (13, 14, 15),
+            (16, 17, 18),
+        ]);
+        let density = Density { unit: DensityUnit::DotsPerCm, x: 20, y: 40 };
+        let image = BmpImage::from_pixels(pixels, Some(density.clone()));
+        let mut data = Vec::new();
+        image.write_bmp(&mut data).unwrap();
+        assert_eq!(data.len(), 54 + 2 * 12);
+        // the bottom row goes first
+        assert_eq!(&data[54..66], &[12, 11, 10, 15, 14, 13, 18, 17, 16, 0, 0, 0]);
+
+        let path = std::env::temp_dir().join("bmp_image_test_write_and_read_back.bmp");
+        std::fs::write(&path, &data).unwrap();
+        let mut read_image = BmpImage::new(&path.to_str().unwrap().to_string());
+        read_image.load_pixels();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((read_image.width, read_image.height), (3, 2));
+        assert_eq!(read_image.pixels.pixels, image.pixels.pixels);
+        assert_eq!(read_image.density, Some(density));
+    }
 }
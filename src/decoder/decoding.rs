@@ -0,0 +1,112 @@
+use crate::decoder::idct::IdctAlgorithm;
+use crate::decoder::jpeg_file::JpegFile;
+use crate::decoder::upsampling::Upsampling;
+use crate::jpeg::jpeg_image::ColorSpace;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::{ RGBValue, ycbcr_to_rgb };
+
+impl JpegFile {
+    pub fn decode(&self, upsampling: Upsampling, idct_algorithm: IdctAlgorithm) -> PixelMatrix<RGBValue> {
+        // Every component is taken back to samples at the size of the image, and then to RGB.
+        // Samples of more than 8 bits are rounded to 8, since that is what the output holds
+        let (max_horizontal, max_vertical) = self.get_max_sampling();
+        let shift = self.precision - 8;
+        let channels: Vec<Vec<u8>> = self.components
+            .iter()
+            .map(|component| {
+                if max_horizontal % component.horizontal_sampling != 0 || max_vertical % component.vertical_sampling != 0 {
+                    panic!("Unsupported sampling factors in the JPEG image\n");
+                }
+                let samples = Self::upsample(
+                    &self.get_component_samples(component, idct_algorithm),
+                    max_horizontal / component.horizontal_sampling,
+                    max_vertical / component.vertical_sampling,
+                    self.width,
+                    self.height,
+                    upsampling
+                );
+                samples.pixels
+                    .iter()
+                    .map(|sample| (((*sample as u32) + ((1 << shift) >> 1)) >> shift).min(255) as u8)
+                    .collect()
+            })
+            .collect();
+
+        // CMYK is stored inverted, so each inverted ink is the amount of its color that is left, scaled by the black
+        let apply_black = |value: u8, k: u8| (((value as u32) * (k as u32) + 127) / 255) as u8;
+        let color_space = self.get_color_space();
+        let pixels = (0..self.width * self.height)
+            .map(|i| {
+                match color_space {
+                    ColorSpace::Grayscale => (channels[0][i], channels[0][i], channels[0][i]),
+                    ColorSpace::Rgb => (channels[0][i], channels[1][i], channels[2][i]),
+                    ColorSpace::YCbCr => ycbcr_to_rgb(&(channels[0][i], channels[1][i], channels[2][i])),
+                    ColorSpace::Cmyk => {
+                        let k = channels[3][i];
+                        (apply_black(channels[0][i], k), apply_black(channels[1][i], k), apply_black(channels[2][i], k))
+                    }
+                    ColorSpace::Ycck => {
+                        // the YCbCr of YCCK is the one of the amounts of ink, not of the inverted ones
+                        let (c, m, y) = ycbcr_to_rgb(&(channels[0][i], channels[1][i], channels[2][i]));
+                        let k = channels[3][i];
+                        (apply_black(255 - c, k), apply_black(255 - m, k), apply_black(255 - y, k))
+                    }
+                }
+            })
+            .collect();
+        PixelMatrix::new_from_pixels(self.width, self.height, pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JpegImage;
+    use crate::jpeg::test_images::{ encode_test_image, get_test_image };
+
+    fn encode_smooth_image(width: usize, height: usize, ratio: (u8, u8, u8), color_space: ColorSpace) -> (Vec<RGBValue>, Vec<u8>) {
+        // smooth colors, which come back close to the original after the quantization
+        let get_pixel = |row: usize, col: usize| ((40 + row * 3) as u16, (200 - col * 2) as u16, (60 + row + col) as u16);
+        let conversion = if color_space == ColorSpace::Rgb { JpegImage::keep_rgb } else { JpegImage::convert_to_ycbcr };
+        let mut image = get_test_image(width, height, ratio, |row, col| conversion(get_pixel(row, col), 8));
+        image.color_space = color_space;
+        image.chrominance_downsampling();
+        image.dct_and_quantization();
+        let pixels = (0..width * height)
+            .map(|i| {
+                let (r, g, b) = get_pixel(i / width, i % width);
+                (r as u8, g as u8, b as u8)
+            })
+            .collect();
+        (pixels, encode_test_image(&mut image))
+    }
+
+    fn get_max_difference(a: &[RGBValue], b: &[RGBValue]) -> u8 {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| a.0.abs_diff(b.0).max(a.1.abs_diff(b.1)).max(a.2.abs_diff(b.2)))
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_decode_subsampled_image() {
+        // whole MCUs, since the encoder pads the edges with zeros, which ring into the last samples
+        let (pixels, data) = encode_smooth_image(48, 32, (4, 2, 0), ColorSpace::YCbCr);
+        let jpeg_file = JpegFile::from_bytes(&data);
+        for upsampling in [Upsampling::Nearest, Upsampling::Fancy] {
+            for idct_algorithm in [IdctAlgorithm::Float, IdctAlgorithm::Integer] {
+                let decoded = jpeg_file.decode(upsampling, idct_algorithm);
+                assert_eq!((decoded.width, decoded.height), (48, 32));
+                assert!(get_max_difference(&decoded.pixels, &pixels) <= 16);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_rgb_image() {
+        let (pixels, data) = encode_smooth_image(16, 16, (4, 4, 4), ColorSpace::Rgb);
+        let decoded = JpegFile::from_bytes(&data).decode(Upsampling::Fancy, IdctAlgorithm::Integer);
+        assert!(get_max_difference(&decoded.pixels, &pixels) <= 16);
+    }
+}
@@ -0,0 +1,144 @@
+use clap::ValueEnum;
+use std::f32::consts::{ PI, SQRT_2 };
+use crate::decoder::jpeg_file::{ JpegComponent, JpegFile };
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+
+// fractional bits of the cosines of the integer IDCT, and the extra bits kept between its two passes, like libjpeg
+const CONST_BITS: u32 = 13;
+const PASS1_BITS: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum IdctAlgorithm {
+    Float,
+    Integer, // fixed point, with the same results on every platform
+}
+
+impl JpegFile {
+    fn get_idct_cosines() -> [[f32; 8]; 8] {
+        // the weight of frequency u in sample x, for a single dimension
+        let mut cosines = [[0.0; 8]; 8];
+        for (x, row) in cosines.iter_mut().enumerate() {
+            for (u, cosine) in row.iter_mut().enumerate() {
+                let alpha = if u == 0 { 1.0 / SQRT_2 } else { 1.0 };
+                *cosine = 0.5 * alpha * (((((2 * x + 1) * u) as f32) * PI) / 16.0).cos();
+            }
+        }
+        cosines
+    }
+
+    pub fn inverse_float_dct(coeffs: &[i32; 64]) -> [i32; 64] {
+        // the columns first and then the rows, which is the 2D formula split in two
+        let cosines = Self::get_idct_cosines();
+        let mut columns = [0.0f32; 64];
+        for x in 0..8 {
+            for v in 0..8 {
+                columns[x * 8 + v] = (0..8).map(|u| cosines[x][u] * (coeffs[u * 8 + v] as f32)).sum();
+            }
+        }
+        let mut samples = [0i32; 64];
+        for x in 0..8 {
+            for y in 0..8 {
+                let sample: f32 = (0..8).map(|v| cosines[y][v] * columns[x * 8 + v]).sum();
+                samples[x * 8 + y] = sample.round() as i32;
+            }
+        }
+        samples
+    }
+
+    pub fn inverse_integer_dct(coeffs: &[i32; 64]) -> [i32; 64] {
+        // Same as the float one with the cosines in fixed point. The first pass keeps a few fractional bits,
+        // and the sums are 64 bits wide so that corrupt coefficients can't overflow them
+        let cosines = Self::get_idct_cosines().map(|row| {
+            row.map(|cosine| (cosine * ((1 << CONST_BITS) as f32)).round() as i64)
+        });
+        let mut columns = [0i64; 64];
+        for x in 0..8 {
+            for v in 0..8 {
+                let sum: i64 = (0..8).map(|u| cosines[x][u] * (coeffs[u * 8 + v] as i64)).sum();
+                columns[x * 8 + v] = (sum + (1 << (CONST_BITS - PASS1_BITS - 1))) >> (CONST_BITS - PASS1_BITS);
+            }
+        }
+        let mut samples = [0i32; 64];
+        for x in 0..8 {
+            for y in 0..8 {
+                let sum: i64 = (0..8).map(|v| cosines[y][v] * columns[x * 8 + v]).sum();
+                samples[x * 8 + y] = ((sum + (1 << (CONST_BITS + PASS1_BITS - 1))) >> (CONST_BITS + PASS1_BITS)) as i32;
+            }
+        }
+        samples
+    }
+
+    pub fn get_component_size(&self, component: &JpegComponent) -> (usize, usize) {
+        // the samples of the component that are part of the image, without the padding of the last MCUs
+        let (max_horizontal, max_vertical) = self.get_max_sampling();
+        (
+            (self.width * component.horizontal_sampling).div_ceil(max_horizontal),
+            (self.height * component.vertical_sampling).div_ceil(max_vertical),
+        )
+    }
+
+    pub fn get_component_samples(&self, component: &JpegComponent, idct_algorithm: IdctAlgorithm) -> PixelMatrix<u16> {
+        // dequantizes every block, takes it back to samples and undoes the level shift of the encoder
+        let idct = match idct_algorithm {
+            IdctAlgorithm::Float => Self::inverse_float_dct,
+            IdctAlgorithm::Integer => Self::inverse_integer_dct,
+        };
+        let quantization_table = self.quantization_tables[component.quantization_table].expect(
+            "JPEG component uses an undefined quantization table\n"
+        );
+        let max_sample = (1i32 << self.precision) - 1;
+        let center = 1i32 << (self.precision - 1);
+
+        let (width, height) = self.get_component_size(component);
+        let mut samples = PixelMatrix::new_with_default(width, height);
+        let mut coeffs = [0i32; 64];
+        for block_row in 0..height.div_ceil(8) {
+            for block_col in 0..width.div_ceil(8) {
+                for (i, coeff) in coeffs.iter_mut().enumerate() {
+                    let quantized = component.coeffs.pixels
+                        [(block_row * 8 + i / 8) * component.coeffs.width + block_col * 8 + (i % 8)];
+                    *coeff = (quantized as i32) * (quantization_table[i] as i32);
+                }
+                let block = idct(&coeffs);
+                for (i, sample) in block.iter().enumerate() {
+                    let (row, col) = (block_row * 8 + i / 8, block_col * 8 + (i % 8));
+                    if row < height && col < width {
+                        samples.set_pixel(row, col, (sample + center).clamp(0, max_sample) as u16);
+                    }
+                }
+            }
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JpegImage;
+
+    #[test]
+    fn test_idct_inverts_the_encoder_dct() {
+        // the samples come back up to the rounding of the coefficients
+        let mut samples = [0i32; 64];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            *sample = ((i * 37) % 200) as i32 - 100;
+        }
+        let coeffs = JpegImage::forward_real_dct(&samples).map(|coeff| coeff.round() as i32);
+        for idct in [JpegFile::inverse_float_dct, JpegFile::inverse_integer_dct] {
+            let decoded = idct(&coeffs);
+            for i in 0..64 {
+                assert!((decoded[i] - samples[i]).abs() <= 1, "{} {}", decoded[i], samples[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dc_only_block() {
+        // a DC coefficient of 8 times the value makes a flat block
+        let mut coeffs = [0i32; 64];
+        coeffs[0] = -8 * 25;
+        assert_eq!(JpegFile::inverse_float_dct(&coeffs), [-25; 64]);
+        assert_eq!(JpegFile::inverse_integer_dct(&coeffs), [-25; 64]);
+    }
+}
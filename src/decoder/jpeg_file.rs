@@ -0,0 +1,739 @@
+use byteorder::{ BigEndian, ByteOrder };
+use crate::JpegImage;
+use crate::jpeg::huffman_tables::ZIG_ZAG_MAP;
+use crate::jpeg::jpeg_image::{ ColorSpace, Density, DensityUnit };
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+
+const MARKER_SOF0: u8 = 0xc0; // baseline
+const MARKER_SOF1: u8 = 0xc1; // extended, Huffman coding
+const MARKER_SOF2: u8 = 0xc2; // progressive, Huffman coding
+const MARKER_DHT: u8 = 0xc4;
+const MARKER_RST0: u8 = 0xd0;
+const MARKER_RST7: u8 = 0xd7;
+const MARKER_SOI: u8 = 0xd8;
+const MARKER_EOI: u8 = 0xd9;
+const MARKER_SOS: u8 = 0xda;
+const MARKER_DQT: u8 = 0xdb;
+const MARKER_DRI: u8 = 0xdd;
+const MARKER_APP0: u8 = 0xe0;
+const MARKER_APP15: u8 = 0xef;
+const MARKER_COM: u8 = 0xfe;
+
+pub struct JpegComponent {
+    pub id: u8,
+    pub horizontal_sampling: usize,
+    pub vertical_sampling: usize,
+    pub quantization_table: usize,
+    // quantized coefficients, stored like y_dct_coeffs: each 8x8 block in natural order, padded to whole MCUs
+    pub coeffs: PixelMatrix<i16>,
+}
+
+pub struct JpegFile {
+    pub width: usize,
+    pub height: usize,
+    pub precision: u8,
+    pub progressive: bool,
+    pub components: Vec<JpegComponent>,
+    pub quantization_tables: [Option<[u16; 64]>; 4], // in natural order
+    pub segments: Vec<(u8, Vec<u8>)>, // the APPn and COM segments (marker and content), in the order of the file
+}
+
+// the part of the coefficients that a scan holds: a band of them in zigzag order, from the high bit to the low bit.
+// Sequential scans hold all of them at once
+struct Scan {
+    spectral_start: usize,
+    spectral_end: usize,
+    high_bit: u8, // 0 in the first scan of the band, otherwise the low bit of the previous one
+    low_bit: u8,
+}
+
+// canonical Huffman code, decoded by comparing the code read so far with the biggest code of each length
+struct HuffmanDecoder {
+    max_codes: [i32; 17], // -1 if there are no codes of that length
+    first_indexes: [i32; 17], // index in symbols of the first code of each length, minus that code
+    symbols: Vec<u8>,
+}
+
+impl HuffmanDecoder {
+    fn new(code_counts: &[u8], symbols: &[u8]) -> HuffmanDecoder {
+        let mut max_codes = [-1; 17];
+        let mut first_indexes = [0; 17];
+        let mut code = 0i32;
+        let mut index = 0i32;
+        for length in 1..=16 {
+            let count = code_counts[length - 1] as i32;
+            if count > 0 {
+                first_indexes[length] = index - code;
+                code += count;
+                index += count;
+                max_codes[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        HuffmanDecoder { max_codes, first_indexes, symbols: symbols.to_vec() }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> u8 {
+        let mut code = 0i32;
+        for length in 1..=16 {
+            code = (code << 1) | (reader.read_bit() as i32);
+            if code <= self.max_codes[length] {
+                return self.symbols[(self.first_indexes[length] + code) as usize];
+            }
+        }
+        panic!("Invalid Huffman code in JPEG data\n");
+    }
+}
+
+// reads the entropy coded data, removing the stuffed zeros after 0xff bytes.
+// Once a marker is found, it only returns zeros until the reader is restarted
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    byte: u8,
+    bits_left: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8], position: usize) -> BitReader<'a> {
+        BitReader { data, position, byte: 0, bits_left: 0 }
+    }
+
+    fn read_bit(&mut self) -> u8 {
+        if self.bits_left == 0 {
+            self.byte = match (self.data.get(self.position), self.data.get(self.position + 1)) {
+                (Some(0xff), Some(0x00)) => {
+                    self.position += 2;
+                    0xff
+                }
+                (Some(0xff), _) | (None, _) => 0, // a marker, or the end of the file
+                (Some(byte), _) => {
+                    self.position += 1;
+                    *byte
+                }
+            };
+            self.bits_left = 8;
+        }
+        self.bits_left -= 1;
+        (self.byte >> self.bits_left) & 1
+    }
+
+    fn read_bits(&mut self, length: u8) -> u16 {
+        let mut value = 0u16;
+        for _ in 0..length {
+            value = (value << 1) | (self.read_bit() as u16);
+        }
+        value
+    }
+
+    fn restart(&mut self) {
+        // the rest of the current byte is padding, and a restart marker follows
+        self.bits_left = 0;
+        if
+            self.data.get(self.position) == Some(&0xff) &&
+            self.data.get(self.position + 1).is_some_and(|marker| (MARKER_RST0..=MARKER_RST7).contains(marker))
+        {
+            self.position += 2;
+        }
+    }
+}
+
+impl JpegFile {
+    pub fn new(path: &String) -> JpegFile {
+        let data = std::fs::read(path).expect("Could not open JPEG image file!\n");
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> JpegFile {
+        // Only images with Huffman coding (baseline, extended and progressive) are supported, which are the most common ones.
+        // Their coefficients are read as they are, without dequantizing them
+        if data.len() < 4 || data[0] != 0xff || data[1] != MARKER_SOI {
+            panic!("Invalid JPEG header\n");
+        }
+
+        let mut jpeg_file = JpegFile {
+            width: 0,
+            height: 0,
+            precision: 8,
+            progressive: false,
+            components: Vec::new(),
+            quantization_tables: [None; 4],
+            segments: Vec::new(),
+        };
+        let mut dc_tables: [Option<HuffmanDecoder>; 4] = [None, None, None, None];
+        let mut ac_tables: [Option<HuffmanDecoder>; 4] = [None, None, None, None];
+        let mut restart_interval = 0;
+
+        let mut position = 2;
+        loop {
+            // markers can be preceded by any amount of 0xff fill bytes
+            while data.get(position) == Some(&0xff) && data.get(position + 1) == Some(&0xff) {
+                position += 1;
+            }
+            if position + 2 > data.len() || data[position] != 0xff {
+                panic!("Invalid JPEG marker at byte {}\n", position);
+            }
+            let marker = data[position + 1];
+            if marker == MARKER_EOI {
+                break;
+            }
+            let length = BigEndian::read_u16(
+                data.get(position + 2..position + 4).expect("JPEG segment goes beyond the end of the file\n")
+            ) as usize;
+            if length < 2 {
+                panic!("Invalid JPEG segment length {} at byte {}\n", length, position);
+            }
+            let content = data
+                .get(position + 4..position + 2 + length)
+                .expect("JPEG segment goes beyond the end of the file\n");
+            position += 2 + length;
+
+            match marker {
+                MARKER_SOF0 | MARKER_SOF1 | MARKER_SOF2 => {
+                    jpeg_file.progressive = marker == MARKER_SOF2;
+                    jpeg_file.read_frame_header(content);
+                }
+                0xc3..=0xcf if marker != MARKER_DHT && marker != 0xc8 && marker != 0xcc => {
+                    panic!("Only sequential and progressive JPEG images with Huffman coding are supported\n");
+                }
+                MARKER_DHT => {
+                    let mut offset = 0;
+                    while offset < content.len() {
+                        Self::check_segment_length(&content[offset..], 17, "DHT");
+                        let (class, id) = ((content[offset] >> 4) as usize, (content[offset] & 0x0f) as usize);
+                        if class > 1 || id > 3 {
+                            panic!("Invalid Huffman table {} of class {}\n", id, class);
+                        }
+                        let code_counts = &content[offset + 1..offset + 17];
+                        let symbols_amount = code_counts
+                            .iter()
+                            .map(|count| *count as usize)
+                            .sum::<usize>();
+                        if symbols_amount > 256 {
+                            panic!("Huffman table {} of class {} has more than 256 symbols\n", id, class);
+                        }
+                        Self::check_segment_length(&content[offset..], 17 + symbols_amount, "DHT");
+                        let symbols = &content[offset + 17..offset + 17 + symbols_amount];
+                        let decoder = Some(HuffmanDecoder::new(code_counts, symbols));
+                        if class == 0 {
+                            dc_tables[id] = decoder;
+                        } else {
+                            ac_tables[id] = decoder;
+                        }
+                        offset += 17 + symbols_amount;
+                    }
+                }
+                MARKER_DQT => {
+                    let mut offset = 0;
+                    while offset < content.len() {
+                        let (entry_size, id) = (1 + (content[offset] >> 4) as usize, (content[offset] & 0x0f) as usize);
+                        if entry_size > 2 || id > 3 {
+                            panic!("Invalid quantization table {} with {} byte entries\n", id, entry_size);
+                        }
+                        Self::check_segment_length(&content[offset..], 1 + 64 * entry_size, "DQT");
+                        let mut table = [0u16; 64];
+                        for (i, index) in ZIG_ZAG_MAP.iter().enumerate() {
+                            let entry = offset + 1 + i * entry_size;
+                            table[*index] = if entry_size == 2 {
+                                BigEndian::read_u16(&content[entry..entry + 2])
+                            } else {
+                                content[entry] as u16
+                            };
+                        }
+                        jpeg_file.quantization_tables[id] = Some(table);
+                        offset += 1 + 64 * entry_size;
+                    }
+                }
+                MARKER_DRI => {
+                    Self::check_segment_length(content, 2, "DRI");
+                    restart_interval = BigEndian::read_u16(content) as usize;
+                }
+                MARKER_SOS => {
+                    position = jpeg_file.read_scan(data, content, position, &dc_tables, &ac_tables, restart_interval);
+                }
+                MARKER_APP0..=MARKER_APP15 | MARKER_COM => {
+                    jpeg_file.segments.push((marker, content.to_vec()));
+                }
+                _ => {} // other segments (like DNL or DHP) are skipped
+            }
+        }
+
+        if jpeg_file.components.is_empty() {
+            panic!("The JPEG image has no frame header\n");
+        }
+        jpeg_file
+    }
+
+    fn check_segment_length(content: &[u8], needed: usize, segment: &str) {
+        // corrupt or truncated headers would otherwise be read beyond their segment
+        if content.len() < needed {
+            panic!("JPEG {} segment is {} bytes long, but its header needs {}\n", segment, content.len(), needed);
+        }
+    }
+
+    fn read_frame_header(&mut self, content: &[u8]) {
+        if !self.components.is_empty() {
+            panic!("JPEG images with more than one frame header are not supported\n");
+        }
+        Self::check_segment_length(content, 6, "SOF");
+        self.precision = content[0];
+        if self.precision != 8 && self.precision != 12 {
+            panic!("Invalid JPEG precision {}, only 8 and 12 bits are supported\n", self.precision);
+        }
+        self.height = BigEndian::read_u16(&content[1..3]) as usize;
+        self.width = BigEndian::read_u16(&content[3..5]) as usize;
+        if self.height == 0 {
+            panic!("JPEG images with the height defined after the scan (DNL) are not supported\n");
+        }
+        let components_amount = content[5] as usize;
+        if !(1..=4).contains(&components_amount) {
+            panic!("JPEG images with {} components are not supported\n", components_amount);
+        }
+        Self::check_segment_length(content, 6 + 3 * components_amount, "SOF");
+
+        for i in 0..components_amount {
+            let component = &content[6 + i * 3..9 + i * 3];
+            if !(1..=4).contains(&(component[1] >> 4)) || !(1..=4).contains(&(component[1] & 0x0f)) {
+                panic!("Invalid sampling factors {}x{} in a JPEG component\n", component[1] >> 4, component[1] & 0x0f);
+            }
+            self.components.push(JpegComponent {
+                id: component[0],
+                horizontal_sampling: (component[1] >> 4) as usize,
+                vertical_sampling: (component[1] & 0x0f) as usize,
+                quantization_table: (component[2] & 3) as usize,
+                coeffs: PixelMatrix::new(0, 0),
+            });
+        }
+
+        // the sampling factors of a single component don't matter, its blocks are never interleaved
+        if components_amount == 1 {
+            self.components[0].horizontal_sampling = 1;
+            self.components[0].vertical_sampling = 1;
+        }
+
+        let (mcu_columns, mcu_rows) = self.get_mcus_amount();
+        for component in self.components.iter_mut() {
+            component.coeffs = PixelMatrix::new_with_default(
+                mcu_columns * component.horizontal_sampling * 8,
+                mcu_rows * component.vertical_sampling * 8
+            );
+        }
+    }
+
+    pub fn get_max_sampling(&self) -> (usize, usize) {
+        (
+            self.components.iter().map(|component| component.horizontal_sampling).max().unwrap_or(1),
+            self.components.iter().map(|component| component.vertical_sampling).max().unwrap_or(1),
+        )
+    }
+
+    pub fn get_mcus_amount(&self) -> (usize, usize) {
+        let (max_horizontal, max_vertical) = self.get_max_sampling();
+        (self.width.div_ceil(8 * max_horizontal), self.height.div_ceil(8 * max_vertical))
+    }
+
+    pub fn get_color_space(&self) -> ColorSpace {
+        // the Adobe segment tells the color transform of the images that JFIF can't describe
+        let adobe_transform = self.segments
+            .iter()
+            .find(|(marker, content)| *marker == 0xee && content.len() >= 12 && content.starts_with(b"Adobe"))
+            .map(|(_, content)| content[11]);
+        let component_ids: Vec<u8> = self.components
+            .iter()
+            .map(|component| component.id)
+            .collect();
+        match self.components.len() {
+            1 => ColorSpace::Grayscale,
+            3 if adobe_transform == Some(0) || component_ids == b"RGB" => ColorSpace::Rgb,
+            3 => ColorSpace::YCbCr,
+            4 if adobe_transform == Some(2) => ColorSpace::Ycck,
+            4 => ColorSpace::Cmyk,
+            _ => panic!("JPEG images with {} components are not supported\n", self.components.len()),
+        }
+    }
+
+    pub fn get_density(&self) -> Option<Density> {
+        // the one of the JFIF header, if it has one
+        let (_, content) = self.segments
+            .iter()
+            .find(|(marker, content)| *marker == MARKER_APP0 && content.starts_with(b"JFIF\0") && content.len() >= 12)?;
+        let unit = match content[7] {
+            1 => DensityUnit::DotsPerInch,
+            2 => DensityUnit::DotsPerCm,
+            _ => DensityUnit::AspectRatio,
+        };
+        let (x, y) = (BigEndian::read_u16(&content[8..10]), BigEndian::read_u16(&content[10..12]));
+        if x == 0 || y == 0 {
+            return None;
+        }
+        Some(Density { unit, x, y })
+    }
+
+    fn read_scan(
+        &mut self,
+        data: &[u8],
+        header: &[u8],
+        position: usize,
+        dc_tables: &[Option<HuffmanDecoder>; 4],
+        ac_tables: &[Option<HuffmanDecoder>; 4],
+        restart_interval: usize
+    ) -> usize {
+        // returns the position of the marker that follows the entropy coded data
+        Self::check_segment_length(header, 1, "SOS");
+        let components_amount = header[0] as usize;
+        if !(1..=4).contains(&components_amount) {
+            panic!("Invalid amount of components {} in a JPEG scan\n", components_amount);
+        }
+        Self::check_segment_length(header, 4 + 2 * components_amount, "SOS");
+        let scan_components: Vec<(usize, usize, usize)> = (0..components_amount)
+            .map(|i| {
+                let id = header[1 + i * 2];
+                let (dc_table, ac_table) = ((header[2 + i * 2] >> 4) as usize, (header[2 + i * 2] & 0x0f) as usize);
+                if dc_table > 3 || ac_table > 3 {
+                    panic!("JPEG scan uses an undefined Huffman table\n");
+                }
+                let index = self.components
+                    .iter()
+                    .position(|component| component.id == id)
+                    .expect("JPEG scan refers to an unknown component\n");
+                (index, dc_table, ac_table)
+            })
+            .collect();
+
+        // the coefficients of the scan (the whole block in sequential images) and the bits of them it holds
+        let parameters = &header[1 + scan_components.len() * 2..];
+        let scan = Scan {
+            spectral_start: parameters[0] as usize,
+            spectral_end: parameters[1].min(63) as usize,
+            high_bit: parameters[2] >> 4,
+            low_bit: parameters[2] & 0x0f,
+        };
+        if !self.progressive && (scan.spectral_start != 0 || scan.spectral_end != 63) {
+            panic!("Invalid spectral selection in a sequential JPEG scan\n");
+        }
+
+        // Interleaved scans go MCU by MCU, with all the blocks of each component in it.
+        // Scans of a single component go block by block, only through the ones that have samples of the image
+        let (max_horizontal, max_vertical) = self.get_max_sampling();
+        let (mcu_columns, mcu_rows, blocks_in_mcu): (usize, usize, Vec<(usize, usize, usize)>) = if
+            scan_components.len() == 1
+        {
+            let component = &self.components[scan_components[0].0];
+            let component_width = (self.width * component.horizontal_sampling).div_ceil(max_horizontal);
+            let component_height = (self.height * component.vertical_sampling).div_ceil(max_vertical);
+            (component_width.div_ceil(8), component_height.div_ceil(8), vec![(0, 0, 0)])
+        } else {
+            let (mcu_columns, mcu_rows) = self.get_mcus_amount();
+            let mut blocks = Vec::new();
+            for (scan_index, (index, _, _)) in scan_components.iter().enumerate() {
+                let component = &self.components[*index];
+                for row in 0..component.vertical_sampling {
+                    for col in 0..component.horizontal_sampling {
+                        blocks.push((scan_index, row, col));
+                    }
+                }
+            }
+            (mcu_columns, mcu_rows, blocks)
+        };
+
+        let mut reader = BitReader::new(data, position);
+        let mut prev_dc_coeffs = vec![0i32; scan_components.len()];
+        let mut end_of_band_run = 0;
+        let mut block = [0i16; 64];
+        for mcu in 0..mcu_columns * mcu_rows {
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+                reader.restart();
+                prev_dc_coeffs.fill(0);
+                end_of_band_run = 0;
+            }
+            let (mcu_row, mcu_column) = (mcu / mcu_columns, mcu % mcu_columns);
+
+            for (scan_index, row, col) in &blocks_in_mcu {
+                let (index, dc_table, ac_table) = scan_components[*scan_index];
+                let component = &mut self.components[index];
+                let (block_row, block_col) = if blocks_in_mcu.len() == 1 {
+                    (mcu_row, mcu_column)
+                } else {
+                    (mcu_row * component.vertical_sampling + row, mcu_column * component.horizontal_sampling + col)
+                };
+
+                // progressive scans add to what the previous ones read
+                for (i, coeff) in block.iter_mut().enumerate() {
+                    *coeff = component.coeffs.get_pixel(block_row * 8 + i / 8, block_col * 8 + (i % 8)).unwrap();
+                }
+                let dc_table = dc_tables[dc_table].as_ref();
+                let ac_table = ac_tables[ac_table].as_ref();
+                let undefined_table = "JPEG scan uses an undefined Huffman table\n";
+                match (scan.spectral_start, scan.high_bit) {
+                    _ if !self.progressive => {
+                        Self::read_block(
+                            &mut reader,
+                            dc_table.expect(undefined_table),
+                            ac_table.expect(undefined_table),
+                            &mut prev_dc_coeffs[*scan_index],
+                            &mut block
+                        );
+                    }
+                    (0, 0) => {
+                        prev_dc_coeffs[*scan_index] += Self::read_dc_difference(
+                            &mut reader,
+                            dc_table.expect(undefined_table)
+                        );
+                        block[0] = (prev_dc_coeffs[*scan_index] << scan.low_bit) as i16;
+                    }
+                    (0, _) => {
+                        block[0] |= (reader.read_bit() as i16) << scan.low_bit;
+                    }
+                    (_, 0) => {
+                        Self::read_ac_first(
+                            &mut reader,
+                            ac_table.expect(undefined_table),
+                            &scan,
+                            &mut end_of_band_run,
+                            &mut block
+                        );
+                    }
+                    _ => {
+                        Self::read_ac_refinement(
+                            &mut reader,
+                            ac_table.expect(undefined_table),
+                            &scan,
+                            &mut end_of_band_run,
+                            &mut block
+                        );
+                    }
+                }
+                for (i, coeff) in block.iter().enumerate() {
+                    component.coeffs.set_pixel(block_row * 8 + i / 8, block_col * 8 + (i % 8), *coeff);
+                }
+            }
+        }
+
+        // the data ends at the first marker that isn't a restart marker
+        let mut end = reader.position;
+        while
+            end + 1 < data.len() &&
+            !(data[end] == 0xff && data[end + 1] != 0x00 && !(MARKER_RST0..=MARKER_RST7).contains(&data[end + 1]))
+        {
+            end += 1;
+        }
+        end
+    }
+
+    fn read_block(
+        reader: &mut BitReader,
+        dc_table: &HuffmanDecoder,
+        ac_table: &HuffmanDecoder,
+        prev_dc_coeff: &mut i32,
+        block: &mut [i16; 64]
+    ) {
+        block.fill(0);
+        *prev_dc_coeff += Self::read_dc_difference(reader, dc_table);
+        block[0] = *prev_dc_coeff as i16;
+
+        let mut k = 1;
+        while k < 64 {
+            let symbol = ac_table.decode(reader);
+            let (zeros_count, bit_length) = ((symbol >> 4) as usize, symbol & 0x0f);
+            if bit_length == 0 {
+                if zeros_count != 15 {
+                    break; // end of block
+                }
+                k += 16;
+                continue;
+            }
+            k += zeros_count;
+            if k > 63 {
+                panic!("Invalid run length in JPEG data\n");
+            }
+            block[ZIG_ZAG_MAP[k]] = JpegImage::amplitude_to_coeff(reader.read_bits(bit_length), bit_length) as i16;
+            k += 1;
+        }
+    }
+
+    fn read_dc_difference(reader: &mut BitReader, dc_table: &HuffmanDecoder) -> i32 {
+        // the symbol is the length of the difference, which has at most 16 bits
+        let bit_length = dc_table.decode(reader);
+        if bit_length > 16 {
+            panic!("Invalid DC difference length {} in JPEG data\n", bit_length);
+        }
+        JpegImage::amplitude_to_coeff(reader.read_bits(bit_length), bit_length)
+    }
+
+    fn read_end_of_band_run(reader: &mut BitReader, zeros_count: usize) -> usize {
+        // the amount of blocks after this one that have no more coefficients in the band (G.1.2.2)
+        (1 << zeros_count) - 1 + (reader.read_bits(zeros_count as u8) as usize)
+    }
+
+    fn read_ac_first(
+        reader: &mut BitReader,
+        ac_table: &HuffmanDecoder,
+        scan: &Scan,
+        end_of_band_run: &mut usize,
+        block: &mut [i16; 64]
+    ) {
+        if *end_of_band_run > 0 {
+            *end_of_band_run -= 1;
+            return;
+        }
+        let mut k = scan.spectral_start;
+        while k <= scan.spectral_end {
+            let symbol = ac_table.decode(reader);
+            let (zeros_count, bit_length) = ((symbol >> 4) as usize, symbol & 0x0f);
+            if bit_length == 0 {
+                if zeros_count != 15 {
+                    *end_of_band_run = Self::read_end_of_band_run(reader, zeros_count);
+                    break;
+                }
+                k += 16;
+                continue;
+            }
+            k += zeros_count;
+            if k > scan.spectral_end {
+                panic!("Invalid run length in JPEG data\n");
+            }
+            let coeff = JpegImage::amplitude_to_coeff(reader.read_bits(bit_length), bit_length);
+            block[ZIG_ZAG_MAP[k]] = (coeff << scan.low_bit) as i16;
+            k += 1;
+        }
+    }
+
+    fn read_ac_refinement(
+        reader: &mut BitReader,
+        ac_table: &HuffmanDecoder,
+        scan: &Scan,
+        end_of_band_run: &mut usize,
+        block: &mut [i16; 64]
+    ) {
+        // Adds one bit to the coefficients that were already nonzero, and places the ones that become nonzero with this bit.
+        // The zero runs only count the coefficients that are still zero (G.1.2.3)
+        let bit = 1i16 << scan.low_bit;
+        let refine = |reader: &mut BitReader, coeff: &mut i16| {
+            if reader.read_bit() == 1 && (*coeff & bit) == 0 {
+                *coeff += if *coeff >= 0 { bit } else { -bit };
+            }
+        };
+
+        let mut k = scan.spectral_start;
+        if *end_of_band_run == 0 {
+            while k <= scan.spectral_end {
+                let symbol = ac_table.decode(reader);
+                let (mut zeros_count, bit_length) = ((symbol >> 4) as i32, symbol & 0x0f);
+                let mut new_coeff = 0;
+                if bit_length != 0 {
+                    new_coeff = if reader.read_bit() == 1 { bit } else { -bit };
+                } else if zeros_count != 15 {
+                    *end_of_band_run = Self::read_end_of_band_run(reader, zeros_count as usize) + 1;
+                    break;
+                }
+
+                while k <= scan.spectral_end {
+                    let coeff = &mut block[ZIG_ZAG_MAP[k]];
+                    if *coeff != 0 {
+                        refine(reader, coeff);
+                    } else {
+                        zeros_count -= 1;
+                        if zeros_count < 0 {
+                            break;
+                        }
+                    }
+                    k += 1;
+                }
+                if new_coeff != 0 {
+                    if k > scan.spectral_end {
+                        panic!("Invalid run length in JPEG data\n");
+                    }
+                    block[ZIG_ZAG_MAP[k]] = new_coeff;
+                }
+                k += 1;
+            }
+        }
+
+        // the rest of the band has no new coefficients, but the ones that were there still get their bit
+        if *end_of_band_run > 0 {
+            while k <= scan.spectral_end {
+                let coeff = &mut block[ZIG_ZAG_MAP[k]];
+                if *coeff != 0 {
+                    refine(reader, coeff);
+                }
+                k += 1;
+            }
+            *end_of_band_run -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::quant_tables::DEFAULT_Y_QUANTIZATION_TABLE;
+    use crate::jpeg::test_images::{ encode_test_image, get_test_gradient, get_test_image };
+
+    fn encode_rows(width: usize, height: usize, ratio: (u8, u8, u8), first_row: usize) -> (JpegImage, Vec<u8>) {
+        // the rows of the gradient from first_row on, so that an image can be compared with its halves
+        let mut image = get_test_image(width, height, ratio, |row, col| get_test_gradient(first_row + row, col));
+        image.add_comment("test");
+        image.chrominance_downsampling();
+        image.dct_and_quantization();
+        let data = encode_test_image(&mut image);
+        (image, data)
+    }
+
+    fn split_scan(data: &[u8]) -> (&[u8], &[u8]) {
+        // the headers up to the start of the scan, and the entropy coded data after its header
+        let start = data.windows(2).position(|bytes| bytes == [0xff, MARKER_SOS]).unwrap();
+        let data_start = start + 2 + (BigEndian::read_u16(&data[start + 2..start + 4]) as usize);
+        (&data[..start], &data[data_start..data.len() - 2])
+    }
+
+    #[test]
+    fn test_coefficients_read_back() {
+        let (image, data) = encode_rows(48, 32, (4, 2, 0), 0);
+        let jpeg_file = JpegFile::from_bytes(&data);
+
+        assert_eq!((jpeg_file.width, jpeg_file.height), (48, 32));
+        assert_eq!(jpeg_file.components.len(), 3);
+        assert_eq!((jpeg_file.components[0].horizontal_sampling, jpeg_file.components[0].vertical_sampling), (2, 2));
+        assert_eq!(jpeg_file.components[0].coeffs.pixels, image.y_dct_coeffs.pixels);
+        assert_eq!(jpeg_file.components[1].coeffs.pixels, image.cb_dct_coeffs.pixels);
+        assert_eq!(jpeg_file.components[2].coeffs.pixels, image.cr_dct_coeffs.pixels);
+        assert_eq!(jpeg_file.components[2].quantization_table, 1);
+        assert_eq!(jpeg_file.quantization_tables[0].unwrap()[9], DEFAULT_Y_QUANTIZATION_TABLE[9] as u16);
+        assert_eq!(jpeg_file.segments.last(), Some(&(MARKER_COM, b"test".to_vec())));
+    }
+
+    #[test]
+    fn test_restart_markers() {
+        // each MCU row of the image is encoded on its own, so it starts with its own DC prediction like after a restart
+        let (image, data) = encode_rows(16, 16, (4, 4, 4), 0);
+        let (_, top_data) = encode_rows(16, 8, (4, 4, 4), 0);
+        let (_, bottom_data) = encode_rows(16, 8, (4, 4, 4), 8);
+
+        let (headers, _) = split_scan(&data);
+        let scan_header = &data[headers.len()..data.len() - split_scan(&data).1.len() - 2];
+        let mut restart_data = headers.to_vec();
+        restart_data.extend([0xff, MARKER_DRI, 0, 4, 0, 2]);
+        restart_data.extend(scan_header);
+        restart_data.extend(split_scan(&top_data).1);
+        restart_data.extend([0xff, MARKER_RST0]);
+        restart_data.extend(split_scan(&bottom_data).1);
+        restart_data.extend([0xff, MARKER_EOI]);
+
+        let jpeg_file = JpegFile::from_bytes(&restart_data);
+        assert_eq!(jpeg_file.components[0].coeffs.pixels, image.y_dct_coeffs.pixels);
+        assert_eq!(jpeg_file.components[2].coeffs.pixels, image.cr_dct_coeffs.pixels);
+    }
+
+    #[test]
+    #[should_panic(expected = "JPEG DQT segment is 55 bytes long, but its header needs 65")]
+    fn test_truncated_header() {
+        // the segment is consistent with the rest of the file, but too short for the table it declares
+        let (_, data) = encode_rows(16, 16, (4, 4, 4), 0);
+        let start = data.windows(2).position(|bytes| bytes == [0xff, MARKER_DQT]).unwrap();
+        let mut truncated = data[..start + 2].to_vec();
+        truncated.extend(57u16.to_be_bytes());
+        truncated.extend(&data[start + 4..start + 59]);
+        truncated.extend(&data[start + 69..]);
+        JpegFile::from_bytes(&truncated);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod jpeg_file;
+pub mod idct;
+pub mod upsampling;
+pub mod decoding;
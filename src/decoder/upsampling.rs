@@ -0,0 +1,110 @@
+use clap::ValueEnum;
+use crate::decoder::jpeg_file::JpegFile;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Upsampling {
+    Nearest, // each sample is repeated
+    Fancy, // triangular, each sample is 3/4 of the nearest one and 1/4 of the next nearest one, like libjpeg
+}
+
+impl JpegFile {
+    pub fn upsample(
+        samples: &PixelMatrix<u16>,
+        horizontal_factor: usize,
+        vertical_factor: usize,
+        width: usize,
+        height: usize,
+        upsampling: Upsampling
+    ) -> PixelMatrix<u16> {
+        // Takes the samples of a component to the size of the image. The triangular filter is only used
+        // when the component has half the samples, other factors repeat them.
+        // The sums of the filter are only divided at the end, so the two directions are rounded once
+        let fancy = |factor: usize| upsampling == Upsampling::Fancy && factor == 2;
+
+        let mut values: Vec<u32> = samples.pixels
+            .iter()
+            .map(|sample| *sample as u32)
+            .collect();
+        let mut scale = 1;
+        if horizontal_factor > 1 {
+            let mut upsampled = Vec::with_capacity(width * samples.height);
+            for row in values.chunks(samples.width) {
+                upsampled.extend(
+                    (0..width).map(|col| Self::upsample_sample(row, col, horizontal_factor, fancy(horizontal_factor)))
+                );
+            }
+            values = upsampled;
+            if fancy(horizontal_factor) {
+                scale *= 4;
+            }
+        }
+        if vertical_factor > 1 {
+            let mut upsampled = vec![0; width * height];
+            let mut column = vec![0; samples.height];
+            for col in 0..width {
+                for (row, value) in column.iter_mut().enumerate() {
+                    *value = values[row * width + col];
+                }
+                for row in 0..height {
+                    upsampled[row * width + col] = Self::upsample_sample(
+                        &column,
+                        row,
+                        vertical_factor,
+                        fancy(vertical_factor)
+                    );
+                }
+            }
+            values = upsampled;
+            if fancy(vertical_factor) {
+                scale *= 4;
+            }
+        }
+
+        let pixels = values
+            .iter()
+            .map(|value| ((value + scale / 2) / scale) as u16)
+            .collect();
+        PixelMatrix::new_from_pixels(width, height, pixels)
+    }
+
+    fn upsample_sample(samples: &[u32], index: usize, factor: usize, fancy: bool) -> u32 {
+        // the sample of the output at index, 4 times bigger with the triangular filter.
+        // At the edges, the missing neighbour is the nearest sample itself
+        let nearest = index / factor;
+        if !fancy {
+            return samples[nearest];
+        }
+        let neighbour = if index.is_multiple_of(2) {
+            nearest.saturating_sub(1)
+        } else {
+            (nearest + 1).min(samples.len() - 1)
+        };
+        3 * samples[nearest] + samples[neighbour]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nearest_upsampling() {
+        // the last sample only covers one column of the image
+        let samples = PixelMatrix::new_from_pixels(2, 2, vec![10, 20, 30, 40]);
+        let upsampled = JpegFile::upsample(&samples, 2, 2, 3, 4, Upsampling::Nearest);
+        assert_eq!(upsampled.pixels, vec![10, 10, 20, 10, 10, 20, 30, 30, 40, 30, 30, 40]);
+    }
+
+    #[test]
+    fn test_fancy_upsampling() {
+        let samples = PixelMatrix::new_from_pixels(2, 1, vec![0, 100]);
+        assert_eq!(JpegFile::upsample(&samples, 2, 1, 4, 1, Upsampling::Fancy).pixels, vec![0, 25, 75, 100]);
+
+        // both directions are filtered before rounding
+        let samples = PixelMatrix::new_from_pixels(2, 2, vec![0, 100, 100, 0]);
+        let upsampled = JpegFile::upsample(&samples, 2, 2, 4, 4, Upsampling::Fancy);
+        assert_eq!(&upsampled.pixels[0..4], &[0, 25, 75, 100]);
+        assert_eq!(&upsampled.pixels[4..8], &[25, 38, 63, 75]);
+    }
+}
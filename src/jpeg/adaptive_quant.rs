@@ -0,0 +1,184 @@
+use crate::JpegImage;
+use crate::jpeg::huffman_tables::ZIG_ZAG_MAP;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use std::thread;
+
+// blocks with this many times the average activity of the channel get the full suppression
+const FULL_SUPPRESSION_ACTIVITY: f32 = 2.0;
+
+// biggest coefficient (in absolute value) that gets zeroed, at the highest frequency of the busiest blocks.
+// The quantization already truncates everything below one step to zero, so the limit has to go well past 1 to matter
+const MAX_ZEROED_AMPLITUDE: f32 = 6.0;
+
+impl JpegImage {
+    pub fn adapt_quantization(&mut self) {
+        // Our eyes are bad at noticing errors in busy regions (texture, edges, noise), but are really good at noticing them
+        // in smooth gradients, where they show up as banding.
+        // So blocks with more activity than the average of the channel get their small high frequency coefficients zeroed,
+        // while the smooth ones are left untouched.
+        // Only the coefficient values change, so the file is still a regular baseline JPEG
+        if !self.adaptive_quantization || self.lossless_predictor.is_some() {
+            return;
+        }
+
+        thread::scope(|s| {
+            let y_handle = s.spawn(|| {
+                let activity_map = Self::get_activity_map(&mut self.y_channel);
+                Self::suppress_busy_blocks(&mut self.y_dct_coeffs, &activity_map);
+            });
+
+            let cb_handle = s.spawn(|| {
+                let activity_map = Self::get_activity_map(&mut self.cb_channel);
+                Self::suppress_busy_blocks(&mut self.cb_dct_coeffs, &activity_map);
+            });
+
+            let cr_handle = s.spawn(|| {
+                let activity_map = Self::get_activity_map(&mut self.cr_channel);
+                Self::suppress_busy_blocks(&mut self.cr_dct_coeffs, &activity_map);
+            });
+
+            if let (Some(k_channel), Some(k_dct_coeffs)) = (&mut self.k_channel, &mut self.k_dct_coeffs) {
+                s.spawn(|| {
+                    let activity_map = Self::get_activity_map(k_channel);
+                    Self::suppress_busy_blocks(k_dct_coeffs, &activity_map);
+                });
+            }
+
+            _ = y_handle.join();
+            _ = cb_handle.join();
+            _ = cr_handle.join();
+        });
+    }
+
+    fn get_activity_map(channel: &mut PixelMatrix<u16>) -> Vec<f32> {
+        // the activity of a block is the average difference between neighbouring pixels inside of it
+        let mut activity_map = Vec::<f32>::new();
+
+        channel.get_block_iterator(8, 8, true).for_each_block(
+            &mut (|block_buffer: &mut Vec<u16>| {
+                let mut activity = 0.0;
+                for i in 0..8 {
+                    for j in 0..8 {
+                        let pixel = block_buffer[i * 8 + j] as f32;
+                        if j < 7 {
+                            activity += (pixel - (block_buffer[i * 8 + j + 1] as f32)).abs();
+                        }
+                        if i < 7 {
+                            activity += (pixel - (block_buffer[(i + 1) * 8 + j] as f32)).abs();
+                        }
+                    }
+                }
+                activity_map.push(activity / 112.0); // 2 * 7 * 8 differences
+            })
+        );
+
+        activity_map
+    }
+
+    fn get_suppression_strength(activity: f32, average_activity: f32) -> f32 {
+        // 0 for blocks up to the average activity, growing up to 1 for the busiest blocks
+        if average_activity == 0.0 {
+            return 0.0;
+        }
+        let relative_activity = activity / average_activity;
+        ((relative_activity - 1.0) / (FULL_SUPPRESSION_ACTIVITY - 1.0)).clamp(0.0, 1.0)
+    }
+
+    fn suppress_high_frequencies(coeffs: &mut [i16], strength: f32) {
+        // the higher the frequency, the bigger the coefficients that get zeroed
+        for i in 1..64 {
+            let threshold = strength * MAX_ZEROED_AMPLITUDE * (i as f32) / 63.0;
+            if (coeffs[ZIG_ZAG_MAP[i]].abs() as f32) <= threshold {
+                coeffs[ZIG_ZAG_MAP[i]] = 0;
+            }
+        }
+    }
+
+    fn suppress_busy_blocks(dct_coeffs: &mut PixelMatrix<i16>, activity_map: &[f32]) {
+        let average_activity = activity_map.iter().sum::<f32>() / (activity_map.len() as f32);
+
+        dct_coeffs.get_block_iterator(8, 8, true).for_each_block_mut(
+            &mut (|block_idx: usize, block_buffer: &mut Vec<i16>| {
+                let strength = Self::get_suppression_strength(
+                    activity_map[block_idx],
+                    average_activity
+                );
+                if strength > 0.0 {
+                    Self::suppress_high_frequencies(block_buffer, strength);
+                }
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::test_images::{ encode_test_image, get_test_image };
+
+    #[test]
+    fn test_smooth_blocks_are_protected() {
+        // left block is a smooth gradient, right block is a checkerboard
+        let mut channel = PixelMatrix::<u16>::new_with_default(16, 8);
+        for i in 0..8 {
+            for j in 0..8 {
+                channel.set_pixel(i, j, (100 + i + j) as u16);
+                channel.set_pixel(i, 8 + j, if (i + j) % 2 == 0 { 20 } else { 230 });
+            }
+        }
+
+        let activity_map = JpegImage::get_activity_map(&mut channel);
+        assert!(activity_map[0] < activity_map[1]);
+
+        // same small coefficients in both blocks
+        let mut dct_coeffs = PixelMatrix::<i16>::new_with_default(16, 8);
+        for i in 0..8 {
+            for j in 0..16 {
+                dct_coeffs.set_pixel(i, j, 1);
+            }
+        }
+
+        JpegImage::suppress_busy_blocks(&mut dct_coeffs, &activity_map);
+
+        for i in 0..8 {
+            for j in 0..8 {
+                assert_eq!(dct_coeffs.get_pixel(i, j), Some(1));
+            }
+        }
+
+        // the busy block keeps its DC and lowest frequencies, but loses the highest ones
+        assert_eq!(dct_coeffs.get_pixel(0, 8), Some(1));
+        assert_eq!(dct_coeffs.get_pixel(0, 9), Some(1));
+        assert_eq!(dct_coeffs.get_pixel(7, 15), Some(0));
+    }
+
+    #[test]
+    fn test_textured_image_gets_smaller() {
+        // smooth gradient on the left half, fine texture on the right one
+        let pixel = |row: usize, col: usize| {
+            let y = if col < 32 { 60 + row + col } else { 80 + (row * 37 + col * 91 + row * col * 13) % 96 };
+            (y as u16, 128, 128)
+        };
+        let encode = |adaptive_quantization: bool| {
+            let mut image = get_test_image(64, 32, (4, 4, 4), pixel);
+            image.adaptive_quantization = adaptive_quantization;
+            image.chrominance_downsampling();
+            image.dct_and_quantization();
+            image.adapt_quantization();
+            let y_dct_coeffs = image.y_dct_coeffs.pixels.clone();
+            (y_dct_coeffs, encode_test_image(&mut image))
+        };
+
+        let (plain_coeffs, plain) = encode(false);
+        let (adapted_coeffs, adapted) = encode(true);
+        println!("plain: {} bytes, adapted: {} bytes", plain.len(), adapted.len());
+        assert!(adapted.len() < plain.len());
+
+        // the smooth blocks keep all their coefficients
+        for i in 0..32 {
+            for j in 0..32 {
+                assert_eq!(adapted_coeffs[i * 64 + j], plain_coeffs[i * 64 + j]);
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use clap::ValueEnum;
+use crate::JpegImage;
+use crate::jpeg::dct_quant::DctAlgorithm;
+use crate::jpeg::jpeg_image::ColorSpace;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::{ RGBValue, WideRGBValue };
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum AlphaMode {
+    Straight, // the colors don't depend on the alpha
+    Premultiplied, // the colors are already multiplied by the alpha
+}
+
+// JPEG has no transparency, so the alpha of the input has to be dealt with before encoding.
+// By default the image is composited over the background color. If an alpha output is set, the alpha is written
+// as a separate grayscale JPEG instead, and the color image keeps the straight colors
+#[derive(Debug, Clone)]
+pub struct AlphaOptions {
+    pub mode: AlphaMode,
+    pub background: RGBValue,
+    pub alpha_output: Option<String>,
+}
+
+impl AlphaOptions {
+    pub fn new() -> AlphaOptions {
+        AlphaOptions {
+            mode: AlphaMode::Straight,
+            background: (255, 255, 255),
+            alpha_output: None,
+        }
+    }
+
+    pub fn mode(mut self, mode: AlphaMode) -> AlphaOptions {
+        self.mode = mode;
+        self
+    }
+
+    pub fn background(mut self, background: RGBValue) -> AlphaOptions {
+        self.background = background;
+        self
+    }
+
+    pub fn alpha_output(mut self, alpha_output: &str) -> AlphaOptions {
+        self.alpha_output = Some(alpha_output.to_string());
+        self
+    }
+}
+
+impl JpegImage {
+    pub fn resolve_alpha(
+        rgb_pixel: WideRGBValue,
+        opacity: f32, // from 0.0 (transparent) to 1.0 (opaque)
+        max_sample: u16,
+        options: &AlphaOptions
+    ) -> WideRGBValue {
+        let separate_alpha = options.alpha_output.is_some();
+
+        let resolve_channel = |color: u16, background: u8| -> u16 {
+            let color = color as f32;
+            // the background is given in 8 bits, and scaled to the range of the samples
+            let background = ((background as f32) * (max_sample as f32)) / 255.0;
+            let value = match options.mode {
+                AlphaMode::Straight if separate_alpha => color,
+                AlphaMode::Straight => color * opacity + background * (1.0 - opacity),
+                // fully transparent pixels have lost their color, so they get the background one
+                AlphaMode::Premultiplied if separate_alpha && opacity == 0.0 => background,
+                AlphaMode::Premultiplied if separate_alpha => color / opacity,
+                AlphaMode::Premultiplied => color + background * (1.0 - opacity),
+            };
+            value.round().clamp(0.0, max_sample as f32) as u16
+        };
+
+        (
+            resolve_channel(rgb_pixel.0, options.background.0),
+            resolve_channel(rgb_pixel.1, options.background.1),
+            resolve_channel(rgb_pixel.2, options.background.2),
+        )
+    }
+
+    pub fn from_alpha_channel(
+        alpha: &PixelMatrix<u16>,
+        max_value: u16,
+        jpeg_path: &str,
+        dct_algorithm: DctAlgorithm
+    ) -> JpegImage {
+        // the alpha image is always a baseline 8 bit one
+        let mut image = JpegImage::new(
+            jpeg_path.to_string(),
+            alpha.width as i32,
+            alpha.height as i32,
+            (4, 4, 4),
+            dct_algorithm
+        );
+        image.color_space = ColorSpace::Grayscale;
+
+        for i in 0..alpha.height {
+            for j in 0..alpha.width {
+                let value = alpha.get_pixel(i, j).unwrap() as u32;
+                image.y_channel.set_pixel(i, j, ((value * 255 + (max_value as u32) / 2) / (max_value as u32)) as u16);
+            }
+        }
+
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_alpha() {
+        let red_background = AlphaOptions::new().background((255, 0, 0));
+
+        // opaque pixels keep their color, transparent ones get the background
+        assert_eq!(JpegImage::resolve_alpha((10, 20, 30), 1.0, 255, &red_background), (10, 20, 30));
+        assert_eq!(JpegImage::resolve_alpha((10, 20, 30), 0.0, 255, &red_background), (255, 0, 0));
+        assert_eq!(JpegImage::resolve_alpha((0, 0, 200), 128.0 / 255.0, 255, &red_background), (127, 0, 100));
+
+        // premultiplied colors are only added to the background
+        let premultiplied = red_background.clone().mode(AlphaMode::Premultiplied);
+        assert_eq!(JpegImage::resolve_alpha((0, 0, 100), 128.0 / 255.0, 255, &premultiplied), (127, 0, 100));
+
+        // with a separate alpha image, the straight colors are kept
+        let separate = premultiplied.alpha_output("alpha.jpeg");
+        assert_eq!(JpegImage::resolve_alpha((0, 0, 100), 128.0 / 255.0, 255, &separate), (0, 0, 199));
+        assert_eq!(JpegImage::resolve_alpha((0, 0, 0), 0.0, 255, &separate), (255, 0, 0));
+        let separate = separate.mode(AlphaMode::Straight);
+        assert_eq!(JpegImage::resolve_alpha((0, 0, 100), 128.0 / 255.0, 255, &separate), (0, 0, 100));
+    }
+}
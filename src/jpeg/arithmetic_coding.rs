@@ -0,0 +1,566 @@
+use bitvec::order::Msb0;
+use bitvec::vec::BitVec;
+use crate::JpegImage;
+use crate::jpeg::entropy_coding::{ EntropyEncoder, RunLength };
+
+// conditioning parameters of the statistics, written in the DAC segment:
+// DC differences up to 2^(L-1) are considered "small" and the ones bigger than 2^(U-1) "large",
+// and the AC magnitudes of the coefficients up to K use a separate set of statistics
+pub const DC_LOWER_BOUND: u8 = 0;
+pub const DC_UPPER_BOUND: u8 = 1;
+pub const AC_KX: u8 = 5;
+
+const DC_STATISTICS_AMOUNT: usize = 64;
+const AC_STATISTICS_AMOUNT: usize = 256;
+
+// state used for the sign of the AC coefficients, that always has a probability estimate of 0.5
+const FIXED_STATE: u8 = 113;
+
+// Probability estimation state machine (Table D.2 of the standard):
+// (Qe, next state after an MPS, next state after an LPS, whether the MPS switches after an LPS)
+#[rustfmt::skip]
+const QE_TABLE: [(i32, u8, u8, bool); 114] = [
+    (0x5a1d, 1, 1, true), (0x2586, 2, 14, false), (0x1114, 3, 16, false), (0x080b, 4, 18, false),
+    (0x03d8, 5, 20, false), (0x01da, 6, 23, false), (0x00e5, 7, 25, false), (0x006f, 8, 28, false),
+    (0x0036, 9, 30, false), (0x001a, 10, 33, false), (0x000d, 11, 35, false), (0x0006, 12, 9, false),
+    (0x0003, 13, 10, false), (0x0001, 13, 12, false), (0x5a7f, 15, 15, true), (0x3f25, 16, 36, false),
+    (0x2cf2, 17, 38, false), (0x207c, 18, 39, false), (0x17b9, 19, 40, false), (0x1182, 20, 42, false),
+    (0x0cef, 21, 43, false), (0x09a1, 22, 45, false), (0x072f, 23, 46, false), (0x055c, 24, 48, false),
+    (0x0406, 25, 49, false), (0x0303, 26, 51, false), (0x0240, 27, 52, false), (0x01b1, 28, 54, false),
+    (0x0144, 29, 56, false), (0x00f5, 30, 57, false), (0x00b7, 31, 59, false), (0x008a, 32, 60, false),
+    (0x0068, 33, 62, false), (0x004e, 34, 63, false), (0x003b, 35, 32, false), (0x002c, 9, 33, false),
+    (0x5ae1, 37, 37, true), (0x484c, 38, 64, false), (0x3a0d, 39, 65, false), (0x2ef1, 40, 67, false),
+    (0x261f, 41, 68, false), (0x1f33, 42, 69, false), (0x19a8, 43, 70, false), (0x1518, 44, 72, false),
+    (0x1177, 45, 73, false), (0x0e74, 46, 74, false), (0x0bfb, 47, 75, false), (0x09f8, 48, 77, false),
+    (0x0861, 49, 78, false), (0x0706, 50, 79, false), (0x05cd, 51, 48, false), (0x04de, 52, 50, false),
+    (0x040f, 53, 50, false), (0x0363, 54, 51, false), (0x02d4, 55, 52, false), (0x025c, 56, 53, false),
+    (0x01f8, 57, 54, false), (0x01a4, 58, 55, false), (0x0160, 59, 56, false), (0x0125, 60, 57, false),
+    (0x00f6, 61, 58, false), (0x00cb, 62, 59, false), (0x00ab, 63, 61, false), (0x008f, 32, 61, false),
+    (0x5b12, 65, 65, true), (0x4d04, 66, 80, false), (0x412c, 67, 81, false), (0x37d8, 68, 82, false),
+    (0x2fe8, 69, 83, false), (0x293c, 70, 84, false), (0x2379, 71, 86, false), (0x1edf, 72, 87, false),
+    (0x1aa9, 73, 87, false), (0x174e, 74, 72, false), (0x1424, 75, 72, false), (0x119c, 76, 74, false),
+    (0x0f6b, 77, 74, false), (0x0d51, 78, 75, false), (0x0bb6, 79, 77, false), (0x0a40, 48, 77, false),
+    (0x5832, 81, 80, true), (0x4d1c, 82, 88, false), (0x438e, 83, 89, false), (0x3bdd, 84, 90, false),
+    (0x34ee, 85, 91, false), (0x2eae, 86, 92, false), (0x299a, 87, 93, false), (0x2516, 71, 86, false),
+    (0x5570, 89, 88, true), (0x4ca9, 90, 95, false), (0x44d9, 91, 96, false), (0x3e22, 92, 97, false),
+    (0x3824, 93, 99, false), (0x32b4, 94, 99, false), (0x2e17, 86, 93, false), (0x56a8, 96, 95, true),
+    (0x4f46, 97, 101, false), (0x47e5, 98, 102, false), (0x41cf, 99, 103, false), (0x3c3d, 100, 104, false),
+    (0x375e, 93, 99, false), (0x5231, 102, 105, false), (0x4c0f, 103, 106, false), (0x4639, 104, 107, false),
+    (0x415e, 99, 103, false), (0x5627, 106, 105, true), (0x50e7, 107, 108, false), (0x4b85, 103, 109, false),
+    (0x5597, 109, 110, false), (0x504f, 107, 111, false), (0x5a10, 111, 110, true), (0x5522, 109, 112, false),
+    (0x59eb, 111, 112, true),
+    // fixed probability of 0.5, it never leaves this state
+    (0x5a1d, 113, 113, false),
+];
+
+// Each statistics bin is a byte: the lower 7 bits are the state index in the table,
+// and the highest bit is the current more probable symbol (MPS)
+fn update_state(state: &mut u8, lps: bool) {
+    let (_, next_mps, next_lps, switch_mps) = QE_TABLE[(*state & 0x7f) as usize];
+    let mps = *state & 0x80;
+    *state = if !lps {
+        mps | next_mps
+    } else if switch_mps {
+        (mps ^ 0x80) | next_lps
+    } else {
+        mps | next_lps
+    };
+}
+
+// QM-coder, the adaptive binary arithmetic coder of the standard (Annex D), following the libjpeg implementation
+struct QmEncoder {
+    c: i64, // code register
+    a: i64, // probability interval
+    ct: i32, // bits left before the next byte is ready
+    buffer: i32, // last byte, not written yet because a carry could still change it (-1 if there is none)
+    stacked_ff: usize, // 0xff bytes after the buffer, which would become zeros with a carry
+    stacked_zeros: usize, // zero bytes waiting to be written, they are dropped at the end of the data
+    bytes: Vec<u8>,
+}
+
+impl QmEncoder {
+    fn new() -> QmEncoder {
+        QmEncoder {
+            c: 0,
+            a: 0x10000,
+            ct: 11,
+            buffer: -1,
+            stacked_ff: 0,
+            stacked_zeros: 0,
+            bytes: Vec::new(),
+        }
+    }
+
+    fn flush_zeros(&mut self) {
+        for _ in 0..self.stacked_zeros {
+            self.bytes.push(0);
+        }
+        self.stacked_zeros = 0;
+    }
+
+    // the carry propagates to the buffered byte, and all the stacked 0xff bytes become zeros
+    fn flush_with_carry(&mut self) {
+        if self.buffer >= 0 {
+            self.flush_zeros();
+            self.bytes.push((self.buffer + 1) as u8);
+        }
+        self.stacked_zeros += self.stacked_ff;
+        self.stacked_ff = 0;
+    }
+
+    // no carry can reach the buffered byte anymore
+    fn flush_without_carry(&mut self) {
+        if self.buffer == 0 {
+            self.stacked_zeros += 1;
+        } else if self.buffer > 0 {
+            self.flush_zeros();
+            self.bytes.push(self.buffer as u8);
+        }
+        if self.stacked_ff > 0 {
+            self.flush_zeros();
+            for _ in 0..self.stacked_ff {
+                self.bytes.push(0xff);
+            }
+            self.stacked_ff = 0;
+        }
+    }
+
+    fn encode_bit(&mut self, state: &mut u8, bit: bool) {
+        let qe = QE_TABLE[(*state & 0x7f) as usize].0 as i64;
+        let mps = (*state & 0x80) != 0;
+
+        self.a -= qe;
+        if bit != mps {
+            // the LPS gets the bigger sub-interval if the MPS one became smaller (conditional exchange)
+            if self.a >= qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            update_state(state, true);
+        } else {
+            if self.a >= 0x8000 {
+                return;
+            }
+            if self.a < qe {
+                self.c += self.a;
+                self.a = qe;
+            }
+            update_state(state, false);
+        }
+
+        // renormalization, a byte is ready every 8 shifts
+        while self.a < 0x8000 {
+            self.a <<= 1;
+            self.c <<= 1;
+            self.ct -= 1;
+            if self.ct == 0 {
+                let byte = (self.c >> 19) as i32;
+                if byte > 0xff {
+                    self.flush_with_carry();
+                    self.buffer = byte & 0xff;
+                } else if byte == 0xff {
+                    self.stacked_ff += 1;
+                } else {
+                    self.flush_without_carry();
+                    self.buffer = byte;
+                }
+                self.c &= 0x7ffff;
+                self.ct += 8;
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        // termination (section D.1.8), leaving as many trailing zero bits in C as possible
+        let temp = (self.a - 1 + self.c) & 0xffff0000;
+        self.c = if temp < self.c { temp + 0x8000 } else { temp };
+        self.c <<= self.ct;
+
+        if (self.c & 0xf8000000) != 0 {
+            self.flush_with_carry();
+        } else {
+            self.flush_without_carry();
+        }
+
+        // the final bytes are only written if they are not zeros, decoders read zeros after the end of the data
+        if (self.c & 0x7fff800) != 0 {
+            self.flush_zeros();
+            self.bytes.push(((self.c >> 19) & 0xff) as u8);
+            if (self.c & 0x7f800) != 0 {
+                self.bytes.push(((self.c >> 11) & 0xff) as u8);
+            }
+        }
+
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+pub struct ArithmeticEncoder {
+    coder: QmEncoder,
+    dc_statistics: [[u8; DC_STATISTICS_AMOUNT]; 2], // 0 for Y, 1 for Ch
+    ac_statistics: [[u8; AC_STATISTICS_AMOUNT]; 2],
+    dc_context: [usize; 4], // conditioning of the DC statistics on the previous difference of each component
+    fixed_state: u8,
+}
+
+impl ArithmeticEncoder {
+    pub fn new() -> ArithmeticEncoder {
+        ArithmeticEncoder {
+            coder: QmEncoder::new(),
+            dc_statistics: [[0; DC_STATISTICS_AMOUNT]; 2],
+            ac_statistics: [[0; AC_STATISTICS_AMOUNT]; 2],
+            dc_context: [0; 4],
+            fixed_state: FIXED_STATE,
+        }
+    }
+
+    // the bits of the magnitude below its leading one (Figure F.9)
+    fn encode_magnitude_bits(coder: &mut QmEncoder, state: &mut u8, category: i32, magnitude: i32) {
+        let mut m = category >> 1;
+        while m != 0 {
+            coder.encode_bit(state, (m & magnitude) != 0);
+            m >>= 1;
+        }
+    }
+
+    fn encode_dc(&mut self, component: usize, table: usize, difference: i32) {
+        let statistics = &mut self.dc_statistics[table];
+        let context = self.dc_context[component];
+
+        if difference == 0 {
+            self.coder.encode_bit(&mut statistics[context], false);
+            self.dc_context[component] = 0;
+            return;
+        }
+        self.coder.encode_bit(&mut statistics[context], true);
+
+        let negative = difference < 0;
+        self.coder.encode_bit(&mut statistics[context + 1], negative);
+
+        // the magnitude category is coded as a unary number, the first decision with separate statistics
+        // for positive and negative differences, and the rest shared by all the contexts (starting at X1 = 20)
+        let magnitude = difference.abs() - 1;
+        let mut state = context + if negative { 3 } else { 2 };
+        let mut category = 0;
+        if magnitude != 0 {
+            self.coder.encode_bit(&mut statistics[state], true);
+            category = 1;
+            state = 20;
+            let mut remaining = magnitude >> 1;
+            while remaining != 0 {
+                self.coder.encode_bit(&mut statistics[state], true);
+                category <<= 1;
+                state += 1;
+                remaining >>= 1;
+            }
+        }
+        self.coder.encode_bit(&mut statistics[state], false);
+
+        // the context of the next difference depends on how big this one was
+        self.dc_context[component] = if category < (1 << DC_LOWER_BOUND) >> 1 {
+            0
+        } else if category > (1 << DC_UPPER_BOUND) >> 1 {
+            if negative { 16 } else { 12 }
+        } else if negative {
+            8
+        } else {
+            4
+        };
+
+        Self::encode_magnitude_bits(&mut self.coder, &mut statistics[state + 14], category, magnitude);
+    }
+
+    fn encode_ac(&mut self, table: usize, coefficients: &[i32; 64]) {
+        let statistics = &mut self.ac_statistics[table];
+
+        // index of the last non zero coefficient
+        let last = (1..64).rev().find(|&k| coefficients[k] != 0).unwrap_or(0);
+
+        let mut k = 1;
+        while k <= last {
+            // each position has 3 statistics: end of block, zero or non zero, and the first magnitude decision
+            let mut state = 3 * (k - 1);
+            self.coder.encode_bit(&mut statistics[state], false); // not the end of block
+            while coefficients[k] == 0 {
+                self.coder.encode_bit(&mut statistics[state + 1], false);
+                state += 3;
+                k += 1;
+            }
+            self.coder.encode_bit(&mut statistics[state + 1], true);
+            self.coder.encode_bit(&mut self.fixed_state, coefficients[k] < 0);
+
+            // the first two decisions of the magnitude category use the same statistic, and the rest
+            // depend on whether the coefficient is a low or high frequency one
+            let magnitude = coefficients[k].abs() - 1;
+            state += 2;
+            let mut category = 0;
+            if magnitude != 0 {
+                self.coder.encode_bit(&mut statistics[state], true);
+                category = 1;
+                let mut remaining = magnitude >> 1;
+                if remaining != 0 {
+                    self.coder.encode_bit(&mut statistics[state], true);
+                    category <<= 1;
+                    state = if k <= (AC_KX as usize) { 189 } else { 217 };
+                    remaining >>= 1;
+                    while remaining != 0 {
+                        self.coder.encode_bit(&mut statistics[state], true);
+                        category <<= 1;
+                        state += 1;
+                        remaining >>= 1;
+                    }
+                }
+            }
+            self.coder.encode_bit(&mut statistics[state], false);
+
+            Self::encode_magnitude_bits(&mut self.coder, &mut statistics[state + 14], category, magnitude);
+            k += 1;
+        }
+
+        // there is no end of block after the last coefficient of the block
+        if k <= 63 {
+            self.coder.encode_bit(&mut statistics[3 * (k - 1)], true);
+        }
+    }
+}
+
+impl EntropyEncoder for ArithmeticEncoder {
+    fn encode_block(&mut self, component: usize, table: usize, runlength: &[RunLength]) {
+        // the arithmetic coder works with the coefficients themselves, so the run lengths are expanded back
+        let mut coefficients = [0i32; 64];
+        let mut k = 0;
+        for (i, r) in runlength.iter().enumerate() {
+            if i != 0 {
+                if r.symbol == 0x00 {
+                    break; // End Of Block
+                }
+                k += (r.symbol >> 4) as usize;
+                if r.symbol == 0xf0 {
+                    k += 1; // the ZRL symbol stands for 16 zeros
+                    continue;
+                }
+            }
+            coefficients[k] = JpegImage::amplitude_to_coeff(r.amplitude, r.symbol & 0x0f);
+            k += 1;
+        }
+
+        self.encode_dc(component, table, coefficients[0]);
+        self.encode_ac(table, &coefficients);
+    }
+
+    fn take_complete_bytes(&mut self) -> Vec<u8> {
+        // a carry can only change the buffered byte and the ones after it, which are not in bytes yet
+        std::mem::take(&mut self.coder.bytes)
+    }
+
+    fn finish(&mut self) -> BitVec<u8, Msb0> {
+        BitVec::from_vec(self.coder.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::huffman_tables::ZIG_ZAG_MAP;
+
+    // QM decoder (section D.2), following the libjpeg implementation
+    struct QmDecoder<'a> {
+        bytes: &'a [u8],
+        position: usize,
+        c: i64,
+        a: i64,
+        ct: i32,
+    }
+
+    impl<'a> QmDecoder<'a> {
+        fn decode_bit(&mut self, state: &mut u8) -> bool {
+            while self.a < 0x8000 {
+                self.ct -= 1;
+                if self.ct < 0 {
+                    let data = *self.bytes.get(self.position).unwrap_or(&0) as i64;
+                    self.position += 1;
+                    self.c = (self.c << 8) | data;
+                    self.ct += 8;
+                    if self.ct < 0 {
+                        self.ct += 1;
+                        if self.ct == 0 {
+                            self.a = 0x8000;
+                        }
+                    }
+                }
+                self.a <<= 1;
+            }
+
+            let qe = QE_TABLE[(*state & 0x7f) as usize].0 as i64;
+            let mps = (*state & 0x80) != 0;
+            self.a -= qe;
+            let temp = self.a << self.ct;
+            if self.c >= temp {
+                self.c -= temp;
+                if self.a < qe {
+                    self.a = qe;
+                    update_state(state, false);
+                    mps
+                } else {
+                    self.a = qe;
+                    update_state(state, true);
+                    !mps
+                }
+            } else if self.a < 0x8000 {
+                if self.a < qe {
+                    update_state(state, true);
+                    !mps
+                } else {
+                    update_state(state, false);
+                    mps
+                }
+            } else {
+                mps
+            }
+        }
+    }
+
+    struct BlockDecoder<'a> {
+        decoder: QmDecoder<'a>,
+        dc_statistics: [[u8; DC_STATISTICS_AMOUNT]; 2],
+        ac_statistics: [[u8; AC_STATISTICS_AMOUNT]; 2],
+        dc_context: [usize; 3],
+        previous_dc: [i32; 3],
+        fixed_state: u8,
+    }
+
+    impl<'a> BlockDecoder<'a> {
+        fn decode_magnitude(&mut self, statistics: &mut [u8], mut state: usize, mut category: i32) -> i32 {
+            let mut value = category;
+            state += 14;
+            category >>= 1;
+            while category != 0 {
+                if self.decoder.decode_bit(&mut statistics[state]) {
+                    value |= category;
+                }
+                category >>= 1;
+            }
+            value + 1
+        }
+
+        // returns the block in natural order
+        fn decode_block(&mut self, component: usize) -> [i32; 64] {
+            let table = if component == 0 { 0 } else { 1 };
+            let mut block = [0; 64];
+
+            let mut statistics = self.dc_statistics[table];
+            let context = self.dc_context[component];
+            if !self.decoder.decode_bit(&mut statistics[context]) {
+                self.dc_context[component] = 0;
+            } else {
+                let negative = self.decoder.decode_bit(&mut statistics[context + 1]);
+                let mut state = context + if negative { 3 } else { 2 };
+                let mut category = 0;
+                if self.decoder.decode_bit(&mut statistics[state]) {
+                    category = 1;
+                    state = 20;
+                    while self.decoder.decode_bit(&mut statistics[state]) {
+                        category <<= 1;
+                        state += 1;
+                    }
+                }
+                self.dc_context[component] = if category < (1 << DC_LOWER_BOUND) >> 1 {
+                    0
+                } else if category > (1 << DC_UPPER_BOUND) >> 1 {
+                    if negative { 16 } else { 12 }
+                } else if negative {
+                    8
+                } else {
+                    4
+                };
+                let magnitude = self.decode_magnitude(&mut statistics, state, category);
+                self.previous_dc[component] += if negative { -magnitude } else { magnitude };
+            }
+            self.dc_statistics[table] = statistics;
+            block[0] = self.previous_dc[component];
+
+            let mut statistics = self.ac_statistics[table];
+            let mut k = 1;
+            while k < 64 {
+                let mut state = 3 * (k - 1);
+                if self.decoder.decode_bit(&mut statistics[state]) {
+                    break; // end of block
+                }
+                while !self.decoder.decode_bit(&mut statistics[state + 1]) {
+                    state += 3;
+                    k += 1;
+                }
+                let negative = self.decoder.decode_bit(&mut self.fixed_state);
+                state += 2;
+                let mut category = 0;
+                if self.decoder.decode_bit(&mut statistics[state]) {
+                    category = 1;
+                    if self.decoder.decode_bit(&mut statistics[state]) {
+                        category <<= 1;
+                        state = if k <= (AC_KX as usize) { 189 } else { 217 };
+                        while self.decoder.decode_bit(&mut statistics[state]) {
+                            category <<= 1;
+                            state += 1;
+                        }
+                    }
+                }
+                let magnitude = self.decode_magnitude(&mut statistics, state, category);
+                block[ZIG_ZAG_MAP[k]] = if negative { -magnitude } else { magnitude };
+                k += 1;
+            }
+            self.ac_statistics[table] = statistics;
+
+            block
+        }
+    }
+
+    #[test]
+    fn test_arithmetic_round_trip() {
+        // blocks with runs of zeros, long runs, coefficients in the last position and big values,
+        // for the three components so the statistics of both tables get used
+        let mut blocks: Vec<(usize, Vec<i16>)> = Vec::new();
+        for i in 0..60 {
+            let mut block = vec![0i16; 64];
+            block[0] = ((i * 73) % 2047) as i16 - 1023;
+            for k in 1..64 {
+                let seed = (i * 31 + k * 17) % 29;
+                if seed < 4 + (i % 7) {
+                    let value = ((i * 13 + k * 7) % 41) as i16 - 20;
+                    block[ZIG_ZAG_MAP[k]] = if i % 5 == 0 { value * 50 } else { value };
+                }
+            }
+            if i % 9 == 0 {
+                block[ZIG_ZAG_MAP[63]] = -3;
+            }
+            if i % 11 == 0 {
+                block = vec![0; 64];
+            }
+            blocks.push((i % 3, block));
+        }
+
+        let mut encoder = ArithmeticEncoder::new();
+        let mut previous_dc = [0i16; 3];
+        let mut runlength = Vec::new();
+        for (component, block) in blocks.iter() {
+            runlength.clear();
+            JpegImage::runlength_encode(&mut previous_dc[*component], block, &mut runlength);
+            encoder.encode_block(*component, if *component == 0 { 0 } else { 1 }, &runlength);
+        }
+        let bytes = encoder.finish().into_vec();
+
+        let mut decoder = BlockDecoder {
+            decoder: QmDecoder { bytes: &bytes, position: 0, c: 0, a: 0, ct: -16 },
+            dc_statistics: [[0; DC_STATISTICS_AMOUNT]; 2],
+            ac_statistics: [[0; AC_STATISTICS_AMOUNT]; 2],
+            dc_context: [0; 3],
+            previous_dc: [0; 3],
+            fixed_state: FIXED_STATE,
+        };
+        for (component, block) in blocks.iter() {
+            let decoded = decoder.decode_block(*component);
+            for i in 0..64 {
+                assert_eq!(decoded[i], block[i] as i32);
+            }
+        }
+    }
+}
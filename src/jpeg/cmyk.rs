@@ -0,0 +1,115 @@
+use crate::JpegImage;
+use crate::jpeg::jpeg_image::ColorSpace;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::WideCMYKValue;
+
+pub const ADOBE_HEADER: &[u8; 5] = b"Adobe";
+const ADOBE_VERSION: u16 = 100;
+
+// color transforms of the Adobe segment, which tell decoders how to get the colors back
+const ADOBE_TRANSFORM_NONE: u8 = 0; // RGB or CMYK, as they are
+const ADOBE_TRANSFORM_YCCK: u8 = 2;
+
+impl JpegImage {
+    pub fn load_cmyk_pixels(&mut self, pixels: &PixelMatrix<WideCMYKValue>, max_value: u16) {
+        // Adobe applications write CMYK inverted (the max value being no ink), and decoders expect it that way when the
+        // Adobe segment is there. Cyan, magenta and yellow go in the channels of Y, Cb and Cr, and black in its own one
+        self.color_space = ColorSpace::Cmyk;
+        let mut k_channel = PixelMatrix::new_with_default(self.y_channel.width, self.y_channel.height);
+        self.k_dct_coeffs = Some(PixelMatrix::new_with_default(self.y_dct_coeffs.width, self.y_dct_coeffs.height));
+
+        let max_sample = (1u32 << self.precision) - 1;
+        let invert = |sample: u16| {
+            (max_sample - ((sample as u32) * max_sample + (max_value as u32) / 2) / (max_value as u32)) as u16
+        };
+        for i in 0..pixels.height {
+            for j in 0..pixels.width {
+                if let Some((c, m, y, k)) = pixels.get_pixel(i, j) {
+                    self.y_channel.set_pixel(i, j, invert(c));
+                    self.cb_channel.set_pixel(i, j, invert(m));
+                    self.cr_channel.set_pixel(i, j, invert(y));
+                    k_channel.set_pixel(i, j, invert(k));
+                }
+            }
+        }
+        self.k_channel = Some(k_channel);
+    }
+
+    pub fn convert_to_ycck(&mut self) {
+        // the inverted cyan, magenta and yellow are taken back to their amounts of ink, which are converted like RGB,
+        // while the black is kept as it is. It lets the first three components use the chrominance tables, like in YCbCr
+        if self.color_space != ColorSpace::Cmyk {
+            panic!("Only CMYK images can be converted to YCCK\n");
+        }
+        let max_sample = (1u16 << self.precision) - 1;
+        for i in 0..self.height as usize {
+            for j in 0..self.width as usize {
+                let ink = (
+                    max_sample - self.y_channel.get_pixel(i, j).unwrap(),
+                    max_sample - self.cb_channel.get_pixel(i, j).unwrap(),
+                    max_sample - self.cr_channel.get_pixel(i, j).unwrap(),
+                );
+                let ycbcr = Self::convert_to_ycbcr(ink, self.precision);
+                self.y_channel.set_pixel(i, j, ycbcr.0);
+                self.cb_channel.set_pixel(i, j, ycbcr.1);
+                self.cr_channel.set_pixel(i, j, ycbcr.2);
+            }
+        }
+        self.color_space = ColorSpace::Ycck;
+    }
+
+    pub fn get_adobe_segment(&self) -> Option<Vec<u8>> {
+        // only written for the color spaces that JFIF can't describe
+        let transform = match self.color_space {
+            ColorSpace::Cmyk | ColorSpace::Rgb => ADOBE_TRANSFORM_NONE,
+            ColorSpace::Ycck => ADOBE_TRANSFORM_YCCK,
+            _ => {
+                return None;
+            }
+        };
+        let mut segment = ADOBE_HEADER.to_vec();
+        segment.extend_from_slice(&ADOBE_VERSION.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]); // flags
+        segment.push(transform);
+        Some(segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::dct_quant::DctAlgorithm;
+
+    fn get_test_image() -> JpegImage {
+        let mut image = JpegImage::new(String::new(), 16, 16, (4, 4, 4), DctAlgorithm::RealDct);
+        let mut pixels = PixelMatrix::new(16, 16);
+        for i in 0..256u16 {
+            pixels.push_next((i, 255 - i, 0, (i * 7) % 256));
+        }
+        image.load_cmyk_pixels(&pixels, 255);
+        image
+    }
+
+    #[test]
+    fn test_cmyk_is_inverted() {
+        let image = get_test_image();
+        assert_eq!(image.get_component_ids(), vec![1, 2, 3, 4]);
+        assert_eq!(image.y_channel.get_pixel(0, 3), Some(252));
+        assert_eq!(image.cb_channel.get_pixel(0, 3), Some(3));
+        assert_eq!(image.cr_channel.get_pixel(0, 3), Some(255));
+        assert_eq!(image.k_channel.as_ref().unwrap().get_pixel(0, 3), Some(255 - 21));
+        assert_eq!(image.get_adobe_segment().unwrap(), b"Adobe\0\x64\0\0\0\0\0");
+    }
+
+    #[test]
+    fn test_ycck() {
+        let mut image = get_test_image();
+        image.convert_to_ycck();
+        assert_eq!(image.get_component_tables(), vec![0, 1, 1, 0]);
+        // the amounts of ink are converted like RGB, and the black isn't converted
+        assert_eq!(image.y_channel.get_pixel(0, 0), Some(149)); // only magenta
+        assert_eq!(image.y_channel.get_pixel(15, 15), Some(76)); // only cyan
+        assert_eq!(image.k_channel.as_ref().unwrap().get_pixel(0, 3), Some(255 - 21));
+        assert_eq!(image.get_adobe_segment().unwrap()[11], ADOBE_TRANSFORM_YCCK);
+    }
+}
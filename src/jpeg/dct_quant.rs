@@ -1,7 +1,7 @@
 use clap::ValueEnum;
 use crate::JpegImage;
 use crate::pixel_matrix::block_iterator::PixelMatrixBlockIterator;
-use crate::jpeg::quant_tables::*;
+use crate::jpeg::huffman_tables::*;
 use std::thread;
 use std::f32::consts::{ PI, SQRT_2 };
 
@@ -11,28 +11,81 @@ pub enum DctAlgorithm {
     BinDct,
 }
 
+// weight of the distortion against the bits in the trellis search, as in mozjpeg:
+// 2^TRELLIS_LAMBDA_LOG_SCALE1 / (2^TRELLIS_LAMBDA_LOG_SCALE2 + mean energy of the AC coefficients of the block).
+// Busy blocks get a lower weight, as their errors are harder to see
+const TRELLIS_LAMBDA_LOG_SCALE1: f32 = 3.2;
+const TRELLIS_LAMBDA_LOG_SCALE2: f32 = 9.9;
+
 impl JpegImage {
     pub fn dct_and_quantization(&mut self) {
+        if self.lossless_predictor.is_some() {
+            return; // lossless mode encodes the samples themselves
+        }
+
         let dct_algorithm = match self.dct_algorithm {
-            DctAlgorithm::RealDct => Self::forward_real_dct_and_quant,
-            DctAlgorithm::BinDct => Self::forward_bin_dct_and_quant,
+            DctAlgorithm::RealDct => Self::forward_real_dct,
+            DctAlgorithm::BinDct => Self::forward_bin_dct,
         };
 
+        let trellis_quantization = self.trellis_quantization;
+        let precision = self.precision;
+        // deringing is meant for 8 bit graphics, where the hard edges reach the end of the range
+        let deringing = self.deringing && precision == 8;
+        if trellis_quantization {
+            initialize_huffman_tables();
+        }
+
         let f = |
-            block_buffer: &mut Vec<u8>,
+            block_buffer: &mut Vec<u16>,
             quantization_table: [u8; 64],
+            ac_code_lengths: &[u8; 256],
             dct_coeffs_iterator: &mut PixelMatrixBlockIterator<i16>
         | {
-            dct_algorithm(block_buffer, quantization_table, dct_coeffs_iterator)
+            let mut samples = Self::level_shift(block_buffer, precision);
+            if deringing {
+                Self::preprocess_deringing(&mut samples, &quantization_table);
+            }
+            let dct_coeffs = dct_algorithm(&samples);
+            if trellis_quantization {
+                Self::trellis_quantize(
+                    &dct_coeffs,
+                    &quantization_table,
+                    ac_code_lengths,
+                    dct_coeffs_iterator
+                );
+            } else {
+                Self::quantize(&dct_coeffs, &quantization_table, dct_coeffs_iterator);
+            }
+        };
+
+        // the code lengths are only looked up when trellis quantization is enabled
+        let ac_code_lengths = if trellis_quantization {
+            [
+                Self::get_code_lengths(get_huffman_table(HuffmanTableType::YAC)),
+                Self::get_code_lengths(get_huffman_table(HuffmanTableType::CHAC)),
+            ]
+        } else {
+            [[0u8; 256], [0u8; 256]]
         };
 
+        // the tables of each component, the chrominance channels of grayscale images are transformed but not written
+        let quantization_tables = self.quantization_tables;
+        let component_tables = self.get_component_tables();
+        let table = |component: usize| *component_tables.get(component).unwrap_or(&1);
+
         thread::scope(|s| {
             let y_handle = s.spawn(|| {
                 let mut channel_iterator = self.y_channel.get_block_iterator(8, 8, true);
                 let mut coeffs_block_iterator = self.y_dct_coeffs.get_block_iterator(8, 8, true);
                 channel_iterator.for_each_block(
-                    &mut (|block_buffer: &mut Vec<u8>|
-                        f(block_buffer, DEFAULT_Y_QUANTIZATION_TABLE, &mut coeffs_block_iterator))
+                    &mut (|block_buffer: &mut Vec<u16>|
+                        f(
+                            block_buffer,
+                            quantization_tables[table(0)],
+                            &ac_code_lengths[table(0)],
+                            &mut coeffs_block_iterator
+                        ))
                 );
             });
 
@@ -40,8 +93,13 @@ impl JpegImage {
                 let mut channel_iterator = self.cb_channel.get_block_iterator(8, 8, true);
                 let mut coeffs_block_iterator = self.cb_dct_coeffs.get_block_iterator(8, 8, true);
                 channel_iterator.for_each_block(
-                    &mut (|block_buffer: &mut Vec<u8>|
-                        f(block_buffer, DEFAULT_CH_QUANTIZATION_TABLE, &mut coeffs_block_iterator))
+                    &mut (|block_buffer: &mut Vec<u16>|
+                        f(
+                            block_buffer,
+                            quantization_tables[table(1)],
+                            &ac_code_lengths[table(1)],
+                            &mut coeffs_block_iterator
+                        ))
                 );
             });
 
@@ -49,35 +107,56 @@ impl JpegImage {
                 let mut channel_iterator = self.cr_channel.get_block_iterator(8, 8, true);
                 let mut coeffs_block_iterator = self.cr_dct_coeffs.get_block_iterator(8, 8, true);
                 channel_iterator.for_each_block(
-                    &mut (|block_buffer: &mut Vec<u8>|
-                        f(block_buffer, DEFAULT_CH_QUANTIZATION_TABLE, &mut coeffs_block_iterator))
+                    &mut (|block_buffer: &mut Vec<u16>|
+                        f(
+                            block_buffer,
+                            quantization_tables[table(2)],
+                            &ac_code_lengths[table(2)],
+                            &mut coeffs_block_iterator
+                        ))
                 );
             });
 
+            // the black channel of CMYK and YCCK images
+            if let (Some(k_channel), Some(k_dct_coeffs)) = (&mut self.k_channel, &mut self.k_dct_coeffs) {
+                s.spawn(|| {
+                    let mut channel_iterator = k_channel.get_block_iterator(8, 8, true);
+                    let mut coeffs_block_iterator = k_dct_coeffs.get_block_iterator(8, 8, true);
+                    channel_iterator.for_each_block(
+                        &mut (|block_buffer: &mut Vec<u16>|
+                            f(
+                                block_buffer,
+                                quantization_tables[table(3)],
+                                &ac_code_lengths[table(3)],
+                                &mut coeffs_block_iterator
+                            ))
+                    );
+                });
+            }
+
             _ = y_handle.join();
             _ = cb_handle.join();
             _ = cr_handle.join();
         });
     }
 
-    fn dct_shift_range(n: u8) -> i8 {
-        if n <= 127 { (n | 128u8) as i8 } else { (n & 127u8) as i8 }
-    }
-
-    fn forward_bin_dct_and_quant(
-        block_buffer: &mut Vec<u8>,
-        quantization_table: [u8; 64],
-        coeffs_block_iterator: &mut PixelMatrixBlockIterator<i16>
-    ) {
-        // Version "all-lifting binDCT-C" of this paper:
-        // https://thanglong.ece.jhu.edu/Tran/Pub/intDCT.pdf
-        let mut aux_buffer: [i32; 64] = [0; 64];
+    pub fn level_shift(block_buffer: &[u16], precision: u8) -> [i32; 64] {
+        // centers the samples around 0 (-128..127 for 8 bits, -2048..2047 for 12 bits).
+        // They are kept as i32, so that preprocessing steps (like deringing) can go beyond that range
+        let mut samples: [i32; 64] = [0; 64];
         block_buffer
             .iter()
             .enumerate()
             .for_each(|(i, val)| {
-                aux_buffer[i] = Self::dct_shift_range(*val) as i32;
+                samples[i] = (*val as i32) - (1 << (precision - 1));
             });
+        samples
+    }
+
+    pub fn forward_bin_dct(samples: &[i32; 64]) -> [f32; 64] {
+        // Version "all-lifting binDCT-C" of this paper:
+        // https://thanglong.ece.jhu.edu/Tran/Pub/intDCT.pdf
+        let mut aux_buffer: [i32; 64] = *samples;
 
         // transform rows
         for i in 0..8 {
@@ -179,23 +258,16 @@ impl JpegImage {
             aux_buffer[1 * 8 + i] = x7_2;
         }
 
-        for i in 0..64 {
-            coeffs_block_iterator.set_next_pixel(
-                (aux_buffer[i] / (quantization_table[i] as i32)) as i16
-            );
-        }
+        aux_buffer.map(|coeff| coeff as f32)
     }
 
-    fn forward_real_dct_and_quant(
-        block_buffer: &mut Vec<u8>,
-        quantization_table: [u8; 64],
-        coeffs_block_iterator: &mut PixelMatrixBlockIterator<i16>
-    ) {
+    pub fn forward_real_dct(samples: &[i32; 64]) -> [f32; 64] {
         // This code follows the actual DCT mathematical formula.
         // This algorithm is extremely slow due to the cosine calculation and floating point arithmetic
         let inverse_sqrt_two: f32 = 1.0 / SQRT_2;
 
-        let mut quant_idx = 0;
+        let mut dct_coeffs: [f32; 64] = [0.0; 64];
+        let mut coeff_idx = 0;
 
         let mut alpha_u: f32;
         let mut alpha_v: f32;
@@ -216,7 +288,7 @@ impl JpegImage {
 
                 for x in 0..8 {
                     for y in 0..8 {
-                        let block_element = Self::dct_shift_range(block_buffer[x * 8 + y]);
+                        let block_element = samples[x * 8 + y];
                         sum +=
                             (block_element as f32) *
                             (((((2 * x + 1) * u) as f32) * PI) / 16.0).cos() *
@@ -224,20 +296,158 @@ impl JpegImage {
                     }
                 }
 
-                coeffs_block_iterator.set_next_pixel(
-                    ((0.25 * alpha_u * alpha_v * sum) /
-                        (quantization_table[quant_idx] as f32)) as i16
-                );
-                quant_idx += 1;
+                dct_coeffs[coeff_idx] = 0.25 * alpha_u * alpha_v * sum;
+                coeff_idx += 1;
+            }
+        }
+
+        dct_coeffs
+    }
+
+    pub fn quantize(
+        dct_coeffs: &[f32; 64],
+        quantization_table: &[u8; 64],
+        coeffs_block_iterator: &mut PixelMatrixBlockIterator<i16>
+    ) {
+        for i in 0..64 {
+            coeffs_block_iterator.set_next_pixel(
+                (dct_coeffs[i] / (quantization_table[i] as f32)) as i16
+            );
+        }
+    }
+
+    fn get_code_lengths(huffman_table: &HuffmanTable) -> [u8; 256] {
+        // Code length of every symbol. The standard tables have no codes for the sizes of 12 bit coefficients,
+        // those get the longest length, as the tables built for the image will give them long codes anyway.
+        // So every symbol has a length: a missing one is priced as a 16 bit code, it is never ruled out of the search
+        let mut code_lengths = [16u8; 256];
+        for symbol in 0..=255u8 {
+            if let Some((_, code_length)) = huffman_table.get_code(symbol) {
+                code_lengths[symbol as usize] = code_length;
             }
         }
+        code_lengths
+    }
+
+    fn trellis_quantize(
+        dct_coeffs: &[f32; 64],
+        quantization_table: &[u8; 64],
+        ac_code_lengths: &[u8; 256],
+        coeffs_block_iterator: &mut PixelMatrixBlockIterator<i16>
+    ) {
+        // Rate-distortion optimized quantization of the AC coefficients, similar to the one in mozjpeg.
+        // Going through the coefficients in zig-zag order, for every position we keep the cheapest way of
+        // encoding the block up to there with that position being the last non zero coefficient.
+        // The cost being the amount of bits that the run length symbols, the amplitudes and the ZRL codes would take
+        // with the actual Huffman table, plus the weighted squared error of the coefficients.
+        // The DC coefficient depends on the previous block, so it is just rounded
+        let mut quantized: [i16; 64] = [0; 64];
+        quantized[0] = (dct_coeffs[0] / (quantization_table[0] as f32)).round() as i16;
+
+        // the coefficients, in zig-zag order, measured in quantization steps
+        let mut scaled: [f32; 64] = [0.0; 64];
+        for i in 1..64 {
+            scaled[i] = dct_coeffs[ZIG_ZAG_MAP[i]] / (quantization_table[ZIG_ZAG_MAP[i]] as f32);
+        }
+
+        // the error is measured on the coefficients themselves, so an error of one step weighs q^2 times the block weight
+        let energy = dct_coeffs[1..].iter().map(|coeff| coeff * coeff).sum::<f32>() / 63.0;
+        let block_weight =
+            TRELLIS_LAMBDA_LOG_SCALE1.exp2() / (TRELLIS_LAMBDA_LOG_SCALE2.exp2() + energy);
+        let mut step_weight: [f32; 64] = [0.0; 64];
+        for i in 1..64 {
+            let q = quantization_table[ZIG_ZAG_MAP[i]] as f32;
+            step_weight[i] = block_weight * q * q;
+        }
+
+        // zeros_distortion[i] is the distortion of setting the coefficients 1..=i to zero
+        let mut zeros_distortion: [f32; 64] = [0.0; 64];
+        for i in 1..64 {
+            zeros_distortion[i] = zeros_distortion[i - 1] + step_weight[i] * scaled[i] * scaled[i];
+        }
+
+        let bits_cost = |symbol: u8| -> f32 { (ac_code_lengths[symbol as usize] + (symbol & 0x0f)) as f32 };
+        let zrl_cost = bits_cost(0xf0);
+
+        // best_cost[i] is the cost of the coefficients 1..=i when i is the last non zero one (0 means no AC coefficients),
+        // best_value[i] is the value chosen for it and previous_nonzero[i] where the path comes from
+        let mut best_cost: [f32; 64] = [f32::INFINITY; 64];
+        let mut best_value: [i16; 64] = [0; 64];
+        let mut previous_nonzero: [usize; 64] = [0; 64];
+        best_cost[0] = 0.0;
+
+        for i in 1..64 {
+            let amplitude = scaled[i].abs().round();
+            if amplitude == 0.0 {
+                continue; // the coefficient will be zero anyway
+            }
+
+            // candidates: the rounded value and the one closer to zero
+            let candidates = [amplitude, amplitude - 1.0];
+            for candidate in candidates.iter().filter(|c| **c > 0.0) {
+                let value = (*candidate as i16) * (scaled[i].signum() as i16);
+                let error = scaled[i] - (value as f32);
+                let bit_length = Self::bit_length(value.abs() as i32);
+
+                for j in 0..i {
+                    if best_cost[j] == f32::INFINITY {
+                        continue;
+                    }
+                    let zeros_count = i - j - 1;
+                    let cost =
+                        best_cost[j] +
+                        (zeros_distortion[i - 1] - zeros_distortion[j]) +
+                        ((zeros_count / 16) as f32) * zrl_cost +
+                        bits_cost((((zeros_count % 16) as u8) << 4) | bit_length) +
+                        step_weight[i] * error * error;
+
+                    if cost < best_cost[i] {
+                        best_cost[i] = cost;
+                        best_value[i] = value;
+                        previous_nonzero[i] = j;
+                    }
+                }
+            }
+        }
+
+        // choose where the block ends, everything after the last non zero coefficient is covered by the End Of Block
+        let eob_cost = bits_cost(0x00);
+        let mut last_nonzero = 0;
+        let mut lowest_cost = f32::INFINITY;
+        for i in 0..64 {
+            if best_cost[i] == f32::INFINITY {
+                continue;
+            }
+            let mut cost = best_cost[i] + (zeros_distortion[63] - zeros_distortion[i]);
+            if i != 63 {
+                cost += eob_cost;
+            }
+            if cost < lowest_cost {
+                lowest_cost = cost;
+                last_nonzero = i;
+            }
+        }
+
+        let mut i = last_nonzero;
+        while i != 0 {
+            quantized[ZIG_ZAG_MAP[i]] = best_value[i];
+            i = previous_nonzero[i];
+        }
+
+        for value in quantized {
+            coeffs_block_iterator.set_next_pixel(value);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::jpeg::quant_tables::*;
     use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+    use crate::jpeg::test_images::{ encode_test_image, get_test_image };
+    use crate::decoder::jpeg_file::JpegFile;
+    use crate::decoder::idct::IdctAlgorithm;
 
     #[test]
     fn test_real_dct_and_quant() {
@@ -245,7 +455,7 @@ mod tests {
 
         // example taken from wikipedia JPEG article, DCT section
         #[rustfmt::skip]
-        let mut input_block: Vec<u8> = vec![52,55,61,66,70,61,64,73,63,59,55,90,109,85,69,72,62,59,68,113,144,104,66,73,63,58,71,122,154,106,70,69,67,61,68,104,126,88,68,70,79,65,60,70,77,68,58,75,85,71,64,59,55,61,65,83,87,79,69,68,65,76,78,94];
+        let input_block: Vec<u16> = vec![52,55,61,66,70,61,64,73,63,59,55,90,109,85,69,72,62,59,68,113,144,104,66,73,63,58,71,122,154,106,70,69,67,61,68,104,126,88,68,70,79,65,60,70,77,68,58,75,85,71,64,59,55,61,65,83,87,79,69,68,65,76,78,94];
         #[rustfmt::skip]
         let expected: Vec<i16> = vec![-26,-3,-6,2,2,-1,0,0,0,-2,-4,1,1,0,0,0,-3,1,5,-1,-1,0,0,0,-3,1,2,-1,0,0,0,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0];
 
@@ -258,9 +468,9 @@ mod tests {
 
         let mut block_iterator = result.get_block_iterator(8, 8, true);
 
-        JpegImage::forward_real_dct_and_quant(
-            &mut input_block,
-            DEFAULT_Y_QUANTIZATION_TABLE,
+        JpegImage::quantize(
+            &JpegImage::forward_real_dct(&JpegImage::level_shift(&input_block, 8)),
+            &DEFAULT_Y_QUANTIZATION_TABLE,
             &mut block_iterator
         );
 
@@ -286,7 +496,7 @@ mod tests {
 
         // example taken from wikipedia JPEG article, DCT section
         #[rustfmt::skip]
-        let mut input_block: Vec<u8> = vec![52,55,61,66,70,61,64,73,63,59,55,90,109,85,69,72,62,59,68,113,144,104,66,73,63,58,71,122,154,106,70,69,67,61,68,104,126,88,68,70,79,65,60,70,77,68,58,75,85,71,64,59,55,61,65,83,87,79,69,68,65,76,78,94];
+        let input_block: Vec<u16> = vec![52,55,61,66,70,61,64,73,63,59,55,90,109,85,69,72,62,59,68,113,144,104,66,73,63,58,71,122,154,106,70,69,67,61,68,104,126,88,68,70,79,65,60,70,77,68,58,75,85,71,64,59,55,61,65,83,87,79,69,68,65,76,78,94];
         #[rustfmt::skip]
         let expected: Vec<i16> = vec![-26,-3,-6,2,2,-1,0,0,0,-2,-4,1,1,0,0,0,-3,1,5,-1,-1,0,0,0,-3,1,2,-1,0,0,0,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0];
 
@@ -299,9 +509,9 @@ mod tests {
 
         let mut block_iterator = result.get_block_iterator(8, 8, true);
 
-        JpegImage::forward_bin_dct_and_quant(
-            &mut input_block,
-            DEFAULT_Y_QUANTIZATION_TABLE,
+        JpegImage::quantize(
+            &JpegImage::forward_bin_dct(&JpegImage::level_shift(&input_block, 8)),
+            &DEFAULT_Y_QUANTIZATION_TABLE,
             &mut block_iterator
         );
 
@@ -320,4 +530,46 @@ mod tests {
 
         assert!(average_error <= (delta_error_threshold as f64));
     }
+
+    #[test]
+    fn test_trellis_quant() {
+        // a photo-like image: soft shapes with some finer detail over them, the kind of content trellis quantization is meant for
+        let pixel = |row: usize, col: usize| {
+            let (row, col) = (row as f32, col as f32);
+            let y = 128.0 + 80.0 * (col / 13.0).sin() * (row / 11.0).cos() + 30.0 * ((row + col) / 5.0).sin();
+            (y as u16, (160.0 - y / 4.0) as u16, (96.0 + y / 4.0) as u16)
+        };
+        let encode = |trellis_quantization: bool| {
+            let mut image = get_test_image(64, 64, (4, 4, 4), pixel);
+            image.trellis_quantization = trellis_quantization;
+            image.chrominance_downsampling();
+            image.dct_and_quantization();
+            encode_test_image(&mut image)
+        };
+
+        // squared error of the decoded samples of every channel
+        let distortion = |data: &Vec<u8>| -> u64 {
+            let jpeg_file = JpegFile::from_bytes(data);
+            let mut distortion = 0;
+            for (channel, component) in jpeg_file.components.iter().enumerate() {
+                let samples = jpeg_file.get_component_samples(component, IdctAlgorithm::Float);
+                for row in 0..64 {
+                    for col in 0..64 {
+                        let original = pixel(row, col);
+                        let original = [original.0, original.1, original.2][channel];
+                        distortion += (samples.get_pixel(row, col).unwrap().abs_diff(original) as u64).pow(2);
+                    }
+                }
+            }
+            distortion
+        };
+
+        let plain = encode(false);
+        let trellis = encode(true);
+        println!("plain: {} bytes (distortion: {})", plain.len(), distortion(&plain));
+        println!("trellis: {} bytes (distortion: {})", trellis.len(), distortion(&trellis));
+
+        assert!(trellis.len() < plain.len());
+        assert!(distortion(&trellis) <= distortion(&plain));
+    }
 }
@@ -0,0 +1,236 @@
+use crate::JpegImage;
+use crate::jpeg::huffman_tables::ZIG_ZAG_MAP;
+
+// biggest and smallest sample values after the level shift (255 and 0 before it)
+const MAX_SAMPLE: i32 = 127;
+const MIN_SAMPLE: i32 = -128;
+
+// how far beyond the clipping range a sample can be moved
+const MAX_OVERSHOOT: i32 = 31;
+
+impl JpegImage {
+    pub fn preprocess_deringing(samples: &mut [i32; 64], quantization_table: &[u8; 64]) {
+        // Based on the overshoot deringing of mozjpeg.
+        // Hard edges against pure white or pure black (like text in screenshots) need a lot of high frequency coefficients
+        // to be represented, and once those get quantized the edge "rings", creating visible waves next to it.
+        // But decoders clip the samples to the 0..255 range, so the saturated pixels can be replaced by a smooth curve
+        // that goes beyond it: that curve is much easier to represent with few coefficients, and once clipped it looks the same.
+        Self::overshoot_saturated_pixels(samples, MAX_SAMPLE, quantization_table[0]);
+
+        // the same thing is done for black pixels, by flipping the block upside down
+        samples.iter_mut().for_each(|sample| {
+            *sample = -*sample;
+        });
+        Self::overshoot_saturated_pixels(samples, -MIN_SAMPLE, quantization_table[0]);
+        samples.iter_mut().for_each(|sample| {
+            *sample = -*sample;
+        });
+    }
+
+    fn overshoot_saturated_pixels(samples: &mut [i32; 64], max_sample: i32, dc_quantization: u8) {
+        let mut sum = 0;
+        let mut saturated_count = 0;
+        for sample in samples.iter() {
+            sum += *sample;
+            if *sample >= max_sample {
+                saturated_count += 1;
+            }
+        }
+
+        // if nothing reaches the max value there is nothing to overshoot,
+        // and if the whole block does, it is already as flat as it can be
+        if saturated_count == 0 || saturated_count == 64 {
+            return;
+        }
+
+        // too much overshoot costs bits (roughly in proportion to the quantization), and the DC coefficient can't go out of range
+        let max_overshoot =
+            max_sample +
+            MAX_OVERSHOOT.min(2 * (dc_quantization as i32)).min(
+                (max_sample * 64 - sum) / saturated_count
+            );
+
+        // pixels are traversed in zig-zag order to process them as a line
+        let mut n = 0;
+        while n < 64 {
+            if samples[ZIG_ZAG_MAP[n]] < max_sample {
+                n += 1;
+                continue;
+            }
+
+            // find the run of saturated pixels, start is the first one and end the first one after it
+            let start = n;
+            while n < 64 && samples[ZIG_ZAG_MAP[n]] >= max_sample {
+                n += 1;
+            }
+            let end = n;
+
+            // the slope is taken from two pixels around each edge of the run, because the one right next to it
+            // may have been flattened by clipping
+            let first_1 = samples[ZIG_ZAG_MAP[start.saturating_sub(1)]];
+            let first_2 = samples[ZIG_ZAG_MAP[start.saturating_sub(2)]];
+            let last_1 = samples[ZIG_ZAG_MAP[end.min(63)]];
+            let last_2 = samples[ZIG_ZAG_MAP[(end + 1).min(63)]];
+
+            let mut first_slope = (first_1 - first_2).max(max_sample - first_1);
+            let mut last_slope = (last_1 - last_2).max(max_sample - last_1);
+
+            // if the slope of one of the sides is unknown, the curve is made symmetric
+            if start == 0 {
+                first_slope = last_slope;
+            }
+            if end == 64 {
+                last_slope = first_slope;
+            }
+
+            // the run is replaced by a Catmull-Rom curve, leaving out its first and last points, which fits better
+            let length = end - start;
+            let step = 1.0 / ((length + 1) as f32);
+            let mut position = step;
+            for i in start..end {
+                let value = Self::catmull_rom(
+                    max_sample - first_slope,
+                    max_sample,
+                    max_sample,
+                    max_sample - last_slope,
+                    position,
+                    length as i32
+                ).ceil() as i32;
+                samples[ZIG_ZAG_MAP[i]] = value.min(max_overshoot);
+                position += step;
+            }
+
+            n += 1;
+        }
+    }
+
+    fn catmull_rom(
+        value_1: i32,
+        value_2: i32,
+        value_3: i32,
+        value_4: i32,
+        t: f32,
+        size: i32
+    ) -> f32 {
+        let tangent_1 = ((value_3 - value_1) * size) as f32;
+        let tangent_2 = ((value_4 - value_2) * size) as f32;
+
+        let t2 = t * t;
+        let t3 = t2 * t;
+
+        let f1 = 2.0 * t3 - 3.0 * t2 + 1.0;
+        let f2 = -2.0 * t3 + 3.0 * t2;
+        let f3 = t3 - 2.0 * t2 + t;
+        let f4 = t3 - t2;
+
+        (value_2 as f32) * f1 + tangent_1 * f3 + (value_3 as f32) * f2 + tangent_2 * f4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::quant_tables::DEFAULT_Y_QUANTIZATION_TABLE;
+    use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+    use std::f32::consts::{ PI, SQRT_2 };
+
+    fn inverse_real_dct(dct_coeffs: &[f32; 64]) -> [f32; 64] {
+        let mut samples: [f32; 64] = [0.0; 64];
+        for x in 0..8 {
+            for y in 0..8 {
+                let mut sum = 0.0;
+                for u in 0..8 {
+                    for v in 0..8 {
+                        let alpha_u = if u == 0 { 1.0 / SQRT_2 } else { 1.0 };
+                        let alpha_v = if v == 0 { 1.0 / SQRT_2 } else { 1.0 };
+                        sum +=
+                            alpha_u *
+                            alpha_v *
+                            dct_coeffs[u * 8 + v] *
+                            (((((2 * x + 1) * u) as f32) * PI) / 16.0).cos() *
+                            (((((2 * y + 1) * v) as f32) * PI) / 16.0).cos();
+                    }
+                }
+                samples[x * 8 + y] = 0.25 * sum;
+            }
+        }
+        samples
+    }
+
+    // black "text" on a white background: strokes one or two pixels wide in different directions
+    fn generate_text_image() -> PixelMatrix<u16> {
+        let mut image = PixelMatrix::<u16>::new_from_pixels(64, 64, vec![255; 64 * 64]);
+        for glyph in 0..16 {
+            let top = (glyph / 4) * 16 + 3;
+            let left = (glyph % 4) * 16 + 2;
+            for k in 0..10 {
+                // vertical stem
+                image.set_pixel(top + k, left + (glyph % 3), 0);
+                image.set_pixel(top + k, left + (glyph % 3) + 1, 0);
+                // horizontal bar
+                image.set_pixel(top + (glyph % 5) + 2, left + k, 0);
+                // diagonal
+                image.set_pixel(top + k, left + 2 + ((k * (glyph % 4)) / 3).min(11), 0);
+            }
+        }
+        image
+    }
+
+    // squared error of the decoded image (clipped to 0..255, as decoders do) against the original
+    fn encoding_error(image: &mut PixelMatrix<u16>, deringing: bool) -> f32 {
+        let mut squared_error = 0.0;
+        image.get_block_iterator(8, 8, true).for_each_block(
+            &mut (|block_buffer: &mut Vec<u16>| {
+                let mut samples = JpegImage::level_shift(block_buffer, 8);
+                if deringing {
+                    JpegImage::preprocess_deringing(&mut samples, &DEFAULT_Y_QUANTIZATION_TABLE);
+                }
+
+                let mut quantized = PixelMatrix::<i16>::new_with_default(8, 8);
+                JpegImage::quantize(
+                    &JpegImage::forward_real_dct(&samples),
+                    &DEFAULT_Y_QUANTIZATION_TABLE,
+                    &mut quantized.get_block_iterator(8, 8, true)
+                );
+
+                let mut dequantized: [f32; 64] = [0.0; 64];
+                for i in 0..64 {
+                    dequantized[i] =
+                        (quantized.pixels[i] as f32) * (DEFAULT_Y_QUANTIZATION_TABLE[i] as f32);
+                }
+
+                let decoded = inverse_real_dct(&dequantized);
+                for i in 0..64 {
+                    let pixel = (decoded[i] + 128.0).round().clamp(0.0, 255.0);
+                    let error = pixel - (block_buffer[i] as f32);
+                    squared_error += error * error;
+                }
+            })
+        );
+        squared_error / ((image.width * image.height) as f32)
+    }
+
+    #[test]
+    fn test_deringing_keeps_flat_blocks() {
+        let mut white = [MAX_SAMPLE; 64];
+        JpegImage::preprocess_deringing(&mut white, &DEFAULT_Y_QUANTIZATION_TABLE);
+        assert_eq!(white, [MAX_SAMPLE; 64]);
+
+        let mut gray = [10; 64];
+        JpegImage::preprocess_deringing(&mut gray, &DEFAULT_Y_QUANTIZATION_TABLE);
+        assert_eq!(gray, [10; 64]);
+    }
+
+    #[test]
+    fn test_deringing_on_text() {
+        let mut image = generate_text_image();
+
+        let plain_error = encoding_error(&mut image, false);
+        let deringing_error = encoding_error(&mut image, true);
+
+        println!("mean squared error without deringing: {}", plain_error);
+        println!("mean squared error with deringing: {}", deringing_error);
+
+        assert!(deringing_error < plain_error);
+    }
+}
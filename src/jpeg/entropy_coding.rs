@@ -1,27 +1,171 @@
+use clap::ValueEnum;
 use crate::JpegImage;
+use crate::jpeg::jpeg_image::ColorSpace;
 use crate::jpeg::huffman_tables::*;
+use crate::jpeg::arithmetic_coding::ArithmeticEncoder;
 use std::cell::RefCell;
 use bitvec::order::Msb0;
 use bitvec::vec::BitVec;
 use crate::pixel_matrix::pixel_matrix::PixelMatrix;
 use crate::utils::bitvec_utils::write_bits;
 
+#[derive(Debug, Clone, ValueEnum)]
+pub enum EntropyCoder {
+    Huffman,
+    Arithmetic,
+}
+
 #[derive(Debug)]
-struct RunLength {
-    symbol: u8,
-    amplitude: i16, // coefficient
+pub struct RunLength {
+    pub symbol: u8,
+    pub amplitude: u16, // coefficient
+}
+
+// Both entropy coders work on top of the same run length representation of the blocks,
+// which get passed in the same order they appear in the file
+pub trait EntropyEncoder {
+    // component is 0 for Y, 1 for Cb, 2 for Cr and 3 for K, and table is 0 for the luminance tables and 1 for the chrominance ones
+    fn encode_block(&mut self, component: usize, table: usize, runlength: &[RunLength]);
+
+    // the bytes that are complete, so they can be written before the rest of the blocks are encoded
+    fn take_complete_bytes(&mut self) -> Vec<u8>;
+
+    fn finish(&mut self) -> BitVec<u8, Msb0>;
+}
+
+pub struct HuffmanEncoder {
+    bits: BitVec<u8, Msb0>,
+    huffman_tables: [HuffmanTable<'static>; 4], // in the order of HuffmanTableType
+}
+
+impl HuffmanEncoder {
+    pub fn new(huffman_tables: [HuffmanTable<'static>; 4]) -> HuffmanEncoder {
+        HuffmanEncoder {
+            bits: BitVec::new(),
+            huffman_tables,
+        }
+    }
+}
+
+impl EntropyEncoder for HuffmanEncoder {
+    fn encode_block(&mut self, _component: usize, table: usize, runlength: &[RunLength]) {
+        JpegImage::huffman_encode(
+            runlength,
+            &mut self.bits,
+            &self.huffman_tables[table],
+            &self.huffman_tables[table + 2]
+        );
+    }
+
+    fn take_complete_bytes(&mut self) -> Vec<u8> {
+        let remaining_bits = self.bits.split_off(self.bits.len() - (self.bits.len() % 8));
+        std::mem::replace(&mut self.bits, remaining_bits).into_vec()
+    }
+
+    fn finish(&mut self) -> BitVec<u8, Msb0> {
+        // the last byte is padded with 1s, so the padding can't be mistaken for a code
+        while !self.bits.len().is_multiple_of(8) {
+            self.bits.push(true);
+        }
+        std::mem::take(&mut self.bits)
+    }
+}
+
+// Counts how many times each symbol is used, to build the optimal Huffman tables for the image
+pub struct HuffmanStatistics {
+    frequencies: [[u32; 256]; 4], // in the order of HuffmanTableType
+}
+
+impl HuffmanStatistics {
+    pub fn new() -> HuffmanStatistics {
+        HuffmanStatistics {
+            frequencies: [[0; 256]; 4],
+        }
+    }
+
+    pub fn generate_tables(&self) -> [HuffmanTable<'static>; 4] {
+        self.frequencies.each_ref().map(HuffmanTable::from_frequencies)
+    }
+}
+
+impl EntropyEncoder for HuffmanStatistics {
+    fn encode_block(&mut self, _component: usize, table: usize, runlength: &[RunLength]) {
+        for (i, r) in runlength.iter().enumerate() {
+            if i == 0 {
+                self.frequencies[table][r.symbol as usize] += 1;
+            } else {
+                self.frequencies[table + 2][r.symbol as usize] += 1;
+            }
+        }
+    }
+
+    fn take_complete_bytes(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn finish(&mut self) -> BitVec<u8, Msb0> {
+        BitVec::new()
+    }
 }
 
 impl JpegImage {
-    pub fn generate_entropy_encoded_data(&mut self) {
+    pub fn get_huffman_tables(&self) -> [HuffmanTable<'static>; 4] {
+        // the optimized tables if there are any, otherwise the standard ones
+        if let Some(huffman_tables) = &self.huffman_tables {
+            return huffman_tables.clone();
+        }
         initialize_huffman_tables();
+        [
+            get_huffman_table(HuffmanTableType::YDC).clone(),
+            get_huffman_table(HuffmanTableType::CHDC).clone(),
+            get_huffman_table(HuffmanTableType::YAC).clone(),
+            get_huffman_table(HuffmanTableType::CHAC).clone(),
+        ]
+    }
+
+    pub fn generate_entropy_encoded_data(&mut self) {
+        if self.lossless_predictor.is_some() {
+            self.generate_lossless_encoded_data();
+            return;
+        }
 
-        // let bits = RefCell::new(BitVec::new());
+        // progressive images are written in several scans, each one with its own tables
+        if self.progressive {
+            if !matches!(self.entropy_coder, EntropyCoder::Huffman) {
+                panic!("Progressive images can only use Huffman coding\n");
+            }
+            self.generate_progressive_scans();
+            return;
+        }
+
+        // The standard Huffman tables only have codes for the coefficient sizes of 8 bit images,
+        // so with more precision the tables are built for the image, which takes an extra pass over the blocks
+        let optimize_huffman_tables = self.precision > 8 || self.optimize_huffman_tables;
+        if matches!(self.entropy_coder, EntropyCoder::Huffman) && optimize_huffman_tables {
+            let mut statistics = HuffmanStatistics::new();
+            self.encode_blocks(&mut statistics, &mut [0; 4]);
+            self.huffman_tables = Some(statistics.generate_tables());
+        }
+
+        let mut encoder: Box<dyn EntropyEncoder> = match self.entropy_coder {
+            EntropyCoder::Huffman => Box::new(HuffmanEncoder::new(self.get_huffman_tables())),
+            EntropyCoder::Arithmetic => Box::new(ArithmeticEncoder::new()),
+        };
+        self.encode_blocks(encoder.as_mut(), &mut [0; 4]);
+        self.entropy_coded_bits = encoder.finish();
+    }
 
+    pub fn encode_blocks(
+        &mut self,
+        encoder: &mut dyn EntropyEncoder,
+        prev_dc_coeffs: &mut [i16; 4] // of each component, kept by the caller to encode the image in parts
+    ) {
+        // goes through the blocks in the order they are stored in the file: MCU by MCU, with the luminance blocks first
         let (horizontal_downsampling, vertical_downsampling): (
             usize,
             usize,
         ) = Self::get_downsampling_factor(self.chrominance_downsampling_ratio);
+        let component_tables = self.get_component_tables();
 
         let mut y_dct_block_iterator = self.y_dct_coeffs.get_block_iterator(
             8 * horizontal_downsampling,
@@ -33,23 +177,21 @@ impl JpegImage {
 
         let mut cr_dct_block_iterator = self.cr_dct_coeffs.get_block_iterator(8, 8, false);
 
+        // CMYK and YCCK images have no subsampling, so there is a single black block in each MCU
+        let mut k_dct_block_iterator = self.k_dct_coeffs
+            .as_mut()
+            .map(|k_dct_coeffs| k_dct_coeffs.get_block_iterator(8, 8, false));
+
         let mut run_length_result_buffer = Vec::<RunLength>::with_capacity(64);
 
-        let mut y_prev_dc_coeff = 0i16;
-        let mut cb_prev_dc_coeff = 0i16;
-        let mut cr_prev_dc_coeff = 0i16;
+        let [y_prev_dc_coeff, cb_prev_dc_coeff, cr_prev_dc_coeff, k_prev_dc_coeff] = prev_dc_coeffs;
 
         let mut aux_buffer = Vec::<i16>::with_capacity(
             8 * horizontal_downsampling * 8 * vertical_downsampling
         );
 
         let process_single_block = RefCell::new(
-            |
-                block_buffer: &mut Vec<i16>,
-                prev_dc_coeff: &mut i16,
-                dc_huffman_table: &HuffmanTable,
-                ac_huffman_table: &HuffmanTable
-            | {
+            |block_buffer: &mut Vec<i16>, prev_dc_coeff: &mut i16, component: usize| {
                 run_length_result_buffer.clear();
                 JpegImage::runlength_encode(
                     prev_dc_coeff,
@@ -61,12 +203,7 @@ impl JpegImage {
                 //     run_length_result_buffer.len(),
                 //     run_length_result_buffer
                 // );
-                JpegImage::huffman_encode(
-                    &run_length_result_buffer,
-                    &mut self.entropy_coded_bits,
-                    dc_huffman_table,
-                    ac_huffman_table
-                );
+                encoder.encode_block(component, component_tables[component], &run_length_result_buffer);
             }
         );
 
@@ -85,46 +222,47 @@ impl JpegImage {
                 .get_block_iterator(8, 8, false)
                 .for_each_block(
                     &mut (|block_buffer: &mut Vec<i16>|
-                        process_single_block.borrow_mut()(
-                            block_buffer,
-                            &mut y_prev_dc_coeff,
-                            get_huffman_table(HuffmanTableType::YDC),
-                            get_huffman_table(HuffmanTableType::YAC)
-                        ))
+                        process_single_block.borrow_mut()(block_buffer, y_prev_dc_coeff, 0))
                 );
         };
 
+        // grayscale images are made of just the luminance blocks
+        let grayscale = self.color_space == ColorSpace::Grayscale;
+
         for i in 0..cb_dct_block_iterator.get_blocks_amount() {
             if i != 0 {
                 y_dct_block_iterator.increment_block_idx();
                 cb_dct_block_iterator.increment_block_idx();
                 cr_dct_block_iterator.increment_block_idx();
+                if let Some(k_dct_block_iterator) = &mut k_dct_block_iterator {
+                    k_dct_block_iterator.increment_block_idx();
+                }
             }
             y_dct_block_iterator.block_operation(&mut aux_buffer, &mut process_multiple_blocks);
+            if grayscale {
+                continue;
+            }
             cb_dct_block_iterator.block_operation(
                 &mut aux_buffer,
                 &mut (|block_buffer: &mut Vec<i16>|
-                    process_single_block.borrow_mut()(
-                        block_buffer,
-                        &mut cb_prev_dc_coeff,
-                        get_huffman_table(HuffmanTableType::CHDC),
-                        get_huffman_table(HuffmanTableType::CHAC)
-                    ))
+                    process_single_block.borrow_mut()(block_buffer, cb_prev_dc_coeff, 1))
             );
             cr_dct_block_iterator.block_operation(
                 &mut aux_buffer,
                 &mut (|block_buffer: &mut Vec<i16>|
-                    process_single_block.borrow_mut()(
-                        block_buffer,
-                        &mut cr_prev_dc_coeff,
-                        get_huffman_table(HuffmanTableType::CHDC),
-                        get_huffman_table(HuffmanTableType::CHAC)
-                    ))
+                    process_single_block.borrow_mut()(block_buffer, cr_prev_dc_coeff, 2))
             );
+            if let Some(k_dct_block_iterator) = &mut k_dct_block_iterator {
+                k_dct_block_iterator.block_operation(
+                    &mut aux_buffer,
+                    &mut (|block_buffer: &mut Vec<i16>|
+                        process_single_block.borrow_mut()(block_buffer, k_prev_dc_coeff, 3))
+                );
+            }
         }
     }
 
-    fn bit_length(mut value: i16) -> u8 {
+    pub fn bit_length(mut value: i32) -> u8 {
         let mut length: u8 = 0;
         while value > 0 {
             value >>= 1;
@@ -137,21 +275,32 @@ impl JpegImage {
         (zeros_count << 4) | bit_length
     }
 
-    fn coeff_to_amplitude(value: i16, bit_length: u8) -> i16 {
-        if value < 0 { value + (1 << bit_length) - 1 } else { value }
+    pub fn coeff_to_amplitude(value: i32, bit_length: u8) -> u16 {
+        (if value < 0 { value + (1 << bit_length) - 1 } else { value }) as u16
+    }
+
+    pub fn amplitude_to_coeff(amplitude: u16, bit_length: u8) -> i32 {
+        // negative values are stored as the amplitude minus one, so their first bit is 0
+        let amplitude = amplitude as i32;
+        if bit_length > 0 && amplitude < 1 << (bit_length - 1) {
+            amplitude - (1 << bit_length) + 1
+        } else {
+            amplitude
+        }
     }
 
-    fn runlength_encode(
+    pub fn runlength_encode(
         prev_dc_coeff: &mut i16,
         dct_coeffs: &Vec<i16>,
         result_buffer: &mut Vec<RunLength>
     ) {
-        let dc_coeff = dct_coeffs[0] - *prev_dc_coeff;
+        let dc_coeff = (dct_coeffs[0] as i32) - (*prev_dc_coeff as i32);
         *prev_dc_coeff = dct_coeffs[0];
 
+        // 11 and 10 bits are the limits for 8 bit samples, and 15 and 14 bits for 12 bit samples
         let dc_bit_length = Self::bit_length(dc_coeff.abs());
-        if dc_bit_length > 11 {
-            panic!("DC coefficient bit length greater than 11!");
+        if dc_bit_length > 15 {
+            panic!("DC coefficient bit length greater than 15!");
         }
         // handle DC coefficient
         result_buffer.push(RunLength {
@@ -184,10 +333,10 @@ impl JpegImage {
                 zeros_count -= 16;
             }
 
-            let ac_coeff = dct_coeffs[ZIG_ZAG_MAP[i]];
+            let ac_coeff = dct_coeffs[ZIG_ZAG_MAP[i]] as i32;
             let ac_bit_length = Self::bit_length(ac_coeff.abs());
-            if ac_bit_length > 10 {
-                panic!("AC coefficient bit length greater than 10!");
+            if ac_bit_length > 14 {
+                panic!("AC coefficient bit length greater than 14!");
             }
             result_buffer.push(RunLength {
                 symbol: Self::get_run_length_symbol(zeros_count, ac_bit_length),
@@ -198,8 +347,8 @@ impl JpegImage {
         }
     }
 
-    fn huffman_encode(
-        runlength: &Vec<RunLength>,
+    pub fn huffman_encode(
+        runlength: &[RunLength],
         bitvec: &mut BitVec<u8, Msb0>,
         dc_huffman_table: &HuffmanTable,
         ac_huffman_table: &HuffmanTable
@@ -226,3 +375,92 @@ impl JpegImage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::dct_quant::DctAlgorithm;
+
+    // reads one Huffman code, and returns its symbol
+    fn read_symbol(bits: &BitVec<u8, Msb0>, position: &mut usize, table: &HuffmanTable) -> u8 {
+        let mut code: u32 = 0;
+        for length in 0..16 {
+            code = (code << 1) | (bits[*position] as u32);
+            *position += 1;
+            for j in table.offsets[length]..table.offsets[length + 1] {
+                if table.codes[j as usize] == code {
+                    return table.symbols[j as usize];
+                }
+            }
+        }
+        panic!("Invalid Huffman code!");
+    }
+
+    fn read_coeff(bits: &BitVec<u8, Msb0>, position: &mut usize, bit_length: u8) -> i32 {
+        let mut amplitude: u16 = 0;
+        for _ in 0..bit_length {
+            amplitude = (amplitude << 1) | (bits[*position] as u16);
+            *position += 1;
+        }
+        JpegImage::amplitude_to_coeff(amplitude, bit_length)
+    }
+
+    #[test]
+    fn test_12_bit_coefficients_round_trip() {
+        // coefficients of 12 bit images need the categories the standard tables don't have
+        let mut image = JpegImage::new(String::new(), 16, 8, (4, 4, 4), DctAlgorithm::RealDct);
+        image.precision = 12;
+        for (i, coeff) in image.y_dct_coeffs.pixels.iter_mut().enumerate() {
+            *coeff = match i % 7 {
+                0 => 16000 - (i as i16) * 100,
+                1 => -12000,
+                2 => 3,
+                3 => (i as i16) * 37 - 2000,
+                _ => 0,
+            };
+        }
+        image.cb_dct_coeffs.pixels[0] = -16000;
+        image.cr_dct_coeffs.pixels[63] = 9000;
+
+        image.generate_entropy_encoded_data();
+        let tables = image.huffman_tables.as_ref().expect("12 bit images should get their own tables");
+
+        // decode the blocks (Y, Cb, Cr for each of the 2 MCUs) and compare them with the coefficients
+        let bits = &image.entropy_coded_bits;
+        let mut position = 0;
+        let mut prev_dc = [0i32; 3];
+        for mcu in 0..2 {
+            for component in 0..3 {
+                let table = if component == 0 { 0 } else { 1 };
+                let mut block = [0i32; 64];
+
+                let dc_bit_length = read_symbol(bits, &mut position, &tables[table]);
+                prev_dc[component] += read_coeff(bits, &mut position, dc_bit_length);
+                block[0] = prev_dc[component];
+
+                let mut k = 1;
+                while k < 64 {
+                    let symbol = read_symbol(bits, &mut position, &tables[table + 2]);
+                    if symbol == 0x00 {
+                        break;
+                    }
+                    k += (symbol >> 4) as usize;
+                    if symbol == 0xf0 {
+                        k += 1;
+                        continue;
+                    }
+                    block[ZIG_ZAG_MAP[k]] = read_coeff(bits, &mut position, symbol & 0x0f);
+                    k += 1;
+                }
+
+                let coeffs = [&image.y_dct_coeffs, &image.cb_dct_coeffs, &image.cr_dct_coeffs][component];
+                for row in 0..8 {
+                    for col in 0..8 {
+                        let expected = coeffs.get_pixel(row, mcu * 8 + col).unwrap() as i32;
+                        assert_eq!(block[row * 8 + col], expected);
+                    }
+                }
+            }
+        }
+    }
+}
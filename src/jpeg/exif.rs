@@ -0,0 +1,327 @@
+use crate::JpegImage;
+use crate::jpeg::thumbnail::ThumbnailLocation;
+
+pub const EXIF_HEADER: &[u8; 6] = b"Exif\0\0";
+
+// IFD0 tags
+const TAG_IMAGE_DESCRIPTION: u16 = 0x010e;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_SOFTWARE: u16 = 0x0131;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_ARTIST: u16 = 0x013b;
+const TAG_COPYRIGHT: u16 = 0x8298;
+const TAG_EXIF_IFD_POINTER: u16 = 0x8769;
+
+// Exif IFD tags
+const TAG_EXIF_VERSION: u16 = 0x9000;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_PIXEL_X_DIMENSION: u16 = 0xa002;
+const TAG_PIXEL_Y_DIMENSION: u16 = 0xa003;
+
+// IFD1 (thumbnail) tags
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_X_RESOLUTION: u16 = 0x011a;
+const TAG_Y_RESOLUTION: u16 = 0x011b;
+const TAG_RESOLUTION_UNIT: u16 = 0x0128;
+const TAG_JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+const TAG_JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+
+const COMPRESSION_JPEG: u16 = 6;
+const RESOLUTION_UNIT_INCH: u16 = 2;
+
+// field types
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_UNDEFINED: u16 = 7;
+
+// what goes in the EXIF segment, the pixel dimensions are always taken from the image
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExifMetadata {
+    pub orientation: Option<u16>, // 1 to 8, how the image has to be rotated and flipped to be displayed
+    pub date_time: Option<String>, // in the format "YYYY:MM:DD HH:MM:SS"
+    pub software: Option<String>,
+    pub artist: Option<String>,
+    pub copyright: Option<String>,
+    pub image_description: Option<String>,
+}
+
+impl ExifMetadata {
+    pub fn new() -> ExifMetadata {
+        ExifMetadata::default()
+    }
+
+    pub fn orientation(mut self, orientation: u16) -> ExifMetadata {
+        if !(1..=8).contains(&orientation) {
+            panic!("EXIF orientation must go from 1 to 8\n");
+        }
+        self.orientation = Some(orientation);
+        self
+    }
+
+    pub fn date_time(mut self, date_time: &str) -> ExifMetadata {
+        if !is_valid_date_time(date_time) {
+            panic!("EXIF date and time must be in the format YYYY:MM:DD HH:MM:SS\n");
+        }
+        self.date_time = Some(date_time.to_string());
+        self
+    }
+
+    pub fn software(mut self, software: &str) -> ExifMetadata {
+        self.software = Some(software.to_string());
+        self
+    }
+
+    pub fn artist(mut self, artist: &str) -> ExifMetadata {
+        self.artist = Some(artist.to_string());
+        self
+    }
+
+    pub fn copyright(mut self, copyright: &str) -> ExifMetadata {
+        self.copyright = Some(copyright.to_string());
+        self
+    }
+
+    pub fn image_description(mut self, image_description: &str) -> ExifMetadata {
+        self.image_description = Some(image_description.to_string());
+        self
+    }
+}
+
+pub fn is_valid_date_time(date_time: &str) -> bool {
+    // "YYYY:MM:DD HH:MM:SS", only the position of the digits and separators is checked
+    date_time.len() == 19 &&
+        date_time.char_indices().all(|(i, c)| {
+            match i {
+                4 | 7 | 13 | 16 => c == ':',
+                10 => c == ' ',
+                _ => c.is_ascii_digit(),
+            }
+        })
+}
+
+struct ExifEntry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    data: Vec<u8>, // big endian values
+}
+
+impl ExifEntry {
+    fn ascii(tag: u16, value: &str) -> ExifEntry {
+        // strings end with a NUL, which counts as part of the value
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        ExifEntry { tag, field_type: TYPE_ASCII, count: data.len() as u32, data }
+    }
+
+    fn short(tag: u16, value: u16) -> ExifEntry {
+        ExifEntry { tag, field_type: TYPE_SHORT, count: 1, data: value.to_be_bytes().to_vec() }
+    }
+
+    fn long(tag: u16, value: u32) -> ExifEntry {
+        ExifEntry { tag, field_type: TYPE_LONG, count: 1, data: value.to_be_bytes().to_vec() }
+    }
+
+    fn undefined(tag: u16, value: &[u8]) -> ExifEntry {
+        ExifEntry { tag, field_type: TYPE_UNDEFINED, count: value.len() as u32, data: value.to_vec() }
+    }
+
+    fn rational(tag: u16, numerator: u32, denominator: u32) -> ExifEntry {
+        let mut data = numerator.to_be_bytes().to_vec();
+        data.extend_from_slice(&denominator.to_be_bytes());
+        ExifEntry { tag, field_type: TYPE_RATIONAL, count: 1, data }
+    }
+}
+
+impl JpegImage {
+    pub fn get_exif_segment(&self) -> Option<Vec<u8>> {
+        // a thumbnail in the EXIF segment needs one, even if there is no metadata
+        let thumbnail = self.thumbnail.as_deref().filter(|_| self.thumbnail_location == ThumbnailLocation::Exif);
+        if self.exif.is_none() && thumbnail.is_none() {
+            return None;
+        }
+        let exif = self.exif.clone().unwrap_or_default();
+        Some(Self::build_exif_segment(&exif, self.width as u32, self.height as u32, thumbnail))
+    }
+
+    pub fn build_exif_segment(exif: &ExifMetadata, width: u32, height: u32, thumbnail: Option<&[u8]>) -> Vec<u8> {
+        // the content of the APP1 segment: the EXIF header and a big endian TIFF structure,
+        // with the image fields in IFD0 and the pixel dimensions in the Exif IFD it points to.
+        // A JPEG thumbnail goes in IFD1, the one that follows IFD0, with its data after it
+        let mut ifd0 = Vec::new();
+        if let Some(image_description) = &exif.image_description {
+            ifd0.push(ExifEntry::ascii(TAG_IMAGE_DESCRIPTION, image_description));
+        }
+        if let Some(orientation) = exif.orientation {
+            ifd0.push(ExifEntry::short(TAG_ORIENTATION, orientation));
+        }
+        if let Some(software) = &exif.software {
+            ifd0.push(ExifEntry::ascii(TAG_SOFTWARE, software));
+        }
+        if let Some(date_time) = &exif.date_time {
+            ifd0.push(ExifEntry::ascii(TAG_DATE_TIME, date_time));
+        }
+        if let Some(artist) = &exif.artist {
+            ifd0.push(ExifEntry::ascii(TAG_ARTIST, artist));
+        }
+        if let Some(copyright) = &exif.copyright {
+            ifd0.push(ExifEntry::ascii(TAG_COPYRIGHT, copyright));
+        }
+        ifd0.push(ExifEntry::long(TAG_EXIF_IFD_POINTER, 0)); // set once the size of IFD0 is known
+
+        let mut exif_ifd = vec![ExifEntry::undefined(TAG_EXIF_VERSION, b"0232")];
+        if let Some(date_time) = &exif.date_time {
+            exif_ifd.push(ExifEntry::ascii(TAG_DATE_TIME_ORIGINAL, date_time));
+        }
+        exif_ifd.push(ExifEntry::long(TAG_PIXEL_X_DIMENSION, width));
+        exif_ifd.push(ExifEntry::long(TAG_PIXEL_Y_DIMENSION, height));
+
+        // IFD0 goes right after the 8 bytes of the TIFF header, and the Exif IFD right after it
+        let exif_ifd_offset = 8 + Self::get_ifd_size(&ifd0);
+        ifd0.last_mut().unwrap().data = (exif_ifd_offset as u32).to_be_bytes().to_vec();
+
+        let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+        match thumbnail {
+            Some(thumbnail) => {
+                let ifd1_offset = exif_ifd_offset + Self::get_ifd_size(&exif_ifd);
+                let mut ifd1 = vec![
+                    ExifEntry::short(TAG_COMPRESSION, COMPRESSION_JPEG),
+                    ExifEntry::rational(TAG_X_RESOLUTION, 72, 1),
+                    ExifEntry::rational(TAG_Y_RESOLUTION, 72, 1),
+                    ExifEntry::short(TAG_RESOLUTION_UNIT, RESOLUTION_UNIT_INCH),
+                    ExifEntry::long(TAG_JPEG_INTERCHANGE_FORMAT, 0),
+                    ExifEntry::long(TAG_JPEG_INTERCHANGE_FORMAT_LENGTH, thumbnail.len() as u32),
+                ];
+                let thumbnail_offset = ifd1_offset + Self::get_ifd_size(&ifd1);
+                ifd1[4].data = (thumbnail_offset as u32).to_be_bytes().to_vec();
+
+                Self::write_ifd(&mut tiff, &ifd0, ifd1_offset as u32);
+                Self::write_ifd(&mut tiff, &exif_ifd, 0);
+                Self::write_ifd(&mut tiff, &ifd1, 0);
+                tiff.extend_from_slice(thumbnail);
+            }
+            None => {
+                Self::write_ifd(&mut tiff, &ifd0, 0);
+                Self::write_ifd(&mut tiff, &exif_ifd, 0);
+            }
+        }
+
+        let mut segment = EXIF_HEADER.to_vec();
+        segment.extend_from_slice(&tiff);
+        segment
+    }
+
+    fn get_ifd_size(entries: &[ExifEntry]) -> usize {
+        // entry count, entries, offset of the next IFD, and the values that don't fit in the entries (word aligned)
+        let values_size: usize = entries
+            .iter()
+            .filter(|entry| entry.data.len() > 4)
+            .map(|entry| entry.data.len().next_multiple_of(2))
+            .sum();
+        2 + entries.len() * 12 + 4 + values_size
+    }
+
+    fn write_ifd(tiff: &mut Vec<u8>, entries: &[ExifEntry], next_ifd_offset: u32) {
+        // offsets are relative to the start of the TIFF header, which is where the IFD is appended
+        let mut value_offset = tiff.len() + 2 + entries.len() * 12 + 4;
+        let mut values = Vec::new();
+
+        tiff.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+        for entry in entries {
+            tiff.extend_from_slice(&entry.tag.to_be_bytes());
+            tiff.extend_from_slice(&entry.field_type.to_be_bytes());
+            tiff.extend_from_slice(&entry.count.to_be_bytes());
+            if entry.data.len() <= 4 {
+                // small values are left aligned in the 4 bytes of the entry
+                let mut inline_value = [0u8; 4];
+                inline_value[..entry.data.len()].copy_from_slice(&entry.data);
+                tiff.extend_from_slice(&inline_value);
+            } else {
+                tiff.extend_from_slice(&(value_offset as u32).to_be_bytes());
+                values.extend_from_slice(&entry.data);
+                if !values.len().is_multiple_of(2) {
+                    values.push(0);
+                }
+                value_offset += entry.data.len().next_multiple_of(2);
+            }
+        }
+        tiff.extend_from_slice(&next_ifd_offset.to_be_bytes()); // 0 if there is no next IFD
+        tiff.extend_from_slice(&values);
+    }
+}
+
+// reads the fields of an IFD back as (tag, type, count, value bytes), for the tests here and in other modules
+#[cfg(test)]
+pub fn parse_ifd(tiff: &[u8], offset: usize) -> Vec<(u16, u16, u32, Vec<u8>)> {
+    use byteorder::{ BigEndian, ByteOrder };
+    let entries = BigEndian::read_u16(&tiff[offset..offset + 2]) as usize;
+    (0..entries)
+        .map(|i| {
+            let entry = offset + 2 + i * 12;
+            let tag = BigEndian::read_u16(&tiff[entry..entry + 2]);
+            let field_type = BigEndian::read_u16(&tiff[entry + 2..entry + 4]);
+            let count = BigEndian::read_u32(&tiff[entry + 4..entry + 8]);
+            let size = (count as usize) *
+                (match field_type {
+                    TYPE_SHORT => 2,
+                    TYPE_LONG => 4,
+                    TYPE_RATIONAL => 8,
+                    _ => 1,
+                });
+            let value_start = if size <= 4 { entry + 8 } else { BigEndian::read_u32(&tiff[entry + 8..entry + 12]) as usize };
+            (tag, field_type, count, tiff[value_start..value_start + size].to_vec())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ BigEndian, ByteOrder };
+
+    #[test]
+    fn test_exif_ifds_parse_back() {
+        let exif = ExifMetadata::new()
+            .orientation(6)
+            .date_time("2024:05:17 10:30:00")
+            .software("jpeg-encoder")
+            .artist("Someone")
+            .copyright("(c) Someone")
+            .image_description("A test image");
+        let segment = JpegImage::build_exif_segment(&exif, 640, 480, None);
+        assert_eq!(&segment[0..6], EXIF_HEADER);
+
+        let tiff = &segment[6..];
+        assert_eq!(&tiff[0..4], b"MM\0\x2a");
+        let ifd0 = parse_ifd(tiff, BigEndian::read_u32(&tiff[4..8]) as usize);
+
+        // tags are in ascending order
+        let tags: Vec<u16> = ifd0.iter().map(|(tag, _, _, _)| *tag).collect();
+        assert_eq!(tags, vec![0x010e, 0x0112, 0x0131, 0x0132, 0x013b, 0x8298, 0x8769]);
+
+        let get = |ifd: &[(u16, u16, u32, Vec<u8>)], tag: u16| {
+            ifd.iter().find(|(field_tag, _, _, _)| *field_tag == tag).unwrap().clone()
+        };
+        assert_eq!(get(&ifd0, TAG_IMAGE_DESCRIPTION).3, b"A test image\0");
+        assert_eq!(get(&ifd0, TAG_ORIENTATION), (TAG_ORIENTATION, TYPE_SHORT, 1, vec![0, 6]));
+        assert_eq!(get(&ifd0, TAG_SOFTWARE).3, b"jpeg-encoder\0");
+        assert_eq!(get(&ifd0, TAG_DATE_TIME), (TAG_DATE_TIME, TYPE_ASCII, 20, b"2024:05:17 10:30:00\0".to_vec()));
+        assert_eq!(get(&ifd0, TAG_ARTIST).3, b"Someone\0");
+        assert_eq!(get(&ifd0, TAG_COPYRIGHT).3, b"(c) Someone\0");
+
+        let exif_ifd = parse_ifd(tiff, BigEndian::read_u32(&get(&ifd0, TAG_EXIF_IFD_POINTER).3) as usize);
+        assert_eq!(get(&exif_ifd, TAG_EXIF_VERSION).3, b"0232");
+        assert_eq!(get(&exif_ifd, TAG_DATE_TIME_ORIGINAL).3, b"2024:05:17 10:30:00\0");
+        assert_eq!(get(&exif_ifd, TAG_PIXEL_X_DIMENSION).3, 640u32.to_be_bytes());
+        assert_eq!(get(&exif_ifd, TAG_PIXEL_Y_DIMENSION).3, 480u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_date_time_format() {
+        assert!(is_valid_date_time("2024:05:17 10:30:00"));
+        assert!(!is_valid_date_time("2024-05-17 10:30:00"));
+        assert!(!is_valid_date_time("2024:05:17"));
+    }
+}
@@ -1,60 +1,119 @@
 use std::{ fs::File, io::{ self, Write } };
 use byteorder::{ BigEndian, WriteBytesExt };
 use super::{
-    huffman_tables::{ get_huffman_table, HuffmanTable, HuffmanTableType, ZIG_ZAG_MAP },
-    jpeg_image::JpegImage,
-    quant_tables::{ DEFAULT_CH_QUANTIZATION_TABLE, DEFAULT_Y_QUANTIZATION_TABLE },
+    arithmetic_coding::{ AC_KX, DC_LOWER_BOUND, DC_UPPER_BOUND },
+    entropy_coding::EntropyCoder,
+    huffman_tables::{ HuffmanTable, ZIG_ZAG_MAP },
+    jpeg_image::{ ColorSpace, Density, JpegImage },
+    progressive::ProgressiveScan,
 };
 
 impl JpegImage {
-    fn write_soi(file: &mut File) -> io::Result<usize> {
+    fn write_soi(file: &mut impl Write) -> io::Result<usize> {
         file.write(&[0xff, 0xd8])
     }
 
-    fn write_app0(file: &mut File) -> io::Result<usize> {
+    fn write_app0(file: &mut impl Write, density: &Density) -> io::Result<usize> {
         file.write(&[0xff, 0xe0])?;
         file.write_u16::<BigEndian>(16)?; // length
         file.write(b"JFIF\0")?;
         file.write(&[1, 1])?; // version
-        file.write(&[1])?; // units
-        file.write_u16::<BigEndian>(72)?; // density
-        file.write_u16::<BigEndian>(72)?; // density
+        file.write_u8(density.unit as u8)?; // units
+        file.write_u16::<BigEndian>(density.x)?; // density
+        file.write_u16::<BigEndian>(density.y)?; // density
         file.write(&[0, 0]) // thumbnail
     }
 
+    fn write_segment(file: &mut impl Write, marker: u8, content: &[u8]) -> io::Result<usize> {
+        // APPn and COM segments, whose 16 bit length field counts itself
+        if content.len() + 2 > 65535 {
+            panic!("Segment 0xff{:02x} of {} bytes doesn't fit in a JPEG segment\n", marker, content.len());
+        }
+        file.write_all(&[0xff, marker])?;
+        file.write_u16::<BigEndian>((content.len() + 2) as u16)?;
+        file.write_all(content)?;
+        Ok(4 + content.len())
+    }
+
     fn write_quantization_tables(
-        file: &mut File,
+        file: &mut impl Write,
         destination: u8, // 0 for luminance, 1 for chrominance
-        table: &[u8; 64]
+        table: &[u8; 64],
+        precision: u8
     ) -> io::Result<usize> {
+        // 12 bit images get 16 bit entries
+        let entry_size: u16 = if precision > 8 { 2 } else { 1 };
         file.write(&[0xff, 0xdb])?;
-        file.write_u16::<BigEndian>(67)?; // length
-        file.write(&[destination])?;
+        file.write_u16::<BigEndian>(3 + 64 * entry_size)?; // length
+        file.write_u8((((entry_size - 1) as u8) << 4) | destination)?;
         for i in 0..64 {
-            file.write_u8(table[ZIG_ZAG_MAP[i]])?;
+            if entry_size == 2 {
+                file.write_u16::<BigEndian>(table[ZIG_ZAG_MAP[i]] as u16)?;
+            } else {
+                file.write_u8(table[ZIG_ZAG_MAP[i]])?;
+            }
+        }
+        Ok(5 + 64 * (entry_size as usize))
+    }
+
+    pub fn get_component_ids(&self) -> Vec<u8> {
+        // lossless images are stored in RGB, which decoders recognize by the component ids
+        if self.color_space == ColorSpace::Grayscale {
+            vec![1]
+        } else if self.lossless_predictor.is_some() || self.color_space == ColorSpace::Rgb {
+            vec![b'R', b'G', b'B']
+        } else if self.k_dct_coeffs.is_some() {
+            vec![1, 2, 3, 4]
+        } else {
+            vec![1, 2, 3]
+        }
+    }
+
+    pub fn get_component_tables(&self) -> Vec<usize> {
+        // the quantization and Huffman tables of each component: 0 for the luminance ones, 1 for the chrominance ones.
+        // The components of CMYK and RGB are alike, so they share the luminance tables, while YCCK is YCbCr plus black
+        match self.color_space {
+            ColorSpace::Grayscale => vec![0],
+            ColorSpace::Rgb => vec![0, 0, 0],
+            ColorSpace::Cmyk => vec![0, 0, 0, 0],
+            ColorSpace::Ycck => vec![0, 1, 1, 0],
+            ColorSpace::YCbCr => vec![0, 1, 1],
         }
-        Ok(69)
     }
 
-    fn write_start_of_frame(&self, file: &mut File) -> io::Result<usize> {
-        file.write(&[0xff, 0xc0])?;
-        file.write_u16::<BigEndian>(17)?; // length
-        file.write_u8(8)?; // precision
+    pub fn has_jfif_header(&self) -> bool {
+        // JFIF implies YCbCr or grayscale, so the RGB lossless images and the CMYK ones go without it
+        self.lossless_predictor.is_none() && matches!(self.color_space, ColorSpace::YCbCr | ColorSpace::Grayscale)
+    }
+
+    fn write_start_of_frame(&self, file: &mut impl Write) -> io::Result<usize> {
+        match (self.lossless_predictor, &self.entropy_coder) {
+            (Some(_), _) => file.write(&[0xff, 0xc3])?, // lossless
+            (None, EntropyCoder::Huffman) if self.progressive => file.write(&[0xff, 0xc2])?, // progressive
+            (None, EntropyCoder::Huffman) if self.precision == 8 => file.write(&[0xff, 0xc0])?, // baseline
+            (None, EntropyCoder::Huffman) => file.write(&[0xff, 0xc1])?, // extended
+            (None, EntropyCoder::Arithmetic) => file.write(&[0xff, 0xc9])?, // extended, arithmetic coding
+        };
+        let component_ids = self.get_component_ids();
+        let component_tables = self.get_component_tables();
+        file.write_u16::<BigEndian>(8 + 3 * (component_ids.len() as u16))?; // length
+        file.write_u8(self.precision)?;
         file.write_u16::<BigEndian>(self.height as u16)?;
         file.write_u16::<BigEndian>(self.width as u16)?;
-        file.write_u8(3)?; // components
-        for i in 1..4 {
-            file.write_u8(i)?;
+        file.write_u8(component_ids.len() as u8)?; // components
+        for (i, id) in component_ids.iter().enumerate() {
+            file.write_u8(*id)?;
             let (h, v) = Self::get_downsampling_factor(self.chrominance_downsampling_ratio);
-            let sampling_factor: u8 = if i == 1 { ((h as u8) << 4) | (v as u8) } else { 0x11 };
+            let sampling_factor: u8 = if i == 0 { ((h as u8) << 4) | (v as u8) } else { 0x11 };
             file.write_u8(sampling_factor)?;
-            file.write_u8(if i == 1 { 0 } else { 1 })?; // quant table
+            // quant table (not used in lossless mode)
+            file.write_u8(if self.lossless_predictor.is_some() { 0 } else { component_tables[i] as u8 })?;
         }
-        Ok(19)
+        Ok(10 + 3 * component_ids.len())
     }
 
     fn write_huffman_table(
-        file: &mut File,
+        file: &mut impl Write,
         coeff_type: u8, // 0 for DC - 1 for AC
         table_id: u8, // 0 for Y - 1 for Ch
         table: &HuffmanTable
@@ -74,65 +133,199 @@ impl JpegImage {
         Ok(1)
     }
 
-    fn write_start_of_scan(file: &mut File) -> io::Result<usize> {
+    fn write_arithmetic_conditioning(file: &mut impl Write) -> io::Result<usize> {
+        file.write_all(&[0xff, 0xcc])?;
+        file.write_u16::<BigEndian>(10)?; // length
+        for table_id in 0..2 {
+            file.write_u8(table_id)?; // DC
+            file.write_u8((DC_UPPER_BOUND << 4) | DC_LOWER_BOUND)?;
+            file.write_u8((1 << 4) | table_id)?; // AC
+            file.write_u8(AC_KX)?;
+        }
+        Ok(12)
+    }
+
+    fn write_start_of_scan(&self, file: &mut impl Write) -> io::Result<usize> {
         file.write(&[0xff, 0xda])?;
-        file.write_u16::<BigEndian>(12)?; // length
-        file.write_u8(3)?; // components
-        for i in 1..4 {
-            file.write_u8(i)?;
-            file.write_u8(if i == 1 { 0 } else { 0x11 })?; // dc, ac table
+        let component_ids = self.get_component_ids();
+        let component_tables = self.get_component_tables();
+        file.write_u16::<BigEndian>(6 + 2 * (component_ids.len() as u16))?; // length
+        file.write_u8(component_ids.len() as u8)?; // components
+        for (i, id) in component_ids.iter().enumerate() {
+            file.write_u8(*id)?;
+            file.write_u8(((component_tables[i] as u8) << 4) | (component_tables[i] as u8))?; // dc, ac table
+        }
+        match self.lossless_predictor {
+            Some(predictor) => {
+                file.write_u8(predictor)?; // the spectral selection start holds the predictor
+                file.write_u8(0)?;
+            }
+            None => {
+                file.write_u8(0)?;
+                file.write_u8(63)?;
+            }
         }
-        file.write_u8(0)?;
-        file.write_u8(63)?;
         file.write_u8(0)?;
 
-        Ok(14)
+        Ok(8 + 2 * component_ids.len())
     }
 
-    fn write_image_data(&self, file: &mut File) -> io::Result<usize> {
-        self.entropy_coded_bits
-            .as_raw_slice()
-            .into_iter()
-            .for_each(|byte| {
-                _ = file.write_u8(*byte);
-                if *byte == 0xff {
-                    _ = file.write_u8(0); // escape possible marker
-                }
-            });
+    fn write_progressive_scan(&self, file: &mut impl Write, scan: &ProgressiveScan) -> io::Result<usize> {
+        // the tables of the scan go right before it, replacing the ones of the previous scans with the same id
+        for (class, id, table) in &scan.huffman_tables {
+            Self::write_huffman_table(file, *class, *id, table)?;
+        }
+
+        file.write_all(&[0xff, 0xda])?;
+        let component_ids = self.get_component_ids();
+        let component_tables = self.get_component_tables();
+        file.write_u16::<BigEndian>(6 + 2 * (scan.components.len() as u16))?; // length
+        file.write_u8(scan.components.len() as u8)?; // components
+        for component in &scan.components {
+            file.write_u8(component_ids[*component])?;
+            // DC scans only use the DC table, and AC scans the AC one
+            let table = component_tables[*component] as u8;
+            file.write_u8(if scan.spectral_start == 0 { table << 4 } else { table })?;
+        }
+        file.write_u8(scan.spectral_start)?;
+        file.write_u8(scan.spectral_end)?;
+        file.write_u8((scan.high_bit << 4) | scan.low_bit)?;
+
+        Self::write_entropy_coded_bytes(file, scan.data.as_raw_slice())
+    }
+
+    fn write_image_data(&self, file: &mut impl Write) -> io::Result<usize> {
+        if self.progressive {
+            for scan in &self.progressive_scans {
+                self.write_progressive_scan(file, scan)?;
+            }
+            return Ok(1);
+        }
+        Self::write_entropy_coded_bytes(file, self.entropy_coded_bits.as_raw_slice())
+    }
+
+    pub fn write_entropy_coded_bytes(file: &mut impl Write, bytes: &[u8]) -> io::Result<usize> {
+        for byte in bytes {
+            file.write_u8(*byte)?;
+            if *byte == 0xff {
+                file.write_u8(0)?; // escape possible marker
+            }
+        }
         Ok(1)
     }
 
+    pub fn write_eoi(file: &mut impl Write) -> io::Result<usize> {
+        file.write(&[0xff, 0xd9])
+    }
+
     pub fn generate_file(&self) -> std::io::Result<()> {
         let mut file = File::create(&self.path)?;
+        self.write_jpeg(&mut file)
+    }
+
+    pub fn write_jpeg(&self, file: &mut impl Write) -> io::Result<()> {
+        self.write_headers(file)?;
+
+        // IMAGE DATA
+        self.write_image_data(file)?;
 
+        // END OF IMAGE
+        Self::write_eoi(file)?;
+
+        return Ok(());
+    }
+
+    pub fn write_headers(&self, file: &mut impl Write) -> io::Result<()> {
+        // everything that goes before the entropy coded data
         // START OF IMAGE
-        Self::write_soi(&mut file)?;
+        Self::write_soi(file)?;
 
         // APP0
-        Self::write_app0(&mut file)?;
+        if self.has_jfif_header() {
+            Self::write_app0(file, &self.density)?;
+        }
+
+        // APP0 (JFXX thumbnail, right after the JFIF header)
+        if let Some(content) = self.get_jfxx_segment() {
+            Self::write_segment(file, 0xe0, &content)?;
+        }
+
+        // APPn and COM (the ones copied from an input JPEG)
+        for (marker, content) in &self.segments {
+            Self::write_segment(file, *marker, content)?;
+        }
+
+        // APP1 (EXIF, with the thumbnail if it goes there)
+        if let Some(content) = self.get_exif_segment() {
+            Self::write_segment(file, 0xe1, &content)?;
+        }
+
+        // APP1 (XMP)
+        if let Some(xmp) = &self.xmp {
+            for segment in Self::get_xmp_segments(xmp) {
+                Self::write_segment(file, 0xe1, &segment)?;
+            }
+        }
+
+        // APP2 (ICC profile, in as many segments as needed)
+        if let Some(icc_profile) = &self.icc_profile {
+            for chunk in Self::get_icc_chunks(icc_profile) {
+                Self::write_segment(file, 0xe2, &chunk)?;
+            }
+        }
+
+        // APP14 (Adobe, with the color transform of the images that JFIF can't describe)
+        if let Some(content) = self.get_adobe_segment() {
+            Self::write_segment(file, 0xee, &content)?;
+        }
+
+        // COMMENTS
+        for comment in &self.comments {
+            for segment in Self::get_comment_segments(comment) {
+                Self::write_segment(file, 0xfe, segment)?;
+            }
+        }
+
+        // the chrominance tables are only written if some component uses them
+        let chrominance_tables = self.get_component_tables().contains(&1);
 
         // QUANTIZATION TABLES
-        Self::write_quantization_tables(&mut file, 0, &DEFAULT_Y_QUANTIZATION_TABLE)?;
-        Self::write_quantization_tables(&mut file, 1, &DEFAULT_CH_QUANTIZATION_TABLE)?;
+        if self.lossless_predictor.is_none() {
+            Self::write_quantization_tables(file, 0, &self.quantization_tables[0], self.precision)?;
+            if chrominance_tables {
+                Self::write_quantization_tables(file, 1, &self.quantization_tables[1], self.precision)?;
+            }
+        }
 
         // START OF FRAME
-        self.write_start_of_frame(&mut file)?;
+        self.write_start_of_frame(file)?;
 
-        // DEFINE HUFFMAN TABLES
-        Self::write_huffman_table(&mut file, 0, 0, get_huffman_table(HuffmanTableType::YDC))?;
-        Self::write_huffman_table(&mut file, 0, 1, get_huffman_table(HuffmanTableType::CHDC))?;
-        Self::write_huffman_table(&mut file, 1, 0, get_huffman_table(HuffmanTableType::YAC))?;
-        Self::write_huffman_table(&mut file, 1, 1, get_huffman_table(HuffmanTableType::CHAC))?;
-
-        // START OF SCAN
-        Self::write_start_of_scan(&mut file)?;
+        // the tables and headers of the scans of progressive images are written with each scan
+        if self.progressive {
+            return Ok(());
+        }
 
-        // IMAGE DATA
-        self.write_image_data(&mut file)?;
+        if self.lossless_predictor.is_none() && matches!(self.entropy_coder, EntropyCoder::Arithmetic) {
+            // DEFINE ARITHMETIC CODING CONDITIONING
+            Self::write_arithmetic_conditioning(file)?;
+        } else {
+            // DEFINE HUFFMAN TABLES
+            let huffman_tables = self.get_huffman_tables();
+            Self::write_huffman_table(file, 0, 0, &huffman_tables[0])?;
+            if chrominance_tables {
+                Self::write_huffman_table(file, 0, 1, &huffman_tables[1])?;
+            }
+            if self.lossless_predictor.is_none() {
+                Self::write_huffman_table(file, 1, 0, &huffman_tables[2])?;
+                if chrominance_tables {
+                    Self::write_huffman_table(file, 1, 1, &huffman_tables[3])?;
+                }
+            }
+        }
 
-        // END OF IMAGE
-        file.write(&[0xff, 0xd9])?;
+        // START OF SCAN
+        self.write_start_of_scan(file)?;
 
-        return Ok(());
+        Ok(())
     }
 }
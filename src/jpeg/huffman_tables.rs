@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 pub enum HuffmanTableType {
     YDC,
     CHDC,
@@ -5,12 +7,13 @@ pub enum HuffmanTableType {
     CHAC,
 }
 
+#[derive(Clone)]
 pub struct HuffmanTable<'a> {
     pub offsets: [u8; 17], // these are the starting indexes in the symbols or codes arrays of codes that are i+1 bits long
-    pub symbols: &'a [u8],
+    pub symbols: Cow<'a, [u8]>,
     // for DC coeffs: these are the length in bits of diff (= DC - previousDC)
     // for AC coeffs: these are amount of previous zeros (run length - 4 bits) concatenated (a|b) with the length in bits of the AC coeff (4 bits)
-    pub codes: &'a mut [u32],
+    pub codes: Vec<u32>,
     pub set: bool,
 }
 
@@ -18,6 +21,7 @@ impl<'a> HuffmanTable<'a> {
     pub fn generate_codes(&mut self) {
         let mut code: u32 = 0;
 
+        self.codes = vec![0; self.symbols.len()];
         for i in 0..16 {
             for j in self.offsets[i]..self.offsets[i + 1] {
                 self.codes[j as usize] = code;
@@ -40,25 +44,121 @@ impl<'a> HuffmanTable<'a> {
         }
         return None;
     }
+
+    pub fn from_frequencies(frequencies: &[u32; 256]) -> HuffmanTable<'static> {
+        // Optimal table for the given symbol frequencies, following Annex K.2 of the standard.
+        // A reserved symbol with the lowest frequency makes sure no code is made only of 1 bits
+        let mut frequencies: Vec<u64> = frequencies.iter().map(|f| *f as u64).collect();
+        frequencies.push(1);
+        let mut code_sizes = [0usize; 257];
+        let mut others: [Option<usize>; 257] = [None; 257];
+
+        loop {
+            // merge the two least frequent trees (picking the highest symbol on ties)
+            let mut least: Option<usize> = None;
+            let mut second_least: Option<usize> = None;
+            for i in 0..257 {
+                if frequencies[i] == 0 {
+                    continue;
+                }
+                if least.is_none_or(|l| frequencies[i] <= frequencies[l]) {
+                    second_least = least;
+                    least = Some(i);
+                } else if second_least.is_none_or(|l| frequencies[i] <= frequencies[l]) {
+                    second_least = Some(i);
+                }
+            }
+            let (Some(mut v1), Some(mut v2)) = (least, second_least) else {
+                break;
+            };
+
+            frequencies[v1] += frequencies[v2];
+            frequencies[v2] = 0;
+
+            code_sizes[v1] += 1;
+            while let Some(next) = others[v1] {
+                v1 = next;
+                code_sizes[v1] += 1;
+            }
+            others[v1] = Some(v2);
+
+            code_sizes[v2] += 1;
+            while let Some(next) = others[v2] {
+                v2 = next;
+                code_sizes[v2] += 1;
+            }
+        }
+
+        let mut bits = [0u8; 33];
+        for size in code_sizes {
+            if size > 0 {
+                bits[size] += 1;
+            }
+        }
+
+        // codes can't be longer than 16 bits: pairs of the longest codes are moved up,
+        // taking the place of a shorter one that gets split in two
+        for i in (17..33).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+
+        // remove the reserved code, which is the longest one
+        if let Some(longest) = (1..17).rev().find(|&i| bits[i] > 0) {
+            bits[longest] -= 1;
+        }
+
+        // the symbols are sorted by code length, and the code lengths are assigned in that order
+        let mut symbols = Vec::new();
+        for size in 1..33 {
+            for (symbol, code_size) in code_sizes.iter().take(256).enumerate() {
+                if *code_size == size {
+                    symbols.push(symbol as u8);
+                }
+            }
+        }
+
+        let mut offsets = [0u8; 17];
+        for i in 0..16 {
+            offsets[i + 1] = offsets[i] + bits[i + 1];
+        }
+
+        let mut table = HuffmanTable {
+            offsets,
+            symbols: Cow::Owned(symbols),
+            codes: Vec::new(),
+            set: false,
+        };
+        table.generate_codes();
+        table
+    }
 }
 
 pub static mut Y_DC_HUFFMAN_TABLE: HuffmanTable = HuffmanTable {
     offsets: [0, 0, 1, 6, 7, 8, 9, 10, 11, 12, 12, 12, 12, 12, 12, 12, 12],
-    symbols: &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b],
-    codes: &mut [0; 12],
+    symbols: Cow::Borrowed(&[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b]),
+    codes: Vec::new(),
     set: false,
 };
 
 pub static mut CH_DC_HUFFMAN_TABLE: HuffmanTable = HuffmanTable {
     offsets: [0, 0, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 12, 12, 12, 12, 12],
-    symbols: &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b],
-    codes: &mut [0; 12],
+    symbols: Cow::Borrowed(&[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b]),
+    codes: Vec::new(),
     set: false,
 };
 
 pub static mut Y_AC_HUFFMAN_TABLE: HuffmanTable = HuffmanTable {
     offsets: [0, 0, 2, 3, 6, 9, 11, 15, 18, 23, 28, 32, 36, 36, 36, 37, 162],
-    symbols: &[
+    symbols: Cow::Borrowed(&[
         0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
         0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
         0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
@@ -70,14 +170,14 @@ pub static mut Y_AC_HUFFMAN_TABLE: HuffmanTable = HuffmanTable {
         0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
         0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
         0xf9, 0xfa,
-    ],
-    codes: &mut [0; 176],
+    ]),
+    codes: Vec::new(),
     set: false,
 };
 
 pub static mut CH_AC_HUFFMAN_TABLE: HuffmanTable = HuffmanTable {
     offsets: [0, 0, 2, 3, 5, 9, 13, 16, 20, 27, 32, 36, 40, 40, 41, 43, 162],
-    symbols: &[
+    symbols: Cow::Borrowed(&[
         0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
         0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
         0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
@@ -89,8 +189,8 @@ pub static mut CH_AC_HUFFMAN_TABLE: HuffmanTable = HuffmanTable {
         0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
         0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
         0xf9, 0xfa,
-    ],
-    codes: &mut [0; 176],
+    ]),
+    codes: Vec::new(),
     set: false,
 };
 
@@ -129,3 +229,34 @@ pub fn get_huffman_table(table_type: HuffmanTableType) -> &'static HuffmanTable<
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_optimal_table_code_lengths() {
+        // frequencies growing exponentially would need codes much longer than 16 bits without the limit
+        let mut frequencies = [0u32; 256];
+        for (i, frequency) in frequencies.iter_mut().take(30).enumerate() {
+            *frequency = 1 << i;
+        }
+        let table = HuffmanTable::from_frequencies(&frequencies);
+        assert_eq!(table.symbols.len(), 30);
+
+        // the most frequent symbol gets the shortest code
+        assert_eq!(table.symbols[0], 29);
+
+        // no code is the prefix of another one, and none is made only of 1 bits
+        let codes: Vec<(u32, u8)> = (0..30).map(|s| table.get_code(s).unwrap()).collect();
+        for (i, (code, length)) in codes.iter().enumerate() {
+            assert!(*length <= 16);
+            assert_ne!(*code, (1 << *length) - 1);
+            for (j, (other_code, other_length)) in codes.iter().enumerate() {
+                if i != j && other_length >= length {
+                    assert_ne!(other_code >> (other_length - length), *code);
+                }
+            }
+        }
+    }
+}
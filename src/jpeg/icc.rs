@@ -0,0 +1,162 @@
+use crate::JpegImage;
+
+pub const ICC_PROFILE_HEADER: &[u8; 12] = b"ICC_PROFILE\0";
+
+// each APP2 segment has the length (2 bytes), the header, and the chunk number and count (1 byte each)
+pub const MAX_ICC_CHUNK_SIZE: usize = 65535 - 2 - ICC_PROFILE_HEADER.len() - 2;
+
+const ICC_HEADER_SIZE: usize = 128;
+
+// sRGB, as seen from the D50 profile connection space (IEC 61966-2-1 primaries, Bradford adapted)
+const D50_WHITE: [f64; 3] = [0.9642, 1.0, 0.8249];
+const SRGB_RED: [f64; 3] = [0.4360747, 0.2225045, 0.0139322];
+const SRGB_GREEN: [f64; 3] = [0.3850649, 0.7168786, 0.0971045];
+const SRGB_BLUE: [f64; 3] = [0.1430804, 0.0606169, 0.7141733];
+const BRADFORD_D65_TO_D50: [f64; 9] = [
+    1.0478112, 0.0228866, -0.050127, 0.0295424, 0.9904844, -0.0170491, -0.0092345, 0.0150436, 0.7521316,
+];
+// the sRGB transfer function as a parametric curve: gamma, a, b, c, d
+const SRGB_CURVE: [f64; 5] = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+
+impl JpegImage {
+    pub fn is_valid_icc_profile(profile: &[u8]) -> bool {
+        // the declared size has to match, and every profile has the "acsp" signature in its header
+        profile.len() >= ICC_HEADER_SIZE &&
+            u32::from_be_bytes([profile[0], profile[1], profile[2], profile[3]]) as usize == profile.len() &&
+            &profile[36..40] == b"acsp"
+    }
+
+    pub fn get_icc_chunks(profile: &[u8]) -> Vec<Vec<u8>> {
+        // profiles bigger than a segment are split, and every chunk says its position (from 1) and the amount of chunks
+        let chunk_count = profile.len().div_ceil(MAX_ICC_CHUNK_SIZE);
+        if chunk_count > 255 {
+            panic!("ICC profile of {} bytes is too big to be embedded\n", profile.len());
+        }
+        profile
+            .chunks(MAX_ICC_CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, data)| {
+                let mut chunk = ICC_PROFILE_HEADER.to_vec();
+                chunk.push((i + 1) as u8);
+                chunk.push(chunk_count as u8);
+                chunk.extend_from_slice(data);
+                chunk
+            })
+            .collect()
+    }
+
+    pub fn get_srgb_profile() -> Vec<u8> {
+        // a minimal ICC v4 display profile, with a matrix and a parametric curve per channel
+        let s15_fixed_16 = |value: f64| ((value * 65536.0).round() as i32).to_be_bytes();
+        let xyz = |values: &[f64]| {
+            let mut data = b"XYZ \0\0\0\0".to_vec();
+            values.iter().for_each(|value| data.extend_from_slice(&s15_fixed_16(*value)));
+            data
+        };
+        let text = |value: &str| {
+            // multi localized unicode, with a single english record in UTF-16
+            let mut data = b"mluc\0\0\0\0".to_vec();
+            data.extend_from_slice(&(1u32).to_be_bytes());
+            data.extend_from_slice(&(12u32).to_be_bytes());
+            data.extend_from_slice(b"enUS");
+            data.extend_from_slice(&((value.len() * 2) as u32).to_be_bytes());
+            data.extend_from_slice(&(28u32).to_be_bytes());
+            value.encode_utf16().for_each(|character| data.extend_from_slice(&character.to_be_bytes()));
+            data
+        };
+        let mut curve = b"para\0\0\0\0".to_vec();
+        curve.extend_from_slice(&[0, 3, 0, 0]); // function type 3
+        SRGB_CURVE.iter().for_each(|value| curve.extend_from_slice(&s15_fixed_16(*value)));
+        let mut chromatic_adaptation = b"sf32\0\0\0\0".to_vec();
+        BRADFORD_D65_TO_D50.iter().for_each(|value| chromatic_adaptation.extend_from_slice(&s15_fixed_16(*value)));
+
+        let tags: [(&[u8; 4], Vec<u8>); 9] = [
+            (b"desc", text("sRGB")),
+            (b"cprt", text("No copyright, use freely")),
+            (b"wtpt", xyz(&D50_WHITE)),
+            (b"chad", chromatic_adaptation),
+            (b"rXYZ", xyz(&SRGB_RED)),
+            (b"gXYZ", xyz(&SRGB_GREEN)),
+            (b"bXYZ", xyz(&SRGB_BLUE)),
+            (b"rTRC", curve.clone()),
+            (b"gTRC", curve),
+        ];
+
+        // the tag data goes after the header and the tag table, aligned to 4 bytes. The blue curve is the same as the
+        // green one, so both tags point to the same data
+        let table_size = 4 + (tags.len() + 1) * 12;
+        let mut tag_table = ((tags.len() + 1) as u32).to_be_bytes().to_vec();
+        let mut tag_data = Vec::new();
+        for (signature, data) in tags.iter() {
+            let offset = ICC_HEADER_SIZE + table_size + tag_data.len();
+            tag_table.extend_from_slice(*signature);
+            tag_table.extend_from_slice(&(offset as u32).to_be_bytes());
+            tag_table.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            tag_data.extend_from_slice(data);
+            tag_data.resize(tag_data.len().next_multiple_of(4), 0);
+        }
+        let green_curve_entry = tag_table[tag_table.len() - 12..].to_vec();
+        tag_table.extend_from_slice(b"bTRC");
+        tag_table.extend_from_slice(&green_curve_entry[4..]);
+
+        let size = ICC_HEADER_SIZE + table_size + tag_data.len();
+        let mut profile = Vec::with_capacity(size);
+        profile.extend_from_slice(&(size as u32).to_be_bytes());
+        profile.extend_from_slice(&[0; 4]); // preferred CMM
+        profile.extend_from_slice(&[4, 0x30, 0, 0]); // version 4.3
+        profile.extend_from_slice(b"mntrRGB XYZ ");
+        profile.extend_from_slice(&[0x07, 0xe8, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]); // creation date: 2024-01-01
+        profile.extend_from_slice(b"acsp");
+        profile.extend_from_slice(&[0; 28]); // platform, flags, manufacturer, model, attributes and rendering intent
+        D50_WHITE.iter().for_each(|value| profile.extend_from_slice(&s15_fixed_16(*value))); // illuminant
+        profile.extend_from_slice(&[0; 48]); // creator, profile id (not computed) and reserved bytes
+        profile.extend_from_slice(&tag_table);
+        profile.extend_from_slice(&tag_data);
+        profile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srgb_profile_structure() {
+        let profile = JpegImage::get_srgb_profile();
+        assert!(JpegImage::is_valid_icc_profile(&profile));
+        assert_eq!(&profile[12..24], b"mntrRGB XYZ ");
+
+        // every tag is inside the profile and starts with its type signature
+        let read_u32 = |offset: usize| u32::from_be_bytes(profile[offset..offset + 4].try_into().unwrap()) as usize;
+        let tag_count = read_u32(ICC_HEADER_SIZE);
+        assert_eq!(tag_count, 10);
+        for i in 0..tag_count {
+            let entry = ICC_HEADER_SIZE + 4 + i * 12;
+            let (offset, size) = (read_u32(entry + 4), read_u32(entry + 8));
+            assert!(offset.is_multiple_of(4) && offset + size <= profile.len());
+            let expected_type: &[u8] = match &profile[entry..entry + 4] {
+                b"desc" | b"cprt" => b"mluc",
+                b"chad" => b"sf32",
+                b"rTRC" | b"gTRC" | b"bTRC" => b"para",
+                _ => b"XYZ ",
+            };
+            assert_eq!(&profile[offset..offset + 4], expected_type);
+        }
+    }
+
+    #[test]
+    fn test_icc_chunks() {
+        let profile: Vec<u8> = (0..150000).map(|i| (i % 251) as u8).collect();
+        let chunks = JpegImage::get_icc_chunks(&profile);
+        assert_eq!(chunks.len(), 3);
+
+        let mut joined = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert!(chunk.len() + 2 <= 65535);
+            assert_eq!(&chunk[0..12], ICC_PROFILE_HEADER);
+            assert_eq!((chunk[12], chunk[13]), ((i + 1) as u8, 3));
+            joined.extend_from_slice(&chunk[14..]);
+        }
+        assert_eq!(joined, profile);
+    }
+}
@@ -1,23 +1,131 @@
 use bitvec::order::Msb0;
 use bitvec::vec::BitVec;
+use clap::ValueEnum;
+use crate::jpeg::alpha::AlphaOptions;
 use crate::jpeg::dct_quant::DctAlgorithm;
+use crate::jpeg::entropy_coding::EntropyCoder;
+use crate::jpeg::exif::ExifMetadata;
+use crate::jpeg::huffman_tables::HuffmanTable;
+use crate::jpeg::progressive::ProgressiveScan;
+use crate::jpeg::quant_tables::{ DEFAULT_CH_QUANTIZATION_TABLE, DEFAULT_Y_QUANTIZATION_TABLE };
+use crate::jpeg::thumbnail::ThumbnailLocation;
 use crate::pixel_matrix::pixel_matrix::PixelMatrix;
-use crate::bmp::bmp_image::BmpImage;
-use crate::utils::colorspace::{ YCbCrValue, rgb_to_ycbcr };
+use crate::netpbm::netpbm_image::{ HdrConversion, NetpbmImage };
+use crate::yuv::yuv_image::{ YuvFormat, YuvImage };
+use crate::utils::image_format::{ ImageFormat, RgbImage };
+use crate::utils::colorspace::{
+    RGBValue,
+    WideRGBValue,
+    WideYCbCrValue,
+    YCbCrValue,
+    rgb_to_ycbcr,
+    rgb_to_ycbcr_with_precision,
+};
+
+// biggest width and height of a JPEG image
+pub const MAX_DIMENSION: usize = 65535;
+
+// takes the RGB samples of the input image, at the given precision, to the three channels that are encoded
+pub type ColorConversion = fn(WideRGBValue, u8) -> WideYCbCrValue;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColorSpace {
+    YCbCr,
+    Grayscale, // only the Y channel is encoded
+    Cmyk, // four components, stored inverted like Adobe applications do
+    Ycck, // CMYK with the cyan, magenta and yellow converted to YCbCr
+    Rgb, // the RGB samples as they are, without color transform
+}
+
+// units of the pixel density in the JFIF header, with their value in the file
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DensityUnit {
+    AspectRatio = 0, // no unit, only the ratio between the horizontal and vertical densities
+    #[value(name = "dpi")]
+    DotsPerInch = 1,
+    #[value(name = "dpcm")]
+    DotsPerCm = 2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Density {
+    pub unit: DensityUnit,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl Density {
+    pub fn new(unit: DensityUnit, x: f64, y: f64) -> Density {
+        // densities are stored as integers, so they are rounded and kept in range
+        let to_u16 = |value: f64| value.round().clamp(1.0, 65535.0) as u16;
+        Density { unit, x: to_u16(x), y: to_u16(y) }
+    }
+
+    pub fn from_pixels_per_meter(x: i32, y: i32) -> Option<Density> {
+        // JFIF has no meters, so whole amounts of dots per cm are kept exact, and the rest are taken to dots per inch,
+        // which is what most images use (72 dpi is 2835 pixels per meter)
+        if x <= 0 || y <= 0 {
+            return None;
+        }
+        if x % 100 == 0 && y % 100 == 0 {
+            Some(Density::new(DensityUnit::DotsPerCm, (x / 100) as f64, (y / 100) as f64))
+        } else {
+            Some(Density::new(DensityUnit::DotsPerInch, (x as f64) * 0.0254, (y as f64) * 0.0254))
+        }
+    }
+
+    pub fn to_pixels_per_meter(&self) -> Option<(i32, i32)> {
+        // the other way around, for the formats that use meters. An aspect ratio has no size to give
+        match self.unit {
+            DensityUnit::AspectRatio => None,
+            DensityUnit::DotsPerInch => Some((
+                ((self.x as f64) / 0.0254).round() as i32,
+                ((self.y as f64) / 0.0254).round() as i32,
+            )),
+            DensityUnit::DotsPerCm => Some(((self.x as i32) * 100, (self.y as i32) * 100)),
+        }
+    }
+}
 
 pub struct JpegImage {
     pub path: String,
     pub width: i32,
     pub height: i32,
     pub chrominance_downsampling_ratio: (u8, u8, u8),
+    pub chrominance_downsampled: bool, // the chrominance channels were loaded already subsampled, like in raw YUV inputs
     pub dct_algorithm: DctAlgorithm,
-    pub y_channel: PixelMatrix<u8>,
-    pub cb_channel: PixelMatrix<u8>,
-    pub cr_channel: PixelMatrix<u8>,
+    pub color_space: ColorSpace,
+    pub density: Density, // written to the JFIF header, 72 dpi unless the input image has its own
+    pub exif: Option<ExifMetadata>, // written to an APP1 segment if set
+    pub icc_profile: Option<Vec<u8>>, // written to APP2 segments if set
+    pub xmp: Option<String>, // written to APP1 segments if set, with the extended XMP ones if it is too big for one
+    pub comments: Vec<String>, // each one written to a COM segment
+    pub segments: Vec<(u8, Vec<u8>)>, // APPn and COM segments (marker and content) copied from an input JPEG, written as they are
+    pub thumbnail: Option<Vec<u8>>, // a small JPEG of the image, written where thumbnail_location says
+    pub thumbnail_location: ThumbnailLocation,
+    pub deringing: bool,
+    pub trellis_quantization: bool,
+    pub adaptive_quantization: bool,
+    pub roi_mask: Option<PixelMatrix<u8>>,
+    pub lossless_predictor: Option<u8>,
+    pub entropy_coder: EntropyCoder,
+    pub precision: u8, // bits per sample: 8 for baseline, 12 for extended
+    pub quantization_tables: [[u8; 64]; 2], // luminance and chrominance, in natural order
+    pub huffman_tables: Option<[HuffmanTable<'static>; 4]>, // optimized for the image, the standard ones are used if None
+    pub optimize_huffman_tables: bool, // build the Huffman tables for the image instead of using the standard ones
+    pub progressive: bool,
+    pub progressive_scans: Vec<ProgressiveScan>, // the scans of progressive images, with their own Huffman tables
+    // samples are stored in 16 bits, to fit the 12 bit ones
+    pub y_channel: PixelMatrix<u16>,
+    pub cb_channel: PixelMatrix<u16>,
+    pub cr_channel: PixelMatrix<u16>,
+    pub k_channel: Option<PixelMatrix<u16>>, // the black of CMYK and YCCK images, at full resolution like Y
     pub y_dct_coeffs: PixelMatrix<i16>,
     pub cb_dct_coeffs: PixelMatrix<i16>,
     pub cr_dct_coeffs: PixelMatrix<i16>,
+    pub k_dct_coeffs: Option<PixelMatrix<i16>>,
     pub entropy_coded_bits: BitVec<u8, Msb0>,
+    pub alpha_image: Option<Box<JpegImage>>, // the alpha channel of the input, when it is written to its own file
 }
 
 impl JpegImage {
@@ -28,6 +136,8 @@ impl JpegImage {
         chrominance_downsampling_ratio: (u8, u8, u8),
         dct_algorithm: DctAlgorithm
     ) -> JpegImage {
+        Self::validate_dimensions(width as i64, height as i64);
+
         let (horizontal_downsampling, vertical_downsampling): (
             usize,
             usize,
@@ -88,51 +198,399 @@ impl JpegImage {
             width: width,
             height: height,
             chrominance_downsampling_ratio,
+            chrominance_downsampled: false,
             dct_algorithm,
+            color_space: ColorSpace::YCbCr,
+            density: Density { unit: DensityUnit::DotsPerInch, x: 72, y: 72 },
+            exif: None,
+            icc_profile: None,
+            xmp: None,
+            comments: Vec::new(),
+            segments: Vec::new(),
+            thumbnail: None,
+            thumbnail_location: ThumbnailLocation::Exif,
+            deringing: false,
+            trellis_quantization: false,
+            adaptive_quantization: false,
+            roi_mask: None,
+            lossless_predictor: None,
+            entropy_coder: EntropyCoder::Huffman,
+            precision: 8,
+            quantization_tables: [DEFAULT_Y_QUANTIZATION_TABLE, DEFAULT_CH_QUANTIZATION_TABLE],
+            huffman_tables: None,
+            optimize_huffman_tables: false,
+            progressive: false,
+            progressive_scans: Vec::new(),
             y_channel,
             cb_channel,
             cr_channel,
+            k_channel: None,
             y_dct_coeffs,
             cb_dct_coeffs,
             cr_dct_coeffs,
+            k_dct_coeffs: None,
             entropy_coded_bits: BitVec::new(),
+            alpha_image: None,
         };
 
         image
     }
 
-    pub fn from_bmp(
-        bmp_path: &String,
+    pub fn from_rgb_image(
+        image_path: &String,
+        image_format: &ImageFormat, // any of the 8 bit formats: BMP, TIFF, TGA or QOI
+        jpeg_path: &String,
+        chrominance_downsampling_ratio: (u8, u8, u8),
+        dct_algorithm: DctAlgorithm,
+        alpha_options: &AlphaOptions,
+        rgb: bool // keep the samples in RGB instead of converting them to YCbCr
+    ) -> JpegImage {
+        let (width, height) = image_format.read_dimensions(image_path);
+        Self::validate_dimensions(width, height);
+        Self::from_rgb_pixels(
+            image_format.load_rgb(image_path),
+            jpeg_path,
+            chrominance_downsampling_ratio,
+            dct_algorithm,
+            alpha_options,
+            rgb
+        )
+    }
+
+    pub fn from_rgb_pixels(
+        rgb_image: RgbImage, // already loaded, or a part of a bigger image
+        jpeg_path: &String,
+        chrominance_downsampling_ratio: (u8, u8, u8),
+        dct_algorithm: DctAlgorithm,
+        alpha_options: &AlphaOptions,
+        rgb: bool
+    ) -> JpegImage {
+        let (chrominance_downsampling_ratio, color_conversion) = Self::get_color_conversion(
+            chrominance_downsampling_ratio,
+            rgb
+        );
+        let mut image = Self::load_rgb_image(
+            rgb_image,
+            jpeg_path,
+            chrominance_downsampling_ratio,
+            dct_algorithm,
+            alpha_options,
+            color_conversion
+        );
+        if rgb && image.color_space == ColorSpace::YCbCr {
+            image.color_space = ColorSpace::Rgb;
+        }
+        image
+    }
+
+    pub fn from_rgb_image_lossless(
+        image_path: &String,
+        image_format: &ImageFormat,
+        jpeg_path: &String,
+        predictor: u8,
+        alpha_options: &AlphaOptions
+    ) -> JpegImage {
+        let (width, height) = image_format.read_dimensions(image_path);
+        Self::validate_dimensions(width, height);
+
+        // the samples are kept in RGB, since the conversion to YCbCr would already lose information
+        let mut image = Self::load_rgb_image(
+            image_format.load_rgb(image_path),
+            jpeg_path,
+            (4, 4, 4),
+            DctAlgorithm::RealDct,
+            alpha_options,
+            Self::keep_rgb
+        );
+        if image.color_space == ColorSpace::Cmyk {
+            panic!("Lossless mode doesn't support CMYK images\n");
+        }
+        image.lossless_predictor = Some(predictor);
+        image
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_netpbm(
+        netpbm_path: &String,
+        jpeg_path: &String,
+        chrominance_downsampling_ratio: (u8, u8, u8),
+        dct_algorithm: DctAlgorithm,
+        precision: u8, // 8 or 12
+        alpha_options: &AlphaOptions,
+        hdr_conversion: &HdrConversion,
+        rgb: bool // keep the samples in RGB instead of converting them to YCbCr
+    ) -> JpegImage {
+        let netpbm_image = NetpbmImage::new(netpbm_path, hdr_conversion);
+        let (chrominance_downsampling_ratio, color_conversion) = Self::get_color_conversion(
+            chrominance_downsampling_ratio,
+            rgb
+        );
+
+        // single component scans aren't interleaved, so grayscale images have no subsampling, and CMYK images have none either
+        let chrominance_downsampling_ratio = if netpbm_image.grayscale || netpbm_image.cmyk.is_some() {
+            (4, 4, 4)
+        } else {
+            chrominance_downsampling_ratio
+        };
+
+        let mut image = JpegImage::new(
+            jpeg_path.clone(),
+            netpbm_image.width,
+            netpbm_image.height,
+            chrominance_downsampling_ratio,
+            dct_algorithm
+        );
+        image.precision = precision;
+        if netpbm_image.grayscale {
+            image.color_space = ColorSpace::Grayscale;
+        } else if rgb {
+            image.color_space = ColorSpace::Rgb;
+        }
+
+        if let Some(cmyk) = &netpbm_image.cmyk {
+            image.load_cmyk_pixels(cmyk, netpbm_image.max_value);
+            return image;
+        }
+
+        image.load_pixels(
+            &netpbm_image.pixels,
+            netpbm_image.max_value,
+            netpbm_image.alpha.as_ref(),
+            alpha_options,
+            color_conversion
+        );
+        image
+    }
+
+    pub fn from_yuv(
+        yuv_image: &YuvImage,
         jpeg_path: &String,
         chrominance_downsampling_ratio: (u8, u8, u8),
         dct_algorithm: DctAlgorithm
     ) -> JpegImage {
-        let mut bmp_image: BmpImage = BmpImage::new(bmp_path);
-        bmp_image.load_pixels();
+        // the samples are already in YCbCr, so they go straight into the channels.
+        // Inputs that are already subsampled keep their subsampling, the ratio only applies to 4:4:4 ones
+        let chrominance_downsampling_ratio = if yuv_image.format == YuvFormat::Yuv444 {
+            chrominance_downsampling_ratio
+        } else {
+            yuv_image.format.get_downsampling_ratio()
+        };
 
         let mut image = JpegImage::new(
             jpeg_path.clone(),
-            bmp_image.width,
-            bmp_image.height,
+            yuv_image.width,
+            yuv_image.height,
             chrominance_downsampling_ratio,
             dct_algorithm
         );
 
-        for i in 0..bmp_image.height as usize {
-            for j in 0..bmp_image.width as usize {
-                match bmp_image.pixels.get_pixel(i, j) {
-                    Some(rgb_pixel) => {
-                        let ycbcr: YCbCrValue = rgb_to_ycbcr(rgb_pixel);
+        let to_channel = |plane: &PixelMatrix<u8>, width: usize, height: usize| {
+            let mut channel = PixelMatrix::<u16>::new_with_default(width, height);
+            for i in 0..plane.height.min(height) {
+                for j in 0..plane.width.min(width) {
+                    channel.set_pixel(i, j, plane.get_pixel(i, j).unwrap() as u16);
+                }
+            }
+            channel
+        };
+
+        image.y_channel = to_channel(&yuv_image.y_plane, image.y_channel.width, image.y_channel.height);
+        if yuv_image.format == YuvFormat::Yuv444 {
+            image.cb_channel = to_channel(&yuv_image.u_plane, image.cb_channel.width, image.cb_channel.height);
+            image.cr_channel = to_channel(&yuv_image.v_plane, image.cr_channel.width, image.cr_channel.height);
+        } else {
+            // subsampled planes are stored with the size of their coefficients, as chrominance_downsampling would leave them
+            image.cb_channel = to_channel(&yuv_image.u_plane, image.cb_dct_coeffs.width, image.cb_dct_coeffs.height);
+            image.cr_channel = to_channel(&yuv_image.v_plane, image.cr_dct_coeffs.width, image.cr_dct_coeffs.height);
+            image.chrominance_downsampled = true;
+        }
+
+        image
+    }
+
+    fn load_rgb_image(
+        rgb_image: RgbImage,
+        jpeg_path: &String,
+        chrominance_downsampling_ratio: (u8, u8, u8),
+        dct_algorithm: DctAlgorithm,
+        alpha_options: &AlphaOptions,
+        color_conversion: ColorConversion
+    ) -> JpegImage {
+        // CMYK images have their samples apart, and no subsampling, like in from_netpbm
+        let (width, height, chrominance_downsampling_ratio) = match &rgb_image.cmyk {
+            Some(cmyk) => (cmyk.width, cmyk.height, (4, 4, 4)),
+            None => (rgb_image.pixels.width, rgb_image.pixels.height, chrominance_downsampling_ratio),
+        };
+
+        let mut image = JpegImage::new(
+            jpeg_path.clone(),
+            width as i32,
+            height as i32,
+            chrominance_downsampling_ratio,
+            dct_algorithm
+        );
+        if let Some(density) = rgb_image.density {
+            image.density = density;
+        }
+        image.icc_profile = rgb_image.icc_profile.filter(|profile| Self::is_valid_icc_profile(profile));
+
+        if let Some(cmyk) = &rgb_image.cmyk {
+            let pixels = PixelMatrix::new_from_pixels(
+                cmyk.width,
+                cmyk.height,
+                cmyk.pixels
+                    .iter()
+                    .map(|(c, m, y, k)| (*c as u16, *m as u16, *y as u16, *k as u16))
+                    .collect()
+            );
+            image.load_cmyk_pixels(&pixels, 255);
+            return image;
+        }
+
+        let rgb_pixels = &rgb_image.pixels;
+
+        // 8 bit samples are widened to go through the same path as the other formats
+        let mut pixels = PixelMatrix::new(rgb_pixels.width, rgb_pixels.height);
+        rgb_pixels.for_each_pixel(
+            &mut (|(r, g, b): &RGBValue| pixels.push_next((*r as u16, *g as u16, *b as u16)))
+        );
+        let alpha = rgb_image.alpha.as_ref().map(|alpha| {
+            PixelMatrix::new_from_pixels(
+                alpha.width,
+                alpha.height,
+                alpha.pixels.iter().map(|value| *value as u16).collect()
+            )
+        });
+
+        image.load_pixels(&pixels, 255, alpha.as_ref(), alpha_options, color_conversion);
+        image
+    }
+
+    pub fn load_pixels(
+        &mut self,
+        pixels: &PixelMatrix<WideRGBValue>,
+        max_value: u16,
+        alpha: Option<&PixelMatrix<u16>>,
+        alpha_options: &AlphaOptions,
+        color_conversion: ColorConversion
+    ) {
+        if let (Some(alpha), Some(alpha_output)) = (alpha, &alpha_options.alpha_output) {
+            self.alpha_image = Some(
+                Box::new(Self::from_alpha_channel(alpha, max_value, alpha_output, self.dct_algorithm.clone()))
+            );
+        }
 
-                        image.y_channel.set_pixel(i, j, ycbcr.0);
-                        image.cb_channel.set_pixel(i, j, ycbcr.1);
-                        image.cr_channel.set_pixel(i, j, ycbcr.2);
+        // the samples are scaled from the range of the file to the precision of the JPEG image
+        let max_sample = (1u32 << self.precision) - 1;
+        let scale = |sample: u16| {
+            (((sample as u32) * max_sample + (max_value as u32) / 2) / (max_value as u32)) as u16
+        };
+
+        for i in 0..pixels.height {
+            for j in 0..pixels.width {
+                if let Some((r, g, b)) = pixels.get_pixel(i, j) {
+                    let mut rgb_pixel = (scale(r), scale(g), scale(b));
+                    if let Some(alpha) = alpha {
+                        let opacity = (alpha.get_pixel(i, j).unwrap() as f32) / (max_value as f32);
+                        rgb_pixel = Self::resolve_alpha(rgb_pixel, opacity, max_sample as u16, alpha_options);
                     }
-                    _ => {}
+
+                    // grayscale images only have the Y channel, which is the gray value itself
+                    if self.color_space == ColorSpace::Grayscale {
+                        self.y_channel.set_pixel(i, j, rgb_pixel.0);
+                        continue;
+                    }
+
+                    let ycbcr = color_conversion(rgb_pixel, self.precision);
+                    self.y_channel.set_pixel(i, j, ycbcr.0);
+                    self.cb_channel.set_pixel(i, j, ycbcr.1);
+                    self.cr_channel.set_pixel(i, j, ycbcr.2);
                 }
             }
         }
+    }
 
-        image
+    pub fn fits_in_jpeg(width: i64, height: i64) -> bool {
+        (1..=MAX_DIMENSION as i64).contains(&width) && (1..=MAX_DIMENSION as i64).contains(&height)
+    }
+
+    pub fn validate_dimensions(width: i64, height: i64) {
+        // the frame header stores the size in 16 bits, so bigger images would silently get a wrong one
+        if !Self::fits_in_jpeg(width, height) {
+            panic!(
+                "The image is {}x{} pixels, but a JPEG image can only be from 1 to {} pixels wide and high (--tile-size splits it into smaller ones)\n",
+                width,
+                height,
+                MAX_DIMENSION
+            );
+        }
+    }
+
+    fn get_color_conversion(
+        chrominance_downsampling_ratio: (u8, u8, u8),
+        rgb: bool
+    ) -> ((u8, u8, u8), ColorConversion) {
+        // R, G and B are equally important to the eye, so none of them is subsampled
+        if rgb {
+            ((4, 4, 4), Self::keep_rgb)
+        } else {
+            (chrominance_downsampling_ratio, Self::convert_to_ycbcr)
+        }
+    }
+
+    pub fn keep_rgb(rgb_pixel: WideRGBValue, _precision: u8) -> WideYCbCrValue {
+        rgb_pixel
+    }
+
+    pub fn convert_to_ycbcr(rgb_pixel: WideRGBValue, precision: u8) -> WideYCbCrValue {
+        if precision == 8 {
+            let ycbcr: YCbCrValue = rgb_to_ycbcr((rgb_pixel.0 as u8, rgb_pixel.1 as u8, rgb_pixel.2 as u8));
+            (ycbcr.0 as u16, ycbcr.1 as u16, ycbcr.2 as u16)
+        } else {
+            rgb_to_ycbcr_with_precision(rgb_pixel, precision)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_density_from_pixels_per_meter() {
+        let inches = |x, y| Some(Density { unit: DensityUnit::DotsPerInch, x, y });
+        assert_eq!(Density::from_pixels_per_meter(2835, 2835), inches(72, 72));
+        assert_eq!(Density::from_pixels_per_meter(11811, 5906), inches(300, 150));
+        assert_eq!(
+            Density::from_pixels_per_meter(10000, 5000),
+            Some(Density { unit: DensityUnit::DotsPerCm, x: 100, y: 50 })
+        );
+        assert_eq!(Density::from_pixels_per_meter(0, 0), None);
+    }
+
+    #[test]
+    fn test_dimension_limits() {
+        assert!(JpegImage::fits_in_jpeg(65535, 1));
+        assert!(!JpegImage::fits_in_jpeg(70000, 100));
+        assert!(!JpegImage::fits_in_jpeg(100, 65536));
+        assert!(!JpegImage::fits_in_jpeg(0, 100));
+    }
+
+    #[test]
+    fn test_rgb_is_kept() {
+        let (ratio, color_conversion) = JpegImage::get_color_conversion((4, 2, 0), true);
+        let mut image = JpegImage::new(String::new(), 2, 1, ratio, DctAlgorithm::RealDct);
+        image.color_space = ColorSpace::Rgb;
+        let pixels = PixelMatrix::new_from_pixels(2, 1, vec![(255, 0, 0), (10, 20, 30)]);
+        image.load_pixels(&pixels, 255, None, &AlphaOptions::new(), color_conversion);
+
+        assert_eq!(ratio, (4, 4, 4));
+        assert_eq!(image.y_channel.get_pixel(0, 0), Some(255));
+        assert_eq!(image.cb_channel.get_pixel(0, 1), Some(20));
+        assert_eq!(image.cr_channel.get_pixel(0, 1), Some(30));
+        assert_eq!(image.get_component_ids(), vec![b'R', b'G', b'B']);
+        assert_eq!(image.get_component_tables(), vec![0, 0, 0]);
+        assert!(!image.has_jfif_header());
+        assert_eq!(image.get_adobe_segment().unwrap()[11], 0); // no transform
     }
 }
@@ -0,0 +1,190 @@
+use crate::JpegImage;
+use crate::jpeg::huffman_tables::*;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::bitvec_utils::write_bits;
+use bitvec::order::Msb0;
+use bitvec::vec::BitVec;
+
+impl JpegImage {
+    pub fn predict_sample(
+        channel: &PixelMatrix<u16>,
+        row: usize,
+        col: usize,
+        predictor: u8
+    ) -> i32 {
+        // Each sample is predicted from its already encoded neighbours:
+        //     c b
+        //     a x
+        // The first sample of the image has nothing to be predicted from, so it uses the middle of the range (128),
+        // the rest of the first row uses the sample on its left, and the first column uses the sample above
+        if row == 0 && col == 0 {
+            return 128;
+        }
+        if row == 0 {
+            return channel.get_pixel(row, col - 1).unwrap() as i32;
+        }
+        if col == 0 {
+            return channel.get_pixel(row - 1, col).unwrap() as i32;
+        }
+
+        let a = channel.get_pixel(row, col - 1).unwrap() as i32;
+        let b = channel.get_pixel(row - 1, col).unwrap() as i32;
+        let c = channel.get_pixel(row - 1, col - 1).unwrap() as i32;
+
+        match predictor {
+            1 => a,
+            2 => b,
+            3 => c,
+            4 => a + b - c,
+            5 => a + ((b - c) >> 1),
+            6 => b + ((a - c) >> 1),
+            7 => (a + b) >> 1,
+            _ => {
+                panic!("Invalid lossless predictor!");
+            }
+        }
+    }
+
+    pub fn generate_lossless_encoded_data(&mut self) {
+        // Lossless mode skips the DCT, the quantization and the downsampling.
+        // Instead, each sample is predicted from its neighbours, and only the difference is encoded,
+        // exactly like the DC coefficients: the bit length with the Huffman table, followed by the amplitude.
+        // The three components are interleaved sample by sample
+        initialize_huffman_tables();
+
+        let predictor = self.lossless_predictor.expect("Lossless predictor not set!");
+
+        let channels = [&self.y_channel, &self.cb_channel, &self.cr_channel];
+        let dc_huffman_tables = [
+            get_huffman_table(HuffmanTableType::YDC),
+            get_huffman_table(HuffmanTableType::CHDC),
+            get_huffman_table(HuffmanTableType::CHDC),
+        ];
+
+        let mut bits: BitVec<u8, Msb0> = BitVec::new();
+
+        for row in 0..self.height as usize {
+            for col in 0..self.width as usize {
+                for (channel, dc_huffman_table) in channels.iter().zip(dc_huffman_tables.iter()) {
+                    let sample = channel.get_pixel(row, col).unwrap() as i32;
+                    let difference = sample - Self::predict_sample(channel, row, col, predictor);
+
+                    let bit_length = Self::bit_length(difference.abs());
+                    let (code, code_length) = dc_huffman_table
+                        .get_code(bit_length)
+                        .expect("DC Huffman Code Not Found!");
+                    write_bits(&mut bits, code, code_length);
+                    write_bits(
+                        &mut bits,
+                        Self::coeff_to_amplitude(difference, bit_length) as u32,
+                        bit_length
+                    );
+                }
+            }
+        }
+
+        // the last byte is padded with 1s, like in the other modes (F.1.2.3)
+        while !bits.len().is_multiple_of(8) {
+            bits.push(true);
+        }
+        self.entropy_coded_bits = bits;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::dct_quant::DctAlgorithm;
+
+    // reads one Huffman coded value (a bit length followed by its amplitude) from the bits
+    fn read_difference(bits: &BitVec<u8, Msb0>, position: &mut usize, table: &HuffmanTable) -> i32 {
+        let mut code: u32 = 0;
+        for length in 0..16 {
+            code = (code << 1) | (bits[*position] as u32);
+            *position += 1;
+
+            for j in table.offsets[length]..table.offsets[length + 1] {
+                if table.codes[j as usize] != code {
+                    continue;
+                }
+                let bit_length = table.symbols[j as usize];
+                let mut amplitude: i32 = 0;
+                for _ in 0..bit_length {
+                    amplitude = (amplitude << 1) | (bits[*position] as i32);
+                    *position += 1;
+                }
+                // negative values are stored as the amplitude minus one, so their first bit is 0
+                if bit_length > 0 && amplitude < 1 << (bit_length - 1) {
+                    amplitude -= (1 << bit_length) - 1;
+                }
+                return amplitude;
+            }
+        }
+        panic!("Invalid Huffman code!");
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        let (width, height) = (13, 7);
+
+        for predictor in 1..=7 {
+            let mut image = JpegImage::new(
+                String::new(),
+                width as i32,
+                height as i32,
+                (4, 4, 4),
+                DctAlgorithm::RealDct
+            );
+            image.lossless_predictor = Some(predictor);
+
+            // a mix of gradients, hard edges and extreme values
+            for row in 0..height {
+                for col in 0..width {
+                    image.y_channel.set_pixel(row, col, ((row * 37 + col * 11) % 256) as u16);
+                    image.cb_channel.set_pixel(row, col, if col > 6 { 255 } else { 0 });
+                    image.cr_channel.set_pixel(row, col, ((row * col * 53) % 256) as u16);
+                }
+            }
+
+            image.generate_lossless_encoded_data();
+
+            // decode and compare every sample
+            let mut decoded = [
+                PixelMatrix::<u16>::new_with_default(width, height),
+                PixelMatrix::<u16>::new_with_default(width, height),
+                PixelMatrix::<u16>::new_with_default(width, height),
+            ];
+            let tables = [
+                get_huffman_table(HuffmanTableType::YDC),
+                get_huffman_table(HuffmanTableType::CHDC),
+                get_huffman_table(HuffmanTableType::CHDC),
+            ];
+            let mut position = 0;
+            for row in 0..height {
+                for col in 0..width {
+                    for c in 0..3 {
+                        let difference = read_difference(
+                            &image.entropy_coded_bits,
+                            &mut position,
+                            tables[c]
+                        );
+                        let prediction = JpegImage::predict_sample(&decoded[c], row, col, predictor);
+                        decoded[c].set_pixel(row, col, (prediction + difference) as u16);
+                    }
+                }
+            }
+
+            // only the padding of the last byte is left, and it is all 1s
+            assert!(image.entropy_coded_bits.len().is_multiple_of(8));
+            assert!(image.entropy_coded_bits.len() - position < 8);
+            assert!(image.entropy_coded_bits[position..].all());
+            for row in 0..height {
+                for col in 0..width {
+                    assert_eq!(decoded[0].get_pixel(row, col), image.y_channel.get_pixel(row, col));
+                    assert_eq!(decoded[1].get_pixel(row, col), image.cb_channel.get_pixel(row, col));
+                    assert_eq!(decoded[2].get_pixel(row, col), image.cr_channel.get_pixel(row, col));
+                }
+            }
+        }
+    }
+}
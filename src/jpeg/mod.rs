@@ -1,7 +1,26 @@
 pub mod jpeg_image;
+pub mod alpha;
+pub mod exif;
+pub mod icc;
+pub mod xmp;
+pub mod thumbnail;
+pub mod cmyk;
 pub mod dct_quant;
+pub mod deringing;
+pub mod adaptive_quant;
+pub mod region_of_interest;
 pub mod entropy_coding;
+pub mod arithmetic_coding;
+pub mod lossless;
 pub mod quant_tables;
 pub mod sampling;
 pub mod huffman_tables;
 pub mod file;
+pub mod streaming;
+pub mod tiling;
+pub mod transcoding;
+pub mod transform;
+pub mod progressive;
+pub mod requantization;
+#[cfg(test)]
+pub mod test_images;
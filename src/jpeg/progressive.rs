@@ -0,0 +1,402 @@
+use bitvec::order::Msb0;
+use bitvec::vec::BitVec;
+use crate::JpegImage;
+use crate::jpeg::huffman_tables::{ HuffmanTable, ZIG_ZAG_MAP };
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::bitvec_utils::write_bits;
+
+// the biggest end of band run, with the 14 extra bits of the EOB14 symbol
+const MAX_END_OF_BAND_RUN: usize = 0x7fff;
+
+// correction bits kept for an end of band run before it is written, like libjpeg does to bound its buffer
+const MAX_CORRECTION_BITS: usize = 1000 - 64 + 1;
+
+// A scan of a progressive image: a band of coefficients in zigzag order, from the high bit to the low bit.
+// Its Huffman tables are built for it, and written right before it
+pub struct ProgressiveScan {
+    pub components: Vec<usize>, // 0 for Y, 1 for Cb, 2 for Cr and 3 for K
+    pub spectral_start: u8,
+    pub spectral_end: u8,
+    pub high_bit: u8, // 0 in the first scan of the band, otherwise the low bit of the previous one
+    pub low_bit: u8,
+    pub huffman_tables: Vec<(u8, u8, HuffmanTable<'static>)>, // class (0 for DC, 1 for AC), id and table
+    pub data: BitVec<u8, Msb0>,
+}
+
+impl ProgressiveScan {
+    fn new(components: Vec<usize>, spectral_start: u8, spectral_end: u8, high_bit: u8, low_bit: u8) -> ProgressiveScan {
+        ProgressiveScan {
+            components,
+            spectral_start,
+            spectral_end,
+            high_bit,
+            low_bit,
+            huffman_tables: Vec::new(),
+            data: BitVec::new(),
+        }
+    }
+}
+
+// what the scans write: Huffman coded symbols with their extra bits, or bits on their own
+enum ScanCode {
+    Symbol { table: usize, symbol: u8, bits: u32, length: u8 },
+    Bits(u32, u8),
+}
+
+impl JpegImage {
+    pub fn get_progressive_script(&self) -> Vec<ProgressiveScan> {
+        // The standard script of libjpeg: the DC coefficients first, then the low frequencies, and the rest of them
+        // one bit at a time. The components that use the chrominance tables are sent in fewer scans, like Cb and Cr
+        let component_tables = self.get_component_tables();
+        let components: Vec<usize> = (0..component_tables.len()).collect();
+        let luminance: Vec<usize> = components
+            .iter()
+            .copied()
+            .filter(|component| component_tables[*component] == 0)
+            .collect();
+        let chrominance: Vec<usize> = components
+            .iter()
+            .copied()
+            .filter(|component| component_tables[*component] == 1)
+            .collect();
+
+        let mut scans = vec![ProgressiveScan::new(components.clone(), 0, 0, 0, 1)];
+        scans.extend(luminance.iter().map(|component| ProgressiveScan::new(vec![*component], 1, 5, 0, 2)));
+        scans.extend(chrominance.iter().map(|component| ProgressiveScan::new(vec![*component], 1, 63, 0, 1)));
+        scans.extend(luminance.iter().map(|component| ProgressiveScan::new(vec![*component], 6, 63, 0, 2)));
+        scans.extend(luminance.iter().map(|component| ProgressiveScan::new(vec![*component], 1, 63, 2, 1)));
+        scans.push(ProgressiveScan::new(components, 0, 0, 1, 0));
+        scans.extend(chrominance.iter().map(|component| ProgressiveScan::new(vec![*component], 1, 63, 1, 0)));
+        scans.extend(luminance.iter().map(|component| ProgressiveScan::new(vec![*component], 1, 63, 1, 0)));
+        scans
+    }
+
+    pub fn generate_progressive_scans(&mut self) {
+        // each scan is encoded twice: once to count its symbols and build its tables, and once to write them
+        let mut scans = self.get_progressive_script();
+        let component_tables = self.get_component_tables();
+        for scan in scans.iter_mut() {
+            let codes = self.get_scan_codes(scan);
+
+            let mut frequencies = [[0u32; 256]; 4];
+            for code in &codes {
+                if let ScanCode::Symbol { table, symbol, .. } = code {
+                    frequencies[*table][*symbol as usize] += 1;
+                }
+            }
+            let class = if scan.spectral_start == 0 { 0 } else { 1 };
+            let mut tables: Vec<Option<HuffmanTable<'static>>> = vec![None; 4];
+            if scan.high_bit == 0 || scan.spectral_start != 0 {
+                for component in &scan.components {
+                    let id = component_tables[*component];
+                    if tables[id].is_none() {
+                        let table = HuffmanTable::from_frequencies(&frequencies[id]);
+                        scan.huffman_tables.push((class, id as u8, table.clone()));
+                        tables[id] = Some(table);
+                    }
+                }
+            }
+
+            for code in codes {
+                match code {
+                    ScanCode::Symbol { table, symbol, bits, length } => {
+                        let (code, code_length) = tables[table]
+                            .as_ref()
+                            .unwrap()
+                            .get_code(symbol)
+                            .expect("Huffman Code Not Found!");
+                        write_bits(&mut scan.data, code, code_length);
+                        write_bits(&mut scan.data, bits, length);
+                    }
+                    ScanCode::Bits(bits, length) => write_bits(&mut scan.data, bits, length),
+                }
+            }
+            // the last byte is padded with 1s, like in sequential images
+            while !scan.data.len().is_multiple_of(8) {
+                scan.data.push(true);
+            }
+        }
+        self.progressive_scans = scans;
+    }
+
+    fn get_component_coeffs(&self, component: usize) -> &PixelMatrix<i16> {
+        match component {
+            0 => &self.y_dct_coeffs,
+            1 => &self.cb_dct_coeffs,
+            2 => &self.cr_dct_coeffs,
+            _ => self.k_dct_coeffs.as_ref().unwrap(),
+        }
+    }
+
+    pub fn get_scan_blocks(&self, components: &[usize]) -> Vec<(usize, usize, usize)> {
+        // The component, row and column of the blocks in the order they go in the scan.
+        // Scans of several components go MCU by MCU, and the ones of a single component only go
+        // through its blocks that have samples of the image, without the padding of the last MCUs
+        let (horizontal_downsampling, vertical_downsampling) = Self::get_downsampling_factor(
+            self.chrominance_downsampling_ratio
+        );
+        let (width, height) = (self.width as usize, self.height as usize);
+        let get_sampling = |component: usize| if component == 1 || component == 2 {
+            (1, 1)
+        } else {
+            (horizontal_downsampling, vertical_downsampling)
+        };
+
+        let mut blocks = Vec::new();
+        if components.len() == 1 {
+            let (horizontal_sampling, vertical_sampling) = get_sampling(components[0]);
+            let component_width = (width * horizontal_sampling).div_ceil(horizontal_downsampling);
+            let component_height = (height * vertical_sampling).div_ceil(vertical_downsampling);
+            for row in 0..component_height.div_ceil(8) {
+                for col in 0..component_width.div_ceil(8) {
+                    blocks.push((components[0], row, col));
+                }
+            }
+            return blocks;
+        }
+
+        let mcu_columns = width.div_ceil(8 * horizontal_downsampling);
+        let mcu_rows = height.div_ceil(8 * vertical_downsampling);
+        for mcu_row in 0..mcu_rows {
+            for mcu_column in 0..mcu_columns {
+                for component in components {
+                    let (horizontal_sampling, vertical_sampling) = get_sampling(*component);
+                    for row in 0..vertical_sampling {
+                        for col in 0..horizontal_sampling {
+                            blocks.push((
+                                *component,
+                                mcu_row * vertical_sampling + row,
+                                mcu_column * horizontal_sampling + col,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        blocks
+    }
+
+    fn get_scan_codes(&self, scan: &ProgressiveScan) -> Vec<ScanCode> {
+        let component_tables = self.get_component_tables();
+        let mut codes = Vec::new();
+        let mut prev_dc_coeffs = [0i32; 4];
+        let mut end_of_band_run = 0;
+        let mut correction_bits = Vec::new(); // of the blocks in the end of band run, for the refinement scans
+
+        let mut block = [0i16; 64];
+        for (component, block_row, block_col) in self.get_scan_blocks(&scan.components) {
+            let coeffs = self.get_component_coeffs(component);
+            for (i, coeff) in block.iter_mut().enumerate() {
+                *coeff = coeffs.pixels[(block_row * 8 + i / 8) * coeffs.width + block_col * 8 + (i % 8)];
+            }
+            let table = component_tables[component];
+
+            match (scan.spectral_start, scan.high_bit) {
+                (0, 0) => {
+                    // the DC coefficients are shifted down, keeping their sign
+                    let dc_coeff = (block[0] as i32) >> scan.low_bit;
+                    let diff = dc_coeff - prev_dc_coeffs[component];
+                    prev_dc_coeffs[component] = dc_coeff;
+                    let bit_length = Self::bit_length(diff.abs());
+                    codes.push(ScanCode::Symbol {
+                        table,
+                        symbol: bit_length,
+                        bits: Self::coeff_to_amplitude(diff, bit_length) as u32,
+                        length: bit_length,
+                    });
+                }
+                (0, _) => {
+                    codes.push(ScanCode::Bits((((block[0] as i32) >> scan.low_bit) & 1) as u32, 1));
+                }
+                (_, 0) => {
+                    Self::get_ac_first_codes(&block, scan, table, &mut end_of_band_run, &mut codes);
+                }
+                _ => {
+                    Self::get_ac_refinement_codes(
+                        &block,
+                        scan,
+                        table,
+                        &mut end_of_band_run,
+                        &mut correction_bits,
+                        &mut codes
+                    );
+                }
+            }
+        }
+        // the symbol tables of the DC scans are the ones of each component, and there is one for the whole AC scan
+        Self::flush_end_of_band_run(
+            component_tables[scan.components[0]],
+            &mut end_of_band_run,
+            &mut correction_bits,
+            &mut codes
+        );
+        codes
+    }
+
+    fn flush_end_of_band_run(
+        table: usize,
+        end_of_band_run: &mut usize,
+        correction_bits: &mut Vec<u8>,
+        codes: &mut Vec<ScanCode>
+    ) {
+        // EOBn symbol, with the extra bits of the run after the highest one, and then the correction bits of its blocks
+        if *end_of_band_run == 0 {
+            return;
+        }
+        let bit_length = Self::bit_length(*end_of_band_run as i32) - 1;
+        codes.push(ScanCode::Symbol {
+            table,
+            symbol: bit_length << 4,
+            bits: (*end_of_band_run as u32) - (1 << bit_length),
+            length: bit_length,
+        });
+        codes.extend(correction_bits.drain(..).map(|bit| ScanCode::Bits(bit as u32, 1)));
+        *end_of_band_run = 0;
+    }
+
+    fn get_ac_first_codes(
+        block: &[i16; 64],
+        scan: &ProgressiveScan,
+        table: usize,
+        end_of_band_run: &mut usize,
+        codes: &mut Vec<ScanCode>
+    ) {
+        // the magnitudes are shifted down, so the coefficients are rounded towards zero
+        let mut zeros_count = 0;
+        for k in scan.spectral_start as usize..=scan.spectral_end as usize {
+            let coeff = block[ZIG_ZAG_MAP[k]] as i32;
+            let magnitude = coeff.abs() >> scan.low_bit;
+            if magnitude == 0 {
+                zeros_count += 1;
+                continue;
+            }
+            Self::flush_end_of_band_run(table, end_of_band_run, &mut Vec::new(), codes);
+            while zeros_count > 15 {
+                codes.push(ScanCode::Symbol { table, symbol: 0xf0, bits: 0, length: 0 });
+                zeros_count -= 16;
+            }
+            let value = if coeff < 0 { -magnitude } else { magnitude };
+            let bit_length = Self::bit_length(magnitude);
+            codes.push(ScanCode::Symbol {
+                table,
+                symbol: ((zeros_count as u8) << 4) | bit_length,
+                bits: Self::coeff_to_amplitude(value, bit_length) as u32,
+                length: bit_length,
+            });
+            zeros_count = 0;
+        }
+        if zeros_count > 0 {
+            *end_of_band_run += 1;
+            if *end_of_band_run == MAX_END_OF_BAND_RUN {
+                Self::flush_end_of_band_run(table, end_of_band_run, &mut Vec::new(), codes);
+            }
+        }
+    }
+
+    fn get_ac_refinement_codes(
+        block: &[i16; 64],
+        scan: &ProgressiveScan,
+        table: usize,
+        end_of_band_run: &mut usize,
+        correction_bits: &mut Vec<u8>,
+        codes: &mut Vec<ScanCode>
+    ) {
+        // The coefficients that become nonzero with this bit are coded like in the first scan (with a size of 1 and their sign),
+        // and the ones that were already nonzero get their bit appended after the next symbol.
+        // The zero runs don't count the coefficients that were already nonzero
+        let (start, end) = (scan.spectral_start as usize, scan.spectral_end as usize);
+        let magnitudes: Vec<i32> = (start..=end)
+            .map(|k| (block[ZIG_ZAG_MAP[k]] as i32).abs() >> scan.low_bit)
+            .collect();
+        // the last coefficient that becomes nonzero, the zeros after it are left to the end of band
+        let last_new_coeff = magnitudes.iter().rposition(|magnitude| *magnitude == 1);
+
+        let mut zeros_count = 0;
+        let mut block_bits = Vec::new();
+        for (i, magnitude) in magnitudes.iter().enumerate() {
+            if *magnitude == 0 {
+                zeros_count += 1;
+                continue;
+            }
+            while zeros_count > 15 && last_new_coeff.is_some_and(|last| i <= last) {
+                Self::flush_end_of_band_run(table, end_of_band_run, correction_bits, codes);
+                codes.push(ScanCode::Symbol { table, symbol: 0xf0, bits: 0, length: 0 });
+                zeros_count -= 16;
+                codes.extend(block_bits.drain(..).map(|bit| ScanCode::Bits(bit as u32, 1)));
+            }
+            if *magnitude > 1 {
+                block_bits.push((*magnitude & 1) as u8);
+                continue;
+            }
+            Self::flush_end_of_band_run(table, end_of_band_run, correction_bits, codes);
+            codes.push(ScanCode::Symbol {
+                table,
+                symbol: ((zeros_count as u8) << 4) | 1,
+                bits: if block[ZIG_ZAG_MAP[start + i]] < 0 { 0 } else { 1 },
+                length: 1,
+            });
+            codes.extend(block_bits.drain(..).map(|bit| ScanCode::Bits(bit as u32, 1)));
+            zeros_count = 0;
+        }
+
+        if zeros_count > 0 || !block_bits.is_empty() {
+            *end_of_band_run += 1;
+            correction_bits.append(&mut block_bits);
+            if *end_of_band_run == MAX_END_OF_BAND_RUN || correction_bits.len() > MAX_CORRECTION_BITS {
+                Self::flush_end_of_band_run(table, end_of_band_run, correction_bits, codes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decoder::jpeg_file::JpegFile;
+    use crate::jpeg::test_images::{ encode_test_image, get_test_coefficients };
+
+    #[test]
+    fn test_progressive_script() {
+        let image = get_test_coefficients(16, 16, (4, 2, 0));
+        let script: Vec<(Vec<usize>, u8, u8, u8, u8)> = image
+            .get_progressive_script()
+            .into_iter()
+            .map(|scan| (scan.components, scan.spectral_start, scan.spectral_end, scan.high_bit, scan.low_bit))
+            .collect();
+        assert_eq!(
+            script,
+            vec![
+                (vec![0, 1, 2], 0, 0, 0, 1),
+                (vec![0], 1, 5, 0, 2),
+                (vec![1], 1, 63, 0, 1),
+                (vec![2], 1, 63, 0, 1),
+                (vec![0], 6, 63, 0, 2),
+                (vec![0], 1, 63, 2, 1),
+                (vec![0, 1, 2], 0, 0, 1, 0),
+                (vec![1], 1, 63, 1, 0),
+                (vec![2], 1, 63, 1, 0),
+                (vec![0], 1, 63, 1, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_progressive_coefficients_read_back() {
+        // the size isn't a multiple of the MCU, so the scans of a single component skip the padding blocks
+        let mut image = get_test_coefficients(53, 37, (4, 2, 0));
+        let (y_dct_coeffs, cr_dct_coeffs) = (image.y_dct_coeffs.pixels.clone(), image.cr_dct_coeffs.pixels.clone());
+        image.progressive = true;
+
+        let jpeg_file = JpegFile::from_bytes(&encode_test_image(&mut image));
+        assert!(jpeg_file.progressive);
+        assert_eq!((jpeg_file.width, jpeg_file.height), (53, 37));
+        // the decoder only fills the blocks that have samples of the image, so the padding ones are compared as zeros
+        let blocks = image.get_scan_blocks(&[0]);
+        for (_, block_row, block_col) in blocks {
+            for i in 0..64 {
+                let index = (block_row * 8 + i / 8) * image.y_dct_coeffs.width + block_col * 8 + (i % 8);
+                assert_eq!(jpeg_file.components[0].coeffs.pixels[index], y_dct_coeffs[index]);
+            }
+        }
+        assert_eq!(jpeg_file.components[2].coeffs.pixels, cr_dct_coeffs);
+    }
+}
@@ -21,3 +21,10 @@ pub const DEFAULT_CH_QUANTIZATION_TABLE: [u8; 64] = [
     99, 99, 99, 99, 99, 99, 99, 99,
     99, 99, 99, 99, 99, 99, 99, 99,
 ];
+
+pub fn scale_quantization_table(table: &[u8; 64], quality: u8) -> [u8; 64] {
+    // the scaling of the IJG libjpeg: 50 keeps the table, lower qualities make it coarser and higher ones finer
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - 2 * quality };
+    table.map(|value| (((value as u32) * scale + 50) / 100).clamp(1, 255) as u8)
+}
@@ -0,0 +1,180 @@
+use crate::JpegImage;
+use crate::bmp::bmp_image::BmpImage;
+use crate::jpeg::huffman_tables::ZIG_ZAG_MAP;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::rgb_to_ycbcr;
+use std::thread;
+
+impl JpegImage {
+    pub fn load_roi_mask(&mut self, mask_path: &String) {
+        let mut mask_image: BmpImage = BmpImage::new(mask_path);
+
+        if mask_image.width != self.width || mask_image.height != self.height {
+            panic!("The mask image must have the same size as the input image!");
+        }
+
+        mask_image.load_pixels();
+
+        // only the brightness of the mask matters
+        let mut mask = PixelMatrix::<u8>::new(self.width as usize, self.height as usize);
+        mask_image.pixels.for_each_pixel(
+            &mut (|rgb_pixel: &(u8, u8, u8)| {
+                mask.push_next(rgb_to_ycbcr(*rgb_pixel).0);
+            })
+        );
+
+        self.roi_mask = Some(mask);
+    }
+
+    pub fn apply_roi_mask(&mut self) {
+        // Blocks under the bright areas of the mask keep all their coefficients, while the ones under the dark areas
+        // lose more and more of their high frequencies, down to only keeping the DC coefficient under pure black.
+        // The chrominance blocks cover a bigger area of the image, so they use a downsampled version of the mask
+        if self.lossless_predictor.is_some() {
+            return;
+        }
+
+        let mut mask = match self.roi_mask.take() {
+            Some(mask) => mask,
+            None => {
+                return;
+            }
+        };
+
+        let (horizontal_downsampling, vertical_downsampling): (
+            usize,
+            usize,
+        ) = Self::get_downsampling_factor(self.chrominance_downsampling_ratio);
+
+        let chrominance_mask = Self::resample_mask(
+            &mut mask,
+            horizontal_downsampling,
+            vertical_downsampling
+        );
+
+        thread::scope(|s| {
+            let y_handle = s.spawn(|| {
+                Self::suppress_background(&mut self.y_dct_coeffs, &mask);
+            });
+
+            let cb_handle = s.spawn(|| {
+                Self::suppress_background(&mut self.cb_dct_coeffs, &chrominance_mask);
+            });
+
+            let cr_handle = s.spawn(|| {
+                Self::suppress_background(&mut self.cr_dct_coeffs, &chrominance_mask);
+            });
+
+            // the black channel has the full resolution, like the luminance
+            if let Some(k_dct_coeffs) = &mut self.k_dct_coeffs {
+                s.spawn(|| {
+                    Self::suppress_background(k_dct_coeffs, &mask);
+                });
+            }
+
+            _ = y_handle.join();
+            _ = cb_handle.join();
+            _ = cr_handle.join();
+        });
+
+        self.roi_mask = Some(mask);
+    }
+
+    fn resample_mask(
+        mask: &mut PixelMatrix<u8>,
+        horizontal_downsampling: usize,
+        vertical_downsampling: usize
+    ) -> PixelMatrix<u8> {
+        // same averaging used for the chrominance channels
+        let mut resampled_mask = PixelMatrix::<u8>::new(
+            mask.width.div_ceil(horizontal_downsampling),
+            mask.height.div_ceil(vertical_downsampling)
+        );
+
+        mask.get_block_iterator(horizontal_downsampling, vertical_downsampling, false).for_each_block(
+            &mut (|block_buffer: &mut Vec<u8>| {
+                resampled_mask.push_next(
+                    (block_buffer
+                        .iter()
+                        .map(|x| *x as usize)
+                        .sum::<usize>() / block_buffer.len()) as u8
+                );
+            })
+        );
+
+        resampled_mask
+    }
+
+    fn get_mask_level(mask: &PixelMatrix<u8>, block_row: usize, block_col: usize) -> f32 {
+        // average brightness of the mask under an 8x8 block, from 0 to 1.
+        // Padding blocks, completely outside of the image, count as black
+        let mut sum = 0.0;
+        let mut count = 0;
+        for i in 0..8 {
+            for j in 0..8 {
+                if let Some(p) = mask.get_pixel(block_row * 8 + i, block_col * 8 + j) {
+                    sum += p as f32;
+                    count += 1;
+                }
+            }
+        }
+
+        if count == 0 {
+            return 0.0;
+        }
+        sum / (count as f32) / 255.0
+    }
+
+    fn suppress_background(dct_coeffs: &mut PixelMatrix<i16>, mask: &PixelMatrix<u8>) {
+        let blocks_per_row = dct_coeffs.width / 8;
+
+        dct_coeffs.get_block_iterator(8, 8, true).for_each_block_mut(
+            &mut (|block_idx: usize, block_buffer: &mut Vec<i16>| {
+                let level = Self::get_mask_level(
+                    mask,
+                    block_idx / blocks_per_row,
+                    block_idx % blocks_per_row
+                );
+
+                // amount of coefficients (in zig-zag order) that are kept
+                let kept_coeffs = 1 + ((63.0 * level).round() as usize);
+                for i in kept_coeffs..64 {
+                    block_buffer[ZIG_ZAG_MAP[i]] = 0;
+                }
+            })
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suppress_background() {
+        // white mask on the left block, black on the right one
+        let mut mask = PixelMatrix::<u8>::new_with_default(16, 8);
+        for i in 0..8 {
+            for j in 0..8 {
+                mask.set_pixel(i, j, 255);
+            }
+        }
+
+        let mut dct_coeffs = PixelMatrix::<i16>::new_from_pixels(16, 8, vec![3; 128]);
+        JpegImage::suppress_background(&mut dct_coeffs, &mask);
+
+        for i in 0..8 {
+            for j in 0..8 {
+                assert_eq!(dct_coeffs.get_pixel(i, j), Some(3));
+                let expected = if i == 0 && j == 0 { 3 } else { 0 };
+                assert_eq!(dct_coeffs.get_pixel(i, 8 + j), Some(expected));
+            }
+        }
+
+        // a 4:2:0 chrominance block covers 16x16 pixels of the mask
+        let resampled_mask = JpegImage::resample_mask(&mut mask, 2, 2);
+        assert_eq!((resampled_mask.width, resampled_mask.height), (8, 4));
+        assert_eq!(resampled_mask.get_pixel(0, 3), Some(255));
+        assert_eq!(resampled_mask.get_pixel(3, 4), Some(0));
+    }
+}
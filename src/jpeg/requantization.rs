@@ -0,0 +1,93 @@
+use crate::JpegImage;
+use crate::jpeg::quant_tables::{
+    DEFAULT_CH_QUANTIZATION_TABLE,
+    DEFAULT_Y_QUANTIZATION_TABLE,
+    scale_quantization_table,
+};
+
+impl JpegImage {
+    pub fn get_requantization_tables(&self, quality: u8) -> [[u8; 64]; 2] {
+        // The standard tables scaled to the quality, without going below the current ones,
+        // since smaller steps can't bring back what the image already lost, they would only make it bigger
+        let scaled_tables = [
+            scale_quantization_table(&DEFAULT_Y_QUANTIZATION_TABLE, quality),
+            scale_quantization_table(&DEFAULT_CH_QUANTIZATION_TABLE, quality),
+        ];
+        let mut tables = self.quantization_tables;
+        for (table, scaled_table) in tables.iter_mut().zip(scaled_tables) {
+            for (value, scaled_value) in table.iter_mut().zip(scaled_table) {
+                *value = (*value).max(scaled_value);
+            }
+        }
+        tables
+    }
+
+    pub fn requantize_coefficients(&mut self, quantization_tables: [[u8; 64]; 2]) {
+        // Each coefficient is dequantized with its current step and quantized again with the new one, rounding to the nearest.
+        // Working on the integer coefficients avoids the rounding of decoding the samples and doing the DCT again
+        let component_tables = self.get_component_tables();
+        let current_tables = self.quantization_tables;
+        let requantize = |coeffs: &mut [i16], width: usize, table: usize| {
+            for (i, coeff) in coeffs.iter_mut().enumerate() {
+                let frequency = ((i / width) % 8) * 8 + (i % width) % 8;
+                let value = (*coeff as i32) * (current_tables[table][frequency] as i32);
+                let step = quantization_tables[table][frequency] as i32;
+                let requantized = (value.abs() + step / 2) / step;
+                *coeff = (if value < 0 { -requantized } else { requantized }) as i16;
+            }
+        };
+
+        requantize(&mut self.y_dct_coeffs.pixels, self.y_dct_coeffs.width, component_tables[0]);
+        // grayscale images have no chrominance components, their coefficients are left as zeros
+        if component_tables.len() > 1 {
+            requantize(&mut self.cb_dct_coeffs.pixels, self.cb_dct_coeffs.width, component_tables[1]);
+            requantize(&mut self.cr_dct_coeffs.pixels, self.cr_dct_coeffs.width, component_tables[2]);
+        }
+        if let Some(k_dct_coeffs) = &mut self.k_dct_coeffs {
+            requantize(&mut k_dct_coeffs.pixels, k_dct_coeffs.width, component_tables[3]);
+        }
+        self.quantization_tables = quantization_tables;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::dct_quant::DctAlgorithm;
+
+    #[test]
+    fn test_requantization_tables() {
+        let mut image = JpegImage::new(String::new(), 16, 16, (4, 2, 0), DctAlgorithm::RealDct);
+        assert_eq!(image.get_requantization_tables(50), image.quantization_tables);
+        // finer tables don't replace the current ones
+        image.quantization_tables[0][0] = 40;
+        let tables = image.get_requantization_tables(25);
+        assert_eq!((tables[0][0], tables[0][1]), (40, 22));
+        assert_eq!(tables[1][63], 198);
+        assert_eq!(image.get_requantization_tables(100), image.quantization_tables);
+    }
+
+    #[test]
+    fn test_requantize_coefficients() {
+        let mut image = JpegImage::new(String::new(), 16, 8, (4, 4, 4), DctAlgorithm::RealDct);
+        image.quantization_tables[0][0] = 10;
+        image.quantization_tables[0][9] = 10;
+        // the DC coefficient of the second block has the same steps as the one of the first block
+        image.y_dct_coeffs.set_pixel(0, 0, 5);
+        image.y_dct_coeffs.set_pixel(1, 1, -7);
+        image.y_dct_coeffs.set_pixel(0, 8, 3);
+        image.cb_dct_coeffs.set_pixel(0, 0, -12);
+
+        let mut tables = image.quantization_tables;
+        tables[0][0] = 16;
+        tables[0][9] = 20;
+        tables[1][0] = 34;
+        image.requantize_coefficients(tables);
+        assert_eq!(image.quantization_tables, tables);
+        // 50 / 16 rounds down, -70 / 20 rounds away from zero, and 17 * 12 / 34 is exact
+        assert_eq!(image.y_dct_coeffs.get_pixel(0, 0), Some(3));
+        assert_eq!(image.y_dct_coeffs.get_pixel(1, 1), Some(-4));
+        assert_eq!(image.y_dct_coeffs.get_pixel(0, 8), Some(2));
+        assert_eq!(image.cb_dct_coeffs.get_pixel(0, 0), Some(-6));
+    }
+}
@@ -49,7 +49,7 @@ impl JpegImage {
             usize,
         ) = Self::get_downsampling_factor(self.chrominance_downsampling_ratio);
 
-        if horizontal_downsampling == 1 && vertical_downsampling == 1 {
+        if (horizontal_downsampling == 1 && vertical_downsampling == 1) || self.chrominance_downsampled {
             return;
         }
 
@@ -60,23 +60,23 @@ impl JpegImage {
             vertical_downsampling
         );
 
-        let mut new_cb = PixelMatrix::<u8>::new(downsampled_width, downsampled_height);
-        let mut new_cr = PixelMatrix::<u8>::new(downsampled_width, downsampled_height);
+        let mut new_cb = PixelMatrix::<u16>::new(downsampled_width, downsampled_height);
+        let mut new_cr = PixelMatrix::<u16>::new(downsampled_width, downsampled_height);
 
-        let add_average = |new_channel: &mut PixelMatrix<u8>, block_buffer: &mut Vec<u8>| {
+        let add_average = |new_channel: &mut PixelMatrix<u16>, block_buffer: &mut Vec<u16>| {
             new_channel.push_next(
                 (block_buffer
                     .iter()
                     .map(|x| *x as usize)
-                    .sum::<usize>() / block_buffer.len()) as u8
+                    .sum::<usize>() / block_buffer.len()) as u16
             );
         };
 
-        let mut add_average_cb = |block_buffer: &mut Vec<u8>| {
+        let mut add_average_cb = |block_buffer: &mut Vec<u16>| {
             add_average(&mut new_cb, block_buffer);
         };
 
-        let mut add_average_cr = |block_buffer: &mut Vec<u8>| {
+        let mut add_average_cr = |block_buffer: &mut Vec<u16>| {
             add_average(&mut new_cr, block_buffer);
         };
 
@@ -0,0 +1,312 @@
+use std::io::{ self, Write };
+use crate::JpegImage;
+use crate::bmp::bmp_image::BmpImage;
+use crate::jpeg::alpha::AlphaOptions;
+use crate::jpeg::arithmetic_coding::ArithmeticEncoder;
+use crate::jpeg::dct_quant::DctAlgorithm;
+use crate::jpeg::entropy_coding::{ EntropyCoder, EntropyEncoder, HuffmanEncoder };
+use crate::jpeg::jpeg_image::ColorSpace;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::{ RGBValue, WideRGBValue };
+
+// Encodes an image that is given a few rows at a time, keeping only one MCU row of samples and coefficients in memory.
+// The image field holds the settings of the output, like a regular JpegImage, but its channels are the size of an MCU row,
+// and the entropy coded data is written as soon as each MCU row is encoded
+pub struct StreamingEncoder<W: Write> {
+    pub image: JpegImage,
+    pub alpha_options: AlphaOptions, // how the alpha of the streamed pixels is composited, it can't be written to its own image
+    writer: W,
+    height: usize,
+    mcu_height: usize,
+    rows: Vec<WideRGBValue>, // the rows of the MCU row that is being filled
+    alpha: Vec<u16>, // and their alpha, 255 for the rows that were given without one
+    rows_received: usize,
+    encoder: Option<Box<dyn EntropyEncoder>>, // created when the headers are written, with the first rows
+    prev_dc_coeffs: [i16; 4],
+}
+
+impl<W: Write> StreamingEncoder<W> {
+    pub fn new(
+        writer: W,
+        width: usize,
+        height: usize,
+        chrominance_downsampling_ratio: (u8, u8, u8),
+        dct_algorithm: DctAlgorithm
+    ) -> StreamingEncoder<W> {
+        // the image only holds an MCU row, so it can't check the height of the whole image
+        JpegImage::validate_dimensions(width as i64, height as i64);
+        let (_, vertical_downsampling) = JpegImage::get_downsampling_factor(chrominance_downsampling_ratio);
+        let mcu_height = 8 * vertical_downsampling;
+        StreamingEncoder {
+            image: JpegImage::new(
+                String::new(),
+                width as i32,
+                mcu_height as i32,
+                chrominance_downsampling_ratio,
+                dct_algorithm
+            ),
+            alpha_options: AlphaOptions::new(),
+            writer,
+            height,
+            mcu_height,
+            rows: Vec::with_capacity(width * mcu_height),
+            alpha: Vec::with_capacity(width * mcu_height),
+            rows_received: 0,
+            encoder: None,
+            prev_dc_coeffs: [0; 4],
+        }
+    }
+
+    pub fn write_rows(&mut self, pixels: &[RGBValue]) -> io::Result<()> {
+        // the pixels of one or more whole rows, left to right and top to bottom
+        self.write_rows_with_alpha(pixels, None)
+    }
+
+    pub fn write_rows_with_alpha(&mut self, pixels: &[RGBValue], alpha: Option<&[u8]>) -> io::Result<()> {
+        // the same, with the alpha of every pixel, which is resolved with the alpha options like in the regular encoder
+        let width = self.image.width as usize;
+        if !pixels.len().is_multiple_of(width) {
+            panic!("Streamed pixels must be whole rows of {} pixels\n", width);
+        }
+        if alpha.is_some_and(|alpha| alpha.len() != pixels.len()) {
+            panic!("Streamed alpha must have a value for every pixel\n");
+        }
+        if self.rows_received + pixels.len() / width > self.height {
+            panic!("More rows were streamed than the {} of the image\n", self.height);
+        }
+        if self.encoder.is_none() {
+            self.write_headers()?;
+        }
+
+        for (i, row) in pixels.chunks(width).enumerate() {
+            self.rows.extend(row.iter().map(|(r, g, b)| (*r as u16, *g as u16, *b as u16)));
+            match alpha {
+                Some(alpha) => self.alpha.extend(alpha[i * width..(i + 1) * width].iter().map(|a| *a as u16)),
+                None => self.alpha.extend(std::iter::repeat_n(255, width)),
+            }
+            self.rows_received += 1;
+            if self.rows.len() == width * self.mcu_height {
+                self.encode_mcu_row()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.rows_received != self.height {
+            panic!("Only {} of the {} rows of the image were streamed\n", self.rows_received, self.height);
+        }
+        if self.encoder.is_none() {
+            self.write_headers()?;
+        }
+
+        // the last MCU row may be incomplete, the rest of it is padded like in the regular encoder
+        if !self.rows.is_empty() {
+            self.encode_mcu_row()?;
+        }
+
+        let remaining_bits = self.encoder.as_mut().unwrap().finish();
+        JpegImage::write_entropy_coded_bytes(&mut self.writer, remaining_bits.as_raw_slice())?;
+        JpegImage::write_eoi(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    pub fn write_bmp(&mut self, bmp_image: &mut BmpImage) -> io::Result<()> {
+        // the rows are read one MCU row at a time, so the image is never loaded as a whole
+        let has_alpha = bmp_image.has_alpha_channel();
+        for start_row in (0..bmp_image.height as usize).step_by(self.mcu_height) {
+            let end_row = (start_row + self.mcu_height).min(bmp_image.height as usize);
+            let (pixels, alpha): (Vec<RGBValue>, Vec<u8>) = (start_row..end_row)
+                .flat_map(|row| bmp_image.read_row(row))
+                .unzip();
+            if has_alpha {
+                self.write_rows_with_alpha(&pixels, Some(&alpha))?;
+            } else {
+                self.write_rows(&pixels)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_headers(&mut self) -> io::Result<()> {
+        // the options that need the whole image can't be used
+        let image = &self.image;
+        if image.lossless_predictor.is_some() {
+            panic!("Lossless images can't be streamed\n");
+        }
+        if image.precision != 8 {
+            panic!("Only 8 bit images can be streamed\n");
+        }
+        if self.alpha_options.alpha_output.is_some() {
+            panic!("The alpha of streamed images can't be written to its own image\n");
+        }
+        if matches!(image.color_space, ColorSpace::Cmyk | ColorSpace::Ycck) {
+            panic!("CMYK images can't be streamed\n");
+        }
+        if image.color_space != ColorSpace::YCbCr && image.chrominance_downsampling_ratio != (4, 4, 4) {
+            panic!("Grayscale and RGB images can only be streamed without subsampling\n");
+        }
+
+        // the frame header has the height of the whole image, not the one of the MCU row
+        self.image.height = self.height as i32;
+        let result = self.image.write_headers(&mut self.writer);
+        self.image.height = self.mcu_height as i32;
+        result?;
+
+        self.encoder = Some(match self.image.entropy_coder {
+            EntropyCoder::Huffman => Box::new(HuffmanEncoder::new(self.image.get_huffman_tables())),
+            EntropyCoder::Arithmetic => Box::new(ArithmeticEncoder::new()),
+        });
+        Ok(())
+    }
+
+    fn encode_mcu_row(&mut self) -> io::Result<()> {
+        // the channels start from zeros every time, which is the padding of the last MCU row
+        let image = &mut self.image;
+        let (width, height) = (image.y_dct_coeffs.width, image.y_dct_coeffs.height);
+        image.y_channel = PixelMatrix::new_with_default(width, height);
+        image.cb_channel = PixelMatrix::new_with_default(width, height);
+        image.cr_channel = PixelMatrix::new_with_default(width, height);
+
+        let rows = std::mem::take(&mut self.rows);
+        let pixels = PixelMatrix::new_from_pixels(image.width as usize, rows.len() / (image.width as usize), rows);
+        // opaque MCU rows skip the alpha, so they are encoded exactly like images without one
+        let alpha = if self.alpha.iter().any(|a| *a != 255) {
+            Some(PixelMatrix::new_from_pixels(pixels.width, pixels.height, self.alpha.clone()))
+        } else {
+            None
+        };
+        self.alpha.clear();
+        let color_conversion = if image.color_space == ColorSpace::Rgb {
+            JpegImage::keep_rgb
+        } else {
+            JpegImage::convert_to_ycbcr
+        };
+        image.load_pixels(&pixels, 255, alpha.as_ref(), &self.alpha_options, color_conversion);
+        self.rows = pixels.pixels;
+        self.rows.clear();
+
+        // adaptive quantization compares the blocks with the average of the MCU row, instead of the whole image
+        image.chrominance_downsampling();
+        image.dct_and_quantization();
+        image.adapt_quantization();
+
+        let encoder = self.encoder.as_mut().unwrap();
+        image.encode_blocks(encoder.as_mut(), &mut self.prev_dc_coeffs);
+        JpegImage::write_entropy_coded_bytes(&mut self.writer, &encoder.take_complete_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::test_images::encode_test_image;
+    use crate::utils::image_format::ImageFormat;
+
+    fn get_test_pixels(width: usize, height: usize) -> Vec<RGBValue> {
+        (0..width * height)
+            .map(|i| {
+                let (row, col) = (i / width, i % width);
+                (((row * 7 + col * 3) % 256) as u8, ((row * col) % 256) as u8, ((col * 11) % 256) as u8)
+            })
+            .collect()
+    }
+
+    fn encode_whole_image(
+        pixels: &[RGBValue],
+        width: usize,
+        height: usize,
+        entropy_coder: EntropyCoder
+    ) -> Vec<u8> {
+        let mut image = JpegImage::new(String::new(), width as i32, height as i32, (4, 2, 0), DctAlgorithm::RealDct);
+        image.entropy_coder = entropy_coder;
+        let wide_pixels = pixels
+            .iter()
+            .map(|(r, g, b)| (*r as u16, *g as u16, *b as u16))
+            .collect();
+        let pixels = PixelMatrix::new_from_pixels(width, height, wide_pixels);
+        image.load_pixels(&pixels, 255, None, &AlphaOptions::new(), JpegImage::convert_to_ycbcr);
+        image.chrominance_downsampling();
+        image.dct_and_quantization();
+        encode_test_image(&mut image)
+    }
+
+    fn encode_streamed(
+        pixels: &[RGBValue],
+        width: usize,
+        height: usize,
+        entropy_coder: EntropyCoder,
+        rows_per_call: usize
+    ) -> Vec<u8> {
+        let mut encoder = StreamingEncoder::new(Vec::new(), width, height, (4, 2, 0), DctAlgorithm::RealDct);
+        encoder.image.entropy_coder = entropy_coder;
+        for rows in pixels.chunks(width * rows_per_call) {
+            encoder.write_rows(rows).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_streamed_huffman_matches_whole_image() {
+        // the size isn't a multiple of the MCU, so the last MCU row is padded
+        let pixels = get_test_pixels(37, 45);
+        let expected = encode_whole_image(&pixels, 37, 45, EntropyCoder::Huffman);
+        assert_eq!(encode_streamed(&pixels, 37, 45, EntropyCoder::Huffman, 1), expected);
+        assert_eq!(encode_streamed(&pixels, 37, 45, EntropyCoder::Huffman, 7), expected);
+    }
+
+    #[test]
+    fn test_streamed_arithmetic_matches_whole_image() {
+        let pixels = get_test_pixels(50, 35);
+        let expected = encode_whole_image(&pixels, 50, 35, EntropyCoder::Arithmetic);
+        assert_eq!(encode_streamed(&pixels, 50, 35, EntropyCoder::Arithmetic, 5), expected);
+    }
+
+    #[test]
+    fn test_streamed_bmp_with_alpha_matches_whole_image() {
+        // a 32 bit BMP whose first MCU row is opaque and the rest goes from transparent to opaque
+        let (width, height) = (20, 37);
+        let mut data = vec![0u8; 54];
+        data[0..2].copy_from_slice(b"BM");
+        data[2..6].copy_from_slice(&((54 + width * height * 4) as u32).to_le_bytes());
+        data[10..14].copy_from_slice(&54u32.to_le_bytes());
+        data[14..18].copy_from_slice(&40u32.to_le_bytes());
+        data[18..22].copy_from_slice(&(width as i32).to_le_bytes());
+        data[22..26].copy_from_slice(&(height as i32).to_le_bytes());
+        data[26..28].copy_from_slice(&1u16.to_le_bytes());
+        data[28..30].copy_from_slice(&32u16.to_le_bytes());
+        let pixels = get_test_pixels(width, height);
+        for row in (0..height).rev() {
+            for col in 0..width {
+                let (r, g, b) = pixels[row * width + col];
+                let alpha = if row < 16 { 255 } else { (col * 13) as u8 };
+                data.extend([b, g, r, alpha]);
+            }
+        }
+        let path = std::env::temp_dir().join("streaming_test_bmp_with_alpha.bmp");
+        std::fs::write(&path, &data).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let alpha_options = AlphaOptions::new().background((0, 128, 255));
+
+        let mut image = JpegImage::from_rgb_image(
+            &path,
+            &ImageFormat::Bmp,
+            &String::new(),
+            (4, 2, 0),
+            DctAlgorithm::RealDct,
+            &alpha_options,
+            false
+        );
+        image.chrominance_downsampling();
+        image.dct_and_quantization();
+        let expected = encode_test_image(&mut image);
+
+        let mut encoder = StreamingEncoder::new(Vec::new(), width, height, (4, 2, 0), DctAlgorithm::RealDct);
+        encoder.alpha_options = alpha_options;
+        encoder.write_bmp(&mut BmpImage::new(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(encoder.finish().unwrap(), expected);
+    }
+}
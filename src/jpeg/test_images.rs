@@ -0,0 +1,48 @@
+use crate::JpegImage;
+use crate::jpeg::dct_quant::DctAlgorithm;
+use crate::utils::colorspace::WideYCbCrValue;
+
+// Images for the tests of the encoder and the decoder, built straight from their channels
+
+pub fn get_test_gradient(row: usize, col: usize) -> WideYCbCrValue {
+    // different in every channel and every block, with some texture so that all the coefficients get used
+    (
+        ((row * 7 + col * 13 + ((row * col) % 31) * 5) % 256) as u16,
+        ((row * 3 + col) % 256) as u16,
+        ((row * row + col * 2) % 256) as u16,
+    )
+}
+
+pub fn get_test_image(
+    width: usize,
+    height: usize,
+    ratio: (u8, u8, u8),
+    pixel: impl Fn(usize, usize) -> WideYCbCrValue
+) -> JpegImage {
+    // the samples of the channels, before they are subsampled
+    let mut image = JpegImage::new(String::new(), width as i32, height as i32, ratio, DctAlgorithm::RealDct);
+    for i in 0..height {
+        for j in 0..width {
+            let (y, cb, cr) = pixel(i, j);
+            image.y_channel.set_pixel(i, j, y);
+            image.cb_channel.set_pixel(i, j, cb);
+            image.cr_channel.set_pixel(i, j, cr);
+        }
+    }
+    image
+}
+
+pub fn get_test_coefficients(width: usize, height: usize, ratio: (u8, u8, u8)) -> JpegImage {
+    // the gradient, already quantized
+    let mut image = get_test_image(width, height, ratio, get_test_gradient);
+    image.chrominance_downsampling();
+    image.dct_and_quantization();
+    image
+}
+
+pub fn encode_test_image(image: &mut JpegImage) -> Vec<u8> {
+    image.generate_entropy_encoded_data();
+    let mut data = Vec::new();
+    image.write_jpeg(&mut data).unwrap();
+    data
+}
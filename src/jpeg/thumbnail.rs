@@ -0,0 +1,202 @@
+use clap::ValueEnum;
+use crate::JpegImage;
+use crate::jpeg::dct_quant::DctAlgorithm;
+use crate::jpeg::jpeg_image::ColorSpace;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+
+pub const JFXX_HEADER: &[u8; 5] = b"JFXX\0";
+const JFXX_JPEG_THUMBNAIL: u8 = 0x10;
+
+// the segment length field counts itself
+const MAX_SEGMENT_SIZE: usize = 65535 - 2;
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub enum ThumbnailLocation {
+    Jfxx, // an APP0 extension right after the JFIF header
+    Exif, // the IFD1 of the EXIF segment
+}
+
+impl JpegImage {
+    pub fn generate_thumbnail(&mut self, max_width: usize, max_height: usize, location: ThumbnailLocation) {
+        // the thumbnail is taken from the loaded channels, so it has to be generated before the chrominance downsampling
+        // (and before the conversion to YCCK).
+        // It keeps the aspect ratio of the image inside the given size, and is shrunk further until its segment fits
+        if !self.has_jfif_header() && location == ThumbnailLocation::Jfxx {
+            panic!("JFXX thumbnails need the JFIF header, which lossless and CMYK images don't have\n");
+        }
+        if self.color_space == ColorSpace::Ycck {
+            panic!("Thumbnails have to be generated before the conversion to YCCK\n");
+        }
+        self.thumbnail_location = location;
+
+        let scale = (max_width as f64 / self.width as f64).min(max_height as f64 / self.height as f64).min(1.0);
+        let mut width = ((self.width as f64) * scale).round().max(1.0) as usize;
+        let mut height = ((self.height as f64) * scale).round().max(1.0) as usize;
+        loop {
+            self.thumbnail = Some(self.encode_thumbnail(width, height));
+            if self.get_thumbnail_segment_size() <= MAX_SEGMENT_SIZE {
+                return;
+            }
+            if width == 1 && height == 1 {
+                panic!("The thumbnail doesn't fit in its segment\n");
+            }
+            width = (width / 2).max(1);
+            height = (height / 2).max(1);
+        }
+    }
+
+    fn get_thumbnail_segment_size(&self) -> usize {
+        match self.thumbnail_location {
+            ThumbnailLocation::Jfxx => self.get_jfxx_segment().map_or(0, |segment| segment.len()),
+            ThumbnailLocation::Exif => self.get_exif_segment().map_or(0, |segment| segment.len()),
+        }
+    }
+
+    pub fn get_jfxx_segment(&self) -> Option<Vec<u8>> {
+        let thumbnail = self.thumbnail.as_ref().filter(|_| self.thumbnail_location == ThumbnailLocation::Jfxx)?;
+        let mut segment = JFXX_HEADER.to_vec();
+        segment.push(JFXX_JPEG_THUMBNAIL);
+        segment.extend_from_slice(thumbnail);
+        Some(segment)
+    }
+
+    fn encode_thumbnail(&self, width: usize, height: usize) -> Vec<u8> {
+        // a baseline 8 bit JPEG, in 4:2:0 unless the image is grayscale
+        let grayscale = self.color_space == ColorSpace::Grayscale;
+        let mut thumbnail = JpegImage::new(
+            String::new(),
+            width as i32,
+            height as i32,
+            if grayscale { (4, 4, 4) } else { (4, 2, 0) },
+            DctAlgorithm::RealDct
+        );
+        if grayscale {
+            thumbnail.color_space = ColorSpace::Grayscale;
+        }
+
+        // subsampled inputs have smaller chrominance channels
+        let (chroma_width, chroma_height) = if self.chrominance_downsampled {
+            let (h, v) = Self::get_downsampling_factor(self.chrominance_downsampling_ratio);
+            ((self.width as usize).div_ceil(h), (self.height as usize).div_ceil(v))
+        } else {
+            (self.width as usize, self.height as usize)
+        };
+        let shift = self.precision - 8;
+        let y = Self::shrink_channel(&self.y_channel, self.width as usize, self.height as usize, width, height, shift);
+        let cb = Self::shrink_channel(&self.cb_channel, chroma_width, chroma_height, width, height, shift);
+        let cr = Self::shrink_channel(&self.cr_channel, chroma_width, chroma_height, width, height, shift);
+        let k = self.k_channel
+            .as_ref()
+            .map(|k_channel| Self::shrink_channel(k_channel, self.width as usize, self.height as usize, width, height, shift));
+
+        for i in 0..height {
+            for j in 0..width {
+                let index = i * width + j;
+                if grayscale {
+                    thumbnail.y_channel.set_pixel(i, j, y[index]);
+                    continue;
+                }
+                // lossless images keep their samples in RGB, and CMYK ones (inverted) get the black applied to each color
+                let ycbcr = if self.lossless_predictor.is_some() || self.color_space == ColorSpace::Rgb {
+                    Self::convert_to_ycbcr((y[index], cb[index], cr[index]), 8)
+                } else if let Some(k) = &k {
+                    let apply_black = |sample: u16| ((sample as u32) * (k[index] as u32) / 255) as u16;
+                    Self::convert_to_ycbcr((apply_black(y[index]), apply_black(cb[index]), apply_black(cr[index])), 8)
+                } else {
+                    (y[index], cb[index], cr[index])
+                };
+                thumbnail.y_channel.set_pixel(i, j, ycbcr.0);
+                thumbnail.cb_channel.set_pixel(i, j, ycbcr.1);
+                thumbnail.cr_channel.set_pixel(i, j, ycbcr.2);
+            }
+        }
+
+        thumbnail.chrominance_downsampling();
+        thumbnail.dct_and_quantization();
+        thumbnail.generate_entropy_encoded_data();
+        let mut data = Vec::new();
+        thumbnail.write_jpeg(&mut data).unwrap();
+        data
+    }
+
+    fn shrink_channel(
+        channel: &PixelMatrix<u16>,
+        channel_width: usize, // the part of the channel that has samples, without the padding
+        channel_height: usize,
+        width: usize,
+        height: usize,
+        shift: u8 // to take 12 bit samples to 8 bits
+    ) -> Vec<u16> {
+        // every thumbnail pixel is the average of the area of the channel it covers
+        let mut samples = Vec::with_capacity(width * height);
+        for i in 0..height {
+            let (row_start, row_end) = Self::get_covered_range(i, height, channel_height);
+            for j in 0..width {
+                let (col_start, col_end) = Self::get_covered_range(j, width, channel_width);
+                let mut sum = 0u64;
+                for row in row_start..row_end {
+                    for col in col_start..col_end {
+                        sum += channel.get_pixel(row, col).unwrap_or_default() as u64;
+                    }
+                }
+                let count = ((row_end - row_start) * (col_end - col_start)) as u64;
+                samples.push(((sum + count / 2) / count) as u16 >> shift);
+            }
+        }
+        samples
+    }
+
+    fn get_covered_range(index: usize, size: usize, channel_size: usize) -> (usize, usize) {
+        // at least one sample, even if the thumbnail is bigger than the channel in this direction
+        let start = (index * channel_size / size).min(channel_size - 1);
+        let end = ((index + 1) * channel_size / size).max(start + 1);
+        (start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::exif::parse_ifd;
+    use crate::jpeg::test_images::{ get_test_gradient, get_test_image };
+    use byteorder::{ BigEndian, ByteOrder };
+
+    #[test]
+    fn test_jfxx_thumbnail() {
+        let mut image = get_test_image(640, 480, (4, 2, 0), get_test_gradient);
+        image.generate_thumbnail(160, 160, ThumbnailLocation::Jfxx);
+        let segment = image.get_jfxx_segment().unwrap();
+        assert_eq!(&segment[..5], JFXX_HEADER);
+        assert_eq!(segment[5], JFXX_JPEG_THUMBNAIL);
+
+        // a complete JPEG, with the size of the image scaled to fit in 160x160
+        let thumbnail = &segment[6..];
+        assert_eq!(&thumbnail[..2], &[0xff, 0xd8]);
+        assert_eq!(&thumbnail[thumbnail.len() - 2..], &[0xff, 0xd9]);
+        let sof = thumbnail.windows(2).position(|marker| marker == [0xff, 0xc0]).unwrap();
+        assert_eq!(BigEndian::read_u16(&thumbnail[sof + 5..]), 120);
+        assert_eq!(BigEndian::read_u16(&thumbnail[sof + 7..]), 160);
+        assert!(image.get_exif_segment().is_none());
+    }
+
+    #[test]
+    fn test_exif_thumbnail() {
+        let mut image = get_test_image(640, 480, (4, 2, 0), get_test_gradient);
+        image.generate_thumbnail(64, 64, ThumbnailLocation::Exif);
+        let segment = image.get_exif_segment().unwrap();
+        assert!(segment.len() <= MAX_SEGMENT_SIZE);
+
+        // IFD1 follows IFD0, and points to the thumbnail data
+        let tiff = &segment[6..];
+        let ifd0_offset = BigEndian::read_u32(&tiff[4..8]) as usize;
+        let ifd0_entries = BigEndian::read_u16(&tiff[ifd0_offset..]) as usize;
+        let ifd1_offset = BigEndian::read_u32(&tiff[ifd0_offset + 2 + ifd0_entries * 12..]) as usize;
+        let ifd1 = parse_ifd(tiff, ifd1_offset);
+        let get = |tag: u16| ifd1.iter().find(|(field_tag, _, _, _)| *field_tag == tag).unwrap().3.clone();
+        assert_eq!(get(0x0103), vec![0, 6]);
+        let offset = BigEndian::read_u32(&get(0x0201)) as usize;
+        let length = BigEndian::read_u32(&get(0x0202)) as usize;
+        assert_eq!(&tiff[offset..offset + length], image.thumbnail.as_ref().unwrap().as_slice());
+        assert!(image.get_jfxx_segment().is_none());
+    }
+}
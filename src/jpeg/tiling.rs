@@ -0,0 +1,103 @@
+use std::path::Path;
+use crate::JpegImage;
+
+// a part of an image that is written to its own file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tile {
+    pub path: String,
+    pub x: usize, // position of the top left pixel in the whole image
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl JpegImage {
+    pub fn get_tiles(width: usize, height: usize, tile_width: usize, tile_height: usize, jpeg_path: &str) -> Vec<Tile> {
+        // Tiles go left to right and top to bottom, the last ones of each row and column get what is left of the image.
+        // They are named after the output path, with their row and column: image.jpg becomes image_0_0.jpg, image_0_1.jpg...
+        let path = Path::new(jpeg_path);
+        let stem = path.with_extension("");
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or("jpg".to_string());
+
+        let mut tiles = Vec::new();
+        for (row, y) in (0..height).step_by(tile_height).enumerate() {
+            for (col, x) in (0..width).step_by(tile_width).enumerate() {
+                tiles.push(Tile {
+                    path: format!("{}_{}_{}.{}", stem.to_string_lossy(), row, col, extension),
+                    x,
+                    y,
+                    width: tile_width.min(width - x),
+                    height: tile_height.min(height - y),
+                });
+            }
+        }
+        tiles
+    }
+
+    pub fn get_manifest_path(jpeg_path: &str) -> String {
+        Path::new(jpeg_path).with_extension("json").to_string_lossy().to_string()
+    }
+
+    pub fn get_tile_manifest(width: usize, height: usize, tiles: &[Tile]) -> String {
+        // JSON with the size of the whole image, and the position of each tile in it.
+        // The files are given by their name, since the manifest is written next to them
+        let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+        let tile_entries: Vec<String> = tiles
+            .iter()
+            .map(|tile| {
+                let file_name = Path::new(&tile.path).file_name().unwrap().to_string_lossy().to_string();
+                format!(
+                    "    {{ \"file\": \"{}\", \"x\": {}, \"y\": {}, \"width\": {}, \"height\": {} }}",
+                    escape(&file_name),
+                    tile.x,
+                    tile.y,
+                    tile.width,
+                    tile.height
+                )
+            })
+            .collect();
+        format!(
+            "{{\n  \"width\": {},\n  \"height\": {},\n  \"tiles\": [\n{}\n  ]\n}}\n",
+            width,
+            height,
+            tile_entries.join(",\n")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_cover_the_image() {
+        let tiles = JpegImage::get_tiles(70000, 1000, 30000, 600, "out/pano.jpg");
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(tiles[0], Tile { path: "out/pano_0_0.jpg".to_string(), x: 0, y: 0, width: 30000, height: 600 });
+        assert_eq!(tiles[2], Tile { path: "out/pano_0_2.jpg".to_string(), x: 60000, y: 0, width: 10000, height: 600 });
+        assert_eq!(tiles[4], Tile { path: "out/pano_1_1.jpg".to_string(), x: 30000, y: 600, width: 30000, height: 400 });
+        assert_eq!(tiles.iter().map(|tile| tile.width * tile.height).sum::<usize>(), 70000 * 1000);
+    }
+
+    #[test]
+    fn test_tile_manifest() {
+        let tiles = JpegImage::get_tiles(100, 50, 64, 64, "dir/a\"b.jpg");
+        assert_eq!(JpegImage::get_manifest_path("dir/a\"b.jpg"), "dir/a\"b.json");
+        assert_eq!(
+            JpegImage::get_tile_manifest(100, 50, &tiles),
+            concat!(
+                "{\n",
+                "  \"width\": 100,\n",
+                "  \"height\": 50,\n",
+                "  \"tiles\": [\n",
+                "    { \"file\": \"a\\\"b_0_0.jpg\", \"x\": 0, \"y\": 0, \"width\": 64, \"height\": 50 },\n",
+                "    { \"file\": \"a\\\"b_0_1.jpg\", \"x\": 64, \"y\": 0, \"width\": 36, \"height\": 50 }\n",
+                "  ]\n",
+                "}\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use crate::JpegImage;
+use crate::jpeg::dct_quant::DctAlgorithm;
+use crate::jpeg::jpeg_image::ColorSpace;
+use crate::decoder::jpeg_file::JpegFile;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+
+impl JpegImage {
+    pub fn from_jpeg_file(jpeg_file: JpegFile, jpeg_path: &str) -> JpegImage {
+        // Takes the quantized coefficients of an existing JPEG as they are, so it can be written again without any loss.
+        // The samples aren't decoded, so only the steps that work on the coefficients can be used on the image
+        if jpeg_file.precision != 8 {
+            panic!("Only 8 bit JPEG images can be transcoded\n");
+        }
+
+        let color_space = jpeg_file.get_color_space();
+        let density = jpeg_file.get_density();
+
+        // the chrominance components must be at the lowest resolution, like the subsampling ratios of the encoder
+        let luminance = &jpeg_file.components[0];
+        let chrominance_downsampling_ratio = match (luminance.horizontal_sampling, luminance.vertical_sampling) {
+            (1, 1) => (4, 4, 4),
+            (2, 1) => (4, 2, 2),
+            (2, 2) => (4, 2, 0),
+            _ => panic!("Unsupported sampling factors in the JPEG image\n"),
+        };
+        if
+            jpeg_file.components[1..]
+                .iter()
+                .any(|component| component.horizontal_sampling != 1 || component.vertical_sampling != 1) ||
+            (jpeg_file.components.len() == 4 && chrominance_downsampling_ratio != (4, 4, 4))
+        {
+            panic!("Unsupported sampling factors in the JPEG image\n");
+        }
+
+        let mut image = JpegImage::new(
+            jpeg_path.to_string(),
+            jpeg_file.width as i32,
+            jpeg_file.height as i32,
+            chrominance_downsampling_ratio,
+            DctAlgorithm::RealDct
+        );
+        let grayscale = color_space == ColorSpace::Grayscale;
+        image.color_space = color_space;
+        image.y_channel = PixelMatrix::new(0, 0);
+        image.cb_channel = PixelMatrix::new(0, 0);
+        image.cr_channel = PixelMatrix::new(0, 0);
+
+        // the tables are written in the layout of the encoder, so the components that share a table there must share it in the file
+        let mut quantization_tables: [Option<[u8; 64]>; 2] = [None, None];
+        for (component, table) in jpeg_file.components.iter().zip(image.get_component_tables()) {
+            let file_table = jpeg_file.quantization_tables[component.quantization_table].expect(
+                "JPEG component uses an undefined quantization table\n"
+            );
+            if file_table.iter().any(|value| *value > 255) {
+                panic!("Quantization tables with 16 bit values are not supported\n");
+            }
+            let file_table = file_table.map(|value| value as u8);
+            match quantization_tables[table] {
+                Some(existing_table) if existing_table != file_table => {
+                    panic!("Unsupported quantization table layout in the JPEG image\n");
+                }
+                _ => {
+                    quantization_tables[table] = Some(file_table);
+                }
+            }
+        }
+        for (i, table) in quantization_tables.iter().enumerate() {
+            if let Some(table) = table {
+                image.quantization_tables[i] = *table;
+            }
+        }
+
+        // grayscale images still need chrominance coefficients of the size of the luminance ones, which aren't written
+        let mut components = jpeg_file.components.into_iter();
+        image.y_dct_coeffs = components.next().unwrap().coeffs;
+        if grayscale {
+            image.cb_dct_coeffs = PixelMatrix::new_with_default(image.y_dct_coeffs.width, image.y_dct_coeffs.height);
+            image.cr_dct_coeffs = PixelMatrix::new_with_default(image.y_dct_coeffs.width, image.y_dct_coeffs.height);
+        } else {
+            image.cb_dct_coeffs = components.next().unwrap().coeffs;
+            image.cr_dct_coeffs = components.next().unwrap().coeffs;
+        }
+        image.k_dct_coeffs = components.next().map(|component| component.coeffs);
+
+        // the JFIF and Adobe segments are written again from the image, the rest of them are kept
+        if let Some(density) = density {
+            image.density = density;
+        }
+        for (marker, content) in jpeg_file.segments {
+            match marker {
+                0xe0 if content.starts_with(b"JFIF\0") => {}
+                0xee if content.starts_with(b"Adobe") => {}
+                _ => image.segments.push((marker, content)),
+            }
+        }
+
+        image
+    }
+
+    pub fn strip_metadata(&mut self) {
+        // the ICC profile is kept, since it changes how the colors are shown
+        self.segments.retain(|(marker, content)| *marker == 0xe2 && content.starts_with(b"ICC_PROFILE\0"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::jpeg_image::{ Density, DensityUnit };
+    use crate::jpeg::test_images::{ encode_test_image, get_test_gradient, get_test_image };
+
+    #[test]
+    fn test_transcoding_keeps_the_file() {
+        // an image of the encoder is written again exactly as it was
+        let mut image = get_test_image(40, 24, (4, 2, 2), get_test_gradient);
+        image.density = Density { unit: DensityUnit::DotsPerCm, x: 118, y: 118 };
+        image.xmp = Some("<x:xmpmeta/>".to_string());
+        image.quantization_tables[1][0] = 5;
+        image.chrominance_downsampling();
+        image.dct_and_quantization();
+        let data = encode_test_image(&mut image);
+
+        let mut transcoded = JpegImage::from_jpeg_file(JpegFile::from_bytes(&data), "");
+        assert_eq!(transcoded.chrominance_downsampling_ratio, (4, 2, 2));
+        assert_eq!(transcoded.density, image.density);
+        assert_eq!(transcoded.segments.len(), 1);
+        assert_eq!(encode_test_image(&mut transcoded), data);
+    }
+
+    #[test]
+    fn test_transcoding_color_spaces() {
+        for (color_space, ratio) in [
+            (ColorSpace::Grayscale, (4, 4, 4)),
+            (ColorSpace::Rgb, (4, 4, 4)),
+            (ColorSpace::Ycck, (4, 4, 4)),
+        ] {
+            let mut image = JpegImage::new(String::new(), 16, 16, ratio, DctAlgorithm::RealDct);
+            image.color_space = color_space.clone();
+            if color_space == ColorSpace::Ycck {
+                image.k_dct_coeffs = Some(PixelMatrix::new_with_default(16, 16));
+            }
+            image.dct_and_quantization();
+
+            let transcoded = JpegImage::from_jpeg_file(JpegFile::from_bytes(&encode_test_image(&mut image)), "");
+            assert_eq!(transcoded.color_space, color_space);
+            assert!(transcoded.segments.is_empty());
+            assert_eq!(transcoded.k_dct_coeffs.is_some(), color_space == ColorSpace::Ycck);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use clap::ValueEnum;
+use crate::JpegImage;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+
+// lossless transformations of the coefficient blocks, which move the blocks and the coefficients inside of them
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Transform {
+    Rotate90, // clockwise
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose, // across the top left to bottom right diagonal
+}
+
+impl Transform {
+    fn get_steps(&self) -> (bool, bool, bool) {
+        // every transform is a transpose followed by horizontal and vertical flips
+        match self {
+            Transform::Rotate90 => (true, true, false),
+            Transform::Rotate180 => (false, true, true),
+            Transform::Rotate270 => (true, false, true),
+            Transform::FlipHorizontal => (false, true, false),
+            Transform::FlipVertical => (false, false, true),
+            Transform::Transpose => (true, false, false),
+        }
+    }
+}
+
+impl JpegImage {
+    pub fn get_mcu_size(&self) -> (usize, usize) {
+        let (horizontal_downsampling, vertical_downsampling) = Self::get_downsampling_factor(
+            self.chrominance_downsampling_ratio
+        );
+        (8 * horizontal_downsampling, 8 * vertical_downsampling)
+    }
+
+    fn for_each_dct_coeffs(&mut self, f: impl Fn(&PixelMatrix<i16>) -> PixelMatrix<i16>) {
+        self.y_dct_coeffs = f(&self.y_dct_coeffs);
+        self.cb_dct_coeffs = f(&self.cb_dct_coeffs);
+        self.cr_dct_coeffs = f(&self.cr_dct_coeffs);
+        self.k_dct_coeffs = self.k_dct_coeffs.as_ref().map(f);
+    }
+
+    pub fn transform_coefficients(&mut self, transform: Transform) {
+        // The edges of the image that don't fill a whole MCU can't be moved to the other side, since the padding
+        // of the last MCU would end up inside the image, so they are trimmed off, like jpegtran -trim does
+        let (transpose, horizontal_flip, vertical_flip) = transform.get_steps();
+        let (mcu_width, mcu_height) = self.get_mcu_size();
+        if transpose && mcu_width != mcu_height {
+            panic!("4:2:2 images can't be transposed or rotated by 90 or 270 degrees\n");
+        }
+        let (flipped_columns, flipped_rows) = if transpose {
+            (vertical_flip, horizontal_flip)
+        } else {
+            (horizontal_flip, vertical_flip)
+        };
+        let (mut width, mut height) = (self.width as usize, self.height as usize);
+        if flipped_columns {
+            width -= width % mcu_width;
+        }
+        if flipped_rows {
+            height -= height % mcu_height;
+        }
+        if width == 0 || height == 0 {
+            panic!("The image is smaller than an MCU, so it can't be transformed\n");
+        }
+        self.crop_coefficients(0, 0, width, height);
+
+        self.for_each_dct_coeffs(|coeffs| Self::transform_blocks(coeffs, transpose, horizontal_flip, vertical_flip));
+        if transpose {
+            (self.width, self.height) = (self.height, self.width);
+        }
+    }
+
+    pub fn crop_coefficients(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        // The top left corner is moved to the MCU it is in, since only whole MCUs can be cut off,
+        // and the size is reduced to what is left of the image
+        let (mcu_width, mcu_height) = self.get_mcu_size();
+        if x >= (self.width as usize) || y >= (self.height as usize) {
+            panic!("The crop area is outside of the image\n");
+        }
+        let width = (width + (x % mcu_width)).min((self.width as usize) - x + (x % mcu_width));
+        let height = (height + (y % mcu_height)).min((self.height as usize) - y + (y % mcu_height));
+        let (x, y) = (x - (x % mcu_width), y - (y % mcu_height));
+
+        // each matrix has the same amount of MCUs, with a different size depending on its subsampling
+        let (mcu_columns, mcu_rows) = (width.div_ceil(mcu_width), height.div_ceil(mcu_height));
+        let (first_column, first_row) = (x / mcu_width, y / mcu_height);
+        let (total_mcu_columns, total_mcu_rows) = (
+            (self.width as usize).div_ceil(mcu_width),
+            (self.height as usize).div_ceil(mcu_height),
+        );
+        self.for_each_dct_coeffs(|coeffs| {
+            let (component_mcu_width, component_mcu_height) = (
+                coeffs.width / total_mcu_columns,
+                coeffs.height / total_mcu_rows,
+            );
+            coeffs.crop(
+                first_row * component_mcu_height,
+                first_column * component_mcu_width,
+                mcu_columns * component_mcu_width,
+                mcu_rows * component_mcu_height
+            )
+        });
+        (self.width, self.height) = (width as i32, height as i32);
+    }
+
+    fn transform_blocks(
+        coeffs: &PixelMatrix<i16>,
+        transpose: bool,
+        horizontal_flip: bool,
+        vertical_flip: bool
+    ) -> PixelMatrix<i16> {
+        // Flipping a block negates the coefficients of odd frequencies in that direction,
+        // and transposing it swaps the horizontal and vertical frequencies
+        let (width, height) = if transpose { (coeffs.height, coeffs.width) } else { (coeffs.width, coeffs.height) };
+        let (columns, rows) = (width / 8, height / 8);
+        let mut transformed = PixelMatrix::new_with_default(width, height);
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let flipped_row = if vertical_flip { rows - 1 - row } else { row };
+                let flipped_column = if horizontal_flip { columns - 1 - column } else { column };
+                let (source_row, source_column) = if transpose {
+                    (flipped_column, flipped_row)
+                } else {
+                    (flipped_row, flipped_column)
+                };
+
+                for v in 0..8 {
+                    for u in 0..8 {
+                        let (source_v, source_u) = if transpose { (u, v) } else { (v, u) };
+                        let coeff = coeffs.pixels
+                            [(source_row * 8 + source_v) * coeffs.width + source_column * 8 + source_u];
+                        let negate = (vertical_flip && v % 2 == 1) != (horizontal_flip && u % 2 == 1);
+                        transformed.set_pixel(row * 8 + v, column * 8 + u, if negate { -coeff } else { coeff });
+                    }
+                }
+            }
+        }
+        transformed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::test_images::get_test_coefficients;
+
+    #[test]
+    fn test_transforms_go_back_to_the_original() {
+        // the size is a multiple of the MCU, so nothing is trimmed
+        let original = get_test_coefficients(48, 32, (4, 2, 0));
+        let mut image = get_test_coefficients(48, 32, (4, 2, 0));
+        for _ in 0..4 {
+            image.transform_coefficients(Transform::Rotate90);
+        }
+        assert_eq!(image.y_dct_coeffs.pixels, original.y_dct_coeffs.pixels);
+
+        image.transform_coefficients(Transform::Rotate90);
+        assert_eq!((image.width, image.height), (32, 48));
+        assert_eq!((image.y_dct_coeffs.width, image.cb_dct_coeffs.width), (32, 16));
+        image.transform_coefficients(Transform::Rotate270);
+        image.transform_coefficients(Transform::Transpose);
+        image.transform_coefficients(Transform::Rotate90);
+        image.transform_coefficients(Transform::FlipHorizontal);
+        assert_eq!(image.y_dct_coeffs.pixels, original.y_dct_coeffs.pixels);
+        assert_eq!(image.cb_dct_coeffs.pixels, original.cb_dct_coeffs.pixels);
+
+        image.transform_coefficients(Transform::FlipVertical);
+        image.transform_coefficients(Transform::FlipHorizontal);
+        image.transform_coefficients(Transform::Rotate180);
+        assert_eq!(image.y_dct_coeffs.pixels, original.y_dct_coeffs.pixels);
+    }
+
+    #[test]
+    fn test_trim_and_crop() {
+        let mut image = get_test_coefficients(40, 37, (4, 2, 0));
+        image.transform_coefficients(Transform::FlipHorizontal);
+        assert_eq!((image.width, image.height), (32, 37));
+        assert_eq!((image.y_dct_coeffs.width, image.y_dct_coeffs.height), (32, 48));
+        // the first block of the flipped image is the last whole one of the first row, with its odd columns negated
+        let original = get_test_coefficients(40, 37, (4, 2, 0));
+        assert_eq!(image.y_dct_coeffs.pixels[0], original.y_dct_coeffs.pixels[24]);
+        assert_eq!(image.y_dct_coeffs.pixels[1], -original.y_dct_coeffs.pixels[25]);
+        assert_ne!(original.y_dct_coeffs.pixels[25], 0);
+
+        image.crop_coefficients(20, 20, 100, 100);
+        assert_eq!((image.width, image.height), (16, 21));
+        assert_eq!((image.y_dct_coeffs.width, image.y_dct_coeffs.height), (16, 32));
+        assert_eq!((image.cb_dct_coeffs.width, image.cb_dct_coeffs.height), (8, 16));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::JpegImage;
+
+pub const XMP_HEADER: &[u8; 29] = b"http://ns.adobe.com/xap/1.0/\0";
+pub const EXTENDED_XMP_HEADER: &[u8; 35] = b"http://ns.adobe.com/xmp/extension/\0";
+
+// the APP1 length field counts itself
+const MAX_XMP_SIZE: usize = 65535 - 2 - XMP_HEADER.len();
+// each extended chunk also has the GUID, the full length and its offset in the extended packet
+const MAX_EXTENDED_XMP_CHUNK_SIZE: usize = 65535 - 2 - EXTENDED_XMP_HEADER.len() - 32 - 4 - 4;
+const MAX_COMMENT_SIZE: usize = 65535 - 2;
+
+impl JpegImage {
+    pub fn set_xmp(&mut self, xmp: &str) {
+        self.xmp = Some(xmp.to_string());
+    }
+
+    pub fn add_comment(&mut self, comment: &str) {
+        self.comments.push(comment.to_string());
+    }
+
+    pub fn get_xmp_segments(xmp: &str) -> Vec<Vec<u8>> {
+        // packets that fit are written as they are
+        if xmp.len() <= MAX_XMP_SIZE {
+            let mut segment = XMP_HEADER.to_vec();
+            segment.extend_from_slice(xmp.as_bytes());
+            return vec![segment];
+        }
+
+        // bigger ones go whole to the extended XMP, without the packet wrapper, and the standard packet only points to it
+        // through the MD5 digest of the extended one
+        let extended_xmp = Self::strip_xpacket_wrapper(xmp);
+        let guid = format!("{:X}", md5::compute(extended_xmp.as_bytes()));
+        let standard_xmp = format!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\
+            <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\
+            <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+            <rdf:Description rdf:about=\"\" xmlns:xmpNote=\"http://ns.adobe.com/xmp/note/\" xmpNote:HasExtendedXMP=\"{}\"/>\
+            </rdf:RDF></x:xmpmeta><?xpacket end=\"w\"?>",
+            guid
+        );
+
+        let mut segments = Self::get_xmp_segments(&standard_xmp);
+        for (i, data) in extended_xmp.as_bytes().chunks(MAX_EXTENDED_XMP_CHUNK_SIZE).enumerate() {
+            let mut segment = EXTENDED_XMP_HEADER.to_vec();
+            segment.extend_from_slice(guid.as_bytes());
+            segment.extend_from_slice(&(extended_xmp.len() as u32).to_be_bytes());
+            segment.extend_from_slice(&((i * MAX_EXTENDED_XMP_CHUNK_SIZE) as u32).to_be_bytes());
+            segment.extend_from_slice(data);
+            segments.push(segment);
+        }
+        segments
+    }
+
+    fn strip_xpacket_wrapper(xmp: &str) -> &str {
+        // the "<?xpacket begin ...?>" and "<?xpacket end ...?>" processing instructions around the XMP, if there are any
+        let mut xmp = xmp.trim();
+        if xmp.starts_with("<?xpacket") {
+            if let Some(end) = xmp.find("?>") {
+                xmp = xmp[end + 2..].trim_start();
+            }
+        }
+        if let Some(start) = xmp.rfind("<?xpacket") {
+            if xmp[start..].trim_end().ends_with("?>") {
+                xmp = xmp[..start].trim_end();
+            }
+        }
+        xmp
+    }
+
+    pub fn get_comment_segments(comment: &str) -> Vec<&[u8]> {
+        // comments that don't fit in a segment continue in the next one
+        if comment.is_empty() {
+            return vec![&[]];
+        }
+        comment.as_bytes().chunks(MAX_COMMENT_SIZE).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_standard_xmp() {
+        let xmp = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>";
+        let segments = JpegImage::get_xmp_segments(xmp);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(&segments[0][..XMP_HEADER.len()], XMP_HEADER);
+        assert_eq!(&segments[0][XMP_HEADER.len()..], xmp.as_bytes());
+    }
+
+    #[test]
+    fn test_extended_xmp() {
+        let description = "a".repeat(150000);
+        let extended_xmp = format!("<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">{}</x:xmpmeta>", description);
+        let xmp = format!("<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n{}\n<?xpacket end=\"w\"?>", extended_xmp);
+        let segments = JpegImage::get_xmp_segments(&xmp);
+        assert_eq!(segments.len(), 4);
+
+        // the standard packet names the extended one by its digest
+        let guid = format!("{:X}", md5::compute(extended_xmp.as_bytes()));
+        let standard_xmp = std::str::from_utf8(&segments[0][XMP_HEADER.len()..]).unwrap();
+        assert!(standard_xmp.contains(&format!("xmpNote:HasExtendedXMP=\"{}\"", guid)));
+
+        let mut joined = vec![0u8; extended_xmp.len()];
+        for segment in &segments[1..] {
+            assert!(segment.len() + 2 <= 65535);
+            assert_eq!(&segment[..EXTENDED_XMP_HEADER.len()], EXTENDED_XMP_HEADER);
+            let data = &segment[EXTENDED_XMP_HEADER.len()..];
+            assert_eq!(&data[..32], guid.as_bytes());
+            assert_eq!(u32::from_be_bytes(data[32..36].try_into().unwrap()) as usize, extended_xmp.len());
+            let offset = u32::from_be_bytes(data[36..40].try_into().unwrap()) as usize;
+            joined[offset..offset + data.len() - 40].copy_from_slice(&data[40..]);
+        }
+        assert_eq!(joined, extended_xmp.as_bytes());
+    }
+}
@@ -1,25 +1,128 @@
 mod utils;
-use crate::utils::arguments::Args;
+use crate::utils::arguments::{ Args, Command, DecodeArgs, OptimizeArgs, RequantizeArgs, TransformArgs };
 mod jpeg;
-use jpeg::jpeg_image::JpegImage;
+use jpeg::jpeg_image::{ ColorSpace, JpegImage };
+use jpeg::streaming::StreamingEncoder;
+use decoder::jpeg_file::JpegFile;
+use yuv::yuv_image::YuvImage;
+use bmp::bmp_image::BmpImage;
+use std::{ fs::File, io::{ self, BufWriter, Write } };
 mod bmp;
+mod netpbm;
+mod qoi;
+mod tga;
+mod tiff;
+mod yuv;
+mod decoder;
 mod pixel_matrix;
 
 fn main() {
     // parse arguments
 
     let args: Args = Args::get_args();
+
+    if let Some(command) = &args.command {
+        match command {
+            Command::Transform(transform_args) => transform_jpeg(transform_args),
+            Command::Optimize(optimize_args) => optimize_jpeg(optimize_args),
+            Command::Requantize(requantize_args) => requantize_jpeg(requantize_args),
+            Command::Decode(decode_args) => decode_jpeg(decode_args),
+        }
+        return;
+    }
+
     args.print_args();
 
-    // create jpeg image object from bmp file, with color space conversion to ycbcr
+    // BMP images can be encoded a few rows at a time, without ever loading them whole
 
-    println!("Loading bmp...");
-    let mut jpeg_image: JpegImage = JpegImage::from_bmp(
-        &args.image,
-        &args.output,
-        args.subsampling_ratio,
-        args.dct_algorithm
-    );
+    if args.streaming {
+        println!("Streaming image...");
+        stream_bmp(&args);
+        println!("Done!");
+        return;
+    }
+
+    // images too big for a single JPEG can be split into tiles, with a manifest to put them back together
+
+    if let Some((tile_width, tile_height)) = args.tile_size {
+        encode_tiles(&args, tile_width as usize, tile_height as usize);
+        return;
+    }
+
+    // create jpeg image object from bmp, tga, qoi, tiff, netpbm or raw yuv file, with color space conversion to ycbcr (except for yuv)
+
+    println!("Loading image...");
+    let alpha_options = args.get_alpha_options();
+    let jpeg_image: JpegImage = match args.lossless {
+        Some(predictor) =>
+            JpegImage::from_rgb_image_lossless(
+                &args.image,
+                args.image_format.as_ref().unwrap(),
+                &args.output,
+                predictor,
+                &alpha_options
+            ),
+        None if args.yuv_format.is_some() =>
+            JpegImage::from_yuv(
+                &YuvImage::new(
+                    &args.image,
+                    args.yuv_format.clone().unwrap(),
+                    args.width.unwrap(),
+                    args.height.unwrap(),
+                    args.stride
+                ),
+                &args.output,
+                args.subsampling_ratio,
+                args.dct_algorithm.clone()
+            ),
+        None if args.is_netpbm() =>
+            JpegImage::from_netpbm(
+                &args.image,
+                &args.output,
+                args.subsampling_ratio,
+                args.dct_algorithm.clone(),
+                args.precision,
+                &alpha_options,
+                &args.hdr_conversion,
+                args.rgb
+            ),
+        None =>
+            JpegImage::from_rgb_image(
+                &args.image,
+                args.image_format.as_ref().unwrap(),
+                &args.output,
+                args.subsampling_ratio,
+                args.dct_algorithm.clone(),
+                &alpha_options,
+                args.rgb
+            ),
+    };
+
+    encode(jpeg_image, &args);
+}
+
+fn encode(mut jpeg_image: JpegImage, args: &Args) {
+    set_options(&mut jpeg_image, args);
+
+    // the thumbnail is taken from the full resolution channels
+    if let Some((width, height)) = args.thumbnail {
+        println!("Generating thumbnail...");
+        jpeg_image.generate_thumbnail(width as usize, height as usize, args.thumbnail_location.clone());
+    }
+
+    // the CMYK channels are converted after the thumbnail has been taken from them
+    if args.ycck {
+        if jpeg_image.color_space == ColorSpace::Cmyk {
+            jpeg_image.convert_to_ycck();
+        } else {
+            println!("The image isn't CMYK, so it wasn't converted to YCCK");
+        }
+    }
+
+    if let Some(roi_mask) = &args.roi_mask {
+        println!("Loading region of interest mask...");
+        jpeg_image.load_roi_mask(roi_mask);
+    }
 
     println!("Loaded!");
     // println!("Y channel: ");
@@ -47,6 +150,20 @@ fn main() {
     println!("Performing Discrete Cosine Transform + Quantization...");
     jpeg_image.dct_and_quantization();
 
+    println!("Done!");
+
+    // Adaptive Quantization
+
+    println!("Adapting quantization to the image activity...");
+    jpeg_image.adapt_quantization();
+
+    println!("Done!");
+
+    // Region of interest
+
+    println!("Applying region of interest mask...");
+    jpeg_image.apply_roi_mask();
+
     println!("Done!");
     // println!("Y channel: ");
     // jpeg_image.y_dct_coeffs.pretty_print();
@@ -55,7 +172,7 @@ fn main() {
     // println!("Cr channel: ");
     // jpeg_image.cr_dct_coeffs.pretty_print();
 
-    // Run Length and Huffman Encoding
+    // Run Length and Huffman / Arithmetic Encoding
 
     println!("Entropy encoding...");
     jpeg_image.generate_entropy_encoded_data();
@@ -65,4 +182,224 @@ fn main() {
     println!("Creating file");
     jpeg_image.generate_file().unwrap();
     println!("Done!");
+
+    // the alpha channel goes through the same steps, as a grayscale image
+
+    if let Some(mut alpha_image) = jpeg_image.alpha_image.take() {
+        println!("Encoding alpha channel...");
+        alpha_image.dct_and_quantization();
+        alpha_image.generate_entropy_encoded_data();
+        alpha_image.generate_file().unwrap();
+        println!("Done!");
+    } else if args.alpha_output.is_some() {
+        println!("The image has no alpha channel, so no alpha image was written");
+    }
+}
+
+fn set_options(jpeg_image: &mut JpegImage, args: &Args) {
+    // the encoding options and metadata, which don't depend on how the image was loaded
+    jpeg_image.deringing = args.deringing;
+    jpeg_image.trellis_quantization = args.trellis;
+    jpeg_image.adaptive_quantization = args.adaptive_quantization;
+    jpeg_image.entropy_coder = args.entropy_coder.clone();
+    if let Some(density) = args.get_density() {
+        jpeg_image.density = density;
+    }
+    jpeg_image.exif = args.get_exif_metadata();
+    if let Some(xmp) = &args.xmp {
+        jpeg_image.set_xmp(&std::fs::read_to_string(xmp).unwrap());
+    }
+    for comment in &args.comment {
+        jpeg_image.add_comment(comment);
+    }
+
+    // a given profile replaces the one of the input image, and only RGB images (stored as YCbCr or RGB) get the sRGB default
+    if let Some(icc_profile) = &args.icc_profile {
+        jpeg_image.icc_profile = Some(std::fs::read(icc_profile).unwrap());
+    } else if args.default_srgb && jpeg_image.icc_profile.is_none() &&
+        matches!(jpeg_image.color_space, ColorSpace::YCbCr | ColorSpace::Rgb)
+    {
+        jpeg_image.icc_profile = Some(JpegImage::get_srgb_profile());
+    }
+}
+
+// The output of streamed images, only created with the first bytes that are written to it.
+// The encoder checks the dimensions and the options before writing anything,
+// so an image that can't be streamed doesn't leave an empty file behind
+struct StreamedFile<'a> {
+    path: &'a str,
+    file: Option<BufWriter<File>>,
+}
+
+impl Write for StreamedFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            self.file = Some(BufWriter::new(File::create(self.path)?));
+        }
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn stream_bmp(args: &Args) {
+    let mut bmp_image = BmpImage::new(&args.image);
+
+    // RGB images are never subsampled, like in from_rgb_image
+    let chrominance_downsampling_ratio = if args.rgb { (4, 4, 4) } else { args.subsampling_ratio };
+    let mut encoder = StreamingEncoder::new(
+        StreamedFile { path: &args.output, file: None },
+        bmp_image.width as usize,
+        bmp_image.height as usize,
+        chrominance_downsampling_ratio,
+        args.dct_algorithm.clone()
+    );
+    if args.rgb {
+        encoder.image.color_space = ColorSpace::Rgb;
+    }
+    if let Some(density) = bmp_image.density.take() {
+        encoder.image.density = density;
+    }
+    encoder.image.icc_profile = bmp_image.icc_profile
+        .take()
+        .filter(|profile| JpegImage::is_valid_icc_profile(profile));
+    set_options(&mut encoder.image, args);
+    encoder.alpha_options = args.get_alpha_options();
+
+    encoder.write_bmp(&mut bmp_image).unwrap();
+    encoder.finish().unwrap();
+}
+
+fn encode_tiles(args: &Args, tile_width: usize, tile_height: usize) {
+    println!("Loading image...");
+    let rgb_image = args.image_format.as_ref().unwrap().load_rgb(&args.image);
+    let (width, height) = rgb_image.get_size();
+    let tiles = JpegImage::get_tiles(width, height, tile_width, tile_height, &args.output);
+    println!("Loaded! Splitting it into {} tiles", tiles.len());
+
+    for tile in &tiles {
+        println!("Encoding tile at {},{} ({}x{})...", tile.x, tile.y, tile.width, tile.height);
+        let jpeg_image = JpegImage::from_rgb_pixels(
+            rgb_image.crop(tile.x, tile.y, tile.width, tile.height),
+            &tile.path,
+            args.subsampling_ratio,
+            args.dct_algorithm.clone(),
+            &args.get_alpha_options(),
+            args.rgb
+        );
+        encode(jpeg_image, args);
+    }
+
+    let manifest_path = JpegImage::get_manifest_path(&args.output);
+    std::fs::write(&manifest_path, JpegImage::get_tile_manifest(width, height, &tiles)).expect(
+        "Could not write tile manifest!\n"
+    );
+    println!("Tile manifest written to \"{}\"", manifest_path);
+}
+
+fn transform_jpeg(args: &TransformArgs) {
+    // the coefficients are moved around as they are, so the image is never decoded or quantized again
+    args.print_args();
+
+    println!("Reading JPEG image...");
+    let mut jpeg_image = JpegImage::from_jpeg_file(JpegFile::new(&args.image), &args.output);
+    println!("Done!");
+
+    if let Some(transform) = args.transform {
+        println!("Transforming coefficients...");
+        jpeg_image.transform_coefficients(transform);
+        println!("Done!");
+    }
+
+    if let Some((width, height, x, y)) = args.crop {
+        println!("Cropping coefficients...");
+        jpeg_image.crop_coefficients(x as usize, y as usize, width as usize, height as usize);
+        println!("Done!");
+    }
+
+    println!("Entropy encoding...");
+    jpeg_image.generate_entropy_encoded_data();
+    println!("Done!");
+
+    println!("Creating file");
+    jpeg_image.generate_file().unwrap();
+    println!("Done!");
+}
+
+fn optimize_jpeg(args: &OptimizeArgs) {
+    // the quantized coefficients are kept, only the way they are coded changes
+    args.print_args();
+
+    println!("Reading JPEG image...");
+    let mut jpeg_image = JpegImage::from_jpeg_file(JpegFile::new(&args.image), &args.output);
+    println!("Done!");
+
+    if args.strip_metadata {
+        jpeg_image.strip_metadata();
+    }
+    jpeg_image.optimize_huffman_tables = true;
+    jpeg_image.progressive = args.progressive;
+
+    println!("Entropy encoding...");
+    jpeg_image.generate_entropy_encoded_data();
+    println!("Done!");
+
+    println!("Creating file");
+    jpeg_image.generate_file().unwrap();
+    println!("Done!");
+
+    let input_size = std::fs::metadata(&args.image).unwrap().len();
+    let output_size = std::fs::metadata(&args.output).unwrap().len();
+    println!("{} bytes -> {} bytes", input_size, output_size);
+}
+
+fn requantize_jpeg(args: &RequantizeArgs) {
+    // the coefficients are scaled to the new tables, so the samples are never decoded
+    args.print_args();
+
+    println!("Reading JPEG image...");
+    let mut jpeg_image = JpegImage::from_jpeg_file(JpegFile::new(&args.image), &args.output);
+    println!("Done!");
+
+    println!("Requantizing coefficients...");
+    let quantization_tables = jpeg_image.get_requantization_tables(args.quality);
+    jpeg_image.requantize_coefficients(quantization_tables);
+    println!("Done!");
+
+    // the tables are built for the image, since the standard ones were made for other quantization steps
+    jpeg_image.optimize_huffman_tables = true;
+    jpeg_image.progressive = args.progressive;
+
+    println!("Entropy encoding...");
+    jpeg_image.generate_entropy_encoded_data();
+    println!("Done!");
+
+    println!("Creating file");
+    jpeg_image.generate_file().unwrap();
+    println!("Done!");
+
+    let input_size = std::fs::metadata(&args.image).unwrap().len();
+    let output_size = std::fs::metadata(&args.output).unwrap().len();
+    println!("{} bytes -> {} bytes", input_size, output_size);
+}
+
+fn decode_jpeg(args: &DecodeArgs) {
+    args.print_args();
+
+    println!("Reading JPEG image...");
+    let jpeg_file = JpegFile::new(&args.image);
+    println!("Done!");
+
+    println!("Decoding...");
+    let pixels = jpeg_file.decode(args.upsampling, args.idct_algorithm);
+    println!("Done!");
+
+    println!("Creating file");
+    BmpImage::from_pixels(pixels, jpeg_file.get_density()).generate_file(&args.output).unwrap();
+    println!("Done!");
 }
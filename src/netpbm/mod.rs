@@ -0,0 +1 @@
+pub mod netpbm_image;
@@ -0,0 +1,355 @@
+use byteorder::{ BigEndian, ByteOrder, LittleEndian };
+use clap::ValueEnum;
+use crate::JpegImage;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::{ WideCMYKValue, WideRGBValue };
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum HdrConversion {
+    Clamp, // values above 1.0 are clipped to white
+    ToneMap, // the whole range of the image is compressed into 0.0..1.0, so the highlights keep their detail
+}
+
+// PFM images are converted to 16 bit samples
+const PFM_MAX_VALUE: u16 = 65535;
+
+pub struct NetpbmImage {
+    pub width: i32,
+    pub height: i32,
+    pub max_value: u16, // samples go from 0 to max_value, which can be up to 65535
+    pub grayscale: bool, // PGM, grayscale PAM and grayscale PFM images, whose pixels have r = g = b
+    pub pixels: PixelMatrix<WideRGBValue>,
+    pub alpha: Option<PixelMatrix<u16>>, // only PAM images with a _ALPHA tuple type have it, in the same range as the pixels
+    pub cmyk: Option<PixelMatrix<WideCMYKValue>>, // CMYK PAM images have their samples here, and none in pixels
+}
+
+impl NetpbmImage {
+    pub fn new(path: &String, hdr_conversion: &HdrConversion) -> NetpbmImage {
+        let data = std::fs::read(path).expect("Could not open netpbm image file!\n");
+        Self::from_bytes(&data, hdr_conversion)
+    }
+
+    pub fn from_bytes(data: &[u8], hdr_conversion: &HdrConversion) -> NetpbmImage {
+        let mut position = 0;
+
+        let magic_number = Self::read_token(data, &mut position);
+        match magic_number.as_str() {
+            "P2" | "P3" | "P5" | "P6" => Self::read_pixmap(data, &mut position, &magic_number),
+            "P7" => Self::read_pam(data, &mut position),
+            "PF" | "Pf" => Self::read_pfm(data, &mut position, magic_number == "Pf", hdr_conversion),
+            _ =>
+                panic!(
+                    "Unsupported netpbm format {}, only PGM/PPM (P2, P3, P5, P6), PAM (P7) and PFM (PF, Pf) images are supported\n",
+                    magic_number
+                ),
+        }
+    }
+
+    fn read_pixmap(data: &[u8], position: &mut usize, magic_number: &str) -> NetpbmImage {
+        // P2 and P5 are grayscale, P3 and P6 are RGB, and the first two of each are written in ASCII
+        let grayscale = magic_number == "P2" || magic_number == "P5";
+        let ascii = magic_number == "P2" || magic_number == "P3";
+
+        let (width, height) = Self::read_dimensions(data, position);
+        let max_value = Self::read_max_value(data, position);
+        if !ascii {
+            *position += 1; // single whitespace before the pixel data
+        }
+
+        let channels = if grayscale { 1 } else { 3 };
+        let count = (width * height) as usize * channels;
+        let samples = if ascii {
+            (0..count).map(|_| Self::read_number(data, position) as u16).collect()
+        } else {
+            Self::read_binary_samples(data, position, count, max_value)
+        };
+
+        Self::from_samples(width, height, max_value, &samples, channels, false)
+    }
+
+    fn read_pam(data: &[u8], position: &mut usize) -> NetpbmImage {
+        // the header is a list of "KEY value" lines, in any order, ending with ENDHDR
+        let (mut width, mut height, mut depth, mut max_value) = (0, 0, 0, 0);
+        let mut tuple_type = String::new();
+        loop {
+            let key = Self::read_token(data, position);
+            match key.as_str() {
+                "WIDTH" => width = Self::read_number(data, position),
+                "HEIGHT" => height = Self::read_number(data, position),
+                "DEPTH" => depth = Self::read_number(data, position) as usize,
+                "MAXVAL" => max_value = Self::read_max_value(data, position),
+                "TUPLTYPE" => tuple_type = Self::read_token(data, position),
+                "ENDHDR" => break,
+                "" => panic!("PAM header has no ENDHDR\n"),
+                _ => panic!("Unknown PAM header field {}\n", key),
+            }
+        }
+        *position += 1; // newline before the pixel data
+
+        if width <= 0 || height <= 0 || max_value == 0 {
+            panic!("PAM header is missing the WIDTH, HEIGHT or MAXVAL fields\n");
+        }
+        JpegImage::validate_dimensions(width as i64, height as i64);
+
+        // without a tuple type, the channels are guessed from the depth
+        let has_alpha = if tuple_type.is_empty() {
+            depth == 2 || depth == 4
+        } else {
+            tuple_type.ends_with("_ALPHA")
+        };
+        let channels = depth - (has_alpha as usize);
+        let expected_channels = match tuple_type.trim_end_matches("_ALPHA") {
+            "BLACKANDWHITE" | "GRAYSCALE" => Some(1),
+            "RGB" => Some(3),
+            "CMYK" => Some(4),
+            "" => None,
+            _ => panic!("Unsupported PAM tuple type {}\n", tuple_type),
+        };
+        if ![1, 3, 4].contains(&channels) || expected_channels.is_some_and(|expected| expected != channels) {
+            panic!("PAM depth {} doesn't match the tuple type {}\n", depth, tuple_type);
+        }
+
+        let count = (width * height) as usize * depth;
+        let samples = Self::read_binary_samples(data, position, count, max_value);
+
+        Self::from_samples(width, height, max_value, &samples, channels, has_alpha)
+    }
+
+    fn read_pfm(
+        data: &[u8],
+        position: &mut usize,
+        grayscale: bool,
+        hdr_conversion: &HdrConversion
+    ) -> NetpbmImage {
+        let (width, height) = Self::read_dimensions(data, position);
+        // the sign of the scale gives the byte order of the samples, negative being little endian
+        let scale = Self::read_token(data, position)
+            .parse::<f32>()
+            .expect("Invalid scale in PFM header!\n");
+        *position += 1; // single whitespace before the pixel data
+
+        let channels = if grayscale { 1 } else { 3 };
+        let row_size = (width as usize) * channels;
+        if data.len() < *position + row_size * (height as usize) * 4 {
+            panic!("Netpbm image data is too short\n");
+        }
+
+        // rows are stored from the bottom to the top of the image, and samples are linear light
+        let mut samples = Vec::<f32>::with_capacity(row_size * (height as usize));
+        for row in (0..height as usize).rev() {
+            let row_start = *position + row * row_size * 4;
+            for i in 0..row_size {
+                let bytes = &data[row_start + i * 4..row_start + i * 4 + 4];
+                let sample = if scale < 0.0 { LittleEndian::read_f32(bytes) } else { BigEndian::read_f32(bytes) };
+                // NaN and negative values have no meaning as colors
+                samples.push(if sample.is_nan() { 0.0 } else { sample.max(0.0) });
+            }
+        }
+
+        let samples: Vec<u16> = Self::convert_hdr_samples(&samples, hdr_conversion)
+            .iter()
+            .map(|sample| (Self::linear_to_srgb(*sample) * (PFM_MAX_VALUE as f32)).round() as u16)
+            .collect();
+
+        Self::from_samples(width, height, PFM_MAX_VALUE, &samples, channels, false)
+    }
+
+    fn convert_hdr_samples(samples: &[f32], hdr_conversion: &HdrConversion) -> Vec<f32> {
+        match hdr_conversion {
+            HdrConversion::Clamp => samples.iter().map(|sample| sample.min(1.0)).collect(),
+            HdrConversion::ToneMap => {
+                // extended Reinhard operator, with the brightest sample of the image mapped to white.
+                // Images that are already in the 0.0..1.0 range are left untouched
+                let white = samples.iter().fold(1.0f32, |white, sample| white.max(*sample));
+                let white_squared = white * white;
+                samples
+                    .iter()
+                    .map(|sample| ((sample * (1.0 + sample / white_squared)) / (1.0 + sample)).min(1.0))
+                    .collect()
+            }
+        }
+    }
+
+    fn linear_to_srgb(value: f32) -> f32 {
+        if value <= 0.0031308 {
+            value * 12.92
+        } else {
+            1.055 * value.powf(1.0 / 2.4) - 0.055
+        }
+    }
+
+    fn read_binary_samples(data: &[u8], position: &mut usize, count: usize, max_value: u16) -> Vec<u16> {
+        // samples take 2 bytes (big endian) when the max value doesn't fit in one
+        let sample_size = if max_value > 255 { 2 } else { 1 };
+        if data.len() < *position + count * sample_size {
+            panic!("Netpbm image data is too short\n");
+        }
+
+        let samples = (0..count)
+            .map(|i| {
+                let start = *position + i * sample_size;
+                if sample_size == 2 {
+                    BigEndian::read_u16(&data[start..start + 2])
+                } else {
+                    data[start] as u16
+                }
+            })
+            .collect();
+        *position += count * sample_size;
+        samples
+    }
+
+    fn from_samples(
+        width: i32,
+        height: i32,
+        max_value: u16,
+        samples: &[u16],
+        channels: usize,
+        has_alpha: bool
+    ) -> NetpbmImage {
+        let depth = channels + (has_alpha as usize);
+
+        let mut pixels = PixelMatrix::new(width as usize, height as usize);
+        let mut alpha = PixelMatrix::new(width as usize, height as usize);
+        let mut cmyk = PixelMatrix::new(width as usize, height as usize);
+        for (i, tuple) in samples.chunks_exact(depth).enumerate() {
+            if tuple[..channels].iter().any(|sample| *sample > max_value) {
+                panic!("Netpbm sample at pixel {} is bigger than the max value {}\n", i, max_value);
+            }
+            if has_alpha {
+                alpha.push_next(tuple[channels].min(max_value));
+            }
+            if channels == 4 {
+                cmyk.push_next((tuple[0], tuple[1], tuple[2], tuple[3]));
+                continue;
+            }
+            let pixel = if channels == 1 { (tuple[0], tuple[0], tuple[0]) } else { (tuple[0], tuple[1], tuple[2]) };
+            pixels.push_next(pixel);
+        }
+
+        NetpbmImage {
+            width,
+            height,
+            max_value,
+            grayscale: channels == 1,
+            pixels,
+            alpha: if has_alpha { Some(alpha) } else { None },
+            cmyk: if channels == 4 { Some(cmyk) } else { None },
+        }
+    }
+
+    fn read_token(data: &[u8], position: &mut usize) -> String {
+        // header fields are separated by whitespace, and comments go from # to the end of the line
+        loop {
+            while *position < data.len() && data[*position].is_ascii_whitespace() {
+                *position += 1;
+            }
+            if *position < data.len() && data[*position] == b'#' {
+                while *position < data.len() && data[*position] != b'\n' {
+                    *position += 1;
+                }
+                continue;
+            }
+            break;
+        }
+
+        let start = *position;
+        while *position < data.len() && !data[*position].is_ascii_whitespace() {
+            *position += 1;
+        }
+        String::from_utf8_lossy(&data[start..*position]).to_string()
+    }
+
+    fn read_dimensions(data: &[u8], position: &mut usize) -> (i32, i32) {
+        // netpbm images are never tiled, so their size is checked before the samples are allocated
+        let width = Self::read_number(data, position);
+        let height = Self::read_number(data, position);
+        JpegImage::validate_dimensions(width as i64, height as i64);
+        (width, height)
+    }
+
+    fn read_number(data: &[u8], position: &mut usize) -> i32 {
+        Self::read_token(data, position)
+            .parse::<i32>()
+            .expect("Invalid number in netpbm image!\n")
+    }
+
+    fn read_max_value(data: &[u8], position: &mut usize) -> u16 {
+        let max_value = Self::read_number(data, position);
+        if max_value <= 0 || max_value > 65535 {
+            panic!("Invalid netpbm max value {}\n", max_value);
+        }
+        max_value as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_16_bit_ppm() {
+        let mut data = b"P6\n# comment\n2 1\n65535\n".to_vec();
+        for sample in [0u16, 1000, 65535, 4095, 256, 1] {
+            data.extend_from_slice(&sample.to_be_bytes());
+        }
+
+        let image = NetpbmImage::from_bytes(&data, &HdrConversion::Clamp);
+        assert_eq!((image.width, image.height, image.max_value), (2, 1, 65535));
+        assert_eq!(image.pixels.get_pixel(0, 0), Some((0, 1000, 65535)));
+        assert_eq!(image.pixels.get_pixel(0, 1), Some((4095, 256, 1)));
+    }
+
+    #[test]
+    fn test_ascii_and_binary_formats_match() {
+        let ascii_gray = NetpbmImage::from_bytes(b"P2 2 2 15\n0 5\n# comment\n10 15\n", &HdrConversion::Clamp);
+        let binary_gray = NetpbmImage::from_bytes(b"P5 2 2 15\n\x00\x05\x0a\x0f", &HdrConversion::Clamp);
+        assert!(ascii_gray.grayscale && binary_gray.grayscale);
+        assert_eq!(ascii_gray.pixels.pixels, binary_gray.pixels.pixels);
+        assert_eq!(ascii_gray.pixels.get_pixel(1, 0), Some((10, 10, 10)));
+
+        let ascii_rgb = NetpbmImage::from_bytes(b"P3\n1 2\n255\n1 2 3\n4 5 6\n", &HdrConversion::Clamp);
+        let binary_rgb = NetpbmImage::from_bytes(b"P6\n1 2\n255\n\x01\x02\x03\x04\x05\x06", &HdrConversion::Clamp);
+        assert!(!ascii_rgb.grayscale);
+        assert_eq!(ascii_rgb.pixels.pixels, binary_rgb.pixels.pixels);
+        assert_eq!(ascii_rgb.pixels.get_pixel(1, 0), Some((4, 5, 6)));
+    }
+
+    #[test]
+    fn test_read_pam() {
+        let data = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n\x0a\x14\x1e\xff\x28\x32\x3c\x00";
+        let image = NetpbmImage::from_bytes(data, &HdrConversion::Clamp);
+        assert!(!image.grayscale);
+        assert_eq!(image.pixels.pixels, vec![(10, 20, 30), (40, 50, 60)]);
+        assert_eq!(image.alpha.unwrap().pixels, vec![255, 0]);
+
+        let data = b"P7\nWIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 1\nTUPLTYPE BLACKANDWHITE\nENDHDR\n\x01";
+        let image = NetpbmImage::from_bytes(data, &HdrConversion::Clamp);
+        assert!(image.grayscale && image.alpha.is_none());
+        assert_eq!((image.max_value, image.pixels.get_pixel(0, 0)), (1, Some((1, 1, 1))));
+    }
+
+    #[test]
+    fn test_read_pfm() {
+        // little endian, bottom row first: the top row is black and white, the bottom one is mid gray and very bright
+        let mut data = b"Pf\n2 2\n-1.0\n".to_vec();
+        for sample in [0.214f32, 4.0, 0.0, 1.0] {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        let clamped = NetpbmImage::from_bytes(&data, &HdrConversion::Clamp);
+        assert!(clamped.grayscale);
+        assert_eq!(clamped.pixels.get_pixel(0, 0), Some((0, 0, 0)));
+        assert_eq!(clamped.pixels.get_pixel(0, 1), Some((65535, 65535, 65535)));
+        // linear 0.214 is about half way in sRGB
+        let (gray, _, _) = clamped.pixels.get_pixel(1, 0).unwrap();
+        assert!((gray as i32 - 32768).abs() < 300);
+        assert_eq!(clamped.pixels.get_pixel(1, 1), Some((65535, 65535, 65535)));
+
+        // tone mapping keeps the brightest sample white, but moves 1.0 below it
+        let tone_mapped = NetpbmImage::from_bytes(&data, &HdrConversion::ToneMap);
+        assert_eq!(tone_mapped.pixels.get_pixel(1, 1), Some((65535, 65535, 65535)));
+        let (white, _, _) = tone_mapped.pixels.get_pixel(0, 1).unwrap();
+        assert!(white < 65535 && white > 40000);
+    }
+}
@@ -119,6 +119,26 @@ impl<'a, T: Default + Copy + Debug> PixelMatrixBlockIterator<'a, T> {
         }
     }
 
+    pub fn set_block(&mut self, block_buffer: &[T]) {
+        // inverse of get_block: pixels outside of the matrix are skipped (or were default padding)
+        let blocks_per_row = self.get_blocks_per_row();
+        let block_start_i = (self.block_idx / blocks_per_row) * self.block_height;
+        let block_start_j = (self.block_idx % blocks_per_row) * self.block_width;
+
+        let mut buffer_idx = 0;
+        for i in 0..self.block_height {
+            for j in 0..self.block_width {
+                let (row, col) = (block_start_i + i, block_start_j + j);
+                if row < self.pixel_matrix.height && col < self.pixel_matrix.width {
+                    self.pixel_matrix.set_pixel(row, col, block_buffer[buffer_idx]);
+                    buffer_idx += 1;
+                } else if self.use_default_padding {
+                    buffer_idx += 1;
+                }
+            }
+        }
+    }
+
     pub fn block_operation<F>(&self, block_buffer: &mut Vec<T>, f: &mut F)
         where F: FnMut(&mut Vec<T>)
     {
@@ -137,6 +157,21 @@ impl<'a, T: Default + Copy + Debug> PixelMatrixBlockIterator<'a, T> {
             self.block_operation(&mut block_buffer, f);
         }
     }
+
+    pub fn for_each_block_mut<F>(&mut self, f: &mut F) where F: FnMut(usize, &mut Vec<T>) {
+        // same as for_each_block, but the closure also gets the block index,
+        // and whatever it leaves in the buffer is written back to the matrix
+        let mut block_buffer = Vec::<T>::with_capacity(self.block_width * self.block_height);
+
+        for i in 0..self.get_blocks_amount() {
+            self.block_idx = i;
+            self.row_in_block_idx = 0;
+            self.col_in_block_idx = 0;
+            self.get_block(&mut block_buffer);
+            f(i, &mut block_buffer);
+            self.set_block(&block_buffer);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -189,4 +224,21 @@ mod tests {
 
         assert_eq!(biggest_of_each_block, vec![2, 4, 8, 9, 4, 2, 2, 3, 8, i32::default()]);
     }
+
+    #[test]
+    fn for_each_block_mut() {
+        let mut matrix = initialize_matrix();
+        let block_width = 3;
+        let block_height = 2;
+        let mut iterator = matrix.get_block_iterator(block_width, block_height, false);
+        iterator.for_each_block_mut(
+            &mut (|block_idx: usize, block: &mut Vec<i32>| {
+                for p in block.iter_mut() {
+                    *p += (block_idx as i32) * 10;
+                }
+            })
+        );
+
+        assert_eq!(matrix.pixels, vec![1, 1, 4, 12, 1, 2, 2, 13, 28, 28, 28, 39]);
+    }
 }
@@ -62,6 +62,15 @@ impl<T: Default + Copy + Debug> PixelMatrix<T> {
         PixelMatrixBlockIterator::new(self, block_width, block_height, use_default_padding)
     }
 
+    pub fn crop(&self, row: usize, col: usize, width: usize, height: usize) -> PixelMatrix<T> {
+        // the part of the matrix that starts at row, col, which must be fully inside of it
+        let mut cropped = PixelMatrix::new(width, height);
+        for i in row..row + height {
+            cropped.pixels.extend_from_slice(&self.pixels[i * self.width + col..i * self.width + col + width]);
+        }
+        cropped
+    }
+
     pub fn pretty_print(&self) {
         for i in 0..self.height {
             for j in 0..self.width {
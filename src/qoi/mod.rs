@@ -0,0 +1 @@
+pub mod qoi_image;
@@ -0,0 +1,152 @@
+use byteorder::{ BigEndian, ByteOrder };
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::RGBValue;
+
+pub const QOI_MAGIC: &[u8; 4] = b"qoif";
+const QOI_HEADER_SIZE: usize = 14;
+
+const QOI_OP_RGB: u8 = 0xfe;
+const QOI_OP_RGBA: u8 = 0xff;
+// the rest of the operations are identified by their 2 highest bits
+const QOI_OP_INDEX: u8 = 0x00;
+const QOI_OP_DIFF: u8 = 0x40;
+const QOI_OP_LUMA: u8 = 0x80;
+const QOI_OP_RUN: u8 = 0xc0;
+const QOI_OP_MASK: u8 = 0xc0;
+
+pub struct QoiImage {
+    pub pixels: PixelMatrix<RGBValue>,
+    pub alpha: Option<PixelMatrix<u8>>, // only images with 4 channels have it
+}
+
+impl QoiImage {
+    pub fn new(path: &String) -> QoiImage {
+        let data = std::fs::read(path).expect("Could not open QOI image file!\n");
+        Self::from_bytes(&data)
+    }
+
+    pub fn read_dimensions(header: &[u8]) -> (usize, usize) {
+        if header.len() < QOI_HEADER_SIZE || &header[0..4] != QOI_MAGIC {
+            panic!("Invalid QOI header\n");
+        }
+        let width = BigEndian::read_u32(&header[4..8]) as usize;
+        let height = BigEndian::read_u32(&header[8..12]) as usize;
+        if width == 0 || height == 0 || width > (i32::MAX as usize) || height > (i32::MAX as usize) {
+            panic!("Invalid QOI image dimensions {}x{}\n", width, height);
+        }
+        (width, height)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> QoiImage {
+        let (width, height) = Self::read_dimensions(data);
+        let channels = data[12];
+        if channels != 3 && channels != 4 {
+            panic!("Invalid QOI channel amount {}\n", channels);
+        }
+        // the color space byte (sRGB or linear) is only informative, the samples are used as they are
+
+        let mut pixels = PixelMatrix::new(width, height);
+        let mut alpha = PixelMatrix::new(width, height);
+
+        // every decoded pixel is also stored in a small hash table, so that it can be referenced again
+        let mut index = [(0u8, 0u8, 0u8, 0u8); 64];
+        let mut pixel = (0u8, 0u8, 0u8, 255u8);
+        let mut run = 0;
+        let mut position = QOI_HEADER_SIZE;
+        let mut next_byte = || {
+            let byte = *data.get(position).expect("QOI image data is too short\n");
+            position += 1;
+            byte
+        };
+
+        for _ in 0..width * height {
+            if run > 0 {
+                run -= 1;
+            } else {
+                let tag = next_byte();
+                match tag {
+                    QOI_OP_RGB => {
+                        pixel = (next_byte(), next_byte(), next_byte(), pixel.3);
+                    }
+                    QOI_OP_RGBA => {
+                        pixel = (next_byte(), next_byte(), next_byte(), next_byte());
+                    }
+                    _ =>
+                        match tag & QOI_OP_MASK {
+                            QOI_OP_INDEX => {
+                                pixel = index[tag as usize];
+                            }
+                            QOI_OP_DIFF => {
+                                // differences from -2 to 1 for each channel, stored with a bias of 2
+                                let difference = |shift: u8| ((tag >> shift) & 0x03).wrapping_sub(2);
+                                pixel.0 = pixel.0.wrapping_add(difference(4));
+                                pixel.1 = pixel.1.wrapping_add(difference(2));
+                                pixel.2 = pixel.2.wrapping_add(difference(0));
+                            }
+                            QOI_OP_LUMA => {
+                                // the green difference, and the red and blue ones relative to it
+                                let green_difference = (tag & 0x3f).wrapping_sub(32);
+                                let second = next_byte();
+                                let red_difference = green_difference.wrapping_sub(8).wrapping_add(second >> 4);
+                                let blue_difference = green_difference.wrapping_sub(8).wrapping_add(second & 0x0f);
+                                pixel.0 = pixel.0.wrapping_add(red_difference);
+                                pixel.1 = pixel.1.wrapping_add(green_difference);
+                                pixel.2 = pixel.2.wrapping_add(blue_difference);
+                            }
+                            QOI_OP_RUN => {
+                                // the previous pixel is repeated 1 to 62 times, this one included
+                                run = tag & 0x3f;
+                            }
+                            _ => unreachable!(),
+                        }
+                }
+
+                let hash =
+                    ((pixel.0 as usize) * 3 +
+                        (pixel.1 as usize) * 5 +
+                        (pixel.2 as usize) * 7 +
+                        (pixel.3 as usize) * 11) %
+                    64;
+                index[hash] = pixel;
+            }
+
+            pixels.push_next((pixel.0, pixel.1, pixel.2));
+            alpha.push_next(pixel.3);
+        }
+
+        QoiImage {
+            pixels,
+            alpha: if channels == 4 { Some(alpha) } else { None },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_all_operations() {
+        let mut data = QOI_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 0, 0, 7, 0, 0, 0, 1, 4, 0]); // 7x1, RGBA, sRGB
+        data.extend_from_slice(&[QOI_OP_RGBA, 100, 150, 200, 128]); // (100, 150, 200, 128)
+        data.push(QOI_OP_DIFF | (3 << 4) | (2 << 2)); // (101, 150, 198, 128)
+        data.extend_from_slice(&[QOI_OP_LUMA | (32 + 10), ((8 + 2) << 4) | (8 - 3)]); // (113, 160, 205, 128)
+        data.push(QOI_OP_RUN | 1); // two more of the same pixel
+        data.extend_from_slice(&[QOI_OP_RGB, 1, 2, 3]); // (1, 2, 3, 128)
+        data.push(QOI_OP_INDEX | ((100 * 3 + 150 * 5 + 200 * 7 + 128 * 11) % 64) as u8); // back to the first one
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]); // end marker
+
+        let image = QoiImage::from_bytes(&data);
+        assert_eq!(image.pixels.pixels, vec![
+            (100, 150, 200),
+            (101, 150, 198),
+            (113, 160, 205),
+            (113, 160, 205),
+            (113, 160, 205),
+            (1, 2, 3),
+            (100, 150, 200),
+        ]);
+        assert_eq!(image.alpha.unwrap().pixels, vec![128; 7]);
+    }
+}
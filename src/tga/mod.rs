@@ -0,0 +1 @@
+pub mod tga_image;
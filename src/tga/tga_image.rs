@@ -0,0 +1,216 @@
+use byteorder::{ ByteOrder, LittleEndian };
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::RGBValue;
+
+const TGA_HEADER_SIZE: usize = 18;
+
+// image types, the run length encoded ones are the same plus 8
+const TGA_COLOR_MAPPED: u8 = 1;
+const TGA_TRUE_COLOR: u8 = 2;
+const TGA_GRAYSCALE: u8 = 3;
+const TGA_RLE_FLAG: u8 = 8;
+
+// bits of the image descriptor byte
+const TGA_ALPHA_BITS_MASK: u8 = 0x0f;
+const TGA_RIGHT_TO_LEFT: u8 = 0x10;
+const TGA_TOP_TO_BOTTOM: u8 = 0x20;
+
+pub struct TgaImage {
+    pub pixels: PixelMatrix<RGBValue>,
+    pub alpha: Option<PixelMatrix<u8>>, // only images with alpha bits in their descriptor have it
+}
+
+impl TgaImage {
+    pub fn new(path: &String) -> TgaImage {
+        let data = std::fs::read(path).expect("Could not open TGA image file!\n");
+        Self::from_bytes(&data)
+    }
+
+    pub fn is_valid_header(header: &[u8]) -> bool {
+        // TGA files have no magic number, so the header fields are checked to have known values
+        header.len() >= TGA_HEADER_SIZE &&
+            header[1] <= 1 &&
+            [1, 2, 3, 9, 10, 11].contains(&header[2]) &&
+            [8, 15, 16, 24, 32].contains(&header[16]) &&
+            LittleEndian::read_u16(&header[12..14]) > 0 &&
+            LittleEndian::read_u16(&header[14..16]) > 0
+    }
+
+    pub fn read_dimensions(header: &[u8]) -> (usize, usize) {
+        if !Self::is_valid_header(header) {
+            panic!("Invalid or unsupported TGA header\n");
+        }
+        (LittleEndian::read_u16(&header[12..14]) as usize, LittleEndian::read_u16(&header[14..16]) as usize)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> TgaImage {
+        let (width, height) = Self::read_dimensions(data);
+
+        let id_length = data[0] as usize;
+        let image_type = data[2] & !TGA_RLE_FLAG;
+        let run_length_encoded = data[2] & TGA_RLE_FLAG != 0;
+        let color_map_origin = LittleEndian::read_u16(&data[3..5]) as usize; // the index of the first entry
+        let color_map_length = LittleEndian::read_u16(&data[5..7]) as usize;
+        let color_map_entry_bits = data[7];
+        let bits_per_pixel = data[16];
+        let descriptor = data[17];
+        let alpha_bits = descriptor & TGA_ALPHA_BITS_MASK;
+
+        // the color map goes after the image id, and its entries are stored like true color pixels
+        let mut position = TGA_HEADER_SIZE + id_length;
+        let color_map: Vec<(RGBValue, u8)> = if data[1] == 1 {
+            let entry_size = (color_map_entry_bits as usize).div_ceil(8);
+            let entries = (0..color_map_length)
+                .map(|i| {
+                    let start = position + i * entry_size;
+                    Self::decode_color(Self::slice(data, start, entry_size), color_map_entry_bits, alpha_bits)
+                })
+                .collect();
+            position += color_map_length * entry_size;
+            entries
+        } else {
+            Vec::new()
+        };
+        if image_type == TGA_COLOR_MAPPED && color_map.is_empty() {
+            panic!("Color mapped TGA image has no color map\n");
+        }
+
+        let decode_pixel = |bytes: &[u8]| -> (RGBValue, u8) {
+            match image_type {
+                TGA_COLOR_MAPPED => {
+                    let index = if bytes.len() == 2 { LittleEndian::read_u16(bytes) as usize } else { bytes[0] as usize };
+                    *index
+                        .checked_sub(color_map_origin)
+                        .and_then(|index| color_map.get(index))
+                        .expect("TGA color map index out of range\n")
+                }
+                TGA_GRAYSCALE => ((bytes[0], bytes[0], bytes[0]), if bytes.len() == 2 { bytes[1] } else { 255 }),
+                TGA_TRUE_COLOR => Self::decode_color(bytes, bits_per_pixel, alpha_bits),
+                _ => unreachable!(),
+            }
+        };
+
+        // pixels are decoded in the order of the file, and placed according to the descriptor afterwards
+        let bytes_per_pixel = (bits_per_pixel as usize).div_ceil(8);
+        let pixel_amount = width * height;
+        let mut decoded = Vec::with_capacity(pixel_amount);
+        while decoded.len() < pixel_amount {
+            if !run_length_encoded {
+                decoded.push(decode_pixel(Self::slice(data, position, bytes_per_pixel)));
+                position += bytes_per_pixel;
+                continue;
+            }
+
+            // each packet is either a run of one repeated pixel or a sequence of raw pixels, of up to 128 pixels
+            let packet_header = *data.get(position).expect("TGA image data is too short\n");
+            position += 1;
+            let count = ((packet_header & 0x7f) as usize + 1).min(pixel_amount - decoded.len());
+            if packet_header & 0x80 != 0 {
+                let pixel = decode_pixel(Self::slice(data, position, bytes_per_pixel));
+                position += bytes_per_pixel;
+                decoded.extend(std::iter::repeat_n(pixel, count));
+            } else {
+                for _ in 0..count {
+                    decoded.push(decode_pixel(Self::slice(data, position, bytes_per_pixel)));
+                    position += bytes_per_pixel;
+                }
+            }
+        }
+
+        let mut pixels = PixelMatrix::new_with_default(width, height);
+        let mut alpha = PixelMatrix::new_with_default(width, height);
+        for (i, (rgb_pixel, alpha_value)) in decoded.into_iter().enumerate() {
+            // by default rows go from the bottom to the top, and columns from left to right
+            let row = if descriptor & TGA_TOP_TO_BOTTOM != 0 { i / width } else { height - 1 - i / width };
+            let col = if descriptor & TGA_RIGHT_TO_LEFT != 0 { width - 1 - i % width } else { i % width };
+            pixels.set_pixel(row, col, rgb_pixel);
+            alpha.set_pixel(row, col, alpha_value);
+        }
+
+        TgaImage {
+            pixels,
+            alpha: if alpha_bits > 0 { Some(alpha) } else { None },
+        }
+    }
+
+    fn decode_color(bytes: &[u8], bits: u8, alpha_bits: u8) -> (RGBValue, u8) {
+        match bits {
+            // 5 bits per channel, in the order A RRRRR GGGGG BBBBB
+            15 | 16 => {
+                let value = LittleEndian::read_u16(bytes);
+                let expand = |channel: u16| ((channel << 3) | (channel >> 2)) as u8;
+                let alpha = if bits == 16 && alpha_bits > 0 && value & 0x8000 == 0 { 0 } else { 255 };
+                ((expand((value >> 10) & 0x1f), expand((value >> 5) & 0x1f), expand(value & 0x1f)), alpha)
+            }
+            // TGA stores the pixel in BGR order too, with the alpha at the end
+            24 => ((bytes[2], bytes[1], bytes[0]), 255),
+            32 => ((bytes[2], bytes[1], bytes[0]), bytes[3]),
+            _ => panic!("Unsupported TGA pixel size of {} bits\n", bits),
+        }
+    }
+
+    fn slice(data: &[u8], start: usize, length: usize) -> &[u8] {
+        data.get(start..start + length).expect("TGA image data is too short\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(image_type: u8, bits_per_pixel: u8, descriptor: u8) -> Vec<u8> {
+        let mut header = vec![0u8; TGA_HEADER_SIZE];
+        header[2] = image_type;
+        header[12] = 2; // 2x2 pixels
+        header[14] = 2;
+        header[16] = bits_per_pixel;
+        header[17] = descriptor;
+        header
+    }
+
+    #[test]
+    fn test_rle_and_orientation() {
+        // top row red and green, bottom row two blue pixels
+        let mut bottom_up = header(TGA_TRUE_COLOR, 24, 0);
+        bottom_up.extend_from_slice(&[255, 0, 0, 255, 0, 0, 0, 0, 255, 0, 255, 0]);
+
+        let mut top_down_rle = header(TGA_TRUE_COLOR | TGA_RLE_FLAG, 24, TGA_TOP_TO_BOTTOM);
+        top_down_rle.extend_from_slice(&[0x01, 0, 0, 255, 0, 255, 0]); // raw packet of 2 pixels
+        top_down_rle.extend_from_slice(&[0x81, 255, 0, 0]); // run of 2 pixels
+
+        for data in [bottom_up, top_down_rle] {
+            let image = TgaImage::from_bytes(&data);
+            assert_eq!(image.pixels.pixels, vec![(255, 0, 0), (0, 255, 0), (0, 0, 255), (0, 0, 255)]);
+            assert!(image.alpha.is_none());
+        }
+    }
+
+    #[test]
+    fn test_alpha_and_gray() {
+        let mut data = header(TGA_TRUE_COLOR | TGA_RLE_FLAG, 32, TGA_TOP_TO_BOTTOM | 8);
+        data.extend_from_slice(&[0x83, 30, 20, 10, 128]);
+        let image = TgaImage::from_bytes(&data);
+        assert_eq!(image.pixels.pixels, vec![(10, 20, 30); 4]);
+        assert_eq!(image.alpha.unwrap().pixels, vec![128; 4]);
+
+        let mut data = header(TGA_GRAYSCALE, 8, TGA_TOP_TO_BOTTOM);
+        data.extend_from_slice(&[0, 50, 100, 255]);
+        let image = TgaImage::from_bytes(&data);
+        assert_eq!(image.pixels.get_pixel(0, 1), Some((50, 50, 50)));
+        assert_eq!(image.pixels.get_pixel(1, 1), Some((255, 255, 255)));
+    }
+
+    #[test]
+    fn test_color_map_origin() {
+        // the map starts at index 5, so the pixels use indexes 5 and 6
+        let mut data = header(TGA_COLOR_MAPPED, 8, TGA_TOP_TO_BOTTOM);
+        data[1] = 1;
+        data[3] = 5;
+        data[5] = 2;
+        data[7] = 24;
+        data.extend_from_slice(&[255, 0, 0, 0, 0, 255]); // blue and red
+        data.extend_from_slice(&[5, 6, 6, 5]);
+        let image = TgaImage::from_bytes(&data);
+        assert_eq!(image.pixels.pixels, vec![(0, 0, 255), (255, 0, 0), (255, 0, 0), (0, 0, 255)]);
+    }
+}
@@ -0,0 +1 @@
+pub mod tiff_image;
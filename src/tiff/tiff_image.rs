@@ -0,0 +1,462 @@
+use byteorder::{ BigEndian, ByteOrder, LittleEndian };
+use crate::jpeg::jpeg_image::{ Density, DensityUnit };
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::utils::colorspace::{ CMYKValue, RGBValue };
+
+pub const TIFF_LITTLE_ENDIAN_MAGIC: &[u8; 4] = b"II*\0";
+pub const TIFF_BIG_ENDIAN_MAGIC: &[u8; 4] = b"MM\0*";
+
+// tags of the baseline fields that are used
+const TAG_IMAGE_WIDTH: u16 = 256;
+const TAG_IMAGE_LENGTH: u16 = 257;
+const TAG_BITS_PER_SAMPLE: u16 = 258;
+const TAG_COMPRESSION: u16 = 259;
+const TAG_PHOTOMETRIC_INTERPRETATION: u16 = 262;
+const TAG_STRIP_OFFSETS: u16 = 273;
+const TAG_SAMPLES_PER_PIXEL: u16 = 277;
+const TAG_ROWS_PER_STRIP: u16 = 278;
+const TAG_STRIP_BYTE_COUNTS: u16 = 279;
+const TAG_X_RESOLUTION: u16 = 282;
+const TAG_Y_RESOLUTION: u16 = 283;
+const TAG_PLANAR_CONFIGURATION: u16 = 284;
+const TAG_RESOLUTION_UNIT: u16 = 296;
+const TAG_PREDICTOR: u16 = 317;
+const TAG_TILE_WIDTH: u16 = 322;
+const TAG_TILE_LENGTH: u16 = 323;
+const TAG_TILE_OFFSETS: u16 = 324;
+const TAG_TILE_BYTE_COUNTS: u16 = 325;
+const TAG_INK_SET: u16 = 332;
+const TAG_EXTRA_SAMPLES: u16 = 338;
+const TAG_ICC_PROFILE: u16 = 34675;
+
+const COMPRESSION_NONE: u32 = 1;
+const COMPRESSION_LZW: u32 = 5;
+const COMPRESSION_PACKBITS: u32 = 32773;
+
+const PHOTOMETRIC_WHITE_IS_ZERO: u32 = 0;
+const PHOTOMETRIC_BLACK_IS_ZERO: u32 = 1;
+const PHOTOMETRIC_RGB: u32 = 2;
+const PHOTOMETRIC_SEPARATED: u32 = 5;
+
+const INK_SET_CMYK: u32 = 1;
+
+const PREDICTOR_HORIZONTAL: u32 = 2;
+
+const LZW_CLEAR_CODE: usize = 256;
+const LZW_END_OF_INFORMATION: usize = 257;
+const LZW_FIRST_CODE: usize = 258;
+const LZW_MAX_BITS: u32 = 12;
+
+pub struct TiffImage {
+    pub pixels: PixelMatrix<RGBValue>,
+    pub alpha: Option<PixelMatrix<u8>>, // only images with an extra alpha sample have it
+    pub cmyk: Option<PixelMatrix<CMYKValue>>, // separated images have their samples here, instead of in pixels
+    pub density: Option<Density>, // from the XResolution and YResolution fields
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+// a field of the image file directory: its type, amount of values, and where they are
+struct TiffField {
+    field_type: u16,
+    count: u32,
+    value_offset: usize,
+}
+
+struct TiffReader<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl TiffReader<'_> {
+    fn bytes(&self, offset: usize, length: usize) -> &[u8] {
+        self.data.get(offset..offset + length).expect("TIFF image data is too short\n")
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        let bytes = self.bytes(offset, 2);
+        if self.little_endian { LittleEndian::read_u16(bytes) } else { BigEndian::read_u16(bytes) }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        let bytes = self.bytes(offset, 4);
+        if self.little_endian { LittleEndian::read_u32(bytes) } else { BigEndian::read_u32(bytes) }
+    }
+
+    fn get_values(&self, field: &TiffField) -> Vec<u32> {
+        // BYTE, SHORT and LONG fields, which are the ones used for integers
+        (0..field.count as usize)
+            .map(|i| {
+                match field.field_type {
+                    1 => self.bytes(field.value_offset + i, 1)[0] as u32,
+                    3 => self.read_u16(field.value_offset + i * 2) as u32,
+                    4 => self.read_u32(field.value_offset + i * 4),
+                    _ => panic!("Unexpected TIFF field type {}\n", field.field_type),
+                }
+            })
+            .collect()
+    }
+
+    fn get_rational(&self, field: &TiffField) -> f64 {
+        let numerator = self.read_u32(field.value_offset) as f64;
+        let denominator = self.read_u32(field.value_offset + 4) as f64;
+        if denominator == 0.0 { 0.0 } else { numerator / denominator }
+    }
+}
+
+impl TiffImage {
+    pub fn new(path: &String) -> TiffImage {
+        let data = std::fs::read(path).expect("Could not open TIFF image file!\n");
+        Self::from_bytes(&data)
+    }
+
+    fn get_reader(data: &[u8]) -> TiffReader<'_> {
+        let little_endian = match data.get(0..4) {
+            Some(magic) if magic == TIFF_LITTLE_ENDIAN_MAGIC => true,
+            Some(magic) if magic == TIFF_BIG_ENDIAN_MAGIC => false,
+            _ => panic!("Invalid TIFF header\n"),
+        };
+        TiffReader { data, little_endian }
+    }
+
+    pub fn read_dimensions(data: &[u8]) -> (usize, usize) {
+        // the size of the first image, from its directory alone
+        let reader = Self::get_reader(data);
+        let fields = Self::read_directory(&reader, reader.read_u32(4) as usize);
+        let get_value = |tag: u16| -> usize {
+            match fields.iter().find(|(field_tag, _)| *field_tag == tag) {
+                Some((_, field)) => reader.get_values(field)[0] as usize,
+                None => panic!("TIFF image is missing the field {}\n", tag),
+            }
+        };
+        (get_value(TAG_IMAGE_WIDTH), get_value(TAG_IMAGE_LENGTH))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> TiffImage {
+        let (width, height) = Self::read_dimensions(data);
+        let reader = Self::get_reader(data);
+
+        // only the first image of the file is read
+        let fields = Self::read_directory(&reader, reader.read_u32(4) as usize);
+        let get_field = |tag: u16| fields.iter().find(|(field_tag, _)| *field_tag == tag).map(|(_, field)| field);
+        let get_value = |tag: u16, default: Option<u32>| -> u32 {
+            match get_field(tag) {
+                Some(field) => reader.get_values(field)[0],
+                None => default.unwrap_or_else(|| panic!("TIFF image is missing the field {}\n", tag)),
+            }
+        };
+
+        let samples_per_pixel = get_value(TAG_SAMPLES_PER_PIXEL, Some(1)) as usize;
+        let compression = get_value(TAG_COMPRESSION, Some(COMPRESSION_NONE));
+        let photometric_interpretation = get_value(TAG_PHOTOMETRIC_INTERPRETATION, None);
+        let predictor = get_value(TAG_PREDICTOR, Some(1));
+
+        if let Some(field) = get_field(TAG_BITS_PER_SAMPLE) {
+            if reader.get_values(field).iter().any(|bits| *bits != 8) {
+                panic!("Only TIFF images with 8 bits per sample are supported\n");
+            }
+        }
+        if get_value(TAG_PLANAR_CONFIGURATION, Some(1)) != 1 {
+            panic!("Only TIFF images with interleaved samples are supported\n");
+        }
+        if ![COMPRESSION_NONE, COMPRESSION_LZW, COMPRESSION_PACKBITS].contains(&compression) {
+            panic!("Unsupported TIFF compression {}, only none, LZW and PackBits are supported\n", compression);
+        }
+
+        let color_samples = match photometric_interpretation {
+            PHOTOMETRIC_WHITE_IS_ZERO | PHOTOMETRIC_BLACK_IS_ZERO => 1,
+            PHOTOMETRIC_RGB => 3,
+            PHOTOMETRIC_SEPARATED if get_value(TAG_INK_SET, Some(INK_SET_CMYK)) == INK_SET_CMYK => 4,
+            _ =>
+                panic!(
+                    "Unsupported TIFF photometric interpretation {}, only grayscale, RGB and CMYK are supported\n",
+                    photometric_interpretation
+                ),
+        };
+        if samples_per_pixel < color_samples {
+            panic!("TIFF image has {} samples per pixel, expected at least {}\n", samples_per_pixel, color_samples);
+        }
+        // the first extra sample is the alpha if it is marked as associated (1) or unassociated (2) alpha
+        let has_alpha = match get_field(TAG_EXTRA_SAMPLES) {
+            Some(field) => samples_per_pixel > color_samples && [1, 2].contains(&reader.get_values(field)[0]),
+            None => false,
+        };
+
+        // the image is split in strips of full rows, or in tiles, which are decoded the same way
+        let (chunk_width, chunk_height, offsets_tag, byte_counts_tag) = match get_field(TAG_TILE_WIDTH) {
+            Some(_) => (
+                get_value(TAG_TILE_WIDTH, None) as usize,
+                get_value(TAG_TILE_LENGTH, None) as usize,
+                TAG_TILE_OFFSETS,
+                TAG_TILE_BYTE_COUNTS,
+            ),
+            None => (
+                width,
+                (get_value(TAG_ROWS_PER_STRIP, Some(u32::MAX)) as usize).min(height),
+                TAG_STRIP_OFFSETS,
+                TAG_STRIP_BYTE_COUNTS,
+            ),
+        };
+        let offsets = reader.get_values(get_field(offsets_tag).expect("TIFF image has no strip or tile offsets\n"));
+        let byte_counts = reader.get_values(
+            get_field(byte_counts_tag).expect("TIFF image has no strip or tile byte counts\n")
+        );
+        let chunks_per_row = width.div_ceil(chunk_width);
+
+        let mut pixels = PixelMatrix::new_with_default(width, height);
+        let mut alpha = PixelMatrix::new_with_default(width, height);
+        // only separated images fill the CMYK samples
+        let separated = photometric_interpretation == PHOTOMETRIC_SEPARATED;
+        let (cmyk_width, cmyk_height) = if separated { (width, height) } else { (0, 0) };
+        let mut cmyk = PixelMatrix::new_with_default(cmyk_width, cmyk_height);
+        let row_size = chunk_width * samples_per_pixel;
+        for (chunk, (offset, byte_count)) in offsets.iter().zip(byte_counts.iter()).enumerate() {
+            let compressed = reader.bytes(*offset as usize, *byte_count as usize);
+            let mut samples = match compression {
+                COMPRESSION_LZW => Self::lzw_decode(compressed),
+                COMPRESSION_PACKBITS => Self::packbits_decode(compressed),
+                _ => compressed.to_vec(),
+            };
+            // the last strip can be shorter, and broken files can have less data than expected
+            samples.resize(row_size * chunk_height, 0);
+
+            let start_row = (chunk / chunks_per_row) * chunk_height;
+            let start_col = (chunk % chunks_per_row) * chunk_width;
+            for (chunk_row, row_samples) in samples.chunks_exact_mut(row_size).enumerate() {
+                // the horizontal predictor stores each sample as the difference with the one of the previous pixel
+                if predictor == PREDICTOR_HORIZONTAL {
+                    for i in samples_per_pixel..row_size {
+                        row_samples[i] = row_samples[i].wrapping_add(row_samples[i - samples_per_pixel]);
+                    }
+                }
+
+                let row = start_row + chunk_row;
+                if row >= height {
+                    break;
+                }
+                for (chunk_col, pixel) in row_samples.chunks_exact(samples_per_pixel).enumerate() {
+                    let col = start_col + chunk_col;
+                    if col >= width {
+                        break;
+                    }
+                    if separated {
+                        cmyk.set_pixel(row, col, (pixel[0], pixel[1], pixel[2], pixel[3]));
+                        continue;
+                    }
+                    let rgb_pixel = match photometric_interpretation {
+                        PHOTOMETRIC_WHITE_IS_ZERO => (255 - pixel[0], 255 - pixel[0], 255 - pixel[0]),
+                        PHOTOMETRIC_BLACK_IS_ZERO => (pixel[0], pixel[0], pixel[0]),
+                        _ => (pixel[0], pixel[1], pixel[2]),
+                    };
+                    pixels.set_pixel(row, col, rgb_pixel);
+                    if has_alpha {
+                        alpha.set_pixel(row, col, pixel[color_samples]);
+                    }
+                }
+            }
+        }
+
+        // the resolution unit defaults to inches, and images without it only give the aspect ratio
+        let density = match (get_field(TAG_X_RESOLUTION), get_field(TAG_Y_RESOLUTION)) {
+            (Some(x_resolution), Some(y_resolution)) => {
+                let unit = match get_value(TAG_RESOLUTION_UNIT, Some(2)) {
+                    1 => DensityUnit::AspectRatio,
+                    3 => DensityUnit::DotsPerCm,
+                    _ => DensityUnit::DotsPerInch,
+                };
+                Some(Density::new(unit, reader.get_rational(x_resolution), reader.get_rational(y_resolution)))
+            }
+            _ => None,
+        };
+
+        // the profile is stored as is, in an UNDEFINED field
+        let icc_profile = get_field(TAG_ICC_PROFILE).map(|field| reader.bytes(field.value_offset, field.count as usize).to_vec());
+
+        TiffImage {
+            pixels,
+            alpha: if has_alpha { Some(alpha) } else { None },
+            cmyk: if separated { Some(cmyk) } else { None },
+            density,
+            icc_profile,
+        }
+    }
+
+    fn read_directory(reader: &TiffReader, offset: usize) -> Vec<(u16, TiffField)> {
+        let entries = reader.read_u16(offset) as usize;
+        (0..entries)
+            .map(|i| {
+                let entry = offset + 2 + i * 12;
+                let field_type = reader.read_u16(entry + 2);
+                let count = reader.read_u32(entry + 4);
+                // values that fit in 4 bytes are stored in the entry itself, the rest somewhere else in the file
+                let type_size = match field_type {
+                    3 | 8 => 2,
+                    4 | 9 | 11 => 4,
+                    5 | 10 | 12 => 8,
+                    _ => 1,
+                };
+                let value_offset = if type_size * (count as usize) <= 4 {
+                    entry + 8
+                } else {
+                    reader.read_u32(entry + 8) as usize
+                };
+                (reader.read_u16(entry), TiffField { field_type, count, value_offset })
+            })
+            .collect()
+    }
+
+    fn packbits_decode(data: &[u8]) -> Vec<u8> {
+        // a header byte n is followed by n + 1 literal bytes if positive, or by one byte repeated 1 - n times if negative
+        let mut output = Vec::new();
+        let mut position = 0;
+        while position < data.len() {
+            let header = data[position] as i8;
+            position += 1;
+            if header >= 0 {
+                let end = (position + (header as usize) + 1).min(data.len());
+                output.extend_from_slice(&data[position..end]);
+                position = end;
+            } else if header != -128 && position < data.len() {
+                output.extend(std::iter::repeat_n(data[position], (1 - (header as i32)) as usize));
+                position += 1;
+            }
+        }
+        output
+    }
+
+    fn lzw_decode(data: &[u8]) -> Vec<u8> {
+        // Codes are read MSB first, starting at 9 bits and growing up to 12, one code earlier than in GIF.
+        // Every string in the table is the previous one plus a byte, and both are already in the output one after
+        // the other, so the table only keeps where each string starts in the output and its length
+        let mut output = Vec::<u8>::new();
+        let mut table = Vec::<(usize, usize)>::new(); // from LZW_FIRST_CODE on
+        let mut previous: Option<(usize, usize)> = None;
+        let mut code_bits = 9;
+        let mut bit_position = 0;
+
+        while bit_position + code_bits as usize <= data.len() * 8 {
+            let mut code = 0usize;
+            for _ in 0..code_bits {
+                let bit = (data[bit_position / 8] >> (7 - (bit_position % 8))) & 1;
+                code = (code << 1) | (bit as usize);
+                bit_position += 1;
+            }
+
+            if code == LZW_END_OF_INFORMATION {
+                break;
+            }
+            if code == LZW_CLEAR_CODE {
+                table.clear();
+                code_bits = 9;
+                previous = None;
+                continue;
+            }
+
+            let start = output.len();
+            if code < LZW_CLEAR_CODE {
+                output.push(code as u8);
+            } else if code - LZW_FIRST_CODE < table.len() {
+                let (string_start, string_length) = table[code - LZW_FIRST_CODE];
+                output.extend_from_within(string_start..string_start + string_length);
+            } else if let (Some((previous_start, previous_length)), true) =
+                (previous, code - LZW_FIRST_CODE == table.len())
+            {
+                // the code that is about to be added: the previous string plus its own first byte
+                output.extend_from_within(previous_start..previous_start + previous_length);
+                output.push(output[previous_start]);
+            } else {
+                panic!("Invalid TIFF LZW code {}\n", code);
+            }
+
+            if let Some((previous_start, previous_length)) = previous {
+                if LZW_FIRST_CODE + table.len() < 1 << LZW_MAX_BITS {
+                    table.push((previous_start, previous_length + 1));
+                }
+                if LZW_FIRST_CODE + table.len() + 1 >= 1 << code_bits && code_bits < LZW_MAX_BITS {
+                    code_bits += 1;
+                }
+            }
+            previous = Some((start, output.len() - start));
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packbits_decode() {
+        // example from the TIFF specification
+        let data = [0xfe, 0xaa, 0x02, 0x80, 0x00, 0x2a, 0xfd, 0xaa, 0x03, 0x80, 0x00, 0x2a, 0x22, 0xf7, 0xaa];
+        let mut expected = vec![0xaa, 0xaa, 0xaa, 0x80, 0x00, 0x2a, 0xaa, 0xaa, 0xaa, 0xaa, 0x80, 0x00, 0x2a, 0x22];
+        expected.extend_from_slice(&[0xaa; 10]);
+        assert_eq!(TiffImage::packbits_decode(&data), expected);
+    }
+
+    #[test]
+    fn test_lzw_decode() {
+        // "ABABABA": the last code (260) is used in the same step it is defined
+        let codes = [LZW_CLEAR_CODE, 65, 66, 258, 260, LZW_END_OF_INFORMATION];
+        let mut data = vec![0u8; (codes.len() * 9).div_ceil(8)];
+        for (i, code) in codes.iter().enumerate() {
+            for bit in 0..9 {
+                if (code >> (8 - bit)) & 1 == 1 {
+                    let position = i * 9 + bit;
+                    data[position / 8] |= 0x80 >> (position % 8);
+                }
+            }
+        }
+        assert_eq!(TiffImage::lzw_decode(&data), b"ABABABA");
+    }
+
+    #[test]
+    fn test_read_strips_and_resolution() {
+        // little endian 2x2 RGB image, in two PackBits strips of one row, at 300 dpi
+        let entries: [(u16, u16, u32, u32); 11] = [
+            (TAG_IMAGE_WIDTH, 3, 1, 2),
+            (TAG_IMAGE_LENGTH, 3, 1, 2),
+            (TAG_COMPRESSION, 3, 1, COMPRESSION_PACKBITS),
+            (TAG_PHOTOMETRIC_INTERPRETATION, 3, 1, PHOTOMETRIC_RGB),
+            (TAG_STRIP_OFFSETS, 4, 2, 200),
+            (TAG_SAMPLES_PER_PIXEL, 3, 1, 3),
+            (TAG_ROWS_PER_STRIP, 3, 1, 1),
+            (TAG_STRIP_BYTE_COUNTS, 4, 2, 208),
+            (TAG_X_RESOLUTION, 5, 1, 216),
+            (TAG_Y_RESOLUTION, 5, 1, 216),
+            (TAG_RESOLUTION_UNIT, 3, 1, 2),
+        ];
+        let mut data = vec![0u8; 240];
+        data[0..4].copy_from_slice(TIFF_LITTLE_ENDIAN_MAGIC);
+        LittleEndian::write_u32(&mut data[4..8], 8);
+        LittleEndian::write_u16(&mut data[8..10], entries.len() as u16);
+        for (i, (tag, field_type, count, value)) in entries.iter().enumerate() {
+            let entry = 10 + i * 12;
+            LittleEndian::write_u16(&mut data[entry..entry + 2], *tag);
+            LittleEndian::write_u16(&mut data[entry + 2..entry + 4], *field_type);
+            LittleEndian::write_u32(&mut data[entry + 4..entry + 8], *count);
+            if *field_type == 3 {
+                LittleEndian::write_u16(&mut data[entry + 8..entry + 10], *value as u16);
+            } else {
+                LittleEndian::write_u32(&mut data[entry + 8..entry + 12], *value);
+            }
+        }
+        // strip offsets and byte counts
+        LittleEndian::write_u32(&mut data[200..204], 224);
+        LittleEndian::write_u32(&mut data[204..208], 232);
+        LittleEndian::write_u32(&mut data[208..212], 7);
+        LittleEndian::write_u32(&mut data[212..216], 3);
+        // 300 / 1
+        LittleEndian::write_u32(&mut data[216..220], 300);
+        LittleEndian::write_u32(&mut data[220..224], 1);
+        // first row is red and green as literals, second row is the same gray pixel repeated
+        data[224..231].copy_from_slice(&[5, 255, 0, 0, 0, 255, 0]);
+        data[232..235].copy_from_slice(&[0xfb, 128, 0]);
+
+        let image = TiffImage::from_bytes(&data);
+        assert_eq!(image.pixels.pixels, vec![(255, 0, 0), (0, 255, 0), (128, 128, 128), (128, 128, 128)]);
+        assert!(image.alpha.is_none());
+        assert_eq!(image.density, Some(Density { unit: DensityUnit::DotsPerInch, x: 300, y: 300 }));
+    }
+}
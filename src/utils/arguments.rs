@@ -1,11 +1,34 @@
-use clap::Parser;
-use crate::jpeg::{ dct_quant::DctAlgorithm, jpeg_image::JpegImage };
+use clap::{ Parser, Subcommand };
+use crate::jpeg::{
+    alpha::{ AlphaMode, AlphaOptions },
+    dct_quant::DctAlgorithm,
+    exif::{ ExifMetadata, is_valid_date_time },
+    entropy_coding::EntropyCoder,
+    jpeg_image::{ Density, DensityUnit, JpegImage },
+    thumbnail::ThumbnailLocation,
+    transform::Transform,
+};
+use crate::decoder::{ idct::IdctAlgorithm, upsampling::Upsampling };
+use crate::netpbm::netpbm_image::HdrConversion;
+use crate::utils::image_format::ImageFormat;
+use crate::yuv::yuv_image::YuvFormat;
+
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 pub struct Args {
-    /// the input image path
-    #[arg(short, long, required = true)]
+    // the commands that work on existing JPEG images, instead of encoding a new one
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// the input image path (BMP, TIFF, TGA, QOI, or Netpbm: PPM, PGM, PAM and PFM), the format is detected from its content
+    #[arg(short, long, required = true, default_value_t = String::new(), hide_default_value = true)]
     pub image: String,
 
     /// the output image path (optional)
@@ -19,6 +42,280 @@ pub struct Args {
     /// DCT algorithm to use: either "RealDct" or "BinDct"
     #[arg(short, long, value_enum, default_value_t = DctAlgorithm::RealDct)]
     pub dct_algorithm: DctAlgorithm,
+
+    /// Reduce the ringing around hard black and white edges, like the ones in text and screenshots
+    #[arg(short = 'r', long, default_value_t = false)]
+    pub deringing: bool,
+
+    /// Use trellis quantization, choosing the coefficients that give the best size/quality trade-off
+    #[arg(short, long, default_value_t = false)]
+    pub trellis: bool,
+
+    /// Suppress small high frequency coefficients in busy regions of the image, where the loss is harder to notice
+    #[arg(short, long, default_value_t = false)]
+    pub adaptive_quantization: bool,
+
+    /// Grayscale BMP mask of the same size as the image: bright areas keep their detail, dark areas get compressed harder (optional)
+    #[arg(short = 'm', long)]
+    pub roi_mask: Option<String>,
+
+    /// Encode losslessly (SOF3) in RGB, predicting each sample with one of the 7 standard predictors (1 to 7) (optional)
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=7))]
+    pub lossless: Option<u8>,
+
+    /// Entropy coder to use: either "Huffman" (baseline, SOF0) or "Arithmetic" (SOF9, smaller but less widely supported)
+    #[arg(short, long, value_enum, default_value_t = EntropyCoder::Huffman, conflicts_with = "lossless")]
+    pub entropy_coder: EntropyCoder,
+
+    /// Bits per sample: 8 (baseline) or 12 (extended, SOF1), 12 bits need a Netpbm input, ideally with 16 bit samples
+    #[arg(short, long, value_parser = parse_precision, default_value = "8", conflicts_with = "lossless")]
+    pub precision: u8,
+
+    /// How the float samples of PFM images are brought into range: "Clamp" clips them to white, "ToneMap" compresses the highlights
+    #[arg(long, value_enum, default_value_t = HdrConversion::ToneMap)]
+    pub hdr_conversion: HdrConversion,
+
+    /// Read the input as a raw YUV frame in this layout, with the size given by --width and --height (optional)
+    #[arg(long, value_enum, requires_all = ["width", "height"], conflicts_with_all = ["lossless", "precision"])]
+    pub yuv_format: Option<YuvFormat>,
+
+    /// Width of the raw YUV frame
+    #[arg(long, requires = "yuv_format")]
+    pub width: Option<i32>,
+
+    /// Height of the raw YUV frame
+    #[arg(long, requires = "yuv_format")]
+    pub height: Option<i32>,
+
+    /// Bytes per row of the Y plane (or of the packed frame) of the raw YUV frame, if its rows are padded (optional)
+    #[arg(long, requires = "yuv_format")]
+    pub stride: Option<usize>,
+
+    /// Pixel density written to the JFIF header, in the format `300` or `300x200` (horizontal x vertical), instead of the one of the input image (optional)
+    #[arg(long, value_parser = parse_density)]
+    pub density: Option<(u16, u16)>,
+
+    /// Unit of --density: "dpi", "dpcm", or "aspect-ratio" when only the proportion between both densities matters
+    #[arg(long, value_enum, default_value_t = DensityUnit::DotsPerInch, requires = "density")]
+    pub density_unit: DensityUnit,
+
+    /// EXIF orientation, from 1 (upright) to 8, telling viewers how to rotate and flip the image (optional)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=8))]
+    pub orientation: Option<u16>,
+
+    /// EXIF date and time, in the format `YYYY:MM:DD HH:MM:SS` (optional)
+    #[arg(long, value_parser = parse_date_time)]
+    pub date_time: Option<String>,
+
+    /// EXIF software name (optional)
+    #[arg(long)]
+    pub software: Option<String>,
+
+    /// EXIF artist (optional)
+    #[arg(long)]
+    pub artist: Option<String>,
+
+    /// EXIF copyright notice (optional)
+    #[arg(long)]
+    pub copyright: Option<String>,
+
+    /// EXIF image description (optional)
+    #[arg(long)]
+    pub description: Option<String>,
+
+    /// XMP packet file to embed (optional)
+    #[arg(long)]
+    pub xmp: Option<String>,
+
+    /// Text written to a comment (COM) segment, can be given several times (optional)
+    #[arg(long)]
+    pub comment: Vec<String>,
+
+    /// Encode CMYK input images (CMYK TIFF or PAM) as YCCK instead of CMYK, which compresses better
+    #[arg(long, default_value_t = false, conflicts_with = "lossless")]
+    pub ycck: bool,
+
+    /// Encode the R, G and B samples as they are, without converting them to YCbCr or subsampling them
+    #[arg(long, default_value_t = false, conflicts_with_all = ["lossless", "yuv_format"])]
+    pub rgb: bool,
+
+    /// Encode a BMP input a few rows at a time, with memory use independent of its height (optional)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["lossless", "roi_mask", "thumbnail", "alpha_output", "ycck"])]
+    pub streaming: bool,
+
+    /// Split the image into tiles of this size, in the format `16384` or `16384x8192` (width x height), each one written to
+    /// its own JPEG (named after the output path, with their row and column) along with a JSON manifest of their positions.
+    /// Needed for images bigger than 65535 pixels, the JPEG limit (optional)
+    #[arg(
+        long,
+        value_parser = parse_tile_size,
+        conflicts_with_all = ["lossless", "streaming", "roi_mask", "thumbnail", "alpha_output", "yuv_format"]
+    )]
+    pub tile_size: Option<(u16, u16)>,
+
+    /// Embed a thumbnail that fits in this size, in the format `160` or `160x120` (width x height) (optional)
+    #[arg(long, value_parser = parse_thumbnail_size)]
+    pub thumbnail: Option<(u16, u16)>,
+
+    /// Where the thumbnail goes: "exif" (IFD1 of the EXIF segment) or "jfxx" (JFIF extension, not for lossless images)
+    #[arg(long, value_enum, default_value_t = ThumbnailLocation::Exif, requires = "thumbnail")]
+    pub thumbnail_location: ThumbnailLocation,
+
+    /// ICC color profile file to embed, instead of the one of the input image (optional)
+    #[arg(long)]
+    pub icc_profile: Option<String>,
+
+    /// Embed an sRGB profile when the input image doesn't have one (and no --icc-profile is given)
+    #[arg(long, default_value_t = false)]
+    pub default_srgb: bool,
+
+    /// Whether the colors of images with an alpha channel are "Straight" or already multiplied by the alpha ("Premultiplied")
+    #[arg(long, value_enum, default_value_t = AlphaMode::Straight)]
+    pub alpha_mode: AlphaMode,
+
+    /// Background color that transparent images are composited over, in the format `RRGGBB`
+    #[arg(long, value_parser = parse_color, default_value = "ffffff")]
+    pub background: (u8, u8, u8),
+
+    /// Write the alpha channel to this path as a grayscale JPEG, instead of compositing the image over the background (optional)
+    #[arg(long)]
+    pub alpha_output: Option<String>,
+
+    // detected from the content of the input image, None for raw YUV frames
+    #[arg(skip)]
+    pub image_format: Option<ImageFormat>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Rotate, flip or crop a JPEG image without decoding it, so no quality is lost
+    Transform(TransformArgs),
+    /// Make a JPEG image smaller by coding its coefficients again with optimized Huffman tables, so the decoded pixels don't change
+    Optimize(OptimizeArgs),
+    /// Lower the quality of a JPEG image by quantizing its coefficients again with coarser tables, without decoding it
+    Requantize(RequantizeArgs),
+    /// Decode a JPEG image to a 24 bit BMP image
+    Decode(DecodeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TransformArgs {
+    /// the input JPEG image path (baseline, extended or progressive with Huffman coding, 8 bits)
+    #[arg(short, long)]
+    pub image: String,
+
+    /// the output image path
+    #[arg(short, long)]
+    pub output: String,
+
+    /// Transformation to apply. The edges that don't fill a whole MCU are trimmed off if they would end up on the other side (optional)
+    #[arg(short, long, value_enum)]
+    pub transform: Option<Transform>,
+
+    /// Area to keep after the transformation, in the format `WxH+X+Y`. The top left corner is moved to the MCU it is in (optional)
+    #[arg(short, long, value_parser = parse_crop)]
+    pub crop: Option<(u16, u16, u16, u16)>,
+}
+
+impl TransformArgs {
+    pub fn print_args(&self) {
+        println!("image: \"{}\"", self.image);
+        println!("output: \"{}\"", self.output);
+        if let Some(transform) = &self.transform {
+            println!("transform: \"{:?}\"", transform);
+        }
+        if let Some((width, height, x, y)) = self.crop {
+            println!("crop: {}x{}+{}+{}", width, height, x, y);
+        }
+        print!("\n");
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct OptimizeArgs {
+    /// the input JPEG image path (baseline, extended or progressive with Huffman coding, 8 bits)
+    #[arg(short, long)]
+    pub image: String,
+
+    /// the output image path
+    #[arg(short, long)]
+    pub output: String,
+
+    /// Write a progressive image, which is shown gradually while it loads and is usually smaller if the image is big (optional)
+    #[arg(short, long, default_value_t = false)]
+    pub progressive: bool,
+
+    /// Remove the metadata segments of the input, except for the ICC profile (optional)
+    #[arg(short, long, default_value_t = false)]
+    pub strip_metadata: bool,
+}
+
+impl OptimizeArgs {
+    pub fn print_args(&self) {
+        println!("image: \"{}\"", self.image);
+        println!("output: \"{}\"", self.output);
+        println!("progressive: {}", self.progressive);
+        println!("strip metadata: {}", self.strip_metadata);
+        print!("\n");
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RequantizeArgs {
+    /// the input JPEG image path (baseline, extended or progressive with Huffman coding, 8 bits)
+    #[arg(short, long)]
+    pub image: String,
+
+    /// the output image path
+    #[arg(short, long)]
+    pub output: String,
+
+    /// Quality of the new quantization tables, from 1 to 100, scaled like in libjpeg. The steps of the input are kept where they are already coarser
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Write a progressive image (optional)
+    #[arg(short, long, default_value_t = false)]
+    pub progressive: bool,
+}
+
+impl RequantizeArgs {
+    pub fn print_args(&self) {
+        println!("image: \"{}\"", self.image);
+        println!("output: \"{}\"", self.output);
+        println!("quality: {}", self.quality);
+        println!("progressive: {}", self.progressive);
+        print!("\n");
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DecodeArgs {
+    /// the input JPEG image path (baseline, extended or progressive with Huffman coding)
+    #[arg(short, long)]
+    pub image: String,
+
+    /// the output BMP image path
+    #[arg(short, long)]
+    pub output: String,
+
+    /// How the subsampled chrominance is taken back to the size of the image
+    #[arg(short, long, value_enum, default_value_t = Upsampling::Fancy)]
+    pub upsampling: Upsampling,
+
+    /// IDCT algorithm to use: "Float" or "Integer"
+    #[arg(short = 'd', long, value_enum, default_value_t = IdctAlgorithm::Integer)]
+    pub idct_algorithm: IdctAlgorithm,
+}
+
+impl DecodeArgs {
+    pub fn print_args(&self) {
+        println!("image: \"{}\"", self.image);
+        println!("output: \"{}\"", self.output);
+        println!("upsampling: \"{:?}\"", self.upsampling);
+        println!("idct algorithm: \"{:?}\"", self.idct_algorithm);
+        print!("\n");
+    }
 }
 
 // Custom parser for subsampling ratio
@@ -42,26 +339,225 @@ fn parse_subsampling_ratio(s: &str) -> Result<(u8, u8, u8), String> {
     }
 }
 
+fn parse_color(s: &str) -> Result<(u8, u8, u8), String> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 {
+        return Err("Color must be in the format RRGGBB".to_string());
+    }
+    let parse_component = |i: usize| {
+        u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| "Color must be in hexadecimal".to_string())
+    };
+    Ok((parse_component(0)?, parse_component(2)?, parse_component(4)?))
+}
+
+fn parse_pair(s: &str, error: &str) -> Result<(u16, u16), String> {
+    // `N` or `NxM`, with a single value used for both
+    let parse_value = |value: &str| match value.parse::<u16>() {
+        Ok(value) if value > 0 => Ok(value),
+        _ => Err(error.to_string()),
+    };
+    match s.split_once('x') {
+        Some((x, y)) => Ok((parse_value(x)?, parse_value(y)?)),
+        None => parse_value(s).map(|value| (value, value)),
+    }
+}
+
+fn parse_density(s: &str) -> Result<(u16, u16), String> {
+    parse_pair(s, "Density values must be integers from 1 to 65535")
+}
+
+fn parse_thumbnail_size(s: &str) -> Result<(u16, u16), String> {
+    parse_pair(s, "Thumbnail size must be integers from 1 to 65535")
+}
+
+fn parse_tile_size(s: &str) -> Result<(u16, u16), String> {
+    parse_pair(s, "Tile size must be integers from 1 to 65535")
+}
+
+fn parse_crop(s: &str) -> Result<(u16, u16, u16, u16), String> {
+    // `WxH+X+Y`, or just `WxH` for the top left corner
+    let error = "Crop area must be in the format WxH+X+Y, with integers from 1 to 65535 for the size";
+    let (size, position) = s.split_once('+').unwrap_or((s, "0+0"));
+    let (width, height) = parse_pair(size, error)?;
+    let (x, y) = position.split_once('+').ok_or(error.to_string())?;
+    let parse_position = |value: &str| value.parse::<u16>().map_err(|_| error.to_string());
+    Ok((width, height, parse_position(x)?, parse_position(y)?))
+}
+
+fn parse_date_time(s: &str) -> Result<String, String> {
+    if is_valid_date_time(s) {
+        Ok(s.to_string())
+    } else {
+        Err("Date and time must be in the format YYYY:MM:DD HH:MM:SS".to_string())
+    }
+}
+
+fn parse_precision(s: &str) -> Result<u8, String> {
+    match s {
+        "8" => Ok(8),
+        "12" => Ok(12),
+        _ => Err("Precision must be either 8 or 12".to_string()),
+    }
+}
+
 impl Args {
     pub fn get_args() -> Args {
         let mut args = Args::parse();
 
-        if !args.image.ends_with(".bmp") {
-            panic!("Input image must be a .bmp file\n");
+        // the commands have their own arguments, which don't need any of the checks below
+        if args.command.is_some() {
+            return args;
+        }
+
+        if args.yuv_format.is_none() {
+            match ImageFormat::detect(&args.image) {
+                Some(image_format) => {
+                    args.image_format = Some(image_format);
+                }
+                None => panic!("Input image must be a BMP, TIFF, TGA, QOI or netpbm image\n"),
+            }
+        }
+
+        if args.lossless.is_some() && (args.yuv_format.is_some() || args.is_netpbm()) {
+            panic!("Lossless mode needs a BMP, TIFF, TGA or QOI input image\n");
+        }
+
+        if args.tile_size.is_some() && args.is_netpbm() {
+            panic!("Tiling needs a BMP, TIFF, TGA or QOI input image\n");
+        }
+
+        if args.streaming && args.image_format != Some(ImageFormat::Bmp) {
+            panic!("Streaming needs a BMP input image\n");
+        }
+
+        if args.precision == 12 && !args.is_netpbm() {
+            panic!("12 bit precision needs a netpbm input image\n");
+        }
+
+        if let Some(roi_mask) = &args.roi_mask {
+            if ImageFormat::detect(roi_mask) != Some(ImageFormat::Bmp) {
+                panic!("Region of interest mask must be a BMP image\n");
+            }
+        }
+
+        if let Some(xmp) = &args.xmp {
+            _ = std::fs::read_to_string(xmp).expect("Could not read XMP file, it must be UTF-8 text!\n");
+        }
+
+        if let Some(icc_profile) = &args.icc_profile {
+            let profile = std::fs::read(icc_profile).expect("Could not open ICC profile file!\n");
+            if !JpegImage::is_valid_icc_profile(&profile) {
+                panic!("{} is not a valid ICC profile\n", icc_profile);
+            }
         }
 
         if args.output.is_empty() {
-            args.output = format!("{}.jpeg", args.image.strip_suffix(".bmp").unwrap());
+            let stem = args.image.rsplit_once('.').map_or(args.image.as_str(), |(stem, _)| stem);
+            args.output = format!("{}.jpeg", stem);
         }
 
         args
     }
 
+    pub fn is_netpbm(&self) -> bool {
+        self.image_format == Some(ImageFormat::Netpbm)
+    }
+
+    pub fn get_density(&self) -> Option<Density> {
+        self.density.map(|(x, y)| Density { unit: self.density_unit, x, y })
+    }
+
+    pub fn get_exif_metadata(&self) -> Option<ExifMetadata> {
+        // the EXIF segment is only written if some field was given
+        let mut exif = ExifMetadata::new();
+        if let Some(orientation) = self.orientation {
+            exif = exif.orientation(orientation);
+        }
+        if let Some(date_time) = &self.date_time {
+            exif = exif.date_time(date_time);
+        }
+        if let Some(software) = &self.software {
+            exif = exif.software(software);
+        }
+        if let Some(artist) = &self.artist {
+            exif = exif.artist(artist);
+        }
+        if let Some(copyright) = &self.copyright {
+            exif = exif.copyright(copyright);
+        }
+        if let Some(description) = &self.description {
+            exif = exif.image_description(description);
+        }
+        if exif == ExifMetadata::new() { None } else { Some(exif) }
+    }
+
+    pub fn get_alpha_options(&self) -> AlphaOptions {
+        let alpha_options = AlphaOptions::new()
+            .mode(self.alpha_mode.clone())
+            .background(self.background);
+        match &self.alpha_output {
+            Some(alpha_output) => alpha_options.alpha_output(alpha_output),
+            None => alpha_options,
+        }
+    }
+
     pub fn print_args(&self) {
         println!("image: \"{}\"", self.image);
+        if let Some(image_format) = &self.image_format {
+            println!("image format: \"{:?}\"", image_format);
+        }
         println!("output: \"{}\"", self.output);
         println!("subsampling ratio: \"{:?}\"", self.subsampling_ratio);
         println!("dct algorithm: \"{:?}\"", self.dct_algorithm);
+        println!("deringing: {}", self.deringing);
+        println!("trellis quantization: {}", self.trellis);
+        println!("adaptive quantization: {}", self.adaptive_quantization);
+        if let Some(roi_mask) = &self.roi_mask {
+            println!("region of interest mask: \"{}\"", roi_mask);
+        }
+        if let Some(predictor) = self.lossless {
+            println!("lossless predictor: {}", predictor);
+        }
+        println!("entropy coder: \"{:?}\"", self.entropy_coder);
+        println!("precision: {} bits", self.precision);
+        if let Some(yuv_format) = &self.yuv_format {
+            println!("yuv format: \"{:?}\"", yuv_format);
+            println!("yuv size: {}x{}", self.width.unwrap(), self.height.unwrap());
+            if let Some(stride) = self.stride {
+                println!("yuv stride: {}", stride);
+            }
+        }
+        if let Some(density) = self.get_density() {
+            println!("density: {}x{} {:?}", density.x, density.y, density.unit);
+        }
+        if let Some(exif) = self.get_exif_metadata() {
+            println!("exif: {:?}", exif);
+        }
+        println!("ycck: {}", self.ycck);
+        println!("rgb: {}", self.rgb);
+        println!("streaming: {}", self.streaming);
+        if let Some((width, height)) = self.tile_size {
+            println!("tile size: {}x{}", width, height);
+        }
+        if let Some((width, height)) = self.thumbnail {
+            println!("thumbnail: {}x{} in {:?}", width, height, self.thumbnail_location);
+        }
+        if let Some(xmp) = &self.xmp {
+            println!("xmp: \"{}\"", xmp);
+        }
+        for comment in &self.comment {
+            println!("comment: \"{}\"", comment);
+        }
+        if let Some(icc_profile) = &self.icc_profile {
+            println!("icc profile: \"{}\"", icc_profile);
+        }
+        println!("default srgb profile: {}", self.default_srgb);
+        println!("hdr conversion: \"{:?}\"", self.hdr_conversion);
+        println!("alpha mode: \"{:?}\"", self.alpha_mode);
+        println!("background: {:?}", self.background);
+        if let Some(alpha_output) = &self.alpha_output {
+            println!("alpha output: \"{}\"", alpha_output);
+        }
         print!("\n");
     }
 }
@@ -2,6 +2,14 @@
 pub type RGBValue = (u8, u8, u8);
 pub type YCbCrValue = (u8, u8, u8);
 
+// for samples with more than 8 bits
+pub type WideRGBValue = (u16, u16, u16);
+pub type WideYCbCrValue = (u16, u16, u16);
+
+// amounts of cyan, magenta, yellow and black ink, 0 being no ink
+pub type CMYKValue = (u8, u8, u8, u8);
+pub type WideCMYKValue = (u16, u16, u16, u16);
+
 pub fn rgb_to_ycbcr((r, g, b): RGBValue) -> YCbCrValue {
     let r: f32 = r as f32;
     let g: f32 = g as f32;
@@ -19,9 +27,25 @@ pub fn ycbcr_to_rgb((y, cb, cr): &YCbCrValue) -> RGBValue {
     let cb: f32 = *cb as f32;
     let cr: f32 = *cr as f32;
 
-    let r: u8 = (y + 1.402 * (cr - 128.0)) as u8;
-    let g: u8 = (y - 0.344136 * (cb - 128.0) - 0.714136 * (cr - 128.0)) as u8;
-    let b: u8 = (y + 1.772 * (cb - 128.0)) as u8;
+    // the casts saturate, so the colors out of the RGB range are clamped
+    let r: u8 = (y + 1.402 * (cr - 128.0)).round() as u8;
+    let g: u8 = (y - 0.344136 * (cb - 128.0) - 0.714136 * (cr - 128.0)).round() as u8;
+    let b: u8 = (y + 1.772 * (cb - 128.0)).round() as u8;
 
     (r, g, b)
 }
+
+pub fn rgb_to_ycbcr_with_precision((r, g, b): WideRGBValue, precision: u8) -> WideYCbCrValue {
+    // same conversion for samples of any amount of bits, with the chrominance centered in the middle of the range
+    let r: f32 = r as f32;
+    let g: f32 = g as f32;
+    let b: f32 = b as f32;
+    let max_value: f32 = ((1u32 << precision) - 1) as f32;
+    let center: f32 = (1u32 << (precision - 1)) as f32;
+
+    let y = (0.299 * r + 0.587 * g + 0.114 * b).round().clamp(0.0, max_value) as u16;
+    let cb = (center - 0.168736 * r - 0.331264 * g + 0.5 * b).round().clamp(0.0, max_value) as u16;
+    let cr = (center + 0.5 * r - 0.418688 * g - 0.081312 * b).round().clamp(0.0, max_value) as u16;
+
+    (y, cb, cr)
+}
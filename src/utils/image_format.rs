@@ -0,0 +1,195 @@
+use std::fs::File;
+use std::io::{ Read, Seek, SeekFrom };
+use crate::bmp::bmp_image::BmpImage;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+use crate::qoi::qoi_image::{ QOI_MAGIC, QoiImage };
+use crate::jpeg::jpeg_image::Density;
+use crate::tga::tga_image::TgaImage;
+use crate::tiff::tiff_image::{ TIFF_BIG_ENDIAN_MAGIC, TIFF_LITTLE_ENDIAN_MAGIC, TiffImage };
+use crate::utils::colorspace::{ CMYKValue, RGBValue };
+
+// TGA 2.0 files end with this signature, older ones are recognized by their header
+const TGA_FOOTER_SIGNATURE: &[u8; 18] = b"TRUEVISION-XFILE.\0";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageFormat {
+    Bmp,
+    Netpbm,
+    Tga,
+    Qoi,
+    Tiff,
+}
+
+// the pixels of an 8 bit image, whatever its format was
+pub struct RgbImage {
+    pub pixels: PixelMatrix<RGBValue>,
+    pub alpha: Option<PixelMatrix<u8>>,
+    pub cmyk: Option<PixelMatrix<CMYKValue>>, // only for CMYK TIFF images, which have their samples here instead of in pixels
+    pub density: Option<Density>, // only for formats that store their resolution
+    pub icc_profile: Option<Vec<u8>>, // only for BMP and TIFF images that embed one
+}
+
+impl RgbImage {
+    pub fn get_size(&self) -> (usize, usize) {
+        match &self.cmyk {
+            Some(cmyk) => (cmyk.width, cmyk.height),
+            None => (self.pixels.width, self.pixels.height),
+        }
+    }
+
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> RgbImage {
+        // a part of the image, with the same metadata
+        RgbImage {
+            pixels: if self.cmyk.is_some() { PixelMatrix::new(0, 0) } else { self.pixels.crop(y, x, width, height) },
+            alpha: self.alpha.as_ref().map(|alpha| alpha.crop(y, x, width, height)),
+            cmyk: self.cmyk.as_ref().map(|cmyk| cmyk.crop(y, x, width, height)),
+            density: self.density.clone(),
+            icc_profile: self.icc_profile.clone(),
+        }
+    }
+}
+
+impl ImageFormat {
+    pub fn detect(path: &String) -> Option<ImageFormat> {
+        // the format is recognized by the content of the file, not by its extension
+        let mut file = File::open(path).expect("Could not open input image file!\n");
+        let mut header = Vec::new();
+        _ = file.by_ref().take(18).read_to_end(&mut header);
+        let mut footer = Vec::new();
+        if file.seek(SeekFrom::End(-(TGA_FOOTER_SIGNATURE.len() as i64))).is_ok() {
+            _ = file.read_to_end(&mut footer);
+        }
+        Self::from_signature(&header, &footer)
+    }
+
+    pub fn from_signature(header: &[u8], footer: &[u8]) -> Option<ImageFormat> {
+        if header.starts_with(b"BM") {
+            return Some(ImageFormat::Bmp);
+        }
+        if header.starts_with(TIFF_LITTLE_ENDIAN_MAGIC) || header.starts_with(TIFF_BIG_ENDIAN_MAGIC) {
+            return Some(ImageFormat::Tiff);
+        }
+        if header.starts_with(QOI_MAGIC) {
+            return Some(ImageFormat::Qoi);
+        }
+        // P1 and P4 are bitmaps, which aren't supported
+        let netpbm_magic_numbers = [b"P2", b"P3", b"P5", b"P6", b"P7", b"PF", b"Pf"];
+        if
+            header.len() >= 3 &&
+            netpbm_magic_numbers.contains(&&[header[0], header[1]]) &&
+            header[2].is_ascii_whitespace()
+        {
+            return Some(ImageFormat::Netpbm);
+        }
+        if footer == TGA_FOOTER_SIGNATURE || TgaImage::is_valid_header(header) {
+            return Some(ImageFormat::Tga);
+        }
+        None
+    }
+
+    pub fn read_dimensions(&self, path: &String) -> (i64, i64) {
+        // the size in the header, so that it can be checked before the pixels are allocated
+        let read_header = || {
+            let file = File::open(path).expect("Could not open input image file!\n");
+            let mut header = Vec::new();
+            _ = file.take(18).read_to_end(&mut header);
+            header
+        };
+        let (width, height) = match self {
+            ImageFormat::Bmp => {
+                let bmp_image = BmpImage::new(path);
+                return (bmp_image.width as i64, bmp_image.height as i64);
+            }
+            ImageFormat::Tga => TgaImage::read_dimensions(&read_header()),
+            ImageFormat::Qoi => QoiImage::read_dimensions(&read_header()),
+            // the directory can be anywhere in the file
+            ImageFormat::Tiff => {
+                TiffImage::read_dimensions(&std::fs::read(path).expect("Could not open TIFF image file!\n"))
+            }
+            ImageFormat::Netpbm => panic!("Netpbm images check their size when they are read\n"),
+        };
+        (width as i64, height as i64)
+    }
+
+    pub fn load_rgb(&self, path: &String) -> RgbImage {
+        // the 8 bit formats, all read into the same pixel matrix, with the alpha channel if the image has one
+        match self {
+            ImageFormat::Bmp => {
+                let mut bmp_image = BmpImage::new(path);
+                bmp_image.load_pixels();
+                RgbImage {
+                    pixels: bmp_image.pixels,
+                    alpha: bmp_image.alpha,
+                    cmyk: None,
+                    density: bmp_image.density,
+                    icc_profile: bmp_image.icc_profile,
+                }
+            }
+            ImageFormat::Tga => {
+                let tga_image = TgaImage::new(path);
+                RgbImage {
+                    pixels: tga_image.pixels,
+                    alpha: tga_image.alpha,
+                    cmyk: None,
+                    density: None,
+                    icc_profile: None,
+                }
+            }
+            ImageFormat::Qoi => {
+                let qoi_image = QoiImage::new(path);
+                RgbImage {
+                    pixels: qoi_image.pixels,
+                    alpha: qoi_image.alpha,
+                    cmyk: None,
+                    density: None,
+                    icc_profile: None,
+                }
+            }
+            ImageFormat::Tiff => {
+                let tiff_image = TiffImage::new(path);
+                RgbImage {
+                    pixels: tiff_image.pixels,
+                    alpha: tiff_image.alpha,
+                    cmyk: tiff_image.cmyk,
+                    density: tiff_image.density,
+                    icc_profile: tiff_image.icc_profile,
+                }
+            }
+            ImageFormat::Netpbm => panic!("Netpbm images are loaded with JpegImage::from_netpbm\n"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(ImageFormat::from_signature(b"BM\x36\x00\x00\x00", b""), Some(ImageFormat::Bmp));
+        assert_eq!(ImageFormat::from_signature(b"qoif\x00\x00\x00\x01", b""), Some(ImageFormat::Qoi));
+        assert_eq!(ImageFormat::from_signature(b"P6\n2 2\n255\n", b""), Some(ImageFormat::Netpbm));
+        assert_eq!(ImageFormat::from_signature(b"Pf\n2 2\n-1.0\n", b""), Some(ImageFormat::Netpbm));
+        assert_eq!(ImageFormat::from_signature(b"P4\n2 2\n", b""), None);
+        assert_eq!(ImageFormat::from_signature(b"MM\0*\0\0\0\x08", b""), Some(ImageFormat::Tiff));
+
+        let mut tga_header = [0u8; 18];
+        tga_header[2] = 10;
+        tga_header[12] = 1;
+        tga_header[14] = 1;
+        tga_header[16] = 24;
+        assert_eq!(ImageFormat::from_signature(&tga_header, b""), Some(ImageFormat::Tga));
+        assert_eq!(ImageFormat::from_signature(&[7u8; 18], TGA_FOOTER_SIGNATURE), Some(ImageFormat::Tga));
+        assert_eq!(ImageFormat::from_signature(&[7u8; 18], b""), None);
+    }
+
+    #[test]
+    fn test_read_dimensions() {
+        // only the header is needed, so sizes over the JPEG limit are found before loading the pixels
+        let path = std::env::temp_dir().join("image_format_test_read_dimensions.qoi");
+        std::fs::write(&path, b"qoif\x00\x01\x11\x70\x00\x00\x00\x02\x04\x00").unwrap();
+        let dimensions = ImageFormat::Qoi.read_dimensions(&path.to_str().unwrap().to_string());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(dimensions, (70000, 2));
+    }
+}
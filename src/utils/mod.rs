@@ -1,3 +1,4 @@
 pub mod arguments;
 pub mod colorspace;
 pub mod bitvec_utils;
+pub mod image_format;
@@ -0,0 +1 @@
+pub mod yuv_image;
@@ -0,0 +1,175 @@
+use clap::ValueEnum;
+use crate::pixel_matrix::pixel_matrix::PixelMatrix;
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub enum YuvFormat {
+    I420, // planar 4:2:0: Y plane, then U plane, then V plane
+    Yv12, // same as I420, with the V plane before the U plane
+    Nv12, // Y plane, then a plane with U and V interleaved at 4:2:0
+    Yuyv, // packed 4:2:2: Y0 U Y1 V for every two pixels
+    Yuv444, // planar 4:4:4: Y, U and V planes of the full size
+}
+
+impl YuvFormat {
+    pub fn get_chroma_subsampling(&self) -> (usize, usize) {
+        // horizontal and vertical factors between the luma and chroma resolutions
+        match self {
+            YuvFormat::I420 | YuvFormat::Yv12 | YuvFormat::Nv12 => (2, 2),
+            YuvFormat::Yuyv => (2, 1),
+            YuvFormat::Yuv444 => (1, 1),
+        }
+    }
+
+    pub fn get_downsampling_ratio(&self) -> (u8, u8, u8) {
+        match self.get_chroma_subsampling() {
+            (2, 2) => (4, 2, 0),
+            (2, 1) => (4, 2, 2),
+            _ => (4, 4, 4),
+        }
+    }
+}
+
+// Raw YUV frames have no header, so the format and dimensions have to be given.
+// The samples are taken as full range (0 to 255), which is what JFIF expects
+pub struct YuvImage {
+    pub width: i32,
+    pub height: i32,
+    pub format: YuvFormat,
+    pub y_plane: PixelMatrix<u8>,
+    pub u_plane: PixelMatrix<u8>, // chroma planes are kept at their own resolution
+    pub v_plane: PixelMatrix<u8>,
+}
+
+impl YuvImage {
+    pub fn new(path: &String, format: YuvFormat, width: i32, height: i32, stride: Option<usize>) -> YuvImage {
+        let data = std::fs::read(path).expect("Could not open raw YUV file!\n");
+        Self::from_bytes(&data, format, width, height, stride)
+    }
+
+    pub fn from_bytes(
+        data: &[u8],
+        format: YuvFormat,
+        width: i32,
+        height: i32,
+        stride: Option<usize> // bytes per row of the Y plane (or of the packed image), when rows are padded
+    ) -> YuvImage {
+        if width <= 0 || height <= 0 {
+            panic!("Invalid raw YUV dimensions {}x{}\n", width, height);
+        }
+        let (width_usize, height_usize) = (width as usize, height as usize);
+
+        let (horizontal_subsampling, vertical_subsampling) = format.get_chroma_subsampling();
+        let chroma_width = width_usize.div_ceil(horizontal_subsampling);
+        let chroma_height = height_usize.div_ceil(vertical_subsampling);
+
+        // packed rows take 4 bytes every two pixels, and the interleaved chroma rows of NV12 share the stride of the Y plane
+        let min_stride = match format {
+            YuvFormat::Yuyv => 4 * chroma_width,
+            YuvFormat::Nv12 => 2 * chroma_width,
+            _ => width_usize,
+        };
+        let stride = stride.unwrap_or(min_stride);
+        if stride < min_stride {
+            panic!("Raw YUV stride {} is smaller than a row of the image ({} bytes)\n", stride, min_stride);
+        }
+
+        // the stride of the chroma planes follows the one of the Y plane
+        // (the packed format has no separate chroma plane)
+        let chroma_stride = match format {
+            YuvFormat::I420 | YuvFormat::Yv12 => stride.div_ceil(2),
+            YuvFormat::Nv12 | YuvFormat::Yuv444 | YuvFormat::Yuyv => stride,
+        };
+        let y_size = stride * height_usize;
+        let expected_size = match format {
+            YuvFormat::I420 | YuvFormat::Yv12 => y_size + 2 * chroma_stride * chroma_height,
+            YuvFormat::Nv12 => y_size + chroma_stride * chroma_height,
+            YuvFormat::Yuv444 => 3 * y_size,
+            YuvFormat::Yuyv => y_size,
+        };
+        if data.len() < expected_size {
+            panic!("Raw YUV data is too short: {} bytes, expected {}\n", data.len(), expected_size);
+        }
+
+        // reads a plane of samples that are `step` bytes apart
+        let read_plane = |start: usize, plane_width: usize, plane_height: usize, plane_stride: usize, step: usize| {
+            let mut plane = PixelMatrix::new(plane_width, plane_height);
+            for i in 0..plane_height {
+                for j in 0..plane_width {
+                    plane.push_next(data[start + i * plane_stride + j * step]);
+                }
+            }
+            plane
+        };
+
+        let (y_plane, u_plane, v_plane) = match format {
+            YuvFormat::I420 | YuvFormat::Yv12 => {
+                let first = read_plane(y_size, chroma_width, chroma_height, chroma_stride, 1);
+                let second = read_plane(y_size + chroma_stride * chroma_height, chroma_width, chroma_height, chroma_stride, 1);
+                let (u_plane, v_plane) = if format == YuvFormat::I420 { (first, second) } else { (second, first) };
+                (read_plane(0, width_usize, height_usize, stride, 1), u_plane, v_plane)
+            }
+            YuvFormat::Nv12 =>
+                (
+                    read_plane(0, width_usize, height_usize, stride, 1),
+                    read_plane(y_size, chroma_width, chroma_height, chroma_stride, 2),
+                    read_plane(y_size + 1, chroma_width, chroma_height, chroma_stride, 2),
+                ),
+            YuvFormat::Yuyv =>
+                (
+                    read_plane(0, width_usize, height_usize, stride, 2),
+                    read_plane(1, chroma_width, chroma_height, stride, 4),
+                    read_plane(3, chroma_width, chroma_height, stride, 4),
+                ),
+            YuvFormat::Yuv444 =>
+                (
+                    read_plane(0, width_usize, height_usize, stride, 1),
+                    read_plane(y_size, width_usize, height_usize, stride, 1),
+                    read_plane(2 * y_size, width_usize, height_usize, stride, 1),
+                ),
+        };
+
+        YuvImage {
+            width,
+            height,
+            format,
+            y_plane,
+            u_plane,
+            v_plane,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_planar_and_interleaved_formats_match() {
+        // 4x2 frame with a stride of 6 bytes: the last 2 bytes of each Y row are padding
+        let y_rows: [u8; 12] = [10, 20, 30, 40, 0, 0, 50, 60, 70, 80, 0, 0];
+        let mut i420 = y_rows.to_vec();
+        i420.extend_from_slice(&[1, 2, 0, 3, 4, 0]); // U and V rows, with a stride of 3
+        let mut yv12 = y_rows.to_vec();
+        yv12.extend_from_slice(&[3, 4, 0, 1, 2, 0]);
+        let mut nv12 = y_rows.to_vec();
+        nv12.extend_from_slice(&[1, 3, 2, 4, 0, 0]);
+
+        for (data, format) in [(i420, YuvFormat::I420), (yv12, YuvFormat::Yv12), (nv12, YuvFormat::Nv12)] {
+            let image = YuvImage::from_bytes(&data, format, 4, 2, Some(6));
+            assert_eq!(image.y_plane.pixels, vec![10, 20, 30, 40, 50, 60, 70, 80]);
+            assert_eq!((image.u_plane.width, image.u_plane.height), (2, 1));
+            assert_eq!(image.u_plane.pixels, vec![1, 2]);
+            assert_eq!(image.v_plane.pixels, vec![3, 4]);
+        }
+    }
+
+    #[test]
+    fn test_read_packed_yuyv() {
+        let data: [u8; 8] = [10, 1, 20, 2, 30, 3, 40, 4];
+        let image = YuvImage::from_bytes(&data, YuvFormat::Yuyv, 2, 2, None);
+        assert_eq!(image.format.get_downsampling_ratio(), (4, 2, 2));
+        assert_eq!(image.y_plane.pixels, vec![10, 20, 30, 40]);
+        assert_eq!(image.u_plane.pixels, vec![1, 3]);
+        assert_eq!(image.v_plane.pixels, vec![2, 4]);
+    }
+}